@@ -1,12 +1,44 @@
 use ats_usb::packet::Packet;
+use ats_usb::packet::PacketData;
 use ats_usb::packet::GeneralConfig;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::io::Cursor;
 use std::io::Read;
 use std::io::Seek;
 use std::io::Error;
+use std::io::Write;
 use std::path::PathBuf;
 
+/// Recordings start with this followed by a flags byte. Older recordings don't have the header
+/// and are read as uncompressed.
+const HEADER_MAGIC: [u8; 4] = *b"ATSR";
+
+/// The rest of the recording after the header is gzip compressed.
+const FLAG_GZIP: u8 = 1 << 0;
+
 pub fn read_file(path: &PathBuf) -> Result<(GeneralConfig, Vec<(i128, Packet)>), Error> {
-    let mut file = std::fs::File::open(path).unwrap();
+    let mut bytes = Vec::new();
+    std::fs::File::open(path)?.read_to_end(&mut bytes)?;
+    if let Some(rest) = bytes.strip_prefix(&HEADER_MAGIC) {
+        let Some((&flags, rest)) = rest.split_first() else {
+            return Err(Error::new(std::io::ErrorKind::UnexpectedEof, "recording header is missing its flags"));
+        };
+        if flags & !FLAG_GZIP != 0 {
+            return Err(Error::new(std::io::ErrorKind::InvalidData, format!("unknown recording flags {flags:#04x}")));
+        }
+        bytes = if flags & FLAG_GZIP != 0 {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(rest).read_to_end(&mut decompressed)?;
+            decompressed
+        } else {
+            rest.to_vec()
+        };
+    }
+    read(Cursor::new(bytes))
+}
+
+pub fn read(mut file: impl Read + Seek) -> Result<(GeneralConfig, Vec<(i128, Packet)>), Error> {
     let mut buf = [0; 170];
     file.read_exact(&mut buf).unwrap();
     let general_config = GeneralConfig::parse(&mut &buf[..], ats_usb::packet::PacketType::ReadConfigResponse).unwrap();
@@ -48,3 +80,117 @@ pub fn read_file(path: &PathBuf) -> Result<(GeneralConfig, Vec<(i128, Packet)>),
     }
     Ok((general_config, packets))
 }
+
+pub fn write_file(path: &PathBuf, general_config: &GeneralConfig, packets: &[(u128, PacketData)], compress: bool) -> Result<(), Error> {
+    let file = std::fs::File::create(path)?;
+    write(file, general_config, packets, compress)
+}
+
+/// Write a recording. If `compress` is set, everything after the header is gzip compressed and
+/// flagged as such in the header, `read_file` will decompress it transparently.
+pub fn write(mut writer: impl Write, general_config: &GeneralConfig, packets: &[(u128, PacketData)], compress: bool) -> Result<(), Error> {
+    let mut bytes = Vec::new();
+
+    general_config.serialize(&mut bytes);
+
+    for (timestamp, packet_data) in packets.iter() {
+        let packet = Packet {
+            data: packet_data.clone(),
+            id: 0,
+        };
+        bytes.extend_from_slice(&timestamp.to_le_bytes());
+        packet.serialize(&mut bytes);
+    }
+
+    writer.write_all(&HEADER_MAGIC)?;
+    writer.write_all(&[if compress { FLAG_GZIP } else { 0 }])?;
+    if compress {
+        let mut encoder = GzEncoder::new(writer, flate2::Compression::default());
+        encoder.write_all(&bytes)?;
+        encoder.finish()?;
+    } else {
+        writer.write_all(&bytes)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use ats_usb::packet::{CombinedMarkersReport, GeneralConfig, MotData, ObjectReport, Packet, PacketData, ScreenId};
+
+    fn packets() -> Vec<(u128, PacketData)> {
+        let mut object_report = ObjectReport::default();
+        object_report.timestamp = 1234;
+        object_report.mot_data_nf[0] = MotData { area: 10, cx: 2000, cy: 1000, ..Default::default() };
+        let mut combined_markers = CombinedMarkersReport::default();
+        combined_markers.nf_points[3] = [4000, 12].into();
+        combined_markers.wf_screen_ids[5] = ScreenId::NONE;
+        vec![
+            (1, PacketData::ObjectReport(object_report)),
+            (2, PacketData::CombinedMarkersReport(combined_markers)),
+        ]
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ats_playback_{}_{name}", std::process::id()))
+    }
+
+    fn assert_packets_eq(packets: &[(u128, PacketData)], read_packets: &[(i128, Packet)]) {
+        assert_eq!(read_packets.len(), packets.len());
+        for ((ts, data), (read_ts, read_pkt)) in packets.iter().zip(read_packets) {
+            assert_eq!(*ts as i128, *read_ts);
+            let mut expected = vec![];
+            Packet { data: data.clone(), id: 0 }.serialize(&mut expected);
+            let mut actual = vec![];
+            read_pkt.serialize(&mut actual);
+            assert_eq!(expected, actual);
+        }
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let packets = packets();
+        for compress in [false, true] {
+            let path = temp_path(&format!("round_trip_{compress}.bin"));
+            super::write_file(&path, &GeneralConfig::default(), &packets, compress).unwrap();
+            let written = std::fs::read(&path).unwrap();
+            let result = super::read_file(&path);
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(written[..4], super::HEADER_MAGIC);
+            assert_eq!(written[4] & super::FLAG_GZIP != 0, compress);
+            let (_, read_packets) = result.unwrap();
+            assert_packets_eq(&packets, &read_packets);
+        }
+    }
+
+    #[test]
+    fn file_without_header() {
+        let packets = packets();
+        let mut bytes = Vec::new();
+        GeneralConfig::default().serialize(&mut bytes);
+        for (timestamp, data) in &packets {
+            bytes.extend_from_slice(&timestamp.to_le_bytes());
+            Packet { data: data.clone(), id: 0 }.serialize(&mut bytes);
+        }
+        let path = temp_path("without_header.bin");
+        std::fs::write(&path, &bytes).unwrap();
+        let result = super::read_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let (_, read_packets) = result.unwrap();
+        assert_packets_eq(&packets, &read_packets);
+    }
+
+    #[test]
+    fn file_unknown_flags() {
+        let path = temp_path("unknown_flags.bin");
+        std::fs::write(&path, [&super::HEADER_MAGIC[..], &[0x80]].concat()).unwrap();
+        let result = super::read_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -1,4 +1,4 @@
-use std::{any::Any, borrow::Cow, io::{BufRead, BufReader, ErrorKind, Read, Write}, net::{Ipv4Addr, TcpStream}, pin::Pin, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, Weak}, task::Poll, time::Duration};
+use std::{any::Any, borrow::Cow, future::Future, io::{BufRead, BufReader, ErrorKind, Read, Write}, net::{Ipv4Addr, TcpStream}, pin::Pin, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, Weak}, task::Poll, time::Duration};
 use anyhow::{anyhow, Context, Result};
 use pin_project::{pin_project, pinned_drop};
 use serial2;
@@ -25,6 +25,8 @@ enum ResponseChannel {
 pub struct UsbDevice {
     to_thread: mpsc::Sender<Packet>,
     thread_state: Weak<State>,
+    /// Shared by every clone, see `set_read_only`.
+    read_only: Arc<AtomicBool>,
 }
 
 struct State {
@@ -64,6 +66,8 @@ num_variants! {
 }
 
 impl StreamType {
+    const ALL: [Self; Self::num_variants()] = [Self::MotData, Self::CombinedMarkers, Self::Accel, Self::Impact];
+
     fn mask(self) -> u8 {
         1 << (self as u8)
     }
@@ -86,6 +90,24 @@ impl std::ops::IndexMut<StreamType> for StreamsActive {
     }
 }
 
+/// A register that didn't read back what was written to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegisterMismatch {
+    pub bank: u8,
+    pub address: u8,
+    pub written: u8,
+    /// `None` if the register didn't respond.
+    pub read: Option<u8>,
+}
+
+/// How a `run_restoring` routine ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoutineOutcome<T> {
+    Finished(T),
+    Aborted,
+    TimedOut,
+}
+
 impl UsbDevice {
     /// Connect to the device using the serial port at `path`. Starts two background threads to
     /// service reads and writes.
@@ -240,11 +262,8 @@ impl UsbDevice {
                         return Err(std::io::Error::new(ErrorKind::BrokenPipe, "disconnected"));
                     }
                     buf.pop();
-                    if buf.contains(&SLIP_FRAME_ESC) {
-                        match decode_slip_frame(&mut buf) {
-                            Err(e) => return Ok(Err(e)),
-                            Ok(_) => (),
-                        };
+                    if let Err(e) = decode_packet_frame(&mut buf) {
+                        return Ok(Err(e));
                     }
                     trace!("read frame len={}", buf.len());
                     Ok(Packet::parse(&mut &buf[..])
@@ -321,6 +340,7 @@ impl UsbDevice {
         Self {
             to_thread: sender,
             thread_state,
+            read_only: Default::default(),
         }
     }
 
@@ -374,24 +394,153 @@ impl UsbDevice {
         Ok(result?)
     }
 
+    /// Responses don't say which request they answer except by request id, which gets reused, so
+    /// a late response to an earlier request can show up instead. Those are discarded and the read
+    /// retried.
     pub async fn read_register(&self, port: Port, bank: u8, address: u8) -> Result<u8> {
+        const ATTEMPTS: usize = 3;
         println!("read_register: bank={} address={}", bank, address);
-        let r = self
-            .request(PacketData::ReadRegister(Register {
-                port,
-                bank,
-                address,
-            }))
-            .await?
-            .read_register_response()
-            .with_context(|| "unexpected response")?;
-        println!("read_register: bank={} address={} data={}", bank, address, r.data);
-        assert_eq!(r.bank, bank);
-        assert_eq!(r.address, address);
-        Ok(r.data)
+        for _ in 0..ATTEMPTS {
+            let r = self
+                .request(PacketData::ReadRegister(Register {
+                    port,
+                    bank,
+                    address,
+                }))
+                .await?
+                .read_register_response()
+                .with_context(|| "unexpected response")?;
+            if r.bank == bank && r.address == address {
+                println!("read_register: bank={} address={} data={}", bank, address, r.data);
+                return Ok(r.data);
+            }
+            warn!(
+                "discarding stale response for bank={:#04x} address={:#04x} while reading bank={bank:#04x} address={address:#04x}",
+                r.bank, r.address,
+            );
+        }
+        Err(anyhow!("no matching response reading bank={bank:#04x} address={address:#04x}"))
+    }
+
+    /// Reads each `(bank, address)` in `registers`, a batch of requests at a time. Registers that
+    /// don't get a response within the timeout are `None`.
+    pub async fn read_registers(&self, port: Port, registers: &[(u8, u8)]) -> Result<Vec<Option<u8>>> {
+        const BATCH_SIZE: usize = 16;
+        const TIMEOUT: Duration = Duration::from_millis(200);
+        let mut values = Vec::with_capacity(registers.len());
+        for batch in registers.chunks(BATCH_SIZE) {
+            let mut pending = Vec::with_capacity(batch.len());
+            for &(bank, address) in batch {
+                let (slot, receiver) = self.get_oneshot_slot()?;
+                self.to_thread.send(Packet {
+                    id: slot.id,
+                    data: PacketData::ReadRegister(Register { port, bank, address }),
+                }).await?;
+                pending.push((bank, address, slot, receiver));
+            }
+            for (bank, address, mut slot, receiver) in pending {
+                let value = match tokio::time::timeout(TIMEOUT, receiver).await {
+                    Ok(Ok(PacketData::ReadRegisterResponse(r))) if r.bank == bank && r.address == address => {
+                        slot.finished = true;
+                        Some(r.data)
+                    }
+                    Ok(Ok(r)) => {
+                        slot.finished = true;
+                        warn!("unexpected response reading bank={bank:#04x} address={address:#04x}: {r:?}");
+                        None
+                    }
+                    Ok(Err(_)) => {
+                        slot.finished = true;
+                        warn!("no response reading bank={bank:#04x} address={address:#04x}");
+                        None
+                    }
+                    Err(_) => {
+                        debug!("timed out reading bank={bank:#04x} address={address:#04x}");
+                        None
+                    }
+                };
+                values.push(value);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Reads every address in `bank`. Addresses that don't get a response within the timeout are
+    /// left out of the result.
+    pub async fn dump_bank(&self, port: Port, bank: u8) -> Result<Vec<(u8, u8)>> {
+        let registers: Vec<_> = (0..=u8::MAX).map(|address| (bank, address)).collect();
+        let values = self.read_registers(port, &registers).await?;
+        Ok(registers.iter().zip(values).filter_map(|(&(_, address), value)| Some((address, value?))).collect())
+    }
+
+    /// Reads back each `(bank, address, data)` in `written` and returns the ones that don't match.
+    pub async fn verify_registers(&self, port: Port, written: &[(u8, u8, u8)]) -> Result<Vec<RegisterMismatch>> {
+        let registers: Vec<_> = written.iter().map(|&(bank, address, _)| (bank, address)).collect();
+        let values = self.read_registers(port, &registers).await?;
+        Ok(written.iter().zip(values).filter_map(|(&(bank, address, written), read)| {
+            (read != Some(written)).then_some(RegisterMismatch { bank, address, written, read })
+        }).collect())
+    }
+
+    /// Runs `routine`, a long running device routine like a self-test or exposure sweep that
+    /// changes the `(bank, address)` `registers` on `port` along the way. Gives up when `abort`
+    /// completes or after `timeout`, a bad link could otherwise hang it forever. However it ends,
+    /// the registers are written back to what they held before, so the device isn't left in a
+    /// test state. `abort` is usually the receiving end of a oneshot sent by an abort button.
+    pub async fn run_restoring<T>(
+        &self,
+        port: Port,
+        registers: &[(u8, u8)],
+        timeout: Duration,
+        abort: impl Future<Output = ()>,
+        routine: impl Future<Output = Result<T>>,
+    ) -> Result<RoutineOutcome<T>> {
+        // Don't start what can't be undone
+        let saved = self.read_registers(port, registers).await?;
+        let saved = registers.iter().zip(saved).map(|(&(bank, address), value)| {
+            let value = value.with_context(|| format!("couldn't save bank={bank:#04x} address={address:#04x}"))?;
+            Ok((bank, address, value))
+        }).collect::<Result<Vec<_>>>()?;
+
+        let outcome = tokio::select! {
+            r = routine => r.map(RoutineOutcome::Finished),
+            _ = abort => Ok(RoutineOutcome::Aborted),
+            _ = sleep(timeout) => Ok(RoutineOutcome::TimedOut),
+        };
+        for &(bank, address, data) in &saved {
+            self.write_register(port, bank, address, data).await?;
+        }
+        tokio::try_join!(
+            self.set_bank1_sync_updated(port, 1),
+            self.set_bank0_sync_updated(port, 1),
+        )?;
+        outcome
+    }
+
+    /// While set, register writes, config writes and flashing are dropped with a warning
+    /// instead of being sent, for inspecting a device without risk of changing it. Applies to
+    /// every clone of this device.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Warns and returns true if `what` shouldn't be sent because of `set_read_only`.
+    fn write_blocked(&self, what: std::fmt::Arguments) -> bool {
+        let blocked = self.read_only();
+        if blocked {
+            warn!("read-only mode, not sending {what}");
+        }
+        blocked
     }
 
     pub async fn write_register(&self, port: Port, bank: u8, address: u8, data: u8) -> Result<()> {
+        if self.write_blocked(format_args!("write bank={bank:#04x} address={address:#04x} data={data:#04x}")) {
+            return Ok(());
+        }
         let data = PacketData::WriteRegister(WriteRegister {
             port,
             bank,
@@ -417,6 +566,9 @@ impl UsbDevice {
     }
 
     pub async fn write_config(&self, config: GeneralWriteConfig) -> Result<()> {
+        if self.write_blocked(format_args!("config write")) {
+            return Ok(());
+        }
         let data = PacketData::WriteConfig(config);
         let pkt = Packet {
             id: 255,
@@ -474,7 +626,62 @@ impl UsbDevice {
         Ok(self.stream(StreamType::Impact).await?.filter_map(|x| x.impact_report()))
     }
 
+    /// Tells the device to stop every stream that is active on this connection, so it isn't left
+    /// streaming after the app exits. The connection itself closes once every `UsbDevice` clone
+    /// is dropped.
+    pub async fn shutdown(&self) -> Result<()> {
+        let Some(thread_state) = self.thread_state.upgrade() else {
+            return Ok(());
+        };
+        for stream_type in StreamType::ALL {
+            if thread_state.streams_active[stream_type].load(Ordering::Relaxed) {
+                debug!("stopping {stream_type:?} stream");
+                self.to_thread.send(Packet {
+                    id: 255,
+                    data: PacketData::StreamUpdate(StreamUpdate { mask: stream_type.mask(), active: false }),
+                }).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Overwrites the device uuid, for provisioning. Nothing is sent unless `confirmation` is the
+    /// new uuid typed out again as `format_uuid` writes it, so a stray call or a typo can't
+    /// renumber a device. Needs firmware that knows the provisional `WriteUuid` packet. It isn't
+    /// acked, so the config is read back to check the new uuid took.
+    #[cfg(feature = "experimental-write-uuid")]
+    pub async fn write_uuid(&self, uuid: [u8; 6], confirmation: &str) -> Result<()> {
+        use crate::packet::format_uuid;
+        let formatted = format_uuid(&uuid);
+        if confirmation.trim() != formatted {
+            return Err(anyhow!("uuid confirmation \"{}\" doesn't match {formatted}", confirmation.trim()));
+        }
+        if self.write_blocked(format_args!("write uuid {formatted}")) {
+            return Ok(());
+        }
+        warn!("writing device uuid {formatted}");
+        self.to_thread.send(Packet {
+            id: 255,
+            data: PacketData::WriteUuid(uuid),
+        }).await?;
+        let reported = self.read_config().await?.uuid;
+        if reported != uuid {
+            return Err(anyhow!("device reports uuid {} after writing {formatted}", format_uuid(&reported)));
+        }
+        Ok(())
+    }
+
+    /// Always fails, there's no packet to write the uuid with without the
+    /// `experimental-write-uuid` feature.
+    #[cfg(not(feature = "experimental-write-uuid"))]
+    pub async fn write_uuid(&self, _uuid: [u8; 6], _confirmation: &str) -> Result<()> {
+        Err(anyhow!("writing the uuid needs the experimental-write-uuid feature"))
+    }
+
     pub async fn flash_settings(&self) -> Result<()> {
+        if self.write_blocked(format_args!("flash settings")) {
+            return Ok(());
+        }
         self.to_thread.send(Packet {
             id: 255,
             data: PacketData::FlashSettings(),
@@ -538,6 +745,16 @@ pub fn decode_slip_frame(buf: &mut Vec<u8>) -> Result<()> {
     Ok(())
 }
 
+/// Like `decode_slip_frame`, but also fails frames too short to hold a packet header so they can be
+/// skipped instead of handed to `Packet::parse`.
+pub fn decode_packet_frame(buf: &mut Vec<u8>) -> Result<()> {
+    decode_slip_frame(buf)?;
+    if buf.len() < Packet::HEADER_LEN {
+        anyhow::bail!("{} byte frame is shorter than a packet header", buf.len());
+    }
+    Ok(())
+}
+
 #[pin_project(PinnedDrop)]
 pub struct PacketStream {
     stream_type: StreamType,
@@ -585,11 +802,16 @@ macro_rules! read_register_spec {
 }
 
 macro_rules! write_register_spec {
-    ($name:ident : $ty:ty = $bank:literal; [$($addr:literal),*]) => {
-        pub async fn $name(&self, port: Port, value: $ty) -> ::anyhow::Result<()> {
+    ($name:ident, $registers:ident : $ty:ty = $bank:literal; [$($addr:literal),*]) => {
+        /// The `(bank, address, data)` register writes for `value`.
+        pub fn $registers(value: $ty) -> ::std::vec::Vec<(u8, u8, u8)> {
             let bytes = <$ty>::to_le_bytes(value);
-            for (byte, addr) in ::std::iter::zip(bytes, [$($addr),*]) {
-                self.write_register(port, $bank, addr, byte).await?;
+            ::std::iter::zip(bytes, [$($addr),*]).map(|(byte, addr)| ($bank, addr, byte)).collect()
+        }
+
+        pub async fn $name(&self, port: Port, value: $ty) -> ::anyhow::Result<()> {
+            for (bank, addr, byte) in Self::$registers(value) {
+                self.write_register(port, bank, addr, byte).await?;
             }
             Ok(())
         }
@@ -600,40 +822,324 @@ impl UsbDevice {
     read_register_spec!(product_id: u16 = 0x00; [0x02, 0x03]);
     read_register_spec!(resolution_x: u16 = 0x0c; [0x60, 0x61]);
     read_register_spec!(resolution_y: u16 = 0x0c; [0x62, 0x63]);
-    write_register_spec!(set_resolution_x: u16 = 0x0c; [0x60, 0x61]);
-    write_register_spec!(set_resolution_y: u16 = 0x0c; [0x62, 0x63]);
+    write_register_spec!(set_resolution_x, resolution_x_registers: u16 = 0x0c; [0x60, 0x61]);
+    write_register_spec!(set_resolution_y, resolution_y_registers: u16 = 0x0c; [0x62, 0x63]);
     read_register_spec!(gain_1: u8 = 0x01; [0x05]); // B_global
     read_register_spec!(gain_2: u8 = 0x01; [0x06]); // B_ggh
-    write_register_spec!(set_gain_1: u8 = 0x0c; [0x0b]); // B_global
-    write_register_spec!(set_gain_2: u8 = 0x0c; [0x0c]); // B_ggh
+    write_register_spec!(set_gain_1, gain_1_registers: u8 = 0x0c; [0x0b]); // B_global
+    write_register_spec!(set_gain_2, gain_2_registers: u8 = 0x0c; [0x0c]); // B_ggh
     read_register_spec!(exposure_time: u16 = 0x01; [0x0e, 0x0f]);
-    write_register_spec!(set_exposure_time: u16 = 0x0c; [0x0f, 0x10]);
+    write_register_spec!(set_exposure_time, exposure_time_registers: u16 = 0x0c; [0x0f, 0x10]);
     read_register_spec!(brightness_threshold: u8 = 0x0c; [0x47]);
-    write_register_spec!(set_brightness_threshold: u8 = 0x0c; [0x47]);
+    write_register_spec!(set_brightness_threshold, brightness_threshold_registers: u8 = 0x0c; [0x47]);
     read_register_spec!(noise_threshold: u8 = 0x00; [0x0f]);
-    write_register_spec!(set_noise_threshold: u8 = 0x00; [0x0f]);
+    write_register_spec!(set_noise_threshold, noise_threshold_registers: u8 = 0x00; [0x0f]);
     read_register_spec!(area_threshold_max: u16 = 0x00; [0x0b, 0x0c]);
-    write_register_spec!(set_area_threshold_max: u16 = 0x00; [0x0b, 0x0c]);
+    write_register_spec!(set_area_threshold_max, area_threshold_max_registers: u16 = 0x00; [0x0b, 0x0c]);
     read_register_spec!(area_threshold_min: u8 = 0x0c; [0x46]);
-    write_register_spec!(set_area_threshold_min: u8 = 0x0c; [0x46]);
+    write_register_spec!(set_area_threshold_min, area_threshold_min_registers: u8 = 0x0c; [0x46]);
     read_register_spec!(operation_mode: u8 = 0x00; [0x12]);
-    write_register_spec!(set_operation_mode: u8 = 0x00; [0x12]);
+    write_register_spec!(set_operation_mode, operation_mode_registers: u8 = 0x00; [0x12]);
     read_register_spec!(max_object_cnt: u8 = 0x00; [0x19]);
-    write_register_spec!(set_max_object_cnt: u8 = 0x00; [0x19]);
+    write_register_spec!(set_max_object_cnt, max_object_cnt_registers: u8 = 0x00; [0x19]);
     read_register_spec!(frame_subtraction: u8 = 0x00; [0x28]);
-    write_register_spec!(set_frame_subtraction: u8 = 0x00; [0x28]);
+    write_register_spec!(set_frame_subtraction, frame_subtraction_registers: u8 = 0x00; [0x28]);
     read_register_spec!(frame_period: u32 = 0x0c; [0x07, 0x08, 0x09]);
-    write_register_spec!(set_frame_period: u32 = 0x0c; [0x07, 0x08, 0x09]);
-    write_register_spec!(set_bank1_sync_updated: u8 = 0x01; [0x01]);
-    write_register_spec!(set_bank0_sync_updated: u8 = 0x00; [0x01]);
+    write_register_spec!(set_frame_period, frame_period_registers: u32 = 0x0c; [0x07, 0x08, 0x09]);
+    write_register_spec!(set_bank1_sync_updated, bank1_sync_updated_registers: u8 = 0x01; [0x01]);
+    write_register_spec!(set_bank0_sync_updated, bank0_sync_updated_registers: u8 = 0x00; [0x01]);
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{io::{ErrorKind, Read, Write}, sync::{Arc, Mutex}, time::Duration};
+
+    use crate::packet::{Packet, PacketData, Port, ReadRegisterResponse, StreamUpdate};
+
+    use super::{RegisterMismatch, RoutineOutcome, StreamType, UsbDevice};
+
     #[test]
     fn test_decode_slip() {
         let mut slip_encoded = vec![0x01, 0xDB, 0xDC, 0xDB, 0xDD];
         super::decode_slip_frame(&mut slip_encoded).unwrap();
         assert_eq!([0x01, 0xC0, 0xDB], slip_encoded[..]);
     }
+
+    #[test]
+    fn test_decode_short_packet_frame() {
+        let mut frame = vec![0x01, 0x02];
+        assert!(super::decode_packet_frame(&mut frame).is_err());
+        // Only 2 bytes once the escape is decoded
+        let mut frame = vec![0x01, 0xDB, 0xDC];
+        assert!(super::decode_packet_frame(&mut frame).is_err());
+        let mut frame = vec![0x02, 0x00, 0x01, 0x07];
+        super::decode_packet_frame(&mut frame).unwrap();
+        assert_eq!(frame, [0x02, 0x00, 0x01, 0x07]);
+    }
+
+    /// A device that never says anything.
+    struct SilentReader;
+
+    impl Read for SilentReader {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            std::thread::sleep(Duration::from_millis(10));
+            Err(ErrorKind::TimedOut.into())
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_streams() {
+        let written = SharedWriter::default();
+        let device = UsbDevice::new(SilentReader, written.clone(), false);
+        let _accel = device.stream(StreamType::Accel).await.unwrap();
+        let _impact = device.stream(StreamType::Impact).await.unwrap();
+        device.shutdown().await.unwrap();
+
+        let stop_packet = |stream_type: StreamType| {
+            let mut buf = vec![0xff];
+            Packet {
+                id: 255,
+                data: PacketData::StreamUpdate(StreamUpdate { mask: stream_type.mask(), active: false }),
+            }.serialize(&mut buf);
+            buf
+        };
+        let contains = |needle: &[u8]| written.0.lock().unwrap().windows(needle.len()).any(|w| w == needle);
+
+        // The writer thread sends packets asynchronously
+        for _ in 0..100 {
+            if contains(&stop_packet(StreamType::Accel)) && contains(&stop_packet(StreamType::Impact)) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(contains(&stop_packet(StreamType::Accel)));
+        assert!(contains(&stop_packet(StreamType::Impact)));
+        assert!(!contains(&stop_packet(StreamType::MotData)));
+    }
+
+    /// Answers register reads for a single bank, except for addresses listed in `silent`. The
+    /// first `stale_replies` reads are answered for the wrong address, like a late response to an
+    /// earlier request. Registers read back as three times their address until written.
+    struct MockRegisterDevice {
+        bank: u8,
+        silent: Vec<u8>,
+        stale_replies: usize,
+        to_reader: std::sync::mpsc::Sender<Vec<u8>>,
+        written: std::collections::HashMap<u8, u8>,
+    }
+
+    impl Write for MockRegisterDevice {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let pkt = Packet::parse(&mut &buf[1..]).unwrap();
+            if let PacketData::WriteRegister(w) = &pkt.data {
+                if w.bank == self.bank {
+                    self.written.insert(w.address, w.data);
+                }
+            }
+            if let PacketData::ReadRegister(r) = pkt.data {
+                if r.bank == self.bank && !self.silent.contains(&r.address) {
+                    let address = if self.stale_replies > 0 {
+                        self.stale_replies -= 1;
+                        r.address.wrapping_add(1)
+                    } else {
+                        r.address
+                    };
+                    let mut reply = vec![];
+                    Packet {
+                        id: pkt.id,
+                        data: PacketData::ReadRegisterResponse(ReadRegisterResponse {
+                            bank: r.bank,
+                            address,
+                            data: self.written.get(&address).copied().unwrap_or(address.wrapping_mul(3)),
+                        }),
+                    }.serialize(&mut reply);
+                    super::encode_slip_frame(&mut reply);
+                    self.to_reader.send(reply).unwrap();
+                }
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct MockReader {
+        from_writer: std::sync::mpsc::Receiver<Vec<u8>>,
+        pending: std::collections::VecDeque<u8>,
+    }
+
+    impl Read for MockReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.pending.is_empty() {
+                match self.from_writer.recv_timeout(Duration::from_millis(10)) {
+                    Ok(bytes) => self.pending.extend(bytes),
+                    Err(_) => return Err(ErrorKind::TimedOut.into()),
+                }
+            }
+            self.pending.read(buf)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_only_drops_writes() {
+        let written = SharedWriter::default();
+        let device = UsbDevice::new(SilentReader, written.clone(), false);
+        device.clone().set_read_only(true);
+        assert!(device.read_only());
+
+        device.write_register(Port::Nf, 0x0c, 0x47, 0x20).await.unwrap();
+        device.set_brightness_threshold(Port::Wf, 0x30).await.unwrap();
+        device.flash_settings().await.unwrap();
+        #[cfg(feature = "experimental-write-uuid")]
+        device.write_uuid([1, 2, 3, 4, 5, 6], "01:02:03:04:05:06").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(written.0.lock().unwrap().is_empty());
+
+        device.set_read_only(false);
+        device.write_register(Port::Nf, 0x0c, 0x47, 0x20).await.unwrap();
+        for _ in 0..100 {
+            if !written.0.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!written.0.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dump_bank() {
+        let (to_reader, from_writer) = std::sync::mpsc::channel();
+        let reader = MockReader { from_writer, pending: Default::default() };
+        let writer = MockRegisterDevice { bank: 0x0c, silent: vec![0x10, 0x80], stale_replies: 0, to_reader, written: Default::default() };
+        let device = UsbDevice::new(reader, writer, false);
+
+        let values = device.dump_bank(Port::Nf, 0x0c).await.unwrap();
+        assert_eq!(values.len(), 254);
+        assert!(values.iter().all(|&(address, value)| value == address.wrapping_mul(3)));
+        assert!(!values.iter().any(|&(address, _)| address == 0x10 || address == 0x80));
+
+        let values = device.dump_bank(Port::Nf, 0x01).await.unwrap();
+        assert!(values.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_read_register_discards_stale_response() {
+        let (to_reader, from_writer) = std::sync::mpsc::channel();
+        let reader = MockReader { from_writer, pending: Default::default() };
+        let writer = MockRegisterDevice { bank: 0x0c, silent: vec![], stale_replies: 1, to_reader, written: Default::default() };
+        let device = UsbDevice::new(reader, writer, false);
+
+        assert_eq!(device.read_register(Port::Nf, 0x0c, 0x47).await.unwrap(), 0x47u8.wrapping_mul(3));
+
+        let (to_reader, from_writer) = std::sync::mpsc::channel();
+        let reader = MockReader { from_writer, pending: Default::default() };
+        let writer = MockRegisterDevice { bank: 0x0c, silent: vec![], stale_replies: 10, to_reader, written: Default::default() };
+        let device = UsbDevice::new(reader, writer, false);
+        assert!(device.read_register(Port::Nf, 0x0c, 0x47).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_registers() {
+        let (to_reader, from_writer) = std::sync::mpsc::channel();
+        let reader = MockReader { from_writer, pending: Default::default() };
+        let writer = MockRegisterDevice { bank: 0x0c, silent: vec![0x10], stale_replies: 0, to_reader, written: Default::default() };
+        let device = UsbDevice::new(reader, writer, false);
+
+        let written = [
+            (0x0c, 0x07, 0x15),
+            (0x0c, 0x08, 0x42), // reads back 0x18
+            (0x0c, 0x10, 0x30), // doesn't respond
+            (0x0c, 0x47, 0xd5),
+        ];
+        let mismatches = device.verify_registers(Port::Nf, &written).await.unwrap();
+        assert_eq!(mismatches, [
+            RegisterMismatch { bank: 0x0c, address: 0x08, written: 0x42, read: Some(0x18) },
+            RegisterMismatch { bank: 0x0c, address: 0x10, written: 0x30, read: None },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_run_restoring_aborted() {
+        let (to_reader, from_writer) = std::sync::mpsc::channel();
+        let reader = MockReader { from_writer, pending: Default::default() };
+        let writer = MockRegisterDevice { bank: 0x0c, silent: vec![], stale_replies: 0, to_reader, written: Default::default() };
+        let device = UsbDevice::new(reader, writer, false);
+
+        let exposure: Vec<_> = UsbDevice::exposure_time_registers(0).iter().map(|&(bank, address, _)| (bank, address)).collect();
+        let original = device.read_registers(Port::Nf, &exposure).await.unwrap();
+        let (abort_tx, abort_rx) = tokio::sync::oneshot::channel::<()>();
+        let mut abort_tx = Some(abort_tx);
+        let outcome = device.run_restoring(
+            Port::Nf,
+            &exposure,
+            Duration::from_secs(10),
+            async { let _ = abort_rx.await; },
+            async {
+                device.set_exposure_time(Port::Nf, 0x1234).await?;
+                // Wait for the write to land, then hang like a routine stuck on a bad link
+                while device.read_registers(Port::Nf, &exposure).await? == original {}
+                abort_tx.take().unwrap().send(()).unwrap();
+                std::future::pending::<anyhow::Result<()>>().await
+            },
+        ).await.unwrap();
+        assert_eq!(outcome, RoutineOutcome::Aborted);
+        assert_eq!(device.read_registers(Port::Nf, &exposure).await.unwrap(), original);
+
+        let outcome = device.run_restoring(
+            Port::Nf,
+            &exposure,
+            Duration::from_millis(50),
+            std::future::pending(),
+            async {
+                device.set_exposure_time(Port::Nf, 0x4321).await?;
+                std::future::pending::<anyhow::Result<()>>().await
+            },
+        ).await.unwrap();
+        assert_eq!(outcome, RoutineOutcome::TimedOut);
+        assert_eq!(device.read_registers(Port::Nf, &exposure).await.unwrap(), original);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "experimental-write-uuid")]
+    async fn test_write_uuid_needs_confirmation() {
+        let written = SharedWriter::default();
+        let device = UsbDevice::new(SilentReader, written.clone(), false);
+        let uuid = [0x2a, 0x45, 0x03, 0x07, 0x09, 0x0d];
+        assert!(device.write_uuid(uuid, "").await.is_err());
+        assert!(device.write_uuid(uuid, "2a:45:03:07:09:0e").await.is_err());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(written.0.lock().unwrap().is_empty());
+
+        // Nothing answers the read back, so it doesn't finish
+        let write = tokio::spawn({
+            let device = device.clone();
+            async move { device.write_uuid(uuid, "2a:45:03:07:09:0d").await }
+        });
+        let mut expected = vec![0xff];
+        Packet { id: 255, data: PacketData::WriteUuid(uuid) }.serialize(&mut expected);
+        for _ in 0..100 {
+            if written.0.lock().unwrap().windows(expected.len()).any(|w| w == expected) {
+                write.abort();
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("uuid packet not sent");
+    }
 }
@@ -26,6 +26,8 @@ pub enum PacketData {
     ImpactReport(ImpactReport),
     StreamUpdate(StreamUpdate),
     FlashSettings(),
+    #[cfg(feature = "experimental-write-uuid")]
+    WriteUuid([u8; 6]),
 }
 
 #[cfg_attr(feature = "pyo3", pyo3::pyclass(get_all))]
@@ -88,6 +90,17 @@ impl MarkerPattern {
     }
 }
 
+/// How the distortion coefficients of a camera model are interpreted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CameraModelType {
+    /// OpenCV's plumb bob model, k1, k2, p1, p2, k3.
+    #[default]
+    Pinhole,
+    /// OpenCV's equidistant fisheye model. Its k1 to k4 are kept in the first four coefficients
+    /// and the fifth is always 0.
+    Fisheye,
+}
+
 #[cfg_attr(feature = "pyo3", pyo3::pyclass)]
 #[derive(Clone, Debug)]
 pub struct GeneralConfig {
@@ -95,6 +108,10 @@ pub struct GeneralConfig {
     pub accel_odr: u16,
     pub camera_model_nf: RosOpenCvIntrinsics<f32>,
     pub camera_model_wf: RosOpenCvIntrinsics<f32>,
+    /// Not stored on the device, `parse` always gives `Pinhole`.
+    pub camera_model_type_nf: CameraModelType,
+    /// Not stored on the device, `parse` always gives `Pinhole`.
+    pub camera_model_type_wf: CameraModelType,
     pub stereo_iso: Isometry3<f32>,
     pub uuid: [u8; 6],
 }
@@ -109,6 +126,25 @@ pub struct GeneralWriteConfig {
     pub stereo_iso: Isometry3<f32>,
 }
 
+/// Formats a device uuid as six colon separated hex bytes, e.g. `2a:45:03:07:09:0d`.
+pub fn format_uuid(uuid: &[u8; 6]) -> String {
+    uuid.map(|b| format!("{b:02x}")).join(":")
+}
+
+/// Parses a uuid as formatted by `format_uuid`. The colons are optional, twelve hex digits work
+/// too.
+pub fn parse_uuid(s: &str) -> Option<[u8; 6]> {
+    let digits: String = s.trim().split(':').collect();
+    if digits.len() != 12 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut uuid = [0; 6];
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(uuid)
+}
+
 impl Default for GeneralConfig {
     fn default() -> Self {
         Self {
@@ -116,6 +152,8 @@ impl Default for GeneralConfig {
             accel_odr: 100,
             camera_model_nf: RosOpenCvIntrinsics::from_params(145., 0., 145., 45., 45.),
             camera_model_wf: RosOpenCvIntrinsics::from_params(34., 0., 34., 45., 45.),
+            camera_model_type_nf: CameraModelType::Pinhole,
+            camera_model_type_wf: CameraModelType::Pinhole,
             stereo_iso: Isometry3::identity(),
             uuid: [0; 6],
         }
@@ -158,8 +196,37 @@ pub struct ObjectReport {
 pub struct CombinedMarkersReport {
     pub nf_points: [Point2<u16>; 16],
     pub wf_points: [Point2<u16>; 16],
-    pub nf_screen_ids: [u8; 16],
-    pub wf_screen_ids: [u8; 16],
+    pub nf_screen_ids: [ScreenId; 16],
+    pub wf_screen_ids: [ScreenId; 16],
+}
+
+/// Screen id of a point in a `CombinedMarkersReport`. Sent as 3 bits, with the highest value
+/// reserved for slots that don't contain a marker.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ScreenId(pub u8);
+
+impl ScreenId {
+    pub const BITS: u32 = 3;
+    const MASK: u8 = (1 << Self::BITS) - 1;
+    /// Reported for slots that don't contain a marker.
+    pub const NONE: Self = Self(Self::MASK);
+
+    pub fn is_valid(self) -> bool {
+        self.0 < Self::NONE.0
+    }
+
+    /// Every screen id that identifies an actual marker.
+    pub fn valid() -> impl Iterator<Item = Self> {
+        (0..Self::NONE.0).map(Self)
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        Self(bits & Self::MASK)
+    }
+
+    fn to_bits(self) -> u8 {
+        self.0 & Self::MASK
+    }
 }
 
 #[cfg_attr(feature = "pyo3", pyo3::pyclass)]
@@ -189,6 +256,7 @@ pub enum Error {
     UnrecognizedPacketId,
     UnrecognizedPort,
     UnrecognizedMarkerPattern,
+    BadChecksum,
 }
 
 impl Display for Error {
@@ -200,6 +268,7 @@ impl Display for Error {
             S::UnrecognizedPacketId => write!(f, "unrecognized packet id"),
             S::UnrecognizedPort => write!(f, "unrecognized port"),
             S::UnrecognizedMarkerPattern => write!(f, "unrecognized marker pattern"),
+            S::BadChecksum => write!(f, "bad checksum"),
         }
     }
 }
@@ -241,6 +310,9 @@ pub enum PacketType {
     StreamUpdate,
     FlashSettings,
     End,
+    /// Provisional, the firmware protocol doesn't define it yet.
+    #[cfg(feature = "experimental-write-uuid")]
+    WriteUuid,
 }
 
 impl TryFrom<u8> for PacketType {
@@ -261,12 +333,34 @@ impl TryFrom<u8> for PacketType {
             11 => Ok(Self::StreamUpdate),
             12 => Ok(Self::FlashSettings),
             13 => Ok(Self::End),
+            #[cfg(feature = "experimental-write-uuid")]
+            14 => Ok(Self::WriteUuid),
             _ => Err(Error::UnrecognizedPacketId),
         }
     }
 }
 
+/// CRC-16/CCITT-FALSE, polynomial 0x1021 starting from 0xffff.
+pub fn crc16_ccitt(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for &b in data {
+        crc ^= u16::from(b) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { crc << 1 ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
 impl Packet {
+    /// Length, type and id, the least `parse` needs to make anything of a packet.
+    pub const HEADER_LEN: usize = 4;
+
+    /// Set in the header's word count when a little-endian `crc16_ccitt` of the header and payload
+    /// follows the packet. The word count itself doesn't include the CRC. Firmware without CRC
+    /// support never sets it, packets are far shorter than 64 KiB.
+    pub const CRC_FLAG: u16 = 0x8000;
+
     pub fn ty(&self) -> PacketType {
         match self.data {
             PacketData::WriteRegister(_) => PacketType::WriteRegister,
@@ -282,6 +376,8 @@ impl Packet {
             PacketData::ImpactReport(_) => PacketType::ImpactReport,
             PacketData::StreamUpdate(_) => PacketType::StreamUpdate,
             PacketData::FlashSettings() => PacketType::FlashSettings,
+            #[cfg(feature = "experimental-write-uuid")]
+            PacketData::WriteUuid(_) => PacketType::WriteUuid,
         }
     }
 
@@ -291,10 +387,18 @@ impl Packet {
         };
 
         let words = u16::from_le_bytes([words1, words2]);
+        let has_crc = words & Self::CRC_FLAG != 0;
+        let words = words & !Self::CRC_FLAG;
+        let len = usize::from(words)*2;
+        let crc_len = if has_crc { 2 } else { 0 };
+        let packet = *bytes;
+        // Before the type, a corrupted type byte is a bad checksum rather than an unknown packet
+        if has_crc && packet.len() >= len + 2 && crc16_ccitt(&packet[..len]).to_le_bytes() != packet[len..len + 2] {
+            return Err(Error::BadChecksum);
+        }
         let ty = PacketType::try_from(ty)?;
 
-        let len = usize::from(words)*2;
-        if bytes.len() < len {
+        if bytes.len() < len + crc_len {
             return Err(Error::UnexpectedEof { packet_type: Some(ty) });
         }
         *bytes = &bytes[4..];
@@ -312,11 +416,36 @@ impl Packet {
             PacketType::ImpactReport => PacketData::ImpactReport(ImpactReport::parse(bytes)?),
             PacketType::StreamUpdate => PacketData::StreamUpdate(StreamUpdate::parse(bytes)?),
             PacketType::FlashSettings => PacketData::FlashSettings(),
+            #[cfg(feature = "experimental-write-uuid")]
+            PacketType::WriteUuid => {
+                let Some(uuid) = bytes.get(..6) else {
+                    return Err(Error::UnexpectedEof { packet_type: Some(ty) });
+                };
+                let uuid = uuid.try_into().unwrap();
+                *bytes = &bytes[6..];
+                PacketData::WriteUuid(uuid)
+            }
             p => unimplemented!("{:?}", p),
         };
+        if has_crc {
+            // Past the CRC, whatever the payload parser consumed
+            *bytes = &packet[len + 2..];
+        }
         Ok(Self { id, data })
     }
 
+    /// `serialize` with the CRC trailer, see `CRC_FLAG`. Only for firmware that checks it.
+    pub fn serialize_with_crc(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        self.serialize(buf);
+        let words = u16::from_le_bytes([buf[start], buf[start + 1]]);
+        // The CRC goes exactly where the word count says the packet ends
+        buf.resize(start + usize::from(words) * 2, 0);
+        buf[start..start + 2].copy_from_slice(&(words | Self::CRC_FLAG).to_le_bytes());
+        let crc = crc16_ccitt(&buf[start..]);
+        buf.extend_from_slice(&crc.to_le_bytes());
+    }
+
     pub fn serialize(&self, buf: &mut Vec<u8>) {
         macro_rules! calculate_length {
             ($ty:ty) => {{
@@ -339,6 +468,8 @@ impl Packet {
             PacketData::ImpactReport(_) => 4,
             PacketData::StreamUpdate(_) => calculate_length!(StreamUpdate),
             PacketData::FlashSettings() => 0,
+            #[cfg(feature = "experimental-write-uuid")]
+            PacketData::WriteUuid(_) => 6,
         };
         let words = u16::to_le_bytes((len + 4) / 2);
         let ty = self.ty();
@@ -355,9 +486,11 @@ impl Packet {
             PacketData::ObjectReport(x) => x.serialize(buf),
             PacketData::CombinedMarkersReport(x) => x.serialize(buf),
             PacketData::AccelReport(x) => x.serialize(buf),
-            PacketData::ImpactReport(x) => unimplemented!(),
+            PacketData::ImpactReport(x) => x.serialize(buf),
             PacketData::StreamUpdate(x) => buf.extend_from_slice(&[x.mask as u8, x.active as u8]),
             PacketData::FlashSettings() => (),
+            #[cfg(feature = "experimental-write-uuid")]
+            PacketData::WriteUuid(x) => buf.extend_from_slice(x),
         }
     }
 }
@@ -483,7 +616,16 @@ impl GeneralConfig {
 
         *bytes = &bytes[..7];
 
-        Ok(Self { impact_threshold, accel_odr, camera_model_nf, camera_model_wf, stereo_iso, uuid })
+        Ok(Self {
+            impact_threshold,
+            accel_odr,
+            camera_model_nf,
+            camera_model_wf,
+            camera_model_type_nf: CameraModelType::Pinhole,
+            camera_model_type_wf: CameraModelType::Pinhole,
+            stereo_iso,
+            uuid,
+        })
     }
 
     pub fn serialize(&self, buf: &mut Vec<u8>) {
@@ -510,6 +652,9 @@ impl GeneralWriteConfig {
 
 impl MotData {
     pub fn parse(bytes: &mut &[u8]) -> Result<Self, Error> {
+        if bytes.len() < 16 {
+            return Err(Error::UnexpectedEof { packet_type: None });
+        }
         let mot_data = MotData {
             area: bytes[0] as u16 | ((bytes[1] as u16) << 8),
             cx: bytes[2] as u16 | ((bytes[3] & 0x0f) as u16) << 8,
@@ -555,19 +700,24 @@ impl MotData {
 impl ObjectReport {
     pub fn parse(bytes: &mut &[u8]) -> Result<Self, Error> {
         use Error as E;
+        // timestamp, 16 nf and 16 wf MotData, format
+        if bytes.len() < 4 + 512 + 2 {
+            return Err(E::UnexpectedEof { packet_type: Some(PacketType::ObjectReport) });
+        }
         let timestamp = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
         *bytes = &bytes[4..];
         let data = &mut &bytes[..512];
-        *bytes = &bytes[512..];
-        let [_format, _, ..] = **bytes else {
-            return Err(E::UnexpectedEof { packet_type: Some(PacketType::ObjectReport) });
-        };
-        *bytes = &bytes[2..];
-        Ok(Self {
-            timestamp,
-            mot_data_nf: [(); 16].map(|_| MotData::parse(data).expect("MotData parse error")),
-            mot_data_wf: [(); 16].map(|_| MotData::parse(data).expect("MotData parse error")),
-        })
+        // Skip the format
+        *bytes = &bytes[512 + 2..];
+        let mut mot_data_nf = [MotData::default(); 16];
+        for mot_data in &mut mot_data_nf {
+            *mot_data = MotData::parse(data)?;
+        }
+        let mut mot_data_wf = [MotData::default(); 16];
+        for mot_data in &mut mot_data_wf {
+            *mot_data = MotData::parse(data)?;
+        }
+        Ok(Self { timestamp, mot_data_nf, mot_data_wf })
     }
 
     pub fn serialize(&self, buf: &mut Vec<u8>) {
@@ -603,24 +753,24 @@ impl CombinedMarkersReport {
         let nf_positions = positions[..16].try_into().unwrap();
         let wf_positions = positions[16..].try_into().unwrap();
 
-        let mut screen_ids = [0; 32];
+        let mut screen_ids = [ScreenId::default(); 32];
 
         let mut bit_offset = 0;
         for i in 0..32 {
             let byte_index = bit_offset / 8;
             let bit_index = bit_offset % 8;
             
-            screen_ids[i] = if bit_index <= 5 {
+            screen_ids[i] = ScreenId::from_bits(if bit_index <= 8 - ScreenId::BITS as usize {
                 // The bits are within the same byte
-                (data[byte_index] >> bit_index) & 0x7
+                data[byte_index] >> bit_index
             } else {
                 // The bits span across two bytes
                 let first_part = data[byte_index] >> bit_index;
                 let second_part = data[byte_index + 1] << (8 - bit_index);
-                (first_part | second_part) & 0x7
-            };
+                first_part | second_part
+            });
         
-            bit_offset += 3;
+            bit_offset += ScreenId::BITS as usize;
         }
 
         *data = &data[12..];
@@ -643,17 +793,17 @@ impl CombinedMarkersReport {
         buf.extend({
             let mut buf = [0; 12];
             for i in 0..32 {
-                let byte_index = (i * 3) / 8;
-                let bit_index = (i * 3) % 8;
+                let byte_index = (i * ScreenId::BITS as usize) / 8;
+                let bit_index = (i * ScreenId::BITS as usize) % 8;
                 let screen_id = if i < 16 {
                     self.nf_screen_ids[i]
                 } else {
                     self.wf_screen_ids[i - 16]
-                } & 0x07; // Mask to 3 bits
+                }.to_bits();
         
                 let mask = screen_id << bit_index;
                 buf[byte_index] |= mask;
-                if bit_index > 5 {
+                if bit_index > 8 - ScreenId::BITS as usize {
                     buf[byte_index + 1] |= screen_id >> (8 - bit_index);
                 }
             }
@@ -686,13 +836,14 @@ impl AccelReport {
     }
 
     pub fn serialize(&self, buf: &mut Vec<u8>) {
+        // Rounded, truncating would turn a parsed -3 into -2 after the float round trip
         buf.extend_from_slice(&self.timestamp.to_le_bytes());
         for a in self.accel.iter() {
-            let a = (a / 9.81 * 2048.0) as i16;
+            let a = (a / 9.81 * 2048.0).round() as i16;
             buf.extend_from_slice(&a.to_le_bytes());
         }
         for g in self.gyro.iter() {
-            let g = (g.to_degrees() * 16.4) as i16;
+            let g = (g.to_degrees() * 16.4).round() as i16;
             buf.extend_from_slice(&g.to_le_bytes());
         }
     }
@@ -704,6 +855,10 @@ impl ImpactReport {
         *bytes = &bytes[4..];
         Ok(Self { timestamp })
     }
+
+    pub fn serialize(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.timestamp.to_le_bytes());
+    }
 }
 
 impl StreamUpdate {
@@ -716,3 +871,140 @@ impl StreamUpdate {
         Ok(stream_update)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{crc16_ccitt, format_uuid, parse_uuid, AccelReport, CombinedMarkersReport, Error, ImpactReport, ObjectReport, Packet, PacketData, PacketType, ScreenId};
+
+    #[test]
+    fn test_accel_report_round_trip() {
+        // Timestamp, then accel and gyro x, y, z as i16, including the extremes
+        let mut bytes = vec![0x78, 0x56, 0x34, 0x12];
+        for x in [2048i16, -3, i16::MIN, 1, -1640, i16::MAX] {
+            bytes.extend_from_slice(&x.to_le_bytes());
+        }
+        let report = AccelReport::parse(&mut &bytes[..]).unwrap();
+        assert_eq!(report.timestamp, 0x12345678);
+        assert!((report.accel.x - 9.81).abs() < 1e-5);
+        assert!((report.gyro.y - (-100f32).to_radians()).abs() < 1e-5);
+        let mut buf = vec![];
+        report.serialize(&mut buf);
+        assert_eq!(buf, bytes);
+
+        let packet = Packet { id: 3, data: PacketData::AccelReport(report) };
+        let mut buf = vec![];
+        packet.serialize(&mut buf);
+        let parsed = Packet::parse(&mut &buf[..]).unwrap();
+        assert_eq!(parsed.id, 3);
+        let mut reserialized = vec![];
+        parsed.serialize(&mut reserialized);
+        assert_eq!(reserialized, buf);
+
+        let packet = Packet { id: 4, data: PacketData::ImpactReport(ImpactReport { timestamp: 99 }) };
+        let mut buf = vec![];
+        packet.serialize(&mut buf);
+        assert_eq!(Packet::parse(&mut &buf[..]).unwrap().data.impact_report().unwrap().timestamp, 99);
+    }
+
+    #[test]
+    fn test_crc() {
+        assert_eq!(crc16_ccitt(b"123456789"), 0x29b1);
+
+        let packet = Packet { id: 5, data: PacketData::ImpactReport(ImpactReport { timestamp: 0xdeadbeef }) };
+        let mut buf = vec![];
+        packet.serialize_with_crc(&mut buf);
+        assert_eq!(buf.len(), 4 + 4 + 2);
+        let mut rest = &buf[..];
+        let parsed = Packet::parse(&mut rest).unwrap();
+        assert_eq!(parsed.id, 5);
+        assert_eq!(parsed.data.impact_report().unwrap().timestamp, 0xdeadbeef);
+        assert!(rest.is_empty());
+
+        // Every single bit flip in the header or payload is caught. The length bits are left
+        // alone, those fail as a short packet instead.
+        for byte in 2..8 {
+            for bit in 0..8 {
+                let mut corrupted = buf.clone();
+                corrupted[byte] ^= 1 << bit;
+                let result = Packet::parse(&mut &corrupted[..]);
+                assert!(matches!(result, Err(Error::BadChecksum)), "byte {byte} bit {bit}: {result:?}");
+            }
+        }
+        let mut corrupted = buf.clone();
+        corrupted[9] ^= 0x10;
+        assert!(matches!(Packet::parse(&mut &corrupted[..]), Err(Error::BadChecksum)));
+    }
+
+    #[test]
+    fn test_legacy_packet_without_crc() {
+        let packet = Packet { id: 5, data: PacketData::ImpactReport(ImpactReport { timestamp: 42 }) };
+        let mut buf = vec![];
+        packet.serialize(&mut buf);
+        assert_eq!(u16::from_le_bytes([buf[0], buf[1]]) & Packet::CRC_FLAG, 0);
+        // Trailing bytes aren't mistaken for a CRC
+        buf.extend_from_slice(&[0xff, 0xff]);
+        let mut rest = &buf[..];
+        assert_eq!(Packet::parse(&mut rest).unwrap().data.impact_report().unwrap().timestamp, 42);
+        assert_eq!(rest, [0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_object_report_truncated() {
+        let bytes = [0u8; 300];
+        let result = ObjectReport::parse(&mut &bytes[..]);
+        assert!(matches!(result, Err(Error::UnexpectedEof { packet_type: Some(PacketType::ObjectReport) })), "{result:?}");
+
+        let mut bytes = vec![];
+        ObjectReport { timestamp: 7, ..Default::default() }.serialize(&mut bytes);
+        assert_eq!(bytes.len(), 518);
+        let mut rest = &bytes[..];
+        assert_eq!(ObjectReport::parse(&mut rest).unwrap().timestamp, 7);
+        assert!(rest.is_empty());
+        assert!(ObjectReport::parse(&mut &bytes[..517]).is_err());
+    }
+
+    #[test]
+    fn test_screen_id_validity() {
+        assert!(ScreenId(0).is_valid());
+        assert!(ScreenId(6).is_valid());
+        assert!(!ScreenId(7).is_valid());
+        assert!(!ScreenId::NONE.is_valid());
+        assert_eq!(ScreenId::valid().collect::<Vec<_>>(), (0..7).map(ScreenId).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_screen_ids_round_trip() {
+        let mut report = CombinedMarkersReport::default();
+        report.nf_screen_ids = std::array::from_fn(|i| ScreenId(i as u8 % 8));
+        report.wf_screen_ids = std::array::from_fn(|i| if i % 2 == 0 { ScreenId::NONE } else { ScreenId(6) });
+        let mut buf = vec![];
+        report.serialize(&mut buf);
+        let parsed = CombinedMarkersReport::parse(&mut &buf[..]).unwrap();
+        assert_eq!(parsed.nf_screen_ids, report.nf_screen_ids);
+        assert_eq!(parsed.wf_screen_ids, report.wf_screen_ids);
+    }
+
+    #[test]
+    #[cfg(feature = "experimental-write-uuid")]
+    fn test_write_uuid_packet() {
+        let uuid = [0x2a, 0x45, 0x03, 0x07, 0x09, 0x0d];
+        let mut buf = vec![];
+        Packet { id: 255, data: PacketData::WriteUuid(uuid) }.serialize(&mut buf);
+        // 5 words, type 14, id 255, then the uuid as is
+        assert_eq!(buf, [5, 0, 14, 255, 0x2a, 0x45, 0x03, 0x07, 0x09, 0x0d]);
+        let parsed = Packet::parse(&mut &buf[..]).unwrap();
+        assert!(matches!(parsed.data, PacketData::WriteUuid(u) if u == uuid));
+    }
+
+    #[test]
+    fn test_uuid_format() {
+        let uuid = [0x2a, 0x45, 0x03, 0x07, 0x09, 0xfd];
+        assert_eq!(format_uuid(&uuid), "2a:45:03:07:09:fd");
+        assert_eq!(parse_uuid("2a:45:03:07:09:fd"), Some(uuid));
+        assert_eq!(parse_uuid("2A45030709FD"), Some(uuid));
+        assert_eq!(parse_uuid("2a:45:03:07:09"), None);
+        assert_eq!(parse_uuid("2a:45:03:07:09:fd:00"), None);
+        assert_eq!(parse_uuid("2a:45:03:07:09:fg"), None);
+        assert_eq!(parse_uuid("+a:45:03:07:09:fd"), None);
+    }
+}
@@ -0,0 +1,219 @@
+//! Optional websocket server streaming the aimpoint and impacts as JSON, for web scoreboards.
+//!
+//! Each message is a text frame holding one JSON object tagged by `type`:
+//!
+//! - `{"type":"aimpoint","x":0.5,"y":0.5,"screen_id":0}` after combined markers frames, at most
+//!   `DEFAULT_MAX_AIMPOINT_RATE` (60) per second unless `AimpointServer::rate_limit` is changed
+//! - `{"type":"impact","x":0.5,"y":0.5,"screen_id":0}` for every armed impact
+//!
+//! `x` and `y` go from 0 to 1 across the screen, same as `MotState::fv_aimpoint`. Anything sent
+//! by clients is ignored. A client that falls behind skips messages instead of slowing down
+//! tracking.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::Serialize;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::AbortHandle;
+use tokio_stream::StreamExt;
+use futures_util::SinkExt;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, error, info, warn};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AimpointMessage {
+    Aimpoint { x: f64, y: f64, screen_id: u8 },
+    Impact { x: f64, y: f64, screen_id: u8 },
+}
+
+/// Aimpoint messages per second the server sends by default.
+pub const DEFAULT_MAX_AIMPOINT_RATE: f64 = 60.;
+
+/// Caps how often aimpoints go out, independent of how often tracking updates them. Frames over
+/// the cap are dropped rather than queued, so the stream stays current.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    /// Messages per second, `None` sends every one.
+    pub max_rate: Option<f64>,
+    last_sent: Option<Instant>,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self { max_rate: Some(DEFAULT_MAX_AIMPOINT_RATE), last_sent: None }
+    }
+}
+
+impl RateLimit {
+    /// Longest gap between messages, however low `max_rate` is.
+    const MAX_INTERVAL: Duration = Duration::from_secs(3600);
+
+    /// Call for every aimpoint. Returns whether it should be sent.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let Some(rate) = self.max_rate.filter(|r| *r > 0.) else {
+            return true;
+        };
+        let interval = Duration::try_from_secs_f64(1. / rate).unwrap_or(Self::MAX_INTERVAL).min(Self::MAX_INTERVAL);
+        match self.last_sent {
+            Some(last) if now.duration_since(last) < interval => false,
+            // Step from the last slot instead of `now` while keeping up, otherwise every message
+            // would be up to one input frame late and the rate would fall short of the cap
+            Some(last) if now.duration_since(last) < 2 * interval => {
+                self.last_sent = Some(last + interval);
+                true
+            }
+            _ => {
+                self.last_sent = Some(now);
+                true
+            }
+        }
+    }
+}
+
+pub struct AimpointServer {
+    pub rate_limit: RateLimit,
+    tx: broadcast::Sender<String>,
+    local_addr: SocketAddr,
+    accept_task: AbortHandle,
+}
+
+impl AimpointServer {
+    pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (tx, _) = broadcast::channel(64);
+        let accept_task = tokio::spawn(accept_loop(listener, tx.clone())).abort_handle();
+        info!("aimpoint server listening on ws://{local_addr}");
+        Ok(Self { rate_limit: RateLimit::default(), tx, local_addr, accept_task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Sends `msg` to every connected client. Doesn't block.
+    pub fn publish(&self, msg: &AimpointMessage) {
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+        match serde_json::to_string(msg) {
+            // Only fails if every client disconnected in the meantime
+            Ok(json) => {
+                let _ = self.tx.send(json);
+            }
+            Err(e) => error!("failed to serialize aimpoint message: {e}"),
+        }
+    }
+
+    /// `publish` if `rate_limit` allows it at `now`. For the aimpoint stream, impacts always go
+    /// through `publish`.
+    pub fn publish_limited(&mut self, now: Instant, msg: &AimpointMessage) {
+        if self.rate_limit.allow(now) {
+            self.publish(msg);
+        }
+    }
+}
+
+impl Drop for AimpointServer {
+    fn drop(&mut self) {
+        // Client tasks stop on their own once the sender is gone
+        self.accept_task.abort();
+    }
+}
+
+async fn accept_loop(listener: TcpListener, tx: broadcast::Sender<String>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(serve_client(stream, peer, tx.subscribe()));
+            }
+            Err(e) => warn!("aimpoint server failed to accept: {e}"),
+        }
+    }
+}
+
+async fn serve_client(stream: TcpStream, peer: SocketAddr, mut rx: broadcast::Receiver<String>) {
+    let ws = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            debug!("websocket handshake with {peer} failed: {e}");
+            return;
+        }
+    };
+    info!("aimpoint client connected: {peer}");
+    let (mut sink, mut source) = futures_util::StreamExt::split(ws);
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Ok(json) => {
+                    if sink.send(Message::Text(json)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(n)) => debug!("aimpoint client {peer} lagged, skipped {n} messages"),
+                Err(RecvError::Closed) => break,
+            },
+            // Pings are answered by tungstenite, everything else is ignored
+            msg = source.next() => match msg {
+                Some(Ok(_)) => (),
+                _ => break,
+            },
+        }
+    }
+    info!("aimpoint client disconnected: {peer}");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use tokio_stream::StreamExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::{AimpointMessage, AimpointServer, RateLimit};
+
+    #[tokio::test]
+    async fn test_client_receives_aimpoint() {
+        let server = AimpointServer::bind("127.0.0.1:0").await.unwrap();
+        // The server subscribes the client before answering the handshake
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}", server.local_addr())).await.unwrap();
+        server.publish(&AimpointMessage::Aimpoint { x: 0.25, y: 0.75, screen_id: 1 });
+        let msg = tokio::time::timeout(Duration::from_secs(5), client.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(msg, Message::Text(r#"{"type":"aimpoint","x":0.25,"y":0.75,"screen_id":1}"#.into()));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let start = Instant::now();
+        // A second of 200 Hz input into the default 60 Hz cap
+        let mut rate_limit = RateLimit::default();
+        let sent: Vec<_> = (0..200u64)
+            .map(|i| start + Duration::from_millis(5 * i))
+            .filter(|&t| rate_limit.allow(t))
+            .collect();
+        assert!((59..=61).contains(&sent.len()), "{}", sent.len());
+        // Never more than the cap in any second long window
+        for (i, &t) in sent.iter().enumerate() {
+            let in_window = sent[i..].iter().take_while(|&&u| u < t + Duration::from_secs(1)).count();
+            assert!(in_window <= 61, "{in_window}");
+        }
+
+        // Slower input than the cap goes through untouched
+        let mut rate_limit = RateLimit::default();
+        assert!((0..30u64).all(|i| rate_limit.allow(start + Duration::from_millis(40 * i))));
+
+        let mut rate_limit = RateLimit { max_rate: None, ..Default::default() };
+        assert!((0..200u64).all(|i| rate_limit.allow(start + Duration::from_millis(i))));
+
+        // Rates too low for a `Duration` are capped instead of panicking, NaN sends everything
+        let mut rate_limit = RateLimit { max_rate: Some(1e-300), ..Default::default() };
+        assert!(rate_limit.allow(start));
+        assert!(!rate_limit.allow(start + Duration::from_secs(60)));
+        let mut rate_limit = RateLimit { max_rate: Some(f64::NAN), ..Default::default() };
+        assert!((0..10u64).all(|i| rate_limit.allow(start + Duration::from_millis(i))));
+    }
+}
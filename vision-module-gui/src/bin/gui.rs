@@ -1,5 +1,5 @@
 use std::fs::File;
-use std::io::Write;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use anyhow::Result;
@@ -17,19 +17,30 @@ use bevy_infinite_grid::{
     GridShadowCamera, InfiniteGridBundle, InfiniteGridPlugin, InfiniteGridSettings,
 };
 use iui::prelude::*;
-use leptos_reactive::{create_effect, RwSignal, SignalGet, SignalGetUntracked, SignalSet, SignalWith};
-use nalgebra::Vector2;
-use tracing::Level;
+use leptos_reactive::{create_effect, RwSignal, SignalGet, SignalGetUntracked, SignalSet, SignalUpdate, SignalWith, SignalWithUntracked};
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::EnvFilter;
-use ats_usb::packet::GeneralConfig;
+use ats_usb::packet::{MarkerPattern, PacketType, ScreenId};
+use vision_module_gui::custom_shapes::MarkerGlyph;
+use vision_module_gui::display_settings::DisplaySettings;
 use vision_module_gui::run_canvas::RunCanvas;
-use vision_module_gui::{config_window, plots_window, TestFrame};
-use vision_module_gui::{CloneButShorter, MotState};
+use vision_module_gui::{config_window, devices_window, imu_window, plots_window, pose_override_window, setup_assistant, setup_wizard, stereo_window, TestFrame};
+use vision_module_gui::CloneButShorter;
 use tokio::task::AbortHandle;
-use iui::controls::{Area, HorizontalBox, FileTypeFilter};
-use vision_module_gui::mot_runner::MotRunner;
+use iui::controls::{Area, Checkbox, HorizontalBox, FileTypeFilter};
+use iui::menus::Menu;
+use vision_module_gui::aimpoint_server::AimpointServer;
+use vision_module_gui::devices_window::DeviceRunners;
+use vision_module_gui::mot_runner::{ImpactCapture, MotRunner, PnpDisambiguation, DEFAULT_IMPACT_DEBOUNCE, DEFAULT_PATTERN_MISMATCH_THRESHOLD};
+use vision_module_gui::marker_snapshot::MarkerSnapshot;
+use vision_module_gui::pose_log::PoseLog;
+use vision_module_gui::recording_settings::{self, RecordingSettings};
+use vision_module_gui::target_regions::TargetRegions;
+use vision_module_gui::tracking_canvas_helpers::{LabelVerbosity, Overlay};
+use vision_module_gui::window_layout::WindowLayout;
 use vision_module_gui::run_raw_canvas::RunRawCanvas;
-use vision_module_gui::test_canvas::TestCanvas;
+use vision_module_gui::screen_info::{LengthUnit, ScreenInfo};
+use vision_module_gui::test_canvas::{AimpointLostBehavior, TestCanvas, TestKeyMap};
 use parking_lot::Mutex;
 
 // Things to avoid doing
@@ -158,6 +169,9 @@ fn rotate_cube(mut camera_query: Query<(&Camera, &mut Transform)>, runner: Res<M
     camera_transform.rotation = Quat::from_mat3(&rotation);
 }
 
+/// Shown when a loaded screen info's markers aren't on one plane.
+const NOT_COPLANAR_WARNING: &str = "The markers aren't all on one plane. The ESKF assumes a planar marker pattern, so the pose and aimpoint will be off.";
+
 pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt()
         .with_env_filter(
@@ -180,41 +194,216 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut simulator_addr = None;
     let mut udp_addr = None;
-    match &std::env::args().collect::<Vec<_>>()[1..] {
-        [flag, addr] if flag == "-u" => udp_addr = Some(addr.clone()),
-        [addr] => simulator_addr = Some(addr.clone()),
-        [] => (),
-        _ => panic!("Unrecognized arguments"),
+    let mut serve_addr = None;
+    let mut serve_rate = None;
+    let mut screen_info_arg = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-u" => udp_addr = Some(args.next().expect("-u requires an address")),
+            // Websocket aimpoint stream, see vision_module_gui::aimpoint_server for the messages
+            "--serve" => serve_addr = Some(args.next().expect("--serve requires an address")),
+            // Max aimpoint messages per second, 0 for every frame
+            "--serve-rate" => serve_rate = Some(args.next().and_then(|r| r.parse::<f64>().ok()).expect("--serve-rate requires a number")),
+            "--screen-info" => screen_info_arg = Some(PathBuf::from(args.next().expect("--screen-info requires a path"))),
+            _ if simulator_addr.is_none() && udp_addr.is_none() => simulator_addr = Some(arg),
+            _ => panic!("Unrecognized arguments"),
+        }
     }
+    let aimpoint_server = serve_addr.and_then(|addr| {
+        match tokio_handle.block_on(AimpointServer::bind(&addr)) {
+            Ok(mut server) => {
+                if let Some(rate) = serve_rate {
+                    server.rate_limit.max_rate = Some(rate).filter(|r| *r > 0.);
+                }
+                Some(server)
+            }
+            Err(e) => {
+                error!("failed to start the aimpoint server on {addr}: {e}");
+                None
+            }
+        }
+    });
+    let (screen_info, screen_info_path) = match screen_info_arg {
+        Some(path) => match ScreenInfo::load_from_path(&path) {
+            Ok(screen_info) => {
+                if !screen_info.is_coplanar() {
+                    warn!("{}: {NOT_COPLANAR_WARNING}", path.display());
+                }
+                (screen_info, Some(path))
+            }
+            Err(e) => {
+                error!("{e:#}, using the default screen info");
+                (ScreenInfo::default(), None)
+            }
+        },
+        None => (ScreenInfo::default(), None),
+    };
+    // None is the built in default
+    let screen_info_path = RwSignal::new(screen_info_path);
+    let screen_span = RwSignal::new(screen_info.marker_span());
+    // Index into `LengthUnit::NAMES`, only for display
+    let length_unit = RwSignal::new(0);
     let datapoints: Arc<Mutex<Vec<TestFrame>>> = Arc::new(Mutex::new(Vec::new()));
     let packets = Arc::new(Mutex::new(Vec::new()));
-    let state = MotState::default();
     let ui_update: RwSignal<()> = leptos_reactive::create_rw_signal(());
+    let imu_readout = RwSignal::new(None);
+    let madgwick_readout = RwSignal::new(None);
+    let marker_count = RwSignal::new(None);
 
     let tracking_raw = RwSignal::new(false);
     let tracking = RwSignal::new(false);
     let testing = RwSignal::new(false);
     let recording = RwSignal::new(false);
+    let quick_recording = RwSignal::new(false);
+    let recording_settings = RwSignal::new(RecordingSettings::load_from_file());
+    let last_quick_recording = RwSignal::new(None::<std::path::PathBuf>);
+    let pose_logging = RwSignal::new(false);
+    let impact_capturing = RwSignal::new(false);
+    let pre_roll_ms = RwSignal::new(2000);
+    let post_roll_ms = RwSignal::new(500);
     let marker_offset_calibrating = RwSignal::new(false);
+    let max_redraw_rate = RwSignal::new(60);
+    let max_shots = RwSignal::new(0);
+    let idle_timeout_s = RwSignal::new(0);
+    let impact_debounce_ms = RwSignal::new(DEFAULT_IMPACT_DEBOUNCE.as_millis() as i32);
+    let shot_persistence = RwSignal::new(0);
+    let pattern_id_debounce_frames = RwSignal::new(3);
+    let acquire_frames = RwSignal::new(5);
+    let grid_x = RwSignal::new(10);
+    let grid_y = RwSignal::new(10);
+    let max_imu_dt_ms = RwSignal::new(100);
+    let label_verbosity = RwSignal::new(2);
+    let aimpoint_lost_behavior = RwSignal::new(1);
+    let show_aimpoint_readout = RwSignal::new(false);
+    let aimpoint_readout_precision = RwSignal::new(4);
+    // Per mille of the sensor size
+    let track_gate = RwSignal::new(50);
+    // Percent of the mean marker distance
+    let pattern_tolerance = RwSignal::new((DEFAULT_PATTERN_MISMATCH_THRESHOLD * 100.).round() as i32);
+    let raw_min_area = RwSignal::new(0);
+    let raw_average_frames = RwSignal::new(1);
+    let overlay_order = RwSignal::new(Overlay::format_order(&Overlay::DEFAULT_ORDER));
+    let display_settings = DisplaySettings::load_from_file();
+    // Percent
+    let display_brightness = RwSignal::new((display_settings.brightness * 100.).round() as i32);
+    let display_contrast = RwSignal::new((display_settings.contrast * 100.).round() as i32);
+    let high_contrast_palette = RwSignal::new(display_settings.high_contrast_palette);
+    let color_by_screen = RwSignal::new(display_settings.color_by_screen);
+    let nf_glyph = RwSignal::new(display_settings.nf_glyph.index());
+    let wf_glyph = RwSignal::new(display_settings.wf_glyph.index());
 
     let mot_runner = Arc::new(Mutex::new(MotRunner {
-        state,
-        device: None,
-        markers_settings: Default::default(),
-        record_impact: false,
-        record_packets: false,
         datapoints: datapoints.c(),
         packets: packets.c(),
         ui_update: ui_update.c(),
-        ui_ctx,
-        nf_offset: Vector2::default(),
-        general_config: GeneralConfig::default(),
-        wfnf_realign: true,
+        imu_readout: imu_readout.c(),
+        madgwick_readout,
+        marker_count,
+        target_regions: TargetRegions::load_from_file(),
+        screen_info,
+        aimpoint_server,
+        display: display_settings,
+        ..MotRunner::new(Some(ui_ctx))
     }));
 
+    // Menus have to be created before any window
+    let debug_menu = Menu::new(&ui, "Debug");
+    let diamond_sorter_item = debug_menu.append_check_item("Diamond sorter for raw grid");
+    diamond_sorter_item.on_clicked(&ui, {
+        let mot_runner = mot_runner.c();
+        let ui = ui.c();
+        move |item, _| {
+            let pattern = item.checked(&ui).then_some(MarkerPattern::Diamond);
+            info!("raw canvas sorter: {pattern:?}");
+            mot_runner.lock().raw_sort_pattern = pattern;
+        }
+    });
+    let marker_errors_item = debug_menu.append_check_item("Marker reprojection errors");
+    marker_errors_item.on_clicked(&ui, {
+        let mot_runner = mot_runner.c();
+        let ui = ui.c();
+        move |item, _| {
+            mot_runner.lock().show_marker_errors = item.checked(&ui);
+        }
+    });
+    let mirror_horizontal_item = debug_menu.append_check_item("Mirror raw canvas horizontally");
+    mirror_horizontal_item.on_clicked(&ui, {
+        let mot_runner = mot_runner.c();
+        let ui = ui.c();
+        move |item, _| {
+            let mut runner = mot_runner.lock();
+            runner.raw_mirror.horizontal = item.checked(&ui);
+            runner.redraw_pending = true;
+        }
+    });
+    let mirror_vertical_item = debug_menu.append_check_item("Mirror raw canvas vertically");
+    mirror_vertical_item.on_clicked(&ui, {
+        let mot_runner = mot_runner.c();
+        let ui = ui.c();
+        move |item, _| {
+            let mut runner = mot_runner.lock();
+            runner.raw_mirror.vertical = item.checked(&ui);
+            runner.redraw_pending = true;
+        }
+    });
+    let undistortion_item = debug_menu.append_check_item("Undistortion on raw canvas");
+    undistortion_item.on_clicked(&ui, {
+        let mot_runner = mot_runner.c();
+        let ui = ui.c();
+        move |item, _| {
+            let mut runner = mot_runner.lock();
+            runner.show_undistortion = item.checked(&ui);
+            runner.redraw_pending = true;
+        }
+    });
+    let split_aimpoints_item = debug_menu.append_check_item("ESKF and PnP aimpoints on test canvas");
+    split_aimpoints_item.on_clicked(&ui, {
+        let mot_runner = mot_runner.c();
+        let ui = ui.c();
+        move |item, _| {
+            let mut runner = mot_runner.lock();
+            runner.show_split_aimpoints = item.checked(&ui);
+            runner.redraw_pending = true;
+        }
+    });
+    let pnp_prior_item = debug_menu.append_check_item("Disambiguate PnP by the ESKF pose");
+    pnp_prior_item.on_clicked(&ui, {
+        let mot_runner = mot_runner.c();
+        let ui = ui.c();
+        move |item, _| {
+            let mode = if item.checked(&ui) { PnpDisambiguation::ClosestToPrior } else { PnpDisambiguation::LowestReprojectionError };
+            info!("pnp disambiguation: {mode:?}");
+            mot_runner.lock().pnp_disambiguation = mode;
+        }
+    });
+    debug_menu.append_separator();
+    let allow_simulated_impacts_item = debug_menu.append_check_item("Allow simulated impacts");
+    allow_simulated_impacts_item.on_clicked(&ui, {
+        let mot_runner = mot_runner.c();
+        let ui = ui.c();
+        move |item, _| {
+            mot_runner.lock().allow_simulated_impacts = item.checked(&ui);
+        }
+    });
+    let simulate_impact_item = debug_menu.append_item("Simulate impact");
+    simulate_impact_item.on_clicked(&ui, {
+        let mot_runner = mot_runner.c();
+        move |_, _| {
+            mot_runner.lock().simulate_impact();
+        }
+    });
+    debug_menu.append_separator();
+    let pose_override_item = debug_menu.append_item("Pose override...");
+    let imu_readout_item = debug_menu.append_item("IMU readout...");
+    let stereo_schematic_item = debug_menu.append_item("Stereo schematic...");
+    let setup_assistant_item = debug_menu.append_item("Marker setup assistant...");
+    let setup_wizard_item = debug_menu.append_item("Setup wizard...");
+    let devices_item = debug_menu.append_item("Devices...");
+
     // Create a main_window into which controls can be placed
-    let mut main_win = iui::prelude::Window::new(&ui, "ATS Vision Tool", 640, 480, WindowType::NoMenubar);
-    let (mut config_win, device_rs, accel_odr_memo) = config_window::config_window(
+    let mut main_win = iui::prelude::Window::new(&ui, "ATS Vision Tool", 640, 480, WindowType::HasMenubar);
+    let (mut config_win, device_rs, accel_odr_memo, config_stereo_iso) = config_window::config_window(
         &ui,
         simulator_addr,
         udp_addr,
@@ -222,6 +411,61 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
         tokio_handle,
     );
     let mut plots_window = plots_window::plots_window(&ui);
+    let mut pose_override_win = pose_override_window::pose_override_window(&ui, mot_runner.c());
+    pose_override_item.on_clicked(&ui, {
+        let ui = ui.c();
+        move |_, _| {
+            pose_override_win.show(&ui);
+        }
+    });
+    let mut imu_win = imu_window::imu_window(&ui, imu_readout, madgwick_readout, mot_runner.c());
+    imu_readout_item.on_clicked(&ui, {
+        let ui = ui.c();
+        move |_, _| {
+            imu_win.show(&ui);
+        }
+    });
+    let mut stereo_win = stereo_window::stereo_window(&ui, mot_runner.c(), config_stereo_iso);
+    let mut setup_assistant_win = setup_assistant::setup_assistant_window(&ui, marker_count);
+    let mut setup_wizard_win = setup_wizard::setup_wizard_window(
+        &ui,
+        mot_runner.c(),
+        device_rs,
+        screen_info_path,
+        marker_count,
+        marker_offset_calibrating,
+        setup_wizard::WizardWindows {
+            config: config_win.c(),
+            marker_setup: setup_assistant_win.c(),
+            stereo: stereo_win.c(),
+        },
+    );
+    let device_runners = DeviceRunners::default();
+    let mut devices_win = devices_window::devices_window(&ui, mot_runner.c(), device_runners.c());
+    devices_item.on_clicked(&ui, {
+        let ui = ui.c();
+        move |_, _| {
+            devices_win.show(&ui);
+        }
+    });
+    stereo_schematic_item.on_clicked(&ui, {
+        let ui = ui.c();
+        move |_, _| {
+            stereo_win.show(&ui);
+        }
+    });
+    setup_assistant_item.on_clicked(&ui, {
+        let ui = ui.c();
+        move |_, _| {
+            setup_assistant_win.show(&ui);
+        }
+    });
+    setup_wizard_item.on_clicked(&ui, {
+        let ui = ui.c();
+        move |_, _| {
+            setup_wizard_win.show(&ui);
+        }
+    });
     // let mut marker_config_win = marker_config_window::marker_config_window(
     //     &ui,
     //     marker_offset_calibrating,
@@ -231,6 +475,17 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 
     let mut test_win = iui::prelude::Window::new(&ui, "Aimpoint Test", 640, 480, WindowType::NoMenubar);
+
+    let mut window_layout = WindowLayout::load_from_file();
+    let mut layout_windows = [
+        ("main", main_win.c()),
+        ("config", config_win.c()),
+        ("plots", plots_window.c()),
+        ("test", test_win.c()),
+    ];
+    for (name, window) in &mut layout_windows {
+        window_layout.restore(&ui, name, window);
+    }
     test_win.set_margined(&ui, false);
     test_win.set_borderless(&ui, true);
 
@@ -246,6 +501,7 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
         runner: mot_runner.c(),
         last_draw_width: None,
         last_draw_height: None,
+        key_map: TestKeyMap::load_from_file(),
     }));
     let mut test_hbox = HorizontalBox::new(&ui);
     test_hbox.append(&ui, test_area.c(), LayoutStrategy::Stretchy);
@@ -267,22 +523,114 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
                 (4, 0)(1, 1) Vertical (Fill, Fill) : let test_button = Button("Run Test")
                 (5, 0)(1, 1) Vertical (Fill, Fill) : let windowed_checkbox = Checkbox("Windowed", checked: false)
                 (6, 0)(1, 1) Vertical (Fill, Fill) : let bevy_button = Button("Launch Bevy")
+                (7, 0)(1, 1) Vertical (Fill, Center) : let grid_label = Label("Grid X×Y")
+                (8, 0)(1, 1) Vertical (Fill, Fill) : let grid_x_spinbox = Spinbox(1, 100, signal: grid_x)
+                (9, 0)(1, 1) Vertical (Fill, Fill) : let grid_y_spinbox = Spinbox(1, 100, signal: grid_y)
+                (10, 0)(1, 1) Vertical (Fill, Center) : let max_imu_dt_label = Label("Max IMU dt (ms)")
+                (11, 0)(1, 1) Vertical (Fill, Fill) : let max_imu_dt_spinbox = Spinbox(1, 1000, signal: max_imu_dt_ms)
+                (12, 0)(1, 1) Vertical (Fill, Center) : let label_verbosity_label = Label("Marker labels")
+                (13, 0)(1, 1) Vertical (Fill, Fill) : let label_verbosity_combobox = Combobox(signal: label_verbosity) { "None", "Id only", "Full" }
                 (0, 1)(1, 1) Vertical (Fill, Fill) : let record_button = Button(move || {
-                    if !recording.get() { "Start Recording" } else { "Stop Recording" }
-                })
+                    if !recording.get() || quick_recording.get() { "Start Recording" } else { "Stop Recording" }
+                }, enabled: move || !quick_recording.get())
                 (1, 1)(1, 1) Vertical (Fill, Fill) : let clear_packets_button = Button("Clear")
                 (2, 1)(1, 1) Vertical (Fill, Fill) : let save_packets_button = Button("Save")
+                (5, 1)(1, 1) Vertical (Fill, Fill) : let compress_recording_checkbox = Checkbox("Compress", checked: false)
+                (3, 1)(1, 1) Vertical (Fill, Center) : let max_redraw_rate_label = Label("Max redraw Hz")
+                (4, 1)(1, 1) Vertical (Fill, Fill) : let max_redraw_rate_spinbox = Spinbox(1, 240, signal: max_redraw_rate)
+                (6, 1)(1, 1) Vertical (Fill, Center) : let debounce_label = Label("Id debounce frames")
+                (7, 1)(1, 1) Vertical (Fill, Fill) : let debounce_spinbox = Spinbox(1, 60, signal: pattern_id_debounce_frames)
+                (8, 1)(1, 1) Vertical (Fill, Fill) : let record_markers_checkbox = Checkbox("Rec markers", checked: true)
+                (9, 1)(1, 1) Vertical (Fill, Fill) : let record_mot_checkbox = Checkbox("Rec MOT", checked: true)
+                (10, 1)(1, 1) Vertical (Fill, Fill) : let record_accel_checkbox = Checkbox("Rec IMU", checked: true)
+                (11, 1)(1, 1) Vertical (Fill, Fill) : let pose_log_button = Button(move || {
+                    if !pose_logging.get() { "Start Pose Log" } else { "Stop Pose Log" }
+                })
+                (0, 2)(1, 1) Vertical (Fill, Fill) : let impact_capture_button = Button(move || {
+                    if !impact_capturing.get() { "Start Impact Capture" } else { "Stop Impact Capture" }
+                })
+                (1, 2)(1, 1) Vertical (Fill, Center) : let pre_roll_label = Label("Pre-roll (ms)")
+                (2, 2)(1, 1) Vertical (Fill, Fill) : let pre_roll_spinbox = Spinbox(0, 60000, signal: pre_roll_ms)
+                (3, 2)(1, 1) Vertical (Fill, Center) : let post_roll_label = Label("Post-roll (ms)")
+                (4, 2)(1, 1) Vertical (Fill, Fill) : let post_roll_spinbox = Spinbox(0, 60000, signal: post_roll_ms)
+                (5, 2)(1, 1) Vertical (Fill, Center) : let screen_ids_label = Label("Track screens")
+                (6, 2)(6, 1) Vertical (Fill, Fill) : let screen_ids_hbox = HorizontalBox(padded: true) {}
+                (12, 1)(1, 1) Vertical (Fill, Center) : let overlay_order_label = Label("Overlays (bottom first)")
+                (13, 1)(1, 1) Vertical (Fill, Fill) : let overlay_order_entry = Entry(signal: overlay_order)
+                (12, 2)(1, 1) Vertical (Fill, Center) : let track_gate_label = Label("Max jump (‰)")
+                (13, 2)(1, 1) Vertical (Fill, Fill) : let track_gate_spinbox = Spinbox(1, 1000, signal: track_gate)
+                (12, 3)(1, 1) Vertical (Fill, Center) : let raw_min_area_label = Label("Raw min area")
+                (13, 3)(1, 1) Vertical (Fill, Fill) : let raw_min_area_spinbox = Spinbox(0, 16383, signal: raw_min_area)
+                (11, 3)(1, 1) Vertical (Fill, Fill) : let clear_selection_button = Button("Clear marker selection")
+                (10, 3)(1, 1) Vertical (Fill, Fill) : let dump_assignment_button = Button("Dump marker assignment")
+                (0, 3)(1, 1) Vertical (Fill, Center) : let display_brightness_label = Label("Brightness (%)")
+                (1, 3)(1, 1) Vertical (Fill, Fill) : let display_brightness_spinbox = Spinbox(-100, 100, signal: display_brightness)
+                (2, 3)(1, 1) Vertical (Fill, Center) : let display_contrast_label = Label("Contrast (%)")
+                (3, 3)(1, 1) Vertical (Fill, Fill) : let display_contrast_spinbox = Spinbox(10, 400, signal: display_contrast)
+                (4, 3)(1, 1) Vertical (Fill, Fill) : let high_contrast_checkbox = Checkbox("High contrast markers", checked: high_contrast_palette.get_untracked())
+                (8, 3)(1, 1) Vertical (Fill, Center) : let acquire_frames_label = Label("Acquire frames")
+                (9, 3)(1, 1) Vertical (Fill, Fill) : let acquire_frames_spinbox = Spinbox(0, 120, signal: acquire_frames)
+                (6, 3)(1, 1) Vertical (Fill, Fill) : let capture_reference_button = Button("Capture reference")
+                (7, 3)(1, 1) Vertical (Fill, Fill) : let clear_reference_button = Button("Clear reference")
+                (5, 3)(1, 1) Vertical (Fill, Fill) : let color_by_screen_checkbox = Checkbox("Color by screen", checked: color_by_screen.get_untracked())
+                (0, 5)(1, 1) Vertical (Fill, Fill) : let aimpoint_readout_checkbox = Checkbox("Show aimpoint", checked: false)
+                (1, 5)(1, 1) Vertical (Fill, Center) : let aimpoint_readout_label = Label("Decimals")
+                (2, 5)(1, 1) Vertical (Fill, Fill) : let aimpoint_readout_spinbox = Spinbox(0, 8, signal: aimpoint_readout_precision)
+                (3, 5)(1, 1) Vertical (Fill, Center) : let nf_glyph_label = Label("NF markers")
+                (4, 5)(1, 1) Vertical (Fill, Fill) : let nf_glyph_combobox = Combobox(signal: nf_glyph) { "Cross", "X", "Diamond", "Square", "Circle" }
+                (5, 5)(1, 1) Vertical (Fill, Center) : let wf_glyph_label = Label("WF markers")
+                (6, 5)(1, 1) Vertical (Fill, Fill) : let wf_glyph_combobox = Combobox(signal: wf_glyph) { "Cross", "X", "Diamond", "Square", "Circle" }
+                (7, 5)(1, 1) Vertical (Fill, Center) : let pattern_tolerance_label = Label("Wrong screen tolerance (%)")
+                (8, 5)(1, 1) Vertical (Fill, Fill) : let pattern_tolerance_spinbox = Spinbox(1, 100, signal: pattern_tolerance)
+                (0, 6)(1, 1) Vertical (Fill, Center) : let raw_average_label = Label("Average raw frames")
+                (1, 6)(1, 1) Vertical (Fill, Fill) : let raw_average_spinbox = Spinbox(1, 1000, signal: raw_average_frames)
+                (2, 6)(1, 1) Vertical (Fill, Fill) : let reset_average_button = Button("Reset average")
+                (9, 5)(1, 1) Vertical (Fill, Fill) : let quick_record_button = Button(move || {
+                    if !quick_recording.get() { "Quick Record" } else { "Stop Quick Record" }
+                }, enabled: move || quick_recording.get() || !recording.get())
+                (10, 5)(1, 1) Vertical (Fill, Fill) : let recording_dir_button = Button("Recording Folder...")
+                (11, 5)(3, 1) Vertical (Fill, Center) : let recording_dir_label = Label(move || match last_quick_recording.get() {
+                    Some(path) => format!("Saved {}", path.display()),
+                    None => match recording_settings.with(|s| s.directory()) {
+                        Some(dir) => format!("Quick recordings go to {}", dir.display()),
+                        None => "No folder for quick recordings".into(),
+                    },
+                })
+                (12, 4)(1, 1) Vertical (Fill, Center) : let aimpoint_lost_label = Label("When tracking is lost")
+                (13, 4)(1, 1) Vertical (Fill, Fill) : let aimpoint_lost_combobox = Combobox(signal: aimpoint_lost_behavior) { "Hide aimpoint", "Gray out aimpoint", "Hold last aimpoint" }
+                (0, 4)(1, 1) Vertical (Fill, Fill) : let load_screen_info_button = Button("Load Screen Info")
+                (1, 4)(10, 1) Vertical (Fill, Center) : let screen_info_path_label = Label(move || {
+                    let path = screen_info_path.with(|p| match p {
+                        Some(p) => p.display().to_string(),
+                        None => "default".into(),
+                    });
+                    let unit = LengthUnit::from_index(length_unit.get());
+                    let span = screen_span.get().map(|x| unit.from_meters(x));
+                    format!("Screen info: {path}, markers span {:.2} × {:.2} {}", span.x, span.y, unit.name())
+                })
+                (11, 4)(1, 1) Vertical (Fill, Fill) : let length_unit_combobox = Combobox(signal: length_unit) {}
             }
             Compact: let separator = HorizontalSeparator()
             Compact: let spacer = Spacer()
             Compact: let form_vbox = VerticalBox(padded: true) {
                 Compact: let form = Form(padded: true) {
                     (Compact, "Datapoints added:"): let collected_text = Label("")
+                    (Compact, "Region tally:"): let region_tally_text = Label("")
                     (Compact, "Dataset"): let dataset_controls_group = HorizontalBox(padded: true) {
                         Compact: let add_datapoint_btn = Button("Add datapoint")
                         Compact: let remove_datapoint_btn = Button("Remove datapoint")
                         Compact: let clear_datapoints_btn = Button("Clear datapoints")
-                        Compact: let record_impacts_cbx = Checkbox("Record impacts", checked: false)
+                        Compact: let record_impacts_cbx = Checkbox("Armed", checked: false)
+                        Compact: let max_shots_label = Label("Disarm after shots (0 = never)")
+                        Compact: let max_shots_spinbox = Spinbox(0, 1000, signal: max_shots)
+                        Compact: let idle_timeout_label = Label("Disarm after idle seconds (0 = never)")
+                        Compact: let idle_timeout_spinbox = Spinbox(0, 3600, signal: idle_timeout_s)
+                        Compact: let impact_debounce_label = Label("Ignore repeat impacts within (ms)")
+                        Compact: let impact_debounce_spinbox = Spinbox(0, 1000, signal: impact_debounce_ms)
+                        Compact: let show_shots_cbx = Checkbox("Show shots", checked: true)
+                        Compact: let shot_persistence_label = Label("for seconds (0 = until cleared)")
+                        Compact: let shot_persistence_spinbox = Spinbox(0, 3600, signal: shot_persistence)
+                        Compact: let clear_shots_btn = Button("Clear shots")
                         Compact: let save_datapoints_btn = Button("Save to file")
                     }
                 }
@@ -292,16 +640,23 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
                 Stretchy: let run_raw_area = Area(Box::new(RunRawCanvas {
                     ctx: ui.c(),
                     runner: mot_runner.c(),
+                    raw: true,
                 }))
             }
             Stretchy: let run_hbox = HorizontalBox() {
                 Stretchy: let run_area = Area(Box::new(RunCanvas {
                     ctx: ui.c(),
                     runner: mot_runner.c(),
+                    raw: false,
+                    last_draw_width: None,
+                    last_draw_height: None,
                 }))
             }
         }
     }
+    for name in LengthUnit::NAMES {
+        length_unit_combobox.append(&ui, name);
+    }
     form_vbox.hide(&ui);
     run_raw_hbox.hide(&ui);
     run_hbox.hide(&ui);
@@ -360,15 +715,28 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     create_effect({
         let ui = ui.c();
         let collected_text = collected_text.c();
+        let region_tally_text = region_tally_text.c();
         let datapoints = datapoints.c();
+        let mot_runner = mot_runner.c();
         move |_| {
             ui_update.with(|_| {
+                // Don't hold the runner and datapoints locks at the same time
+                let region_names: Vec<_> = mot_runner.lock().target_regions.regions.iter().map(|r| r.name.clone()).collect();
+
                 let datapoints = datapoints.c();
                 let datapoints = datapoints.lock();
 
                 let mut collected_text = collected_text.c();
+                let mut region_tally_text = region_tally_text.c();
 
                 collected_text.set_text(&ui, &datapoints.len().to_string());
+
+                let tally = region_names.iter().map(|name| {
+                    let count = datapoints.iter().filter(|d| d.region.as_ref() == Some(name)).count();
+                    format!("{}: {}", name, count)
+                }).collect::<Vec<_>>();
+                let misses = datapoints.iter().filter(|d| d.region.is_none()).count();
+                region_tally_text.set_text(&ui, &format!("{}, miss: {}", tally.join(", "), misses));
             });
         }
     });
@@ -512,9 +880,10 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
             let mut frame = TestFrame {
                               fv_aimpoint_x: None,
                               fv_aimpoint_y: None,
+                              region: None,
                               };
 
-            let runner = state.lock();
+            let mut runner = state.lock();
             let state = &runner.state;
 
             {
@@ -522,10 +891,16 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
                 let fv_aimpoint = fv_aimpoint;
                 frame.fv_aimpoint_x = Some(fv_aimpoint.x);
                 frame.fv_aimpoint_y = Some(fv_aimpoint.y);
+                frame.region = runner.target_regions.classify(fv_aimpoint).map(|i| runner.target_regions.regions[i].name.clone());
             }
 
             datapoints.push(frame);
             collected_text.set_text(&ui, datapoints.len().to_string().as_str());
+
+            let fv_aimpoint = runner.state.fv_aimpoint;
+            let now = runner.clock.now();
+            runner.shot_markers.push(now, fv_aimpoint);
+            runner.redraw_pending = true;
         }
     });
 
@@ -556,7 +931,258 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     record_impacts_cbx.on_toggled(&ui, {
         let mot_runner = mot_runner.c();
         move |checked| {
-            mot_runner.lock().record_impact = checked;
+            let mut runner = mot_runner.lock();
+            let now = runner.clock.now();
+            let arming = &mut runner.impact_arming;
+            if checked {
+                arming.arm(now);
+            } else {
+                arming.disarm();
+            }
+        }
+    });
+
+    show_shots_cbx.on_toggled(&ui, {
+        let mot_runner = mot_runner.c();
+        move |checked| {
+            let mut runner = mot_runner.lock();
+            runner.shot_markers.enabled = checked;
+            runner.redraw_pending = true;
+        }
+    });
+
+    clear_shots_btn.on_clicked(&ui, {
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let mut runner = mot_runner.lock();
+            runner.shot_markers.clear();
+            runner.redraw_pending = true;
+        }
+    });
+
+    high_contrast_checkbox.on_toggled(&ui, move |checked| high_contrast_palette.set(checked));
+    color_by_screen_checkbox.on_toggled(&ui, move |checked| color_by_screen.set(checked));
+    aimpoint_readout_checkbox.on_toggled(&ui, move |checked| show_aimpoint_readout.set(checked));
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let mut runner = mot_runner.lock();
+            runner.aimpoint_readout_precision = show_aimpoint_readout.get().then(|| aimpoint_readout_precision.get().clamp(0, 8) as usize);
+            runner.redraw_pending = true;
+        }
+    });
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let mut runner = mot_runner.lock();
+            runner.display = DisplaySettings {
+                brightness: f64::from(display_brightness.get()) / 100.,
+                contrast: f64::from(display_contrast.get()) / 100.,
+                high_contrast_palette: high_contrast_palette.get(),
+                color_by_screen: color_by_screen.get(),
+                nf_glyph: MarkerGlyph::from_index(nf_glyph.get()).unwrap_or(runner.display.nf_glyph),
+                wf_glyph: MarkerGlyph::from_index(wf_glyph.get()).unwrap_or(runner.display.wf_glyph),
+                ..runner.display
+            };
+            runner.redraw_pending = true;
+        }
+    });
+
+    capture_reference_button.on_clicked(&ui, {
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let mut runner = mot_runner.lock();
+            runner.state.capture_reference();
+            runner.redraw_pending = true;
+        }
+    });
+    clear_reference_button.on_clicked(&ui, {
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let mut runner = mot_runner.lock();
+            runner.state.reference_markers = None;
+            runner.redraw_pending = true;
+        }
+    });
+
+    dump_assignment_button.on_clicked(&ui, {
+        let ui = ui.c();
+        let main_win = main_win.c();
+        let mot_runner = mot_runner.c();
+        move |_| {
+            // The frame on screen when the button was clicked, not whenever the dialog is closed
+            let snapshot = {
+                let runner = mot_runner.lock();
+                let timestamp_ms = runner.clock.unix_millis() as u64;
+                MarkerSnapshot::from_state(timestamp_ms, &runner.state, &runner.general_config(), &runner.screen_info.marker_points)
+            };
+            let Some(mut path_buf) = main_win.save_file_with_filter(&ui, &[FileTypeFilter::new("json").extension("json")]) else {
+                return;
+            };
+            if path_buf.extension() != Some("json".as_ref()) {
+                path_buf.as_mut_os_string().push(".json");
+            }
+            if let Err(e) = snapshot.save_to_path(&path_buf) {
+                main_win.modal_err(&ui, "Failed to save marker assignment", &e.to_string());
+            }
+        }
+    });
+
+    clear_selection_button.on_clicked(&ui, {
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let mut runner = mot_runner.lock();
+            runner.state.selected_track = None;
+            runner.redraw_pending = true;
+        }
+    });
+
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let seconds = shot_persistence.get();
+            mot_runner.lock().shot_markers.persistence = (seconds > 0).then(|| std::time::Duration::from_secs(seconds as u64));
+        }
+    });
+
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let max_shots = max_shots.get();
+            mot_runner.lock().impact_arming.max_shots = (max_shots > 0).then_some(max_shots as u32);
+        }
+    });
+
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let seconds = idle_timeout_s.get();
+            mot_runner.lock().impact_arming.idle_timeout = (seconds > 0).then(|| std::time::Duration::from_secs(seconds as u64));
+        }
+    });
+
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let ms = impact_debounce_ms.get().max(0) as u64;
+            mot_runner.lock().impact_debounce.window = std::time::Duration::from_millis(ms);
+        }
+    });
+
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            mot_runner.lock().pattern_id_debounce_frames = pattern_id_debounce_frames.get().max(1) as u32;
+        }
+    });
+
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            mot_runner.lock().acquire_frames = acquire_frames.get().max(0) as u32;
+        }
+    });
+
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let mut runner = mot_runner.lock();
+            runner.grid_subdivisions = (grid_x.get().max(1) as usize, grid_y.get().max(1) as usize);
+            runner.redraw_pending = true;
+        }
+    });
+
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            mot_runner.lock().max_imu_dt = std::time::Duration::from_millis(max_imu_dt_ms.get().max(1) as u64);
+        }
+    });
+
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let mut runner = mot_runner.lock();
+            runner.label_verbosity = LabelVerbosity::from_index(label_verbosity.get());
+            runner.redraw_pending = true;
+        }
+    });
+
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let mut runner = mot_runner.lock();
+            runner.aimpoint_lost_behavior = AimpointLostBehavior::from_index(aimpoint_lost_behavior.get());
+            runner.redraw_pending = true;
+        }
+    });
+
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            mot_runner.lock().track_gate = f64::from(track_gate.get().max(1)) / 1000.;
+        }
+    });
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            mot_runner.lock().pattern_mismatch_threshold = f64::from(pattern_tolerance.get().max(1)) / 100.;
+        }
+    });
+
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let mut runner = mot_runner.lock();
+            runner.raw_min_area = raw_min_area.get().clamp(0, i32::from(u16::MAX)) as u16;
+            runner.redraw_pending = true;
+        }
+    });
+
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            mot_runner.lock().raw_average_frames = raw_average_frames.get().max(1) as usize;
+        }
+    });
+
+    reset_average_button.on_clicked(&ui, {
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let mut runner = mot_runner.lock();
+            runner.nf_average.reset();
+            runner.wf_average.reset();
+        }
+    });
+
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            // Keep the last valid order while the entry is being edited
+            match Overlay::parse_order(&overlay_order.get()) {
+                Ok(order) => {
+                    let mut runner = mot_runner.lock();
+                    runner.overlay_order = order;
+                    runner.redraw_pending = true;
+                }
+                Err(e) => info!("overlay order not applied: {e}"),
+            }
+        }
+    });
+
+    // Uncheck the armed checkbox when the runner disarms itself
+    create_effect({
+        let ui = ui.c();
+        let record_impacts_cbx = record_impacts_cbx.c();
+        let mot_runner = mot_runner.c();
+        move |_| {
+            ui_update.with(|_| {
+                let armed = {
+                    let runner = mot_runner.lock();
+                    runner.impact_arming.is_armed(runner.clock.now())
+                };
+                record_impacts_cbx.c().set_checked(&ui, armed);
+            });
         }
     });
 
@@ -615,6 +1241,162 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
 
+    for (checkbox, ty) in [
+        (&record_markers_checkbox, PacketType::CombinedMarkersReport),
+        (&record_mot_checkbox, PacketType::ObjectReport),
+        (&record_accel_checkbox, PacketType::AccelReport),
+    ] {
+        checkbox.c().on_toggled(&ui, {
+            let mot_runner = mot_runner.c();
+            move |checked| mot_runner.lock().record_mask.set(ty, checked)
+        });
+    }
+
+    pose_log_button.on_clicked(&ui, {
+        let ui = ui.c();
+        let main_win = main_win.c();
+        let mot_runner = mot_runner.c();
+        move |_| {
+            if pose_logging.get_untracked() {
+                // Dropping the log flushes it
+                mot_runner.lock().pose_log = None;
+                pose_logging.set(false);
+                return;
+            }
+            let Some(mut path_buf) = main_win.save_file_with_filter(&ui, &[FileTypeFilter::new("csv").extension("csv")]) else {
+                return;
+            };
+            if path_buf.extension() != Some("csv".as_ref()) {
+                path_buf.as_mut_os_string().push(".csv");
+            }
+            match PoseLog::create(&path_buf) {
+                Ok(pose_log) => {
+                    mot_runner.lock().pose_log = Some(pose_log);
+                    pose_logging.set(true);
+                }
+                Err(e) => main_win.modal_err(&ui, "Failed to create pose log", &e.to_string()),
+            }
+        }
+    });
+
+    load_screen_info_button.on_clicked(&ui, {
+        let ui = ui.c();
+        let main_win = main_win.c();
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let Some(path_buf) = main_win.open_file(&ui) else {
+                return;
+            };
+            match ScreenInfo::load_from_path(&path_buf) {
+                Ok(screen_info) => {
+                    if !screen_info.is_coplanar() {
+                        main_win.modal_msg(&ui, "Screen info isn't planar", NOT_COPLANAR_WARNING);
+                    }
+                    screen_span.set(screen_info.marker_span());
+                    mot_runner.lock().screen_info = screen_info;
+                    screen_info_path.set(Some(path_buf));
+                }
+                Err(e) => main_win.modal_err(&ui, "Failed to load screen info", &format!("{e:#}")),
+            }
+        }
+    });
+
+    for screen_id in ScreenId::valid() {
+        let mut checkbox = Checkbox::new(&ui, &screen_id.0.to_string());
+        checkbox.set_checked(&ui, true);
+        checkbox.on_toggled(&ui, {
+            let mot_runner = mot_runner.c();
+            move |checked| mot_runner.lock().tracked_screen_ids.set(screen_id, checked)
+        });
+        screen_ids_hbox.append(&ui, checkbox, LayoutStrategy::Compact);
+    }
+
+    impact_capture_button.on_clicked(&ui, {
+        let ui = ui.c();
+        let main_win = main_win.c();
+        let mot_runner = mot_runner.c();
+        move |_| {
+            if impact_capturing.get_untracked() {
+                // A capture still in its post-roll is dropped
+                mot_runner.lock().impact_capture = None;
+                impact_capturing.set(false);
+                return;
+            }
+            // Each capture is written next to this file, suffixed with the impact timestamp
+            let Some(path_buf) = main_win.save_file_with_filter(&ui, &[FileTypeFilter::new("bin").extension("bin")]) else {
+                return;
+            };
+            mot_runner.lock().impact_capture = Some(ImpactCapture::new(
+                path_buf,
+                std::time::Duration::from_millis(pre_roll_ms.get_untracked().max(0) as u64),
+                std::time::Duration::from_millis(post_roll_ms.get_untracked().max(0) as u64),
+            ));
+            impact_capturing.set(true);
+        }
+    });
+
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let pre_roll = std::time::Duration::from_millis(pre_roll_ms.get().max(0) as u64);
+            let post_roll = std::time::Duration::from_millis(post_roll_ms.get().max(0) as u64);
+            if let Some(capture) = &mut mot_runner.lock().impact_capture {
+                capture.pre_roll = pre_roll;
+                capture.post_roll = post_roll;
+            }
+        }
+    });
+
+    quick_record_button.on_clicked(&ui, {
+        let ui = ui.c();
+        let main_win = main_win.c();
+        let packets = packets.c();
+        let mot_runner = mot_runner.c();
+        let compress_recording_checkbox = compress_recording_checkbox.c();
+        move |_| {
+            if !quick_recording.get_untracked() {
+                packets.lock().clear();
+                mot_runner.lock().record_packets = true;
+                recording.set(true);
+                quick_recording.set(true);
+                return;
+            }
+            let (general_config, timestamp_ms) = {
+                let mut runner = mot_runner.lock();
+                runner.record_packets = false;
+                (runner.general_config(), runner.clock.unix_millis())
+            };
+            recording.set(false);
+            quick_recording.set(false);
+            let Some(dir) = recording_settings.with_untracked(|s| s.directory()) else {
+                main_win.modal_err(&ui, "Failed to save recording", "No folder for quick recordings, pick one with Recording Folder...");
+                return;
+            };
+            let name = recording_settings::auto_name(timestamp_ms, &general_config.uuid);
+            let compress = compress_recording_checkbox.checked(&ui);
+            let result = recording_settings::create_new(&dir, &name).and_then(|(path_buf, file)| {
+                ats_playback::write(file, &general_config, &packets.lock(), compress).map(|()| path_buf)
+            });
+            match result {
+                Ok(path_buf) => last_quick_recording.set(Some(path_buf)),
+                Err(e) => main_win.modal_err(&ui, "Failed to save recording", &e.to_string()),
+            }
+        }
+    });
+
+    recording_dir_button.on_clicked(&ui, {
+        let ui = ui.c();
+        let main_win = main_win.c();
+        move |_| {
+            let Some(dir) = main_win.open_folder(&ui) else {
+                return;
+            };
+            recording_settings.update(|s| s.directory = Some(dir));
+            recording_settings.with_untracked(RecordingSettings::save_to_file);
+            last_quick_recording.set(None);
+        }
+    });
+
     clear_packets_button.on_clicked(&ui, {
         let packets = packets.c();
         move |_| {
@@ -627,6 +1409,7 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
         let main_win = main_win.c();
         let packets = packets.c();
         let mot_runner = mot_runner.c();
+        let compress_recording_checkbox = compress_recording_checkbox.c();
         move |_| {
             let packets = packets.lock();
             let path_buf = main_win.save_file_with_filter(&ui, &[FileTypeFilter::new("bin").extension("bin")]);
@@ -634,25 +1417,36 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
                 if path_buf.extension() != Some("bin".as_ref()) {
                     path_buf.as_mut_os_string().push(".bin");
                 }
-                let mut file = File::create(path_buf).expect("Could not create file");
-                let mut bytes = Vec::new();
-
-                mot_runner.lock().general_config.serialize(&mut bytes);
-
-                for (timestamp, packet_data) in packets.iter() {
-                    let packet = ats_usb::packet::Packet {
-                        data: packet_data.clone(),
-                        id: 0,
-                    };
-                    bytes.extend_from_slice(&timestamp.to_le_bytes());
-                    packet.serialize(&mut bytes);
-                }
-
-                file.write_all(&bytes).expect("Could not write to file");
+                let general_config = mot_runner.lock().general_config();
+                let compress = compress_recording_checkbox.checked(&ui);
+                ats_playback::write_file(&path_buf, &general_config, &packets, compress).expect("Could not write to file");
             }
         }
     });
 
+    // Stop the devices before quitting rather than after the event loop is gone
+    main_win.on_closing(&ui, {
+        let ui = ui.c();
+        let mot_runner = mot_runner.c();
+        let device_runners = device_runners.c();
+        move |_: &mut _| {
+            tracking_raw.set(false);
+            tracking.set(false);
+            testing.set(false);
+            marker_offset_calibrating.set(false);
+            ui.spawn({
+                let ui = ui.c();
+                let mot_runner = mot_runner.c();
+                let device_runners = device_runners.c();
+                async move {
+                    vision_module_gui::mot_runner::shutdown(mot_runner).await;
+                    device_runners.shutdown().await;
+                    ui.quit();
+                }
+            });
+        }
+    });
+
     main_win.show(&ui);
 
     ui.ui_timer(5, {
@@ -660,7 +1454,24 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
         let run_raw_area = run_raw_area.c();
         let run_area = run_area.c();
         let test_area = test_area.c();
+        let mot_runner = mot_runner.c();
+        let mut last_redraw = std::time::Instant::now();
         move || {
+            // Coalesce updates so the canvases are redrawn at most `max_redraw_rate` times per
+            // second. The pending flag stays set until a redraw happens, so the latest state is
+            // always drawn eventually.
+            let min_period = std::time::Duration::from_secs_f64(1.0 / max_redraw_rate.get_untracked().max(1) as f64);
+            if last_redraw.elapsed() < min_period {
+                return true;
+            }
+            {
+                let mut runner = mot_runner.lock();
+                if !runner.redraw_pending {
+                    return true;
+                }
+                runner.redraw_pending = false;
+            }
+            last_redraw = std::time::Instant::now();
             if tracking_raw.get_untracked() {
                 run_raw_area.queue_redraw_all(&ui);
             }
@@ -678,6 +1489,24 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut ev = ui.event_loop();
     ev.run(&ui);
 
+    for (name, window) in &layout_windows {
+        window_layout.remember(&ui, name, window);
+    }
+    window_layout.save_to_file();
+    mot_runner.lock().display.save_to_file();
+
+    // Don't leave the device streaming after we exit. Already done if the main window was
+    // closed, this covers quitting any other way.
+    tracking_raw.set(false);
+    tracking.set(false);
+    testing.set(false);
+    marker_offset_calibrating.set(false);
+    tokio_handle.block_on(vision_module_gui::mot_runner::shutdown(mot_runner.c()));
+    tokio_handle.block_on(device_runners.shutdown());
+
     leptos_rt.dispose();
+    drop(_enter);
+    // Wait for the stream loops to wind down and release the device
+    tokio_rt.shutdown_timeout(std::time::Duration::from_secs(1));
     Ok(())
 }
@@ -1,9 +1,10 @@
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use ats_usb::packet::{Packet, PacketData, Port, Register};
-use ats_usb::device::{decode_slip_frame, SLIP_FRAME_END};
+use ats_usb::device::{decode_packet_frame, SLIP_FRAME_END};
 // use multicast_socket::MulticastSocket;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use std::net::UdpSocket;
+use tracing::debug;
 
 fn main() {
     let local_addr = SockAddr::from(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
@@ -95,12 +96,11 @@ fn main() {
             while slip_buf.contains(&SLIP_FRAME_END) {
                 let end_idx = slip_buf.iter().position(|&x| x == SLIP_FRAME_END).unwrap();
                 let mut slice_vec = &mut slip_buf[..end_idx].to_vec();
-                if let Ok(()) = decode_slip_frame(slice_vec) {
-                    process_one(start_time, &mut slice_vec, &mut total_accel_samples, &mut total_combined_marker_samples, addr);
-                    slip_buf.drain(0..end_idx+1);
-                } else {
-                    slip_buf.drain(0..end_idx+1);
+                match decode_packet_frame(slice_vec) {
+                    Ok(()) => process_one(start_time, &mut slice_vec, &mut total_accel_samples, &mut total_combined_marker_samples, addr),
+                    Err(e) => debug!("skipping frame from {addr}: {e}"),
                 }
+                slip_buf.drain(0..end_idx+1);
             }
         }
     }
@@ -11,7 +11,7 @@ use iui::{
 use leptos_reactive::{Effect, RwSignal, SignalGet as _, SignalGetUntracked, SignalSet as _};
 use opencv_ros_camera::RosOpenCvIntrinsics;
 use tracing::{error, info};
-use ats_usb::{device::encode_slip_frame, packet::{GeneralConfig, Packet, PacketData, ReadRegisterResponse}};
+use ats_usb::{device::encode_slip_frame, packet::{CameraModelType, GeneralConfig, Packet, PacketData, ReadRegisterResponse}};
 
 // Positive x is right
 // Positive y is up
@@ -153,6 +153,11 @@ fn socket_serve_thread(mut sock: TcpStream, state: Arc<Mutex<State>>, ui_ctx: iu
                 None
             }
             PacketData::FlashSettings() => None,
+            #[cfg(feature = "experimental-write-uuid")]
+            PacketData::WriteUuid(uuid) => {
+                state.general_config.uuid = uuid;
+                None
+            }
             PacketData::CombinedMarkersReport(_) => unreachable!(),
             PacketData::ImpactReport(_) => unreachable!(),
             PacketData::AccelReport(_) => unreachable!(),
@@ -291,6 +296,8 @@ impl State {
                     49.,
                     49.,
                 ),
+                camera_model_type_nf: CameraModelType::Pinhole,
+                camera_model_type_wf: CameraModelType::Pinhole,
                 stereo_iso: nalgebra::Isometry3::identity(),
                 accel_odr: 100,
                 uuid: [42, 69, 3, 7, 9, 13],
@@ -12,19 +12,32 @@ use nalgebra::{
     Matrix3, Matrix4, Point, Point2, Point3, Rotation3, SVector, Scale2, Scale3, Transform3, Translation2, Translation3, Vector3, Vector4, coordinates::XY
 };
 use opencv_ros_camera::RosOpenCvIntrinsics;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use tracing::{error, info};
-use ats_usb::{device::encode_slip_frame, packet::{CombinedMarkersReport, GeneralConfig, ObjectReport, Packet, PacketData, ReadRegisterResponse}};
+use ats_usb::{device::encode_slip_frame, packet::{CameraModelType, CombinedMarkersReport, GeneralConfig, ObjectReport, Packet, PacketData, ReadRegisterResponse, ScreenId}};
 use vision_module_gui::{custom_shapes::draw_diamond, mot_runner::sort_rectangle };
 
 // Positive x is right
 // Positive y is up
 // Right hand rule (z is out from the screen)
 
+/// Usage: sim [port] [--seed N] [--jitter PIXELS] [--dropout PROBABILITY]
 fn main() {
     let mut port = 4444u16;
-    if let Some(port_arg) = std::env::args().nth(1) {
-        port = port_arg.parse().unwrap();
+    let mut seed = None;
+    let mut jitter = 0.0;
+    let mut dropout = 0.0;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => seed = Some(args.next().expect("missing seed").parse().unwrap()),
+            "--jitter" => jitter = args.next().expect("missing jitter").parse().unwrap(),
+            "--dropout" => dropout = args.next().expect("missing dropout").parse().unwrap(),
+            _ => port = arg.parse().unwrap(),
+        }
     }
+    let seed = seed.unwrap_or_else(|| rand::thread_rng().gen());
+    info!("Simulator seed: {seed}");
     let ui = UI::init().expect("Couldn't initialize UI library");
     let mut main_win = Window::new(
         &ui,
@@ -33,7 +46,7 @@ fn main() {
         480,
         WindowType::NoMenubar,
     );
-    let state = Arc::new(Mutex::new(State::new()));
+    let state = Arc::new(Mutex::new(State::new(SensorNoise::new(seed, jitter, dropout))));
     vision_module_gui::layout! { &ui,
         let vbox = VerticalBox(padded: false) {
             Compact : let text = Label("")
@@ -121,6 +134,8 @@ fn socket_serve_thread(mut sock: TcpStream, state: Arc<Mutex<State>>) {
                 None
             }
             PacketData::FlashSettings() => None,
+            #[cfg(feature = "experimental-write-uuid")]
+            PacketData::WriteUuid(_) => None,
             PacketData::CombinedMarkersReport(_) => unreachable!(),
             PacketData::ImpactReport(_) => unreachable!(),
             PacketData::AccelReport(_) => unreachable!(),
@@ -141,6 +156,8 @@ fn socket_serve_thread(mut sock: TcpStream, state: Arc<Mutex<State>>) {
                     49.,
                     49.,
                 ),
+                camera_model_type_nf: CameraModelType::Pinhole,
+                camera_model_type_wf: CameraModelType::Pinhole,
                 stereo_iso: nalgebra::Isometry3::identity(),
                 accel_odr: 100,
                 uuid: [42, 69, 3, 7, 9, 13],
@@ -162,12 +179,9 @@ fn socket_stream_thread(mut sock: TcpStream, state: Arc<Mutex<State>>) {
     let mut buf = vec![0; 1024];
     loop {
         std::thread::sleep(Duration::from_millis(10));
-        let state = state.lock().unwrap();
+        let mut state = state.lock().unwrap();
 
-        let mut nf_markers: Vec<_> = state.calculate_nf_positions();
-        sort_rectangle(&mut nf_markers);
-        let nf_markers = nf_markers;
-        let wf_markers: Vec<_> = state.calculate_wf_positions();
+        let (nf_markers, wf_markers) = state.sample_markers();
 
         if let Some(id) = state.stream_mot {
             let mut object_report = ObjectReport::default();
@@ -213,19 +227,19 @@ fn socket_stream_thread(mut sock: TcpStream, state: Arc<Mutex<State>>) {
             //     marker_pattern[3],
             // ).unwrap();
             let make_positions = |markers: &[Point2<i16>]| {
-                let mut radii = [0; 16];
+                let mut screen_ids = [ScreenId::default(); 16];
                 let positions = std::array::from_fn(|i| {
                     let Some(XY { x, y }) = markers.get(i).map(|x| **x) else {
                         return Point2::default()
                     };
                     if (0..4096).contains(&x) && (0..4096).contains(&y) {
-                        radii[i] = 1;
+                        screen_ids[i] = ScreenId(1);
                         Point2::new(x as u16, y as u16)
                     } else {
                         Point2::default()
                     }
                 });
-                (positions, radii)
+                (positions, screen_ids)
             };
             let (nf_positions, nf_screen_ids) = make_positions(&nf_markers);
             let (wf_positions, wf_screen_ids) = make_positions(&wf_markers);
@@ -260,11 +274,44 @@ struct State {
     moving_down: bool,
     stream_mot: Option<u8>,
     stream_combined_markers: Option<u8>,
+    noise: SensorNoise,
     // marker_pattern: MarkerPattern,
 }
 
+/// Noise added to the marker positions sent to the client. Seeded so a run can be replayed.
+struct SensorNoise {
+    rng: StdRng,
+    /// Maximum offset of each coordinate, in sensor pixels.
+    jitter: f64,
+    /// Probability of a marker not being reported in a frame.
+    dropout: f64,
+}
+
+impl SensorNoise {
+    fn new(seed: u64, jitter: f64, dropout: f64) -> Self {
+        Self { rng: StdRng::seed_from_u64(seed), jitter, dropout }
+    }
+
+    /// Dropped markers are moved off the sensor so they get filtered out like any other marker
+    /// that isn't visible.
+    fn apply(&mut self, markers: &mut [Point2<i16>]) {
+        for marker in markers {
+            // Always draw the same number of samples so the stream doesn't depend on the settings
+            let dropped = self.rng.gen::<f64>() < self.dropout;
+            let dx = self.rng.gen_range(-1.0, 1.0) * self.jitter;
+            let dy = self.rng.gen_range(-1.0, 1.0) * self.jitter;
+            if dropped {
+                *marker = Point2::new(-1, -1);
+            } else {
+                marker.x = marker.x.saturating_add(dx.round() as i16);
+                marker.y = marker.y.saturating_add(dy.round() as i16);
+            }
+        }
+    }
+}
+
 impl State {
-    fn new() -> Self {
+    fn new(noise: SensorNoise) -> Self {
         let tf = Scale3::new(GRID_WIDTH, GRID_HEIGHT, 1.0).to_homogeneous()
             * Translation3::new(-0.5, -0.5, 0.0).to_homogeneous();
         let tf = Transform3::from_matrix_unchecked(tf);
@@ -321,6 +368,7 @@ impl State {
             moving_down: false,
             stream_mot: None,
             stream_combined_markers: None,
+            noise,
             // marker_pattern: MarkerPattern::Rectangle,
         }
     }
@@ -406,6 +454,16 @@ impl State {
             Point2::new(p.x.round() as i16, p.y.round() as i16)
         }).collect()
     }
+
+    /// The nf and wf marker positions for the next frame, with noise.
+    fn sample_markers(&mut self) -> (Vec<Point2<i16>>, Vec<Point2<i16>>) {
+        let mut nf_markers = self.calculate_nf_positions();
+        sort_rectangle(&mut nf_markers);
+        let mut wf_markers = self.calculate_wf_positions();
+        self.noise.apply(&mut nf_markers);
+        self.noise.apply(&mut wf_markers);
+        (nf_markers, wf_markers)
+    }
 }
 
 struct MainCanvas {
@@ -675,3 +733,18 @@ fn wf_focal_length() -> f64 {
     let fov = fov_deg / 180.0 * std::f64::consts::PI;
     1. / (fov / 2.).tan()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{SensorNoise, State};
+
+    #[test]
+    fn test_same_seed_same_markers() {
+        let stream = |seed| {
+            let mut state = State::new(SensorNoise::new(seed, 5.0, 0.2));
+            (0..100).map(|_| state.sample_markers()).collect::<Vec<_>>()
+        };
+        assert_eq!(stream(1234), stream(1234));
+        assert_ne!(stream(1234), stream(4321));
+    }
+}
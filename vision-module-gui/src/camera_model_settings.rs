@@ -0,0 +1,50 @@
+//! Remembers the camera model type picked for each port. The device doesn't store it, so without
+//! this it would go back to pinhole at every launch.
+
+use ats_usb::packet::CameraModelType;
+use serde::{Deserialize, Serialize};
+
+use crate::settings_file;
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "CameraModelType")]
+enum CameraModelTypeDef {
+    Pinhole,
+    Fisheye,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct CameraModelSettings {
+    #[serde(with = "CameraModelTypeDef")]
+    pub nf: CameraModelType,
+    #[serde(with = "CameraModelTypeDef")]
+    pub wf: CameraModelType,
+}
+
+impl CameraModelSettings {
+    /// Load the settings from `camera_model.toml` in the config directory, or the defaults.
+    pub fn load_from_file() -> Self {
+        settings_file::load("camera_model.toml", toml::from_str)
+    }
+
+    pub fn save_to_file(&self) {
+        settings_file::save("camera_model.toml", self, "camera model settings");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ats_usb::packet::CameraModelType;
+
+    use super::CameraModelSettings;
+
+    #[test]
+    fn test_toml_round_trip() {
+        let settings = CameraModelSettings { nf: CameraModelType::Pinhole, wf: CameraModelType::Fisheye };
+        let data = toml::to_string(&settings).unwrap();
+        assert_eq!(toml::from_str::<CameraModelSettings>(&data).unwrap(), settings);
+        // Missing ports are pinhole
+        assert_eq!(toml::from_str::<CameraModelSettings>("wf = \"Fisheye\"").unwrap(), settings);
+    }
+}
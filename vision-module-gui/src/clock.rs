@@ -0,0 +1,90 @@
+//! Time source of the runner loops. Everything time-dependent in `MotRunner` reads the time
+//! through `MotRunner::clock` so it can be tested with a `MockClock`.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+
+pub trait Clock: Send + Sync {
+    /// Monotonic time, for durations and timeouts.
+    fn now(&self) -> Instant;
+
+    /// Wall clock time, for timestamps in recordings and logs.
+    fn system_now(&self) -> SystemTime;
+
+    /// Milliseconds since the unix epoch, the timestamp format of the recordings.
+    fn unix_millis(&self) -> u128 {
+        self.system_now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock that only moves when `advance` is called. For tests.
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    system_start: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl MockClock {
+    /// `system_start` is what `system_now` returns until the clock is advanced.
+    pub fn new(system_start: SystemTime) -> Self {
+        Self { start: Instant::now(), system_start, elapsed: Mutex::new(Duration::ZERO) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock() += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.system_start + *self.elapsed.lock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{Clock, MockClock};
+    use crate::connection_health::{ConnectionHealth, HealthStatus, HealthThresholds};
+
+    #[test]
+    fn test_mock_clock_drives_health() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let thresholds = HealthThresholds::default();
+        let mut health = ConnectionHealth::default();
+
+        // 100 Hz for 2 seconds
+        for _ in 0..200 {
+            health.on_packet(clock.now());
+            clock.advance(Duration::from_millis(10));
+        }
+        assert_eq!(health.report(clock.now(), &thresholds).status, HealthStatus::Good);
+        assert_eq!(clock.unix_millis(), 1_700_000_002_000);
+
+        // Stream stops, stale after 500 ms and dead after 2 s
+        clock.advance(Duration::from_millis(600));
+        assert_eq!(health.report(clock.now(), &thresholds).status, HealthStatus::Degraded);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(health.report(clock.now(), &thresholds).status, HealthStatus::Bad);
+    }
+}
@@ -0,0 +1,134 @@
+//! Compares the device clock in the accel timestamps to when the host received them, to tell
+//! device timing problems apart from host scheduling and buffering
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Samples older than this, by host time, are dropped from the fit.
+const WINDOW: Duration = Duration::from_secs(10);
+/// Device time the window has to span before a drift estimate means anything.
+const MIN_SPAN: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockDriftReport {
+    /// Host seconds per device second.
+    pub ratio: f64,
+    /// How far the device clock runs fast (negative) or slow (positive) against the host, in
+    /// parts per million.
+    pub drift_ppm: f64,
+    /// Host time the fit puts at the oldest sample in the window, relative to when it was
+    /// received. A large offset means the oldest sample was held up.
+    pub offset: Duration,
+    /// RMS of the arrival times around the fit.
+    pub jitter: Duration,
+    /// Latest arrival relative to the fit, usually buffering in the USB stack.
+    pub max_lag: Duration,
+}
+
+impl ClockDriftReport {
+    pub fn summary(&self) -> String {
+        format!(
+            "{:+.0} ppm, jitter {:.2} ms, max lag {:.2} ms",
+            self.drift_ppm,
+            self.jitter.as_secs_f64() * 1000.,
+            self.max_lag.as_secs_f64() * 1000.,
+        )
+    }
+}
+
+/// Sliding window least squares fit of host receive time against the device timestamp, fed from
+/// `accel_stream`.
+#[derive(Clone, Debug, Default)]
+pub struct ClockDrift {
+    /// (device timestamp in microseconds, host receive time)
+    samples: VecDeque<(u32, Instant)>,
+}
+
+impl ClockDrift {
+    pub fn on_sample(&mut self, timestamp: u32, now: Instant) {
+        // The device clock went back, it was reset or wrapped
+        if self.samples.back().is_some_and(|&(prev, _)| timestamp < prev) {
+            self.samples.clear();
+        }
+        self.samples.push_back((timestamp, now));
+        while self.samples.front().is_some_and(|&(_, t)| now.duration_since(t) > WINDOW) {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+
+    pub fn report(&self) -> Option<ClockDriftReport> {
+        let &(device0, host0) = self.samples.front()?;
+        let &(device1, _) = self.samples.back()?;
+        if Duration::from_micros(u64::from(device1 - device0)) < MIN_SPAN {
+            return None;
+        }
+        // Seconds since the oldest sample on both clocks
+        let points: Vec<(f64, f64)> = self.samples.iter()
+            .map(|&(d, h)| (f64::from(d - device0) * 1e-6, h.duration_since(host0).as_secs_f64()))
+            .collect();
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+        let sxx = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum::<f64>();
+        let sxy = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum::<f64>();
+        let ratio = sxy / sxx;
+        let intercept = mean_y - ratio * mean_x;
+
+        let residuals = points.iter().map(|&(x, y)| y - (intercept + ratio * x));
+        let sum_sq = residuals.clone().map(|r| r * r).sum::<f64>();
+        let max_lag = residuals.fold(0., f64::max);
+        Some(ClockDriftReport {
+            ratio,
+            drift_ppm: (ratio - 1.) * 1e6,
+            offset: Duration::from_secs_f64(intercept.abs()),
+            jitter: Duration::from_secs_f64((sum_sq / n).sqrt()),
+            max_lag: Duration::from_secs_f64(max_lag),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::ClockDrift;
+
+    #[test]
+    fn test_known_drift() {
+        let start = Instant::now();
+        let mut drift = ClockDrift::default();
+        // 1 kHz for 5 s, device clock 200 ppm fast, every other sample 100 us late
+        for i in 0..5000u32 {
+            let device_us = 1_000_000 + i * 1000;
+            let host_s = f64::from(i) * 1e-3 * (1. - 200e-6);
+            let late = if i % 2 == 1 { 100e-6 } else { 0. };
+            drift.on_sample(device_us, start + Duration::from_secs_f64(host_s + late));
+            if i == 500 {
+                // Not enough span yet
+                assert!(drift.report().is_none());
+            }
+        }
+        let report = drift.report().unwrap();
+        assert!((report.drift_ppm + 200.).abs() < 1., "{report:?}");
+        assert!((report.jitter.as_secs_f64() - 50e-6).abs() < 2e-6, "{report:?}");
+        assert!((report.max_lag.as_secs_f64() - 50e-6).abs() < 2e-6, "{report:?}");
+        assert!(report.offset < Duration::from_micros(60), "{report:?}");
+    }
+
+    #[test]
+    fn test_device_clock_reset() {
+        let start = Instant::now();
+        let mut drift = ClockDrift::default();
+        for i in 0..2000u32 {
+            drift.on_sample(i * 1000, start + Duration::from_millis(u64::from(i)));
+        }
+        assert!(drift.report().is_some());
+        // Device rebooted, the old samples would make it look like a huge drift
+        drift.on_sample(0, start + Duration::from_millis(2000));
+        assert!(drift.report().is_none());
+    }
+}
@@ -1,11 +1,12 @@
 use std::{sync::Arc, time::Duration};
 
-use ats_usb::{device::UsbDevice, packet::{GeneralConfig, GeneralWriteConfig, MarkerPattern, Port}};
+use ats_usb::{device::UsbDevice, packet::{format_uuid, parse_uuid, CameraModelType, GeneralConfig, GeneralWriteConfig, MarkerPattern, Port}};
+use nalgebra::Vector2;
 use opencv_ros_camera::RosOpenCvIntrinsics;
-use crate::{mot_runner::MotRunner, CloneButShorter};
-use anyhow::Result;
+use crate::{camera_model_settings::CameraModelSettings, connection_health::MAX_RATE_WINDOW, connection_settings::{ConnectionSettings, StartupSelection}, mot_runner::{distortion_mismatch, general_config_with_write, principal_point_outside_sensor, MotRunner, PointWindow, CAMERA_MODEL_RESOLUTION}, CloneButShorter};
+use anyhow::{Context, Result};
 use iui::{
-    controls::{Button, Form},
+    controls::{Button, ColorButton, Form, Label},
     prelude::{Window, WindowType},
     UI,
 };
@@ -22,8 +23,7 @@ pub fn config_window(
     udp_addr: Option<String>,
     mot_runner: Arc<Mutex<MotRunner>>,
     _tokio_handle: &tokio::runtime::Handle,
-) -> (Window, ReadSignal<Option<UsbDevice>>, Memo<u16>) {
-    let ui_ctx = ui.async_context();
+) -> (Window, ReadSignal<Option<UsbDevice>>, Memo<u16>, RwSignal<nalgebra::Isometry3<f32>>) {
     let mut config_win = Window::new(&ui, "Config", 10, 10, WindowType::NoMenubar);
     config_win.on_closing(&ui, {
         let ui = ui.c();
@@ -34,22 +34,85 @@ pub fn config_window(
 
     let device = create_rw_signal(None);
     let connected = move || device.with(|d| d.is_some());
+    // Writes are dropped by the device itself, the buttons are only disabled to make it obvious
+    let read_only = create_rw_signal(false);
+    let writable = move || connected() && !read_only.get();
+    let environment = create_rw_signal(EnvironmentPreset::default().index());
+    let dump_port = create_rw_signal(0);
+    let dump_bank = create_rw_signal(0);
+    let verify_writes = create_rw_signal(false);
+    let new_uuid = create_rw_signal(String::new());
+    let uuid_confirmation = create_rw_signal(String::new());
+    let uuid_valid = move || new_uuid.with(|s| parse_uuid(s).is_some());
+    let auto_save = create_rw_signal(false);
+    let auto_refresh = create_rw_signal(false);
+    let auto_refresh_secs = create_rw_signal(2);
+    let connection_settings = create_rw_signal(ConnectionSettings::load_from_file());
+    let connection_status = create_rw_signal(String::new());
+    let health_thresholds = mot_runner.lock().health_thresholds;
+    let health_stale_ms = create_rw_signal(health_thresholds.stale_after.as_millis() as i32);
+    let health_min_rate = create_rw_signal(health_thresholds.min_rate as i32);
+    let health_max_errors = create_rw_signal(health_thresholds.max_errors as i32);
+    let health_rate_window_ms = create_rw_signal(health_thresholds.rate_window.as_millis() as i32);
     crate::layout! { &ui,
         let vbox = VerticalBox(padded: true) {
             Compact : let device_hbox = HorizontalBox(padded: true) {
                 Stretchy : let device_combobox = Combobox() {}
                 Compact : let refresh_button = Button("Refresh")
+                Compact : let auto_refresh_checkbox = Checkbox("Auto-refresh every (s)", checked: false)
+                Compact : let auto_refresh_spinbox = Spinbox(1, 60, signal: auto_refresh_secs)
+                Compact : let read_only_checkbox = Checkbox("Read-only mode", checked: false)
+                Compact : let auto_connect_checkbox = Checkbox("Auto-connect on startup", checked: connection_settings.with_untracked(|s| s.auto_connect))
             }
+            Compact : let connection_status_label = Label(move || connection_status.get())
+            Compact : let read_only_label = Label(move || {
+                if read_only.get() { "READ-ONLY: nothing is written or flashed to the device" } else { "" }
+            })
             Compact : let tab_group = TabGroup() {} // sensor settings go in here
             Compact : let buttons_hbox = HorizontalBox(padded: true) {
-                Compact : let apply_button = Button("Apply", enabled: connected)
-                Compact : let save_button = Button("Save", enabled: connected)
+                Compact : let apply_button = Button("Apply", enabled: writable)
+                Compact : let save_button = Button("Save", enabled: writable)
                 Compact : let reload_button = Button("Reload", enabled: connected)
                 Compact : let load_defaults_button = Button("Load defaults", enabled: connected)
+                Compact : let environment_combobox = Combobox(enabled: connected, signal: environment) {}
+                Compact : let verify_writes_checkbox = Checkbox("Verify after apply", checked: false)
+                Compact : let auto_save_checkbox = Checkbox("Auto-save after apply", checked: false)
+                Compact : let auto_save_label = Label(move || {
+                    if auto_save.get() { "Auto-save is on, Apply also saves to flash" } else { "" }
+                })
+            }
+            Compact : let dump_hbox = HorizontalBox(padded: true) {
+                Compact : let dump_port_combobox = Combobox(enabled: connected, signal: dump_port) { "Near field", "Wide field" }
+                Compact : let dump_bank_label = Label("Bank")
+                Compact : let dump_bank_spinbox = Spinbox(0, 255, enabled: connected, signal: dump_bank)
+                Compact : let dump_button = Button("Dump registers", enabled: connected)
+            }
+            Compact : let manufacturing_checkbox = Checkbox("Show manufacturing tools", checked: false)
+            Compact : let uuid_hbox = HorizontalBox(padded: true) {
+                Compact : let uuid_label = Label("New UUID (xx:xx:xx:xx:xx:xx)")
+                Compact : let uuid_entry = Entry(signal: new_uuid)
+                Compact : let uuid_confirm_label = Label("Type it again")
+                Compact : let uuid_confirm_entry = Entry(signal: uuid_confirmation)
+                Compact : let write_uuid_button = Button("Write UUID", enabled: move || writable() && uuid_valid())
+                Compact : let uuid_status_label = Label(move || {
+                    if new_uuid.with(|s| s.is_empty()) || uuid_valid() { "" } else { "Not a valid UUID" }
+                })
+            }
+            Compact : let health_hbox = HorizontalBox(padded: true) {
+                Compact : let health_swatch_hbox = HorizontalBox() {}
+                Stretchy : let health_label = Label("")
+                Compact : let health_stale_label = Label("Stale after (ms)")
+                Compact : let health_stale_spinbox = Spinbox(10, 10000, signal: health_stale_ms)
+                Compact : let health_min_rate_label = Label("Min rate (Hz)")
+                Compact : let health_min_rate_spinbox = Spinbox(0, 1000, signal: health_min_rate)
+                Compact : let health_max_errors_label = Label("Max errors")
+                Compact : let health_max_errors_spinbox = Spinbox(0, 100, signal: health_max_errors)
+                Compact : let health_rate_window_label = Label("Rate window (ms)")
+                Compact : let health_rate_window_spinbox = Spinbox(100, MAX_RATE_WINDOW.as_millis() as i32, signal: health_rate_window_ms)
             }
         }
     }
-    let (general_form, general_settings) = GeneralSettingsForm::new(&ui, device.read_only(), mot_runner, config_win.c());
+    let (general_form, general_settings) = GeneralSettingsForm::new(&ui, device.read_only(), mot_runner.c(), config_win.c());
     let (wf_form, wf_settings) = SensorSettingsForm::new(&ui, device.read_only(), Port::Wf);
     let (nf_form, nf_settings) = SensorSettingsForm::new(&ui, device.read_only(), Port::Nf);
     tab_group.append(&ui, "General", general_form);
@@ -58,8 +121,71 @@ pub fn config_window(
     tab_group.set_margined(&ui, 0, true);
     tab_group.set_margined(&ui, 1, true);
     tab_group.set_margined(&ui, 2, true);
+    for preset in EnvironmentPreset::ALL {
+        environment_combobox.append(&ui, &preset.to_string());
+    }
 
     config_win.set_child(&ui, vbox);
+    uuid_hbox.hide(&ui);
+    if !cfg!(feature = "experimental-write-uuid") {
+        manufacturing_checkbox.hide(&ui);
+    }
+    manufacturing_checkbox.on_toggled(&ui, {
+        let ui = ui.c();
+        let mut uuid_hbox = uuid_hbox.c();
+        move |checked| {
+            if checked {
+                uuid_hbox.show(&ui);
+            } else {
+                uuid_hbox.hide(&ui);
+                uuid_confirmation.set(String::new());
+            }
+        }
+    });
+
+    verify_writes_checkbox.on_toggled(&ui, move |checked| verify_writes.set(checked));
+    auto_save_checkbox.on_toggled(&ui, move |checked| auto_save.set(checked));
+    auto_refresh_checkbox.on_toggled(&ui, move |checked| auto_refresh.set(checked));
+    read_only_checkbox.on_toggled(&ui, move |checked| read_only.set(checked));
+    auto_connect_checkbox.on_toggled(&ui, move |checked| {
+        connection_settings.update(|s| s.auto_connect = checked);
+        connection_settings.with_untracked(|s| s.save_to_file());
+    });
+    create_effect({
+        let ui = ui.c();
+        let config_win = config_win.c();
+        move |_| {
+            let read_only = read_only.get();
+            device.with(|d| {
+                if let Some(d) = d {
+                    d.set_read_only(read_only);
+                }
+            });
+            config_win.c().set_title(&ui, if read_only { "Config (read-only)" } else { "Config" });
+        }
+    });
+
+    let mut health_swatch = ColorButton::new(&ui);
+    health_swatch.disable(&ui);
+    health_swatch_hbox.append(&ui, health_swatch.c(), iui::controls::LayoutStrategy::Compact);
+    create_effect({
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let mut runner = mot_runner.lock();
+            runner.health_thresholds.stale_after = Duration::from_millis(health_stale_ms.get() as u64);
+            runner.health_thresholds.min_rate = f64::from(health_min_rate.get());
+            runner.health_thresholds.max_errors = health_max_errors.get() as usize;
+            runner.health_thresholds.rate_window = Duration::from_millis(health_rate_window_ms.get().max(1) as u64);
+        }
+    });
+    ui.ui_timer(250, {
+        let ui = ui.c();
+        let mot_runner = mot_runner.c();
+        move || {
+            update_health_indicator(&ui, &mot_runner, &mut health_swatch, &mut health_label.c());
+            true
+        }
+    });
 
     let device_list = create_rw_signal(Vec::<SerialPortInfo>::new());
     let device_combobox_on_selected = {
@@ -68,16 +194,50 @@ pub fn config_window(
         let sim_addr = simulator_addr.c();
         let udp_addr = udp_addr.c();
         let general_settings = general_settings.c();
+        let mot_runner = mot_runner.c();
         move |i| {
             device.set(None);
             general_settings.clear();
             wf_settings.clear();
             nf_settings.clear();
-            let Ok(i) = usize::try_from(i) else { return };
-            let _device = device_list.with_untracked(|d| d.get(i).cloned());
-            let sim_addr = sim_addr.c();
-            let udp_addr = udp_addr.c();
+            let Ok(i) = usize::try_from(i) else {
+                connection_status.set(String::new());
+                return;
+            };
+            let port_count = device_list.with_untracked(|d| d.len());
+            let entry = match device_entry(i, port_count, sim_addr.as_deref(), udp_addr.as_deref()) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    connection_status.set(String::new());
+                    config_win.modal_err(&ui, "Failed to connect", &e.to_string());
+                    return;
+                }
+            };
+            let _device = match entry {
+                DeviceEntry::Serial(i) => device_list.with_untracked(|d| d.get(i).cloned()),
+                _ => None,
+            };
+            let name = match (&_device, &entry) {
+                (Some(d), _) => d.port_name.clone(),
+                (None, DeviceEntry::Simulator(sim_addr)) => format!("simulator @ {sim_addr}"),
+                (None, DeviceEntry::Hub(udp_addr)) => format!("M4Hub @ {udp_addr}"),
+                // Checked against the same port list just above
+                (None, DeviceEntry::Serial(_)) => return,
+            };
+            connection_status.set(format!("Connecting to {name}..."));
+            // Only serial ports are remembered, the simulator and hub come from the command line
+            let port_name = _device.as_ref().map(|d| d.port_name.clone());
             let general_settings = general_settings.c();
+            {
+                let mut runner = mot_runner.lock();
+                runner.health.reset();
+                runner.clock_drift.reset();
+                runner.accel_sequence.reset();
+                runner.state.nf_marker_tracks = Default::default();
+                // Don't check the new device's markers against the last one's pose
+                runner.state.pnp_solution = None;
+            }
+            let task_runner = mot_runner.c();
             let task = async move {
                 let usb_device = if let Some(_device) = _device {
                     match &_device.port_type {
@@ -86,18 +246,22 @@ pub fn config_window(
                         },
                         _ => Err(anyhow::anyhow!("Not a USB device")),
                     }
-                } else if let Some(sim_addr) = sim_addr.as_ref() {
-                    Ok(UsbDevice::connect_tcp(sim_addr)?)
                 } else {
-                    Ok(UsbDevice::connect_hub("0.0.0.0:0", udp_addr.as_ref().unwrap()).await?)
+                    match &entry {
+                        DeviceEntry::Simulator(sim_addr) => Ok(UsbDevice::connect_tcp(sim_addr)?),
+                        DeviceEntry::Hub(udp_addr) => Ok(UsbDevice::connect_hub("0.0.0.0:0", udp_addr).await?),
+                        DeviceEntry::Serial(_) => Err(anyhow::anyhow!("Serial port is gone")),
+                    }
                 };
                 match usb_device {
                     Ok(usb_device) => {
-                        general_settings.load_from_device(&usb_device, true).await?;
+                        usb_device.set_read_only(read_only.get_untracked());
+                        let rejected = general_settings.load_from_device(&usb_device, true).await?;
                         wf_settings.load_from_device(&usb_device).await?;
                         nf_settings.load_from_device(&usb_device).await?;
+                        update_point_windows(&task_runner, wf_settings, nf_settings);
                         device.set(Some(usb_device));
-                        Result::<()>::Ok(())
+                        Result::<Vec<String>>::Ok(rejected)
                     },
                     Err(e) => Err(e),
                 }
@@ -105,9 +269,26 @@ pub fn config_window(
             ui.spawn({
                 let ui = ui.c();
                 let config_win = config_win.c();
+                let mot_runner = mot_runner.c();
                 async move {
-                    if let Err(e) = task.await {
-                        config_win.modal_err_async(&ui, "Failed to connect", &e.to_string()).await;
+                    match task.await {
+                        Ok(rejected) => {
+                            connection_status.set(format!("Connected to {name}"));
+                            if let Some(port_name) = port_name {
+                                if connection_settings.with_untracked(|s| s.remembered_port.as_ref() != Some(&port_name)) {
+                                    connection_settings.update(|s| s.remembered_port = Some(port_name));
+                                    connection_settings.with_untracked(|s| s.save_to_file());
+                                }
+                            }
+                            if !rejected.is_empty() {
+                                config_win.modal_err_async(&ui, "Rejected camera calibration", &rejected.join("\n")).await;
+                            }
+                        }
+                        Err(e) => {
+                            connection_status.set(format!("Failed to connect to {name}"));
+                            mot_runner.lock().on_connection_error();
+                            config_win.modal_err_async(&ui, "Failed to connect", &e.to_string()).await;
+                        }
                     }
                 }
             });
@@ -138,46 +319,88 @@ pub fn config_window(
             device_combobox.enable(&ui);
         }
     });
+    ui.ui_timer(250, {
+        let ui = ui.c();
+        let mut device_combobox = device_combobox.c();
+        let device_combobox_on_selected = device_combobox_on_selected.c();
+        let mut last_poll = std::time::Instant::now();
+        let mut debounce = PortListDebounce::default();
+        move || {
+            if !auto_refresh.get_untracked() {
+                return true;
+            }
+            if last_poll.elapsed() < Duration::from_secs(auto_refresh_secs.get_untracked().max(1) as u64) {
+                return true;
+            }
+            last_poll = std::time::Instant::now();
+            // Errors are left for the Refresh button to report, a modal every few seconds would
+            // be worse than a stale list
+            let Ok(ports) = list_devices() else { return true };
+            let names = |ports: &[SerialPortInfo]| ports.iter().map(|p| p.port_name.clone()).collect::<Vec<_>>();
+            let current = device_list.with_untracked(|d| names(d));
+            if !debounce.update(&current, &names(&ports), AUTO_REFRESH_DEBOUNCE_POLLS) {
+                return true;
+            }
+
+            let selected = device_combobox.selected(&ui);
+            let selected_port = usize::try_from(selected).ok().and_then(|i| current.get(i).cloned());
+            device_list.set(ports.c());
+            match selected_port {
+                Some(name) => match ports.iter().position(|p| p.port_name == name) {
+                    // Still plugged in, keep the connection
+                    Some(i) => device_combobox.set_selected(&ui, i as i32),
+                    None if ports.is_empty() => device_combobox_on_selected(-1),
+                    None => {
+                        device_combobox.set_selected(&ui, 0);
+                        device_combobox_on_selected(0);
+                    }
+                },
+                // The simulator or hub entry, which comes after the ports
+                None if selected >= 0 => device_combobox.set_selected(&ui, ports.len() as i32),
+                None if !ports.is_empty() => {
+                    device_combobox.set_selected(&ui, 0);
+                    device_combobox_on_selected(0);
+                }
+                None => (),
+            }
+            true
+        }
+    });
     let mut refresh_device_list = {
         let config_win = config_win.c();
         let ui = ui.c();
         let simulator_addr = simulator_addr.c();
         let udp_addr = udp_addr.c();
-        move || {
-            let ports = serialport::available_ports();
-            let ports: Vec<_> = match ports {
+        move |startup: bool| {
+            let ports = match list_devices() {
                 Ok(p) => p,
                 Err(e) => {
                     config_win.modal_err(&ui, "Failed to list serial ports", &e.to_string());
                     return;
                 }
-            }.into_iter().filter(|port| {
-                match &port.port_type {
-                    UsbPort(port_info) => {
-                        if port_info.vid == 0x1915 && port_info.pid == 0x520F || port_info.pid == 0x5210 {
-                            if let Some(i) = port_info.interface {
-                                // interface 0: cdc acm module
-                                // interface 1: cdc acm module functional subordinate interface
-                                // interface 2: cdc acm dfu
-                                // interface 3: cdc acm dfu subordinate interface
-                                i == 0
-                            } else {
-                                true
-                            }
-                        } else {
-                            false
-                        }
-                    },
-                    _ => false,
-                }
-            }).collect();
+            };
             device_list.set(ports.c());
+            let startup_selection = if startup {
+                let names = ports.iter().map(|p| p.port_name.clone()).collect::<Vec<_>>();
+                connection_settings.with_untracked(|s| s.startup_selection(&names))
+            } else {
+                StartupSelection::First
+            };
             if simulator_addr.is_some() {
                 device_combobox.set_selected(&ui, ports.len() as i32);
                 device_combobox_on_selected(ports.len() as i32);
             } else if udp_addr.is_some() {
                 device_combobox.set_selected(&ui, ports.len() as i32);
                 device_combobox_on_selected(ports.len() as i32);
+            } else if let StartupSelection::Remembered(i) = startup_selection {
+                device_combobox.set_selected(&ui, i as i32);
+                device_combobox_on_selected(i as i32);
+            } else if let StartupSelection::NotFound(port) = startup_selection {
+                // Don't connect to whatever else is plugged in, it might not be the device the
+                // user wants to talk to
+                device_combobox.set_selected(&ui, -1);
+                device_combobox_on_selected(-1);
+                connection_status.set(format!("Auto-connect: {port} not found, pick a device"));
             } else if ports.len() > 0 {
                 device_combobox.set_selected(&ui, 0);
                 device_combobox_on_selected(0);
@@ -186,13 +409,14 @@ pub fn config_window(
             }
         }
     };
-    refresh_device_list();
-    refresh_button.on_clicked(&ui, move |_| refresh_device_list());
+    refresh_device_list(true);
+    refresh_button.on_clicked(&ui, move |_| refresh_device_list(false));
 
     let apply_button_on_click = {
         let config_win = config_win.c();
         let ui = ui.c();
         let general_settings = general_settings.c();
+        let mot_runner = mot_runner.c();
         move |device: UsbDevice| async move {
             let mut errors = vec![];
             general_settings.validate(&mut errors);
@@ -228,18 +452,53 @@ pub fn config_window(
                 return false;
             }
 
+            let mut advisories = vec![];
+            let accel_odr = general_settings.accel_odr.get_untracked() as u16;
+            for (name, settings) in [("Wide field", wf_settings), ("Near field", nf_settings)] {
+                let mut sensor_advisories = vec![];
+                settings.advise(accel_odr, &mut sensor_advisories);
+                advisories.extend(sensor_advisories.into_iter().map(|msg| format!("{name}: {msg}")));
+            }
+            if !advisories.is_empty() {
+                // Applied anyway, the user might know what they're doing
+                config_win.modal_msg_async(&ui, "Settings Advisory", &advisories.join("\n")).await;
+            }
+
             if let Err(e) = general_settings.apply(&device).await {
+                mot_runner.lock().on_connection_error();
                 config_win.modal_err_async(&ui, "Failed to apply general settings", &e.to_string()).await;
                 return false;
             };
             if let Err(e) = wf_settings.apply(&device).await {
+                mot_runner.lock().on_connection_error();
                 config_win.modal_err_async(&ui, "Failed to apply wide field settings", &e.to_string()).await;
                 return false;
             };
             if let Err(e) = nf_settings.apply(&device).await {
+                mot_runner.lock().on_connection_error();
                 config_win.modal_err_async(&ui, "Failed to apply near field settings", &e.to_string()).await;
                 return false;
             };
+            // Don't flash settings that didn't make it to the sensors
+            if verify_writes.get_untracked() || auto_save.get_untracked() {
+                let mut message = String::new();
+                for (name, settings) in [("Wide field", wf_settings), ("Near field", nf_settings)] {
+                    match settings.verify(&device).await {
+                        Ok(mismatches) => {
+                            for msg in mismatches {
+                                message.push_str(&format!("{name}: {msg}\n"));
+                            }
+                        }
+                        Err(e) => message.push_str(&format!("{name}: {e}\n")),
+                    }
+                }
+                if !message.is_empty() {
+                    mot_runner.lock().on_connection_error();
+                    config_win.modal_err_async(&ui, "Readback Mismatch", &message).await;
+                    return false;
+                }
+            }
+            update_point_windows(&mot_runner, wf_settings, nf_settings);
             return true;
         }
     };
@@ -247,6 +506,7 @@ pub fn config_window(
         let f = apply_button_on_click.clone();
         let device = device.c();
         let ui = ui.c();
+        let config_win = config_win.c();
         move |apply_button| {
             let Some(device) = device.get_untracked() else {
                 return;
@@ -254,9 +514,21 @@ pub fn config_window(
             let f = f.clone();
             let mut apply_button = apply_button.c();
             let ui2 = ui.c();
+            let config_win = config_win.c();
             ui.spawn(async move {
-                f(device).await;
-                apply_button.set_text(&ui2, "Applied!");
+                if !f(device.clone()).await {
+                    // callback should have already displayed an error modal, just return
+                    return;
+                }
+                if auto_save.get_untracked() {
+                    if let Err(e) = device.flash_settings().await {
+                        config_win.modal_err_async(&ui2, "Failed to request flash settings", &e.to_string()).await;
+                        return;
+                    }
+                    apply_button.set_text(&ui2, "Applied and saved!");
+                } else {
+                    apply_button.set_text(&ui2, "Applied!");
+                }
                 tokio::time::sleep(Duration::from_secs(3)).await;
                 apply_button.set_text(&ui2, "Apply");
             });
@@ -294,20 +566,29 @@ pub fn config_window(
         let general_settings = general_settings.c();
         move |_| {
             if let Some(_device) = device.get_untracked() {
+                let preset = EnvironmentPreset::from_index(environment.get_untracked());
                 general_settings.load_defaults();
-                nf_settings.load_defaults();
-                wf_settings.load_defaults();
+                nf_settings.load_defaults(preset);
+                wf_settings.load_defaults(preset);
             }
         }
     });
 
     reload_button.on_clicked(&ui, {
         let general_settings = general_settings.c();
+        let ui = ui.c();
+        let config_win = config_win.c();
         move |_| {
             if let Some(device) = device.get_untracked() {
                 let general_settings = general_settings.c();
-                ui_ctx.spawn(async move {
-                    _ = general_settings.load_from_device(&device, false).await;
+                let ui2 = ui.c();
+                let config_win = config_win.c();
+                ui.spawn(async move {
+                    if let Ok(rejected) = general_settings.load_from_device(&device, false).await {
+                        if !rejected.is_empty() {
+                            config_win.modal_err_async(&ui2, "Rejected camera calibration", &rejected.join("\n")).await;
+                        }
+                    }
                     _ = nf_settings.load_from_device(&device).await;
                     _ = wf_settings.load_from_device(&device).await;
                 });
@@ -315,8 +596,75 @@ pub fn config_window(
         }
     });
 
+    dump_button.on_clicked(&ui, {
+        let config_win = config_win.c();
+        let ui = ui.c();
+        move |_| {
+            let Some(device) = device.get_untracked() else {
+                return;
+            };
+            let port = if dump_port.get_untracked() == 0 { Port::Nf } else { Port::Wf };
+            let bank = dump_bank.get_untracked() as u8;
+            let Some(path) = config_win.save_file(&ui) else {
+                return;
+            };
+            let config_win = config_win.c();
+            let ui = ui.c();
+            ui.spawn({
+                let ui = ui.c();
+                async move {
+                    let result = async {
+                        let values = device.dump_bank(port, bank).await?;
+                        let mut dump = String::new();
+                        for (address, value) in &values {
+                            dump.push_str(&format!("{address:#04x},{value:#04x}\n"));
+                        }
+                        std::fs::write(&path, dump)?;
+                        Result::<usize>::Ok(values.len())
+                    }.await;
+                    match result {
+                        Ok(n) => {
+                            let msg = format!("Read {n} of 256 addresses in bank {bank:#04x} to {}", path.display());
+                            config_win.modal_msg_async(&ui, "Dumped registers", &msg).await;
+                        }
+                        Err(e) => {
+                            config_win.modal_err_async(&ui, "Failed to dump registers", &e.to_string()).await;
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    write_uuid_button.on_clicked(&ui, {
+        let config_win = config_win.c();
+        let ui = ui.c();
+        move |_| {
+            let Some(device) = device.get_untracked() else {
+                return;
+            };
+            let Some(uuid) = new_uuid.with_untracked(|s| parse_uuid(s)) else {
+                return;
+            };
+            // Has to be typed again for every write
+            let confirmation = uuid_confirmation.get_untracked();
+            uuid_confirmation.set(String::new());
+            let config_win = config_win.c();
+            let ui = ui.c();
+            ui.spawn({
+                let ui = ui.c();
+                async move {
+                    match device.write_uuid(uuid, &confirmation).await {
+                        Ok(()) => config_win.modal_msg_async(&ui, "UUID written", &format!("Wrote {} and read it back", format_uuid(&uuid))).await,
+                        Err(e) => config_win.modal_err_async(&ui, "Failed to write UUID", &e.to_string()).await,
+                    }
+                }
+            });
+        }
+    });
+
     let accel_odr_memo = create_memo(move |_| general_settings.accel_odr.get() as u16);
-    (config_win, device.read_only(), accel_odr_memo)
+    (config_win, device.read_only(), accel_odr_memo, general_settings.stereo_iso)
 }
 
 #[derive(Clone)]
@@ -325,7 +673,16 @@ struct GeneralSettingsForm {
     accel_odr: RwSignal<i32>,
     nf_intrinsics: RwSignal<RosOpenCvIntrinsics<f32>>,
     wf_intrinsics: RwSignal<RosOpenCvIntrinsics<f32>>,
+    /// Index into `CAMERA_MODEL_TYPES`
+    nf_model_type: RwSignal<i32>,
+    wf_model_type: RwSignal<i32>,
     stereo_iso: RwSignal<nalgebra::Isometry3<f32>>,
+    stereo_tx: RwSignal<String>,
+    stereo_ty: RwSignal<String>,
+    stereo_tz: RwSignal<String>,
+    stereo_roll: RwSignal<String>,
+    stereo_pitch: RwSignal<String>,
+    stereo_yaw: RwSignal<String>,
     mot_runner: Arc<Mutex<MotRunner>>,
 }
 
@@ -334,18 +691,77 @@ impl GeneralSettingsForm {
         let connected = move || device.with(|d| d.is_some());
         let impact_threshold = create_rw_signal(0);
         let accel_odr = create_rw_signal(0);
-        let nf_intrinsics = create_rw_signal(RosOpenCvIntrinsics::from_params(145., 0., 145., 45., 45.));
-        let wf_intrinsics = create_rw_signal(RosOpenCvIntrinsics::from_params(34., 0., 34., 45., 45.));
+        let nf_intrinsics = create_rw_signal(default_intrinsics(Port::Nf));
+        let wf_intrinsics = create_rw_signal(default_intrinsics(Port::Wf));
+        let camera_models = CameraModelSettings::load_from_file();
+        let nf_model_type = create_rw_signal(camera_model_index(camera_models.nf));
+        let wf_model_type = create_rw_signal(camera_model_index(camera_models.wf));
         let stereo_iso = create_rw_signal(nalgebra::Isometry3::identity());
+        let stereo_tx = create_rw_signal(String::new());
+        let stereo_ty = create_rw_signal(String::new());
+        let stereo_tz = create_rw_signal(String::new());
+        let stereo_roll = create_rw_signal(String::new());
+        let stereo_pitch = create_rw_signal(String::new());
+        let stereo_yaw = create_rw_signal(String::new());
         crate::layout! { &ui,
             let form = Form(padded: true) {
                 (Compact, "Impact threshold") : let x = Spinbox(enabled: connected, signal: impact_threshold)
                 (Compact, "Accelerometer ODR") : let x = Spinbox(enabled: connected, signal: accel_odr)
+                (Compact, "Nearfield camera model") : let x = Combobox(signal: nf_model_type) { "Pinhole", "Fisheye" }
+                (Compact, "Widefield camera model") : let x = Combobox(signal: wf_model_type) { "Pinhole", "Fisheye" }
                 (Compact, "Upload Nearfield Calibration") : let upload_nf_json = Button("Upload")
                 (Compact, "Upload Widefield Calibration") : let upload_wf_json = Button("Upload")
                 (Compact, "Upload Stereo Calibration") : let upload_stereo_json = Button("Upload")
+                (Compact, "Stereo translation XYZ") : let stereo_translation_hbox = HorizontalBox(padded: true) {
+                    Stretchy : let e = Entry(signal: stereo_tx)
+                    Stretchy : let e = Entry(signal: stereo_ty)
+                    Stretchy : let e = Entry(signal: stereo_tz)
+                }
+                (Compact, "Stereo rotation RPY (deg)") : let stereo_rotation_hbox = HorizontalBox(padded: true) {
+                    Stretchy : let e = Entry(signal: stereo_roll)
+                    Stretchy : let e = Entry(signal: stereo_pitch)
+                    Stretchy : let e = Entry(signal: stereo_yaw)
+                }
+                (Compact, "") : let reset_stereo_button = Button("Reset stereo to identity")
             }
         }
+        reset_stereo_button.on_clicked(&ui, move |_| stereo_iso.set(nalgebra::Isometry3::identity()));
+
+        // Uploads, loads and resets go through `stereo_iso`, the entries are what gets applied
+        create_effect(move |_| {
+            let entries = [stereo_tx, stereo_ty, stereo_tz, stereo_roll, stereo_pitch, stereo_yaw];
+            for (entry, text) in entries.iter().zip(entries_from_isometry(&stereo_iso.get())) {
+                entry.set(text);
+            }
+        });
+
+        // Edits are reflected on the canvas right away, they only reach the device on apply
+        create_effect({
+            let mot_runner = mot_runner.c();
+            move |_| {
+                let entries = [stereo_tx, stereo_ty, stereo_tz, stereo_roll, stereo_pitch, stereo_yaw];
+                if let Some(iso) = isometry_from_entries(entries.map(|e| e.get())) {
+                    mot_runner.lock().update_general_config(|config| config.stereo_iso = iso);
+                }
+            }
+        });
+        // The device doesn't store the model type, it goes straight to the runner and is
+        // remembered on the host
+        create_effect({
+            let mot_runner = mot_runner.c();
+            move |prev: Option<()>| {
+                let nf = camera_model_type(nf_model_type.get());
+                let wf = camera_model_type(wf_model_type.get());
+                mot_runner.lock().update_general_config(|config| {
+                    config.camera_model_type_nf = nf;
+                    config.camera_model_type_wf = wf;
+                });
+                // Nothing changed yet on the first run
+                if prev.is_some() {
+                    CameraModelSettings { nf, wf }.save_to_file();
+                }
+            }
+        });
         set_calibration_upload_handlers(
             &ui,
             &mut upload_nf_json,
@@ -353,6 +769,8 @@ impl GeneralSettingsForm {
             &mut upload_stereo_json,
             nf_intrinsics.c(),
             wf_intrinsics.c(),
+            nf_model_type,
+            wf_model_type,
             stereo_iso.c(),
             win,
         );
@@ -363,26 +781,38 @@ impl GeneralSettingsForm {
                 accel_odr,
                 nf_intrinsics,
                 wf_intrinsics,
+                nf_model_type,
+                wf_model_type,
                 stereo_iso,
+                stereo_tx,
+                stereo_ty,
+                stereo_tz,
+                stereo_roll,
+                stereo_pitch,
+                stereo_yaw,
                 mot_runner,
             },
         )
     }
 
-    async fn load_from_device(&self, device: &UsbDevice, first_load: bool) -> Result<()> {
-        let timeout = Duration::from_millis(5000);
-        let config = retry(|| device.read_config(), timeout, 3).await.unwrap()?;
+    /// Returns a message for each camera model that was rejected and replaced by the default.
+    async fn load_from_device(&self, device: &UsbDevice, first_load: bool) -> Result<Vec<String>> {
+        let policy = RetryPolicy::CONFIG.with_env_overrides(RetryPolicy::CONFIG_TIMEOUT_VAR);
+        let mut config = retry_read("config", || device.read_config(), policy).await??;
+        let rejected = reject_bad_intrinsics(&mut config);
 
         self.impact_threshold.set(i32::from(config.impact_threshold));
         self.accel_odr.set(config.accel_odr as i32);
         self.nf_intrinsics.set(config.camera_model_nf.clone());
         self.wf_intrinsics.set(config.camera_model_wf.clone());
         self.stereo_iso.set(config.stereo_iso.clone());
+        config.camera_model_type_nf = camera_model_type(self.nf_model_type.get_untracked());
+        config.camera_model_type_wf = camera_model_type(self.wf_model_type.get_untracked());
 
         if first_load {
-            self.mot_runner.lock().general_config = config;
+            self.mot_runner.lock().set_general_config(config);
         }
-        Ok(())
+        Ok(rejected)
     }
 
     fn validate(&self, errors: &mut Vec<String>) {
@@ -416,10 +846,40 @@ impl GeneralSettingsForm {
             }
         }
         validators! {
+            "stereo translation X" stereo_tx: f32 { |x: f32| (x.is_finite(), "must be finite") },
+            "stereo translation Y" stereo_ty: f32 { |x: f32| (x.is_finite(), "must be finite") },
+            "stereo translation Z" stereo_tz: f32 { |x: f32| (x.is_finite(), "must be finite") },
+            "stereo roll" stereo_roll: f32 { |x: f32| (x.is_finite(), "must be finite") },
+            "stereo pitch" stereo_pitch: f32 { |x: f32| (x.is_finite(), "must be finite") },
+            "stereo yaw" stereo_yaw: f32 { |x: f32| (x.is_finite(), "must be finite") },
         }
         if !(0..256).contains(&self.impact_threshold.get_untracked()) {
             errors.push("impact threshold: must be between 0 and 255".into());
         }
+        let calibrations = [
+            ("nearfield calibration", self.nf_intrinsics, self.nf_model_type),
+            ("widefield calibration", self.wf_intrinsics, self.wf_model_type),
+        ];
+        for (name, intrinsics, model_type) in calibrations {
+            if let Some(p) = intrinsics.with_untracked(|i| principal_point_outside_sensor(i, CAMERA_MODEL_RESOLUTION)) {
+                errors.push(format!("{name}: principal point ({:.1}, {:.1}) is outside the sensor", p.x, p.y));
+            }
+            let model = camera_model_type(model_type.get_untracked());
+            if let Some(e) = intrinsics.with_untracked(|i| distortion_mismatch(i, model)) {
+                errors.push(format!("{name}: {e}"));
+            }
+        }
+        if let Some(iso) = self.stereo_iso_from_entries() {
+            // The cameras sit next to each other facing the same way
+            if iso.rotation.angle() >= std::f32::consts::FRAC_PI_2 {
+                errors.push("stereo rotation: must be less than 90°".into());
+            }
+        }
+    }
+
+    fn stereo_iso_from_entries(&self) -> Option<nalgebra::Isometry3<f32>> {
+        let entries = [self.stereo_tx, self.stereo_ty, self.stereo_tz, self.stereo_roll, self.stereo_pitch, self.stereo_yaw];
+        isometry_from_entries(entries.map(|e| e.get_untracked()))
     }
 
     /// Make sure to call `validate()` before calling this method.
@@ -429,17 +889,10 @@ impl GeneralSettingsForm {
             accel_odr: self.accel_odr.get_untracked() as u16,
             camera_model_nf: self.nf_intrinsics.get_untracked(),
             camera_model_wf: self.wf_intrinsics.get_untracked(),
-            stereo_iso: self.stereo_iso.get_untracked(),
+            stereo_iso: self.stereo_iso_from_entries().context("stereo calibration isn't a valid transform")?,
         };
         device.write_config(config.clone()).await?;
-        {
-            let general_config = &mut self.mot_runner.lock().general_config;
-            general_config.impact_threshold = config.impact_threshold;
-            general_config.accel_odr = config.accel_odr;
-            general_config.camera_model_nf = config.camera_model_nf;
-            general_config.camera_model_wf = config.camera_model_wf;
-            general_config.stereo_iso = config.stereo_iso;
-        }
+        self.mot_runner.lock().update_general_config(|general_config| *general_config = general_config_with_write(general_config, config));
         Ok(())
     }
 
@@ -450,14 +903,37 @@ impl GeneralSettingsForm {
     fn load_defaults(&self) {
         self.impact_threshold.set(2);
         self.accel_odr.set(100);
-        self.nf_intrinsics.set(RosOpenCvIntrinsics::from_params(145., 0., 145., 45., 45.));
-        self.wf_intrinsics.set(RosOpenCvIntrinsics::from_params(34., 0., 34., 45., 45.));
+        self.nf_intrinsics.set(default_intrinsics(Port::Nf));
+        self.wf_intrinsics.set(default_intrinsics(Port::Wf));
+        self.nf_model_type.set(0);
+        self.wf_model_type.set(0);
         self.stereo_iso.set(nalgebra::Isometry3::identity());
     }
 }
 
+/// Translation followed by roll, pitch and yaw in degrees, the inverse of `isometry_from_entries`.
+fn entries_from_isometry(iso: &nalgebra::Isometry3<f32>) -> [String; 6] {
+    let (roll, pitch, yaw) = iso.rotation.euler_angles();
+    [iso.translation.x, iso.translation.y, iso.translation.z, roll.to_degrees(), pitch.to_degrees(), yaw.to_degrees()]
+        .map(|x| x.to_string())
+}
+
+/// Translation followed by roll, pitch and yaw in degrees.
+fn isometry_from_entries(entries: [String; 6]) -> Option<nalgebra::Isometry3<f32>> {
+    let mut values = [0.0f32; 6];
+    for (value, entry) in values.iter_mut().zip(&entries) {
+        *value = entry.parse().ok().filter(|x: &f32| x.is_finite())?;
+    }
+    let [x, y, z, roll, pitch, yaw] = values;
+    Some(nalgebra::Isometry3::from_parts(
+        nalgebra::Translation3::new(x, y, z),
+        nalgebra::UnitQuaternion::from_euler_angles(roll.to_radians(), pitch.to_radians(), yaw.to_radians()),
+    ))
+}
+
 fn set_calibration_upload_handlers(ui: &UI, upload_nf: &mut Button, upload_wf: &mut Button, upload_stereo: &mut Button,
     nf_intrinsics: RwSignal<RosOpenCvIntrinsics<f32>>, wf_intrinsics: RwSignal<RosOpenCvIntrinsics<f32>>,
+    nf_model_type: RwSignal<i32>, wf_model_type: RwSignal<i32>,
     stereo_iso: RwSignal<nalgebra::Isometry3<f32>>, win: Window)
 {
     upload_nf.on_clicked(&ui, {
@@ -468,6 +944,10 @@ fn set_calibration_upload_handlers(ui: &UI, upload_nf: &mut Button, upload_wf: &
                 let Ok(()) = (|| {
                     let reader = std::fs::File::open(&path)?;
                     let intrinsics = ats_cv::get_intrinsics_from_opencv_camera_calibration_json(reader)?;
+                    if let Some(e) = distortion_mismatch(&intrinsics, camera_model_type(nf_model_type.get_untracked())) {
+                        win.modal_err(&ui, "Calibration doesn't match the camera model", &e);
+                        return Ok(());
+                    }
                     nf_intrinsics.set(intrinsics);
                     win.modal_msg(&ui, "Uploaded calibration", "Successfully uploaded calibration");
                     Ok::<(), Box<dyn std::error::Error>>(())
@@ -489,6 +969,10 @@ fn set_calibration_upload_handlers(ui: &UI, upload_nf: &mut Button, upload_wf: &
                 let Ok(()) = (|| {
                     let reader = std::fs::File::open(&path)?;
                     let intrinsics = ats_cv::get_intrinsics_from_opencv_camera_calibration_json(reader)?;
+                    if let Some(e) = distortion_mismatch(&intrinsics, camera_model_type(wf_model_type.get_untracked())) {
+                        win.modal_err(&ui, "Calibration doesn't match the camera model", &e);
+                        return Ok(());
+                    }
                     wf_intrinsics.set(intrinsics);
                     win.modal_msg(&ui, "Uploaded calibration", "Successfully uploaded calibration");
                     Ok::<(), Box<dyn std::error::Error>>(())
@@ -537,6 +1021,8 @@ struct SensorSettingsForm {
     area_threshold_min: RwSignal<String>,
     area_threshold_max: RwSignal<String>,
     max_object_cnt: RwSignal<String>,
+    /// Not a device setting, only used to filter the points in `MotRunner`.
+    edge_margin: RwSignal<String>,
 
     operation_mode: RwSignal<i32>,
     frame_subtraction: RwSignal<i32>,
@@ -556,6 +1042,7 @@ impl SensorSettingsForm {
         let area_threshold_min = create_rw_signal(String::new());
         let area_threshold_max = create_rw_signal(String::new());
         let max_object_cnt = create_rw_signal(String::new());
+        let edge_margin = create_rw_signal(default_point_window(port).margin.to_string());
         let operation_mode = create_rw_signal(0);
         let frame_subtraction = create_rw_signal(0);
         let gain = create_rw_signal(0);
@@ -580,7 +1067,7 @@ impl SensorSettingsForm {
                 (Compact, "DSP area threshold min")   : let x = Entry(enabled: connected, signal: area_threshold_min)
                 (Compact, "DSP area threshold max")   : let x = Entry(enabled: connected, signal: area_threshold_max)
                 (Compact, "DSP maximum object count") : let x = Entry(enabled: connected, signal: max_object_cnt)
-                (Compact, "DSP operation mode")       : let x = Combobox(enabled: connected, signal: operation_mode) { "Normal", "Tracking" }
+                (Compact, "DSP operation mode")       : let operation_mode_combobox = Combobox(enabled: connected, signal: operation_mode) {}
                 (Compact, "Exposure time") : let x = HorizontalBox(padded: true) {
                     Stretchy : let e = Entry(
                         enabled: connected,
@@ -599,12 +1086,19 @@ impl SensorSettingsForm {
                         (0, 0)(1, 1) Vertical (Start, Center) : let s = Label(move || format!("{} ms ({} fps)", frame_period_ms(), fps()))
                     }
                 }
-                (Compact, "Frame subtraction")  : let x = Combobox(enabled: connected, signal: frame_subtraction) { "Off", "On" }
+                (Compact, "Frame subtraction")  : let frame_subtraction_combobox = Combobox(enabled: connected, signal: frame_subtraction) {}
                 (Compact, "Gain")               : let gain_combobox = Combobox(enabled: connected, signal: gain) {}
                 (Compact, "Scale resolution X") : let x = Entry(enabled: connected, signal: resolution_x)
                 (Compact, "Scale resolution Y") : let x = Entry(enabled: connected, signal: resolution_y)
+                (Compact, "Edge margin")        : let x = Entry(enabled: connected, signal: edge_margin)
             }
         }
+        for mode in OperationMode::ALL {
+            operation_mode_combobox.append(&ui, &mode.to_string());
+        }
+        for mode in FrameSubtraction::ALL {
+            frame_subtraction_combobox.append(&ui, &mode.to_string());
+        }
         for (label, _) in &GAIN_TABLE {
             gain_combobox.append(&ui, label);
         }
@@ -622,6 +1116,7 @@ impl SensorSettingsForm {
                 area_threshold_min,
                 area_threshold_max,
                 max_object_cnt,
+                edge_margin,
                 operation_mode,
                 frame_subtraction,
                 gain,
@@ -631,21 +1126,21 @@ impl SensorSettingsForm {
 
     async fn load_from_device(&self, device: &UsbDevice) -> Result<()> {
         self.pid.set("Connecting...".into());
-        let timeout = Duration::from_millis(2000);
-        let pid = retry(|| device.product_id(self.port), timeout, 3).await.unwrap()?;
-        let res_x = retry(|| device.resolution_x(self.port), timeout, 3).await.unwrap()?;
-        let res_y = retry(|| device.resolution_y(self.port), timeout, 3).await.unwrap()?;
-        let expo = retry(|| device.exposure_time(self.port), timeout, 3).await.unwrap()?;
-        let frame_period = retry(|| device.frame_period(self.port), timeout, 3).await.unwrap()?;
-        let brightness_threshold = retry(|| device.brightness_threshold(self.port), timeout, 3).await.unwrap()?;
-        let noise_threshold = retry(|| device.noise_threshold(self.port), timeout, 3).await.unwrap()?;
-        let area_threshold_min = retry(|| device.area_threshold_min(self.port), timeout, 3).await.unwrap()?;
+        let policy = RetryPolicy::REGISTER.with_env_overrides(RetryPolicy::REGISTER_TIMEOUT_VAR);
+        let pid = retry_read("product_id", || device.product_id(self.port), policy).await??;
+        let res_x = retry_read("resolution_x", || device.resolution_x(self.port), policy).await??;
+        let res_y = retry_read("resolution_y", || device.resolution_y(self.port), policy).await??;
+        let expo = retry_read("exposure_time", || device.exposure_time(self.port), policy).await??;
+        let frame_period = retry_read("frame_period", || device.frame_period(self.port), policy).await??;
+        let brightness_threshold = retry_read("brightness_threshold", || device.brightness_threshold(self.port), policy).await??;
+        let noise_threshold = retry_read("noise_threshold", || device.noise_threshold(self.port), policy).await??;
+        let area_threshold_min = retry_read("area_threshold_min", || device.area_threshold_min(self.port), policy).await??;
         let area_threshold_max = device.area_threshold_max(self.port).await?;
-        let max_object_cnt = retry(|| device.max_object_cnt(self.port), timeout, 3).await.unwrap()?;
-        let operation_mode = retry(|| device.operation_mode(self.port), timeout, 3).await.unwrap()?;
-        let frame_subtraction = retry(|| device.frame_subtraction(self.port), timeout, 3).await.unwrap()?;
-        let gain_1 = retry(|| device.gain_1(self.port), timeout, 3).await.unwrap()?;
-        let gain_2 = retry(|| device.gain_2(self.port), timeout, 3).await.unwrap()?;
+        let max_object_cnt = retry_read("max_object_cnt", || device.max_object_cnt(self.port), policy).await??;
+        let operation_mode = retry_read("operation_mode", || device.operation_mode(self.port), policy).await??;
+        let frame_subtraction = retry_read("frame_subtraction", || device.frame_subtraction(self.port), policy).await??;
+        let gain_1 = retry_read("gain_1", || device.gain_1(self.port), policy).await??;
+        let gain_2 = retry_read("gain_2", || device.gain_2(self.port), policy).await??;
 
         self.pid.set(format!("0x{pid:04x}"));
         self.resolution_x.set(res_x.to_string());
@@ -658,8 +1153,8 @@ impl SensorSettingsForm {
         self.area_threshold_max.set(area_threshold_max.to_string());
         self.max_object_cnt.set(max_object_cnt.to_string());
 
-        self.operation_mode.set(i32::from(operation_mode));
-        self.frame_subtraction.set(i32::from(frame_subtraction));
+        self.operation_mode.set(OperationMode::from_register(operation_mode).index());
+        self.frame_subtraction.set(FrameSubtraction::from_register(frame_subtraction).index());
         self.gain.set(i32::from(Gain::index_from_reg(gain_1, gain_2)));
         Ok(())
     }
@@ -707,9 +1202,31 @@ impl SensorSettingsForm {
             "max object count" max_object_cnt: u8 { |x| ((1..=16).contains(&x), "must be between 1 and 16") },
             "scale resolution X" resolution_x: u16 { |x| ((1..=4095).contains(&x), "must be between 1 and 4095") },
             "scale resolution Y" resolution_y: u16 { |x| ((1..=4095).contains(&x), "must be between 1 and 4095") },
+            "edge margin" edge_margin: f64,
+        }
+        if let Some(window) = self.point_window() {
+            window.validate(errors);
+        }
+    }
+
+    /// Non-blocking warnings about settings that are valid but probably not what's wanted.
+    fn advise(&self, accel_odr: u16, advisories: &mut Vec<String>) {
+        if let Ok(frame_period) = self.frame_period.with_untracked(|s| s.parse()) {
+            advisories.extend(frame_rate_advisory(frame_period, accel_odr));
         }
     }
 
+    /// The window points are accepted from, from the resolution and edge margin entries.
+    fn point_window(&self) -> Option<PointWindow> {
+        Some(PointWindow {
+            margin: self.edge_margin.with_untracked(|s| s.parse().ok())?,
+            resolution: Vector2::new(
+                self.resolution_x.with_untracked(|s| s.parse().ok())?,
+                self.resolution_y.with_untracked(|s| s.parse().ok())?,
+            ),
+        })
+    }
+
     /// Make sure to call `validate()` before calling this method.
     async fn apply(&self, device: &UsbDevice) -> Result<()> {
         let gain = usize::try_from(self.gain.get_untracked()).unwrap();
@@ -725,9 +1242,9 @@ impl SensorSettingsForm {
             device.set_noise_threshold(self.port, self.noise_threshold.with_untracked(|v| v.parse().unwrap())),
             device.set_area_threshold_max(self.port, self.area_threshold_max.with_untracked(|v| v.parse().unwrap())),
             device.set_area_threshold_min(self.port, self.area_threshold_min.with_untracked(|v| v.parse().unwrap())),
-            device.set_operation_mode(self.port, u8::try_from(self.operation_mode.get_untracked()).unwrap()),
+            device.set_operation_mode(self.port, OperationMode::from_index(self.operation_mode.get_untracked()).into()),
             device.set_max_object_cnt(self.port, self.max_object_cnt.with_untracked(|v| v.parse().unwrap())),
-            device.set_frame_subtraction(self.port, u8::try_from(self.frame_subtraction.get_untracked()).unwrap()),
+            device.set_frame_subtraction(self.port, FrameSubtraction::from_index(self.frame_subtraction.get_untracked()).into()),
             device.set_frame_period(self.port, self.frame_period.with_untracked(|v| v.parse().unwrap())),
         )?;
         tokio::try_join!(
@@ -737,6 +1254,38 @@ impl SensorSettingsForm {
         Ok(())
     }
 
+    /// The `(bank, address, data)` register writes `apply()` makes, not counting the sync
+    /// updates. Make sure to call `validate()` before calling this method.
+    fn written_registers(&self) -> Vec<(u8, u8, u8)> {
+        let gain = usize::try_from(self.gain.get_untracked()).unwrap();
+        let gain = GAIN_TABLE[gain].1;
+
+        [
+            UsbDevice::resolution_x_registers(self.resolution_x.with_untracked(|v| v.parse().unwrap())),
+            UsbDevice::resolution_y_registers(self.resolution_y.with_untracked(|v| v.parse().unwrap())),
+            UsbDevice::gain_1_registers(gain.b_global),
+            UsbDevice::gain_2_registers(gain.b_ggh),
+            UsbDevice::exposure_time_registers(self.exposure_time.with_untracked(|v| v.parse().unwrap())),
+            UsbDevice::brightness_threshold_registers(self.brightness_threshold.with_untracked(|v| v.parse().unwrap())),
+            UsbDevice::noise_threshold_registers(self.noise_threshold.with_untracked(|v| v.parse().unwrap())),
+            UsbDevice::area_threshold_max_registers(self.area_threshold_max.with_untracked(|v| v.parse().unwrap())),
+            UsbDevice::area_threshold_min_registers(self.area_threshold_min.with_untracked(|v| v.parse().unwrap())),
+            UsbDevice::operation_mode_registers(OperationMode::from_index(self.operation_mode.get_untracked()).into()),
+            UsbDevice::max_object_cnt_registers(self.max_object_cnt.with_untracked(|v| v.parse().unwrap())),
+            UsbDevice::frame_subtraction_registers(FrameSubtraction::from_index(self.frame_subtraction.get_untracked()).into()),
+            UsbDevice::frame_period_registers(self.frame_period.with_untracked(|v| v.parse().unwrap())),
+        ].concat()
+    }
+
+    /// Reads back the registers written by `apply()` and describes the ones that don't match.
+    async fn verify(&self, device: &UsbDevice) -> Result<Vec<String>> {
+        let mismatches = device.verify_registers(self.port, &self.written_registers()).await?;
+        Ok(mismatches.iter().map(|m| match m.read {
+            Some(read) => format!("bank {:#04x} address {:#04x}: wrote {:#04x}, read {:#04x}", m.bank, m.address, m.written, read),
+            None => format!("bank {:#04x} address {:#04x}: wrote {:#04x}, no response", m.bank, m.address, m.written),
+        }).collect())
+    }
+
     fn clear(&self) {
         self.pid.update(String::clear);
         self.resolution_x.update(String::clear);
@@ -749,33 +1298,205 @@ impl SensorSettingsForm {
         self.area_threshold_max.update(String::clear);
         self.max_object_cnt.update(String::clear);
 
-        self.operation_mode.set(0);
-        self.frame_subtraction.set(0);
+        self.operation_mode.set(OperationMode::default().index());
+        self.frame_subtraction.set(FrameSubtraction::default().index());
         self.gain.set(0);
     }
 
-    fn load_defaults(&self) {
+    fn load_defaults(&self, environment: EnvironmentPreset) {
+        let preset = environment.sensor_preset(self.port);
         self.resolution_x.update(|s| s.replace_range(.., "4095"));
         self.resolution_y.update(|s| s.replace_range(.., "4095"));
-        self.exposure_time.update(|s| s.replace_range(.., "8192"));
+        self.exposure_time.set(preset.exposure_time.to_string());
         self.frame_period.update(|s| s.replace_range(.., "49780"));
-        self.brightness_threshold.update(|s| s.replace_range(.., "110"));
-        self.noise_threshold.update(|s| s.replace_range(.., "10"));
-        self.area_threshold_min.update(|s| s.replace_range(.., "0"));
-        self.area_threshold_max.update(|s| s.replace_range(.., "9605"));
+        self.brightness_threshold.set(preset.brightness_threshold.to_string());
+        self.noise_threshold.set(preset.noise_threshold.to_string());
+        self.area_threshold_min.set(preset.area_threshold_min.to_string());
+        self.area_threshold_max.set(preset.area_threshold_max.to_string());
         self.max_object_cnt.update(|s| s.replace_range(.., "16"));
+        self.edge_margin.set(default_point_window(self.port).margin.to_string());
+
+        self.operation_mode.set(OperationMode::default().index());
+        self.frame_subtraction.set(preset.frame_subtraction.index());
+        self.gain.set(Gain::index_from_reg(preset.gain.0, preset.gain.1));
+    }
+}
+
+/// Starting points for the sensor settings depending on how much ambient light there is. Brighter
+/// places get a shorter exposure, less gain, higher thresholds and frame subtraction to take out
+/// the background.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum EnvironmentPreset {
+    /// What "Load defaults" always gave.
+    #[default]
+    Indoor,
+    /// Daylight through windows, stage lighting.
+    Bright,
+    Outdoor,
+}
+
+/// The part of the sensor settings an `EnvironmentPreset` decides.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct SensorPreset {
+    exposure_time: u16,
+    brightness_threshold: u8,
+    noise_threshold: u8,
+    area_threshold_min: u8,
+    area_threshold_max: u16,
+    frame_subtraction: FrameSubtraction,
+    /// (b_global, b_ggh) register values.
+    gain: (u8, u8),
+}
+
+impl EnvironmentPreset {
+    /// Combobox order.
+    pub const ALL: &'static [EnvironmentPreset] = &[Self::Indoor, Self::Bright, Self::Outdoor];
+
+    pub fn index(self) -> i32 {
+        Self::ALL.iter().position(|&v| v == self).unwrap() as i32
+    }
+
+    /// Out of range indices give the default.
+    pub fn from_index(index: i32) -> Self {
+        usize::try_from(index).ok().and_then(|i| Self::ALL.get(i).copied()).unwrap_or_default()
+    }
+
+    fn sensor_preset(self, port: Port) -> SensorPreset {
+        match (self, port) {
+            (Self::Indoor, Port::Nf) => SensorPreset {
+                exposure_time: 8192,
+                brightness_threshold: 110,
+                noise_threshold: 10,
+                area_threshold_min: 0,
+                area_threshold_max: 9605,
+                frame_subtraction: FrameSubtraction::Off,
+                gain: (16, 0),
+            },
+            (Self::Indoor, Port::Wf) => SensorPreset { gain: (16, 2), ..Self::Indoor.sensor_preset(Port::Nf) },
+            (Self::Bright, Port::Nf) => SensorPreset {
+                exposure_time: 4096,
+                brightness_threshold: 140,
+                noise_threshold: 20,
+                area_threshold_min: 0,
+                area_threshold_max: 9605,
+                frame_subtraction: FrameSubtraction::On,
+                gain: (8, 0),
+            },
+            (Self::Bright, Port::Wf) => SensorPreset { gain: (16, 0), ..Self::Bright.sensor_preset(Port::Nf) },
+            (Self::Outdoor, Port::Nf) => SensorPreset {
+                exposure_time: 1024,
+                brightness_threshold: 180,
+                noise_threshold: 30,
+                // Sunlight glints off small shiny things
+                area_threshold_min: 2,
+                area_threshold_max: 9605,
+                frame_subtraction: FrameSubtraction::On,
+                gain: (0, 0),
+            },
+            (Self::Outdoor, Port::Wf) => SensorPreset { gain: (8, 0), ..Self::Outdoor.sensor_preset(Port::Nf) },
+        }
+    }
+}
+
+impl std::fmt::Display for EnvironmentPreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Indoor => "Indoor",
+            Self::Bright => "Bright",
+            Self::Outdoor => "Outdoor",
+        })
+    }
+}
+
+/// Number of polls in a row the auto-refresh has to see a changed port list before it updates
+/// the device combobox.
+const AUTO_REFRESH_DEBOUNCE_POLLS: u32 = 2;
 
-        self.operation_mode.set(0);
-        self.frame_subtraction.set(0);
+/// Serial ports that belong to a vision module.
+pub(crate) fn list_devices() -> serialport::Result<Vec<SerialPortInfo>> {
+    Ok(serialport::available_ports()?.into_iter().filter(|port| {
+        match &port.port_type {
+            UsbPort(port_info) => {
+                if port_info.vid == 0x1915 && port_info.pid == 0x520F || port_info.pid == 0x5210 {
+                    if let Some(i) = port_info.interface {
+                        // interface 0: cdc acm module
+                        // interface 1: cdc acm module functional subordinate interface
+                        // interface 2: cdc acm dfu
+                        // interface 3: cdc acm dfu subordinate interface
+                        i == 0
+                    } else {
+                        true
+                    }
+                } else {
+                    false
+                }
+            },
+            _ => false,
+        }
+    }).collect())
+}
+
+/// Holds back a change in the port list until the same list has been seen several polls in a
+/// row, so a device that's still enumerating doesn't make the combobox thrash.
+#[derive(Clone, Debug, Default)]
+pub struct PortListDebounce {
+    candidate: Option<Vec<String>>,
+    count: u32,
+}
 
-        match self.port {
-            Port::Nf => self.gain.set(i32::from(Gain::index_from_reg(16, 0))),
-            Port::Wf => self.gain.set(i32::from(Gain::index_from_reg(16, 2))),
+impl PortListDebounce {
+    /// Returns true once `seen` has differed from `current` and stayed the same for `polls`
+    /// calls in a row.
+    pub fn update(&mut self, current: &[String], seen: &[String], polls: u32) -> bool {
+        if seen == current {
+            *self = Self::default();
+            return false;
         }
+        if self.candidate.as_deref() == Some(seen) {
+            self.count += 1;
+        } else {
+            self.candidate = Some(seen.to_vec());
+            self.count = 1;
+        }
+        if self.count >= polls {
+            *self = Self::default();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// What an entry of the device combobox connects to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum DeviceEntry {
+    /// Index into the serial port list.
+    Serial(usize),
+    Simulator(String),
+    Hub(String),
+}
+
+/// The combobox lists the serial ports, then the simulator and the hub if their addresses were
+/// given on the command line, in that order.
+fn device_entry(index: usize, port_count: usize, sim_addr: Option<&str>, udp_addr: Option<&str>) -> Result<DeviceEntry> {
+    if index < port_count {
+        return Ok(DeviceEntry::Serial(index));
+    }
+    let mut index = index - port_count;
+    if let Some(sim_addr) = sim_addr {
+        if index == 0 {
+            return Ok(DeviceEntry::Simulator(sim_addr.into()));
+        }
+        index -= 1;
+    }
+    match udp_addr {
+        Some(udp_addr) if index == 0 => Ok(DeviceEntry::Hub(udp_addr.into())),
+        Some(_) => Err(anyhow::anyhow!("No device at index {}", index + port_count)),
+        None => Err(anyhow::anyhow!("No hub address configured, start with -u <address> to use an M4Hub")),
     }
 }
 
-fn display_for_serial_port(port_info: &SerialPortInfo) -> String {
+pub(crate) fn display_for_serial_port(port_info: &SerialPortInfo) -> String {
     let usb_port = match &port_info.port_type {
         serialport::SerialPortType::UsbPort(u) => u,
         _ => return port_info.port_name.clone(),
@@ -823,6 +1544,82 @@ impl Gain {
     }
 }
 
+/// Defines a sensor register enum whose variants are listed in a combobox in declaration order.
+macro_rules! register_enum {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident = $value:literal $label:literal),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+        pub enum $name {
+            #[default]
+            $($variant = $value),*
+        }
+
+        impl $name {
+            /// Combobox order.
+            pub const ALL: &'static [$name] = &[$($name::$variant),*];
+
+            pub fn index(self) -> i32 {
+                Self::ALL.iter().position(|&v| v == self).unwrap() as i32
+            }
+
+            /// Out of range indices give the default.
+            pub fn from_index(index: i32) -> Self {
+                usize::try_from(index).ok().and_then(|i| Self::ALL.get(i).copied()).unwrap_or_default()
+            }
+
+            /// Unknown register values are logged and give the default, rather than failing the
+            /// whole config read.
+            pub fn from_register(value: u8) -> Self {
+                Self::try_from(value).unwrap_or_else(|e| {
+                    tracing::warn!("{e}, using {}", Self::default());
+                    Self::default()
+                })
+            }
+        }
+
+        impl TryFrom<u8> for $name {
+            type Error = anyhow::Error;
+
+            fn try_from(value: u8) -> Result<Self> {
+                match value {
+                    $($value => Ok($name::$variant),)*
+                    _ => Err(anyhow::anyhow!("invalid {} register value {value:#04x}", stringify!($name))),
+                }
+            }
+        }
+
+        impl From<$name> for u8 {
+            fn from(value: $name) -> u8 {
+                value as u8
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(match self {
+                    $($name::$variant => $label),*
+                })
+            }
+        }
+    };
+}
+
+register_enum! {
+    /// DSP operation mode register.
+    OperationMode {
+        Normal = 0 "Normal",
+        Tracking = 1 "Tracking",
+    }
+}
+
+register_enum! {
+    /// Frame subtraction register.
+    FrameSubtraction {
+        Off = 0 "Off",
+        On = 1 "On",
+    }
+}
+
 // funny
 
 const fn n_to_bstr(n: usize) -> [u8; 6] {
@@ -866,17 +1663,400 @@ pub static GAIN_TABLE: [(&str, Gain); 16*3 + 1] = {
 //         print(f'    ("{value:.4f}", Gain::new(0x{i:02x}, 0x{j:02x})),')
 // ];
 
-/// Retry an asynchronous operation up to `limit` times.
-async fn retry<F, G>(mut op: F, timeout: Duration, limit: usize) -> Option<G::Output>
+/// Colors the swatch and fills in the breakdown next to it.
+pub fn update_health_indicator(ui: &UI, mot_runner: &Mutex<MotRunner>, swatch: &mut ColorButton, label: &mut Label) {
+    let (report, accel_anomalies) = {
+        let runner = mot_runner.lock();
+        let thresholds = runner.health_thresholds;
+        let now = runner.clock.now();
+        (runner.health.report(now, &thresholds), runner.accel_sequence.anomalies)
+    };
+    let (r, g, b) = report.status.color();
+    swatch.set_color(ui, r, g, b, 1.);
+    label.set_text(ui, &format!("{}; accel {}", report.summary(), accel_anomalies.summary()));
+}
+
+/// IMU samples per marker frame above which `frame_rate_advisory` warns. The eskf is predicted
+/// with every IMU sample and only corrected with every marker frame, at more than this many
+/// predictions per correction the pose visibly lags behind and snaps back.
+const MAX_IMU_SAMPLES_PER_FRAME: f64 = 10.;
+
+/// Warns when the marker frame rate is far below the IMU rate. `frame_period` is in units of
+/// 100 ns, `accel_odr` in Hz.
+fn frame_rate_advisory(frame_period: u32, accel_odr: u16) -> Option<String> {
+    let fps = 1e7 / f64::from(frame_period.max(1));
+    let ratio = f64::from(accel_odr) / fps;
+    (ratio > MAX_IMU_SAMPLES_PER_FRAME).then(|| format!(
+        "frame period: {fps:.1} fps is {ratio:.0} IMU samples per frame at {accel_odr} Hz ODR, pose correction will be sluggish (keep it at most {MAX_IMU_SAMPLES_PER_FRAME})",
+    ))
+}
+
+/// In the order of the camera model comboboxes.
+const CAMERA_MODEL_TYPES: [CameraModelType; 2] = [CameraModelType::Pinhole, CameraModelType::Fisheye];
+
+fn camera_model_type(index: i32) -> CameraModelType {
+    usize::try_from(index).ok().and_then(|i| CAMERA_MODEL_TYPES.get(i).copied()).unwrap_or_default()
+}
+
+fn camera_model_index(model: CameraModelType) -> i32 {
+    CAMERA_MODEL_TYPES.iter().position(|&m| m == model).unwrap_or(0) as i32
+}
+
+fn default_intrinsics(port: Port) -> RosOpenCvIntrinsics<f32> {
+    match port {
+        Port::Nf => RosOpenCvIntrinsics::from_params(145., 0., 145., 45., 45.),
+        Port::Wf => RosOpenCvIntrinsics::from_params(34., 0., 34., 45., 45.),
+    }
+}
+
+/// Replaces camera models with a principal point outside the sensor by the defaults, those can't
+/// be used for tracking.
+pub(crate) fn reject_bad_intrinsics(config: &mut GeneralConfig) -> Vec<String> {
+    let mut rejected = vec![];
+    for (port, name, intrinsics) in [
+        (Port::Nf, "nearfield", &mut config.camera_model_nf),
+        (Port::Wf, "widefield", &mut config.camera_model_wf),
+    ] {
+        if let Some(p) = principal_point_outside_sensor(intrinsics, CAMERA_MODEL_RESOLUTION) {
+            rejected.push(format!(
+                "{name} calibration: principal point ({:.1}, {:.1}) is outside the sensor, using the default calibration instead",
+                p.x, p.y,
+            ));
+            *intrinsics = default_intrinsics(port);
+        }
+    }
+    rejected
+}
+
+fn default_point_window(port: Port) -> PointWindow {
+    match port {
+        Port::Nf => PointWindow::NF_DEFAULT,
+        Port::Wf => PointWindow::WF_DEFAULT,
+    }
+}
+
+/// Hand the point windows from the sensor forms to `MotRunner`. Forms that don't parse are left
+/// alone, they get validated before being applied.
+fn update_point_windows(mot_runner: &Mutex<MotRunner>, wf_settings: SensorSettingsForm, nf_settings: SensorSettingsForm) {
+    let mut runner = mot_runner.lock();
+    if let Some(window) = wf_settings.point_window() {
+        runner.wf_point_window = window;
+    }
+    if let Some(window) = nf_settings.point_window() {
+        runner.nf_point_window = window;
+    }
+}
+
+/// How long to wait for a device read and how often to try it. Long cable runs and flaky hubs can
+/// need more patience than the defaults, so they can be overridden from the environment. Each
+/// policy has its own timeout variable, `ODYSSEY_RETRY_ATTEMPTS` and `ODYSSEY_RETRY_BACKOFF` are
+/// shared.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Timeout of the first attempt.
+    pub timeout: Duration,
+    pub attempts: usize,
+    /// Each attempt after a timeout waits this many times longer than the last. 1 keeps the
+    /// timeout constant.
+    pub backoff: f32,
+}
+
+impl RetryPolicy {
+    /// Reading the general config.
+    pub const CONFIG: Self = Self { timeout: Duration::from_millis(5000), attempts: 3, backoff: 1. };
+    pub const CONFIG_TIMEOUT_VAR: &'static str = "ODYSSEY_CONFIG_TIMEOUT_MS";
+    /// Reading a sensor register.
+    pub const REGISTER: Self = Self { timeout: Duration::from_millis(2000), attempts: 3, backoff: 1. };
+    pub const REGISTER_TIMEOUT_VAR: &'static str = "ODYSSEY_REGISTER_TIMEOUT_MS";
+
+    /// Backoff doesn't grow the timeout past this, or past the first timeout if that is longer.
+    const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// `timeout_var` is the variable the timeout of this policy is overridden with.
+    pub fn with_env_overrides(self, timeout_var: &str) -> Self {
+        self.with_overrides(timeout_var, |name| std::env::var(name).ok())
+    }
+
+    /// Replaces the fields `var` has a valid value for, invalid ones are logged and ignored.
+    fn with_overrides(mut self, timeout_var: &str, var: impl Fn(&str) -> Option<String>) -> Self {
+        fn parse<T: std::str::FromStr>(var: &impl Fn(&str) -> Option<String>, name: &str, valid: impl Fn(&T) -> bool) -> Option<T> {
+            let value = var(name)?;
+            let parsed = value.trim().parse().ok().filter(valid);
+            if parsed.is_none() {
+                tracing::warn!("ignoring {name}={value}");
+            }
+            parsed
+        }
+        if let Some(ms) = parse(&var, timeout_var, |ms: &u64| *ms > 0) {
+            self.timeout = Duration::from_millis(ms);
+        }
+        if let Some(attempts) = parse(&var, "ODYSSEY_RETRY_ATTEMPTS", |n: &usize| *n > 0) {
+            self.attempts = attempts;
+        }
+        if let Some(backoff) = parse(&var, "ODYSSEY_RETRY_BACKOFF", |b: &f32| b.is_finite() && *b >= 1.) {
+            self.backoff = backoff;
+        }
+        self
+    }
+
+    /// Timeout of the `attempt`th try, counting from 0.
+    fn timeout(&self, attempt: usize) -> Duration {
+        let max = Self::MAX_TIMEOUT.max(self.timeout);
+        let exponent = i32::try_from(attempt).unwrap_or(i32::MAX);
+        let secs = self.timeout.as_secs_f64() * f64::from(self.backoff).powi(exponent);
+        Duration::try_from_secs_f64(secs).unwrap_or(max).min(max)
+    }
+}
+
+/// Retry an asynchronous operation up to `policy.attempts` times.
+async fn retry<F, G>(mut op: F, policy: RetryPolicy) -> Option<G::Output>
 where
     F: FnMut() -> G,
     G: std::future::Future,
 {
-    for _ in 0..limit {
-        match tokio::time::timeout(timeout, op()).await {
+    for attempt in 0..policy.attempts {
+        match tokio::time::timeout(policy.timeout(attempt), op()).await {
             Ok(r) => return Some(r),
             Err(_) => (),
         }
     }
     None
 }
+
+/// Like `retry`, but gives up with an error naming the register (or whatever `name` is) that
+/// timed out instead of `None`.
+async fn retry_read<F, G>(name: &str, op: F, policy: RetryPolicy) -> Result<G::Output>
+where
+    F: FnMut() -> G,
+    G: std::future::Future,
+{
+    retry(op, policy).await.ok_or_else(|| anyhow::anyhow!("{name} read timed out"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ats_usb::packet::{GeneralConfig, Port};
+    use leptos_reactive::{create_runtime, create_rw_signal, SignalGetUntracked, SignalWithUntracked};
+    use opencv_ros_camera::RosOpenCvIntrinsics;
+
+    use super::{default_intrinsics, device_entry, entries_from_isometry, reject_bad_intrinsics, frame_rate_advisory, isometry_from_entries, retry, retry_read, DeviceEntry, EnvironmentPreset, FrameSubtraction, OperationMode, PortListDebounce, RetryPolicy, SensorSettingsForm, GAIN_TABLE};
+
+    #[test]
+    fn test_device_entry() {
+        assert_eq!(device_entry(1, 2, None, Some("10.0.0.2:23456")).unwrap(), DeviceEntry::Serial(1));
+        assert_eq!(device_entry(2, 2, None, Some("10.0.0.2:23456")).unwrap(), DeviceEntry::Hub("10.0.0.2:23456".into()));
+        assert_eq!(device_entry(0, 0, Some("localhost:4444"), None).unwrap(), DeviceEntry::Simulator("localhost:4444".into()));
+        assert_eq!(device_entry(1, 0, Some("localhost:4444"), Some("10.0.0.2:23456")).unwrap(), DeviceEntry::Hub("10.0.0.2:23456".into()));
+
+        // Past the ports without a hub address, e.g. the port list shrank under the selection
+        let e = device_entry(2, 2, None, None).unwrap_err();
+        assert!(e.to_string().starts_with("No hub address configured"), "{e}");
+        let e = device_entry(1, 0, Some("localhost:4444"), None).unwrap_err();
+        assert!(e.to_string().starts_with("No hub address configured"), "{e}");
+        assert!(device_entry(3, 2, None, Some("10.0.0.2:23456")).is_err());
+    }
+
+    #[test]
+    fn test_frame_rate_advisory() {
+        // Defaults, ~200 fps at 100 Hz
+        assert_eq!(frame_rate_advisory(49780, 100), None);
+        // 10 fps at 100 Hz is right at the limit
+        assert_eq!(frame_rate_advisory(1_000_000, 100), None);
+        // 5 fps at 100 Hz
+        assert!(frame_rate_advisory(2_000_000, 100).is_some());
+        // Same frame rate is fine with a slower IMU
+        assert_eq!(frame_rate_advisory(2_000_000, 25), None);
+    }
+
+    #[test]
+    fn test_register_enum_round_trip() {
+        for &mode in OperationMode::ALL {
+            assert_eq!(OperationMode::from_index(mode.index()), mode);
+            assert_eq!(OperationMode::try_from(u8::from(mode)).unwrap(), mode);
+        }
+        for &mode in FrameSubtraction::ALL {
+            assert_eq!(FrameSubtraction::from_index(mode.index()), mode);
+            assert_eq!(FrameSubtraction::try_from(u8::from(mode)).unwrap(), mode);
+        }
+        assert_eq!(OperationMode::Tracking.index(), 1);
+        assert_eq!(u8::from(FrameSubtraction::On), 1);
+        assert_eq!(OperationMode::Tracking.to_string(), "Tracking");
+        assert_eq!(FrameSubtraction::Off.to_string(), "Off");
+
+        assert!(OperationMode::try_from(2).is_err());
+        assert!(FrameSubtraction::try_from(0xff).is_err());
+        assert_eq!(OperationMode::from_register(2), OperationMode::Normal);
+        assert_eq!(OperationMode::from_register(1), OperationMode::Tracking);
+        assert_eq!(OperationMode::from_index(-1), OperationMode::Normal);
+        assert_eq!(FrameSubtraction::from_index(5), FrameSubtraction::Off);
+    }
+
+    #[test]
+    fn test_environment_presets() {
+        let runtime = create_runtime();
+        for &preset in EnvironmentPreset::ALL {
+            assert_eq!(EnvironmentPreset::from_index(preset.index()), preset);
+            for port in [Port::Nf, Port::Wf] {
+                let empty = || create_rw_signal(String::new());
+                let form = SensorSettingsForm {
+                    port,
+                    pid: empty(),
+                    resolution_x: empty(),
+                    resolution_y: empty(),
+                    exposure_time: empty(),
+                    frame_period: empty(),
+                    brightness_threshold: empty(),
+                    noise_threshold: empty(),
+                    area_threshold_min: empty(),
+                    area_threshold_max: empty(),
+                    max_object_cnt: empty(),
+                    edge_margin: empty(),
+                    operation_mode: create_rw_signal(-1),
+                    frame_subtraction: create_rw_signal(-1),
+                    gain: create_rw_signal(-1),
+                };
+                form.load_defaults(preset);
+                let mut errors = vec![];
+                form.validate(&mut errors);
+                assert!(errors.is_empty(), "{preset} {port:?}: {errors:?}");
+
+                let sensor = preset.sensor_preset(port);
+                assert_eq!(form.frame_subtraction.get_untracked(), sensor.frame_subtraction.index());
+                assert!(usize::try_from(form.gain.get_untracked()).is_ok_and(|g| g < GAIN_TABLE.len()));
+                assert!(form.operation_mode.get_untracked() >= 0);
+                assert_eq!(form.exposure_time.with_untracked(|s| s.parse::<u16>().unwrap()), sensor.exposure_time);
+            }
+        }
+        // Indoor is the old defaults
+        assert_eq!(EnvironmentPreset::default(), EnvironmentPreset::Indoor);
+        assert_eq!(EnvironmentPreset::Indoor.sensor_preset(Port::Nf).frame_subtraction, FrameSubtraction::Off);
+        assert_eq!(EnvironmentPreset::Outdoor.sensor_preset(Port::Wf).frame_subtraction, FrameSubtraction::On);
+        runtime.dispose();
+    }
+
+    #[test]
+    fn test_port_list_debounce() {
+        let list = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let mut debounce = PortListDebounce::default();
+        let current = list(&["COM3"]);
+
+        assert!(!debounce.update(&current, &current, 2));
+        // Plugged in, reported on the second poll that sees it
+        assert!(!debounce.update(&current, &list(&["COM3", "COM4"]), 2));
+        assert!(debounce.update(&current, &list(&["COM3", "COM4"]), 2));
+
+        // Flickers while enumerating, never stays the same long enough
+        assert!(!debounce.update(&current, &list(&["COM3", "COM4"]), 2));
+        assert!(!debounce.update(&current, &list(&["COM3", "COM5"]), 2));
+        assert!(!debounce.update(&current, &current, 2));
+        assert!(!debounce.update(&current, &list(&["COM3", "COM4"]), 2));
+
+        // Unplugged
+        assert!(debounce.update(&current, &list(&[]), 1));
+    }
+
+    #[tokio::test]
+    async fn test_retry_read_names_timed_out_read() {
+        let attempts = std::cell::Cell::new(0);
+        let never = || {
+            attempts.set(attempts.get() + 1);
+            std::future::pending::<anyhow::Result<u16>>()
+        };
+        let policy = RetryPolicy { timeout: Duration::from_millis(1), attempts: 3, backoff: 1. };
+        let e = retry_read("exposure_time", never, policy).await.unwrap_err();
+        assert_eq!(e.to_string(), "exposure_time read timed out");
+        assert_eq!(attempts.get(), 3);
+
+        let value = retry_read("frame_period", || async { anyhow::Ok(49780u32) }, RetryPolicy::REGISTER).await;
+        assert_eq!(value.unwrap().unwrap(), 49780);
+    }
+
+    #[tokio::test]
+    async fn test_retry_policy() {
+        let policy = RetryPolicy { timeout: Duration::from_millis(2), attempts: 4, backoff: 2. };
+        let attempts = std::cell::Cell::new(0);
+        // Answers on the third try
+        let flaky = || {
+            attempts.set(attempts.get() + 1);
+            let answer = attempts.get() >= 3;
+            async move {
+                if !answer {
+                    std::future::pending::<()>().await;
+                }
+                7
+            }
+        };
+        assert_eq!(retry(flaky, policy).await, Some(7));
+        assert_eq!(attempts.get(), 3);
+
+        attempts.set(0);
+        let never = || {
+            attempts.set(attempts.get() + 1);
+            std::future::pending::<()>()
+        };
+        assert_eq!(retry(never, policy).await, None);
+        assert_eq!(attempts.get(), 4);
+        assert!((policy.timeout(3).as_secs_f64() - 0.016).abs() < 1e-6);
+        // Grows up to the cap instead of overflowing
+        assert_eq!(policy.timeout(20), RetryPolicy::MAX_TIMEOUT);
+        assert_eq!(policy.timeout(usize::MAX), RetryPolicy::MAX_TIMEOUT);
+        let long = RetryPolicy { timeout: Duration::from_secs(90), attempts: 2, backoff: 2. };
+        assert_eq!(long.timeout(5), Duration::from_secs(90));
+    }
+
+    #[test]
+    fn test_retry_policy_overrides() {
+        let vars = |name: &str| match name {
+            "ODYSSEY_REGISTER_TIMEOUT_MS" => Some("8000".to_string()),
+            "ODYSSEY_RETRY_ATTEMPTS" => Some("0".to_string()),
+            "ODYSSEY_RETRY_BACKOFF" => Some(" 1.5 ".to_string()),
+            _ => None,
+        };
+        let policy = RetryPolicy::REGISTER.with_overrides(RetryPolicy::REGISTER_TIMEOUT_VAR, vars);
+        assert_eq!(policy, RetryPolicy { timeout: Duration::from_millis(8000), attempts: 3, backoff: 1.5 });
+        // The register timeout doesn't apply to config reads
+        let policy = RetryPolicy::CONFIG.with_overrides(RetryPolicy::CONFIG_TIMEOUT_VAR, vars);
+        assert_eq!(policy, RetryPolicy { backoff: 1.5, ..RetryPolicy::CONFIG });
+        assert_eq!(RetryPolicy::CONFIG.with_overrides(RetryPolicy::CONFIG_TIMEOUT_VAR, |_| None), RetryPolicy::CONFIG);
+    }
+
+    #[test]
+    fn test_reject_bad_intrinsics() {
+        let mut config = GeneralConfig::default();
+        let wf = RosOpenCvIntrinsics::from_params(30., 0., 31., 50., 47.);
+        config.camera_model_nf = RosOpenCvIntrinsics::from_params(145., 0., 145., 120., 45.);
+        config.camera_model_wf = wf.clone();
+        let rejected = reject_bad_intrinsics(&mut config);
+        assert_eq!(rejected.len(), 1);
+        assert!(rejected[0].starts_with("nearfield calibration: principal point (120.0, 45.0)"), "{}", rejected[0]);
+        // Falls back to the default for the rejected one only
+        assert_eq!(config.camera_model_nf.p, default_intrinsics(Port::Nf).p);
+        assert_eq!(config.camera_model_wf.p, wf.p);
+
+        assert!(reject_bad_intrinsics(&mut config).is_empty());
+    }
+
+    #[test]
+    fn test_isometry_from_entries() {
+        let iso = nalgebra::Isometry3::from_parts(
+            nalgebra::Translation3::new(0.012, -0.5, 3.25),
+            nalgebra::UnitQuaternion::from_euler_angles(0.1, -0.2, 1.3),
+        );
+        let parsed = isometry_from_entries(entries_from_isometry(&iso)).unwrap();
+        assert!((parsed.translation.vector - iso.translation.vector).norm() < 1e-6);
+        assert!(parsed.rotation.angle_to(&iso.rotation) < 1e-5);
+
+        let entries = |e: [&str; 6]| e.map(String::from);
+        let parsed = isometry_from_entries(entries(["1", "2", "3", "0", "90", "0"])).unwrap();
+        assert_eq!(parsed.translation.vector, nalgebra::Vector3::new(1., 2., 3.));
+        assert!((parsed.rotation.euler_angles().1 - std::f32::consts::FRAC_PI_2).abs() < 1e-5);
+
+        for invalid in ["", "x", "1,5", "NaN", "inf", "-inf"] {
+            let mut e = entries(["0"; 6]);
+            e[4] = invalid.into();
+            assert!(isometry_from_entries(e).is_none(), "{invalid:?}");
+        }
+    }
+}
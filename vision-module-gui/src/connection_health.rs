@@ -0,0 +1,246 @@
+//! Combines stream freshness, packet rate and recent errors into one glanceable status
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Longest `rate_window` there are packets kept for.
+pub const MAX_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Longest `error_window` there are errors kept for.
+pub const MAX_ERROR_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HealthThresholds {
+    /// Degraded if no packet has arrived for this long.
+    pub stale_after: Duration,
+    /// Bad if no packet has arrived for this long.
+    pub dead_after: Duration,
+    /// Degraded below this many packets per second.
+    pub min_rate: f64,
+    /// Bad with more than this many errors within `error_window`. Any errors at all is degraded.
+    pub max_errors: usize,
+    pub error_window: Duration,
+    /// The rate is averaged over this long, up to `MAX_RATE_WINDOW`. Longer is steadier, shorter
+    /// shows a drop sooner.
+    pub rate_window: Duration,
+}
+
+impl Default for HealthThresholds {
+    fn default() -> Self {
+        Self {
+            stale_after: Duration::from_millis(500),
+            dead_after: Duration::from_secs(2),
+            min_rate: 50.,
+            max_errors: 3,
+            error_window: Duration::from_secs(10),
+            rate_window: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HealthStatus {
+    Good,
+    Degraded,
+    Bad,
+}
+
+impl HealthStatus {
+    /// Green, yellow or red.
+    pub fn color(self) -> (f64, f64, f64) {
+        match self {
+            HealthStatus::Good => (0.0, 0.8, 0.0),
+            HealthStatus::Degraded => (1.0, 0.8, 0.0),
+            HealthStatus::Bad => (0.9, 0.0, 0.0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    /// Time since the last packet, `None` if there hasn't been one.
+    pub age: Option<Duration>,
+    /// Packets per second.
+    pub rate: f64,
+    /// Errors within the error window.
+    pub errors: usize,
+}
+
+impl HealthReport {
+    /// One line breakdown of what the status is based on.
+    pub fn summary(&self) -> String {
+        let age = match self.age {
+            Some(age) => format!("{} ms", age.as_millis()),
+            None => "never".into(),
+        };
+        format!("{:?}: last packet {}, {:.0} packets/s, {} recent errors", self.status, age, self.rate, self.errors)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionHealth {
+    packets: VecDeque<Instant>,
+    errors: VecDeque<Instant>,
+}
+
+impl ConnectionHealth {
+    pub fn on_packet(&mut self, now: Instant) {
+        self.packets.push_back(now);
+        while self.packets.len() > 1 && self.packets.front().is_some_and(|&t| now.duration_since(t) > MAX_RATE_WINDOW) {
+            self.packets.pop_front();
+        }
+    }
+
+    pub fn on_error(&mut self, now: Instant) {
+        self.errors.push_back(now);
+        while self.errors.front().is_some_and(|&t| now.duration_since(t) > MAX_ERROR_WINDOW) {
+            self.errors.pop_front();
+        }
+    }
+
+    /// Forget everything, e.g. when switching devices.
+    pub fn reset(&mut self) {
+        self.packets.clear();
+        self.errors.clear();
+    }
+
+    /// Doesn't modify anything, so it can be called with the runner borrowed for drawing.
+    pub fn report(&self, now: Instant, thresholds: &HealthThresholds) -> HealthReport {
+        let age = self.packets.back().map(|&t| now.duration_since(t));
+        let rate_window = thresholds.rate_window.clamp(Duration::from_millis(1), MAX_RATE_WINDOW);
+        let rate = self.packets.iter().rev().take_while(|&&t| now.duration_since(t) <= rate_window).count() as f64
+            / rate_window.as_secs_f64();
+        let error_window = thresholds.error_window.min(MAX_ERROR_WINDOW);
+        let errors = self.errors.iter().rev().take_while(|&&t| now.duration_since(t) <= error_window).count();
+
+        let status = if age.map_or(true, |a| a > thresholds.dead_after) || errors > thresholds.max_errors {
+            HealthStatus::Bad
+        } else if age.is_some_and(|a| a > thresholds.stale_after) || rate < thresholds.min_rate || errors > 0 {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Good
+        };
+        HealthReport { status, age, rate, errors }
+    }
+}
+
+/// Gaps, repeats and reversals in a stream of device timestamps (µs).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SequenceAnomalies {
+    /// Packets missing from gaps of more than one nominal period.
+    pub dropped: u64,
+    /// Packets with the same timestamp as the one before.
+    pub duplicate: u64,
+    /// Packets older than the one before, out of order or the device clock was reset.
+    pub out_of_order: u64,
+}
+
+impl SequenceAnomalies {
+    pub fn summary(&self) -> String {
+        format!("{} dropped, {} duplicate, {} out of order", self.dropped, self.duplicate, self.out_of_order)
+    }
+}
+
+/// Counts anomalies in a stream that is sent at a fixed rate with a device timestamp.
+#[derive(Clone, Debug, Default)]
+pub struct PacketSequence {
+    prev_timestamp: Option<u32>,
+    pub anomalies: SequenceAnomalies,
+}
+
+impl PacketSequence {
+    /// Gaps up to this many nominal periods count as on time, to allow for timestamp jitter.
+    const GAP_TOLERANCE: f64 = 1.5;
+
+    pub fn on_timestamp(&mut self, timestamp: u32, nominal: Duration) {
+        let prev = self.prev_timestamp.replace(timestamp);
+        let Some(prev) = prev else { return };
+        if timestamp == prev {
+            self.anomalies.duplicate += 1;
+        } else if timestamp < prev {
+            self.anomalies.out_of_order += 1;
+        } else {
+            let periods = f64::from(timestamp - prev) / (nominal.as_secs_f64() * 1e6);
+            if periods > Self::GAP_TOLERANCE {
+                self.anomalies.dropped += periods.round() as u64 - 1;
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{ConnectionHealth, HealthStatus, HealthThresholds, PacketSequence, SequenceAnomalies};
+
+    #[test]
+    fn test_rate_window() {
+        let t0 = Instant::now();
+        let mut health = ConnectionHealth::default();
+        // 250 Hz for 12 s
+        for i in 0..3000 {
+            health.on_packet(t0 + Duration::from_millis(4 * i));
+        }
+        let now = t0 + Duration::from_millis(11_998);
+        for window_ms in [200, 1000, 5000, 10_000, 60_000] {
+            let thresholds = HealthThresholds { rate_window: Duration::from_millis(window_ms), ..Default::default() };
+            let rate = health.report(now, &thresholds).rate;
+            // Off by at most one packet over the window
+            let tolerance = 1000. / window_ms.min(10_000) as f64;
+            assert!((rate - 250.).abs() <= tolerance, "{window_ms} ms: {rate}");
+        }
+    }
+
+    #[test]
+    fn test_health_status() {
+        let thresholds = HealthThresholds::default();
+        let t0 = Instant::now();
+        let mut health = ConnectionHealth::default();
+        assert_eq!(health.report(t0, &thresholds).status, HealthStatus::Bad);
+
+        // 100 Hz
+        for i in 0..200 {
+            health.on_packet(t0 + Duration::from_millis(10 * i));
+        }
+        let now = t0 + Duration::from_millis(1995);
+        let report = health.report(now, &thresholds);
+        assert_eq!(report.status, HealthStatus::Good, "{}", report.summary());
+
+        // Stale
+        let report = health.report(now + Duration::from_secs(1), &thresholds);
+        assert_eq!(report.status, HealthStatus::Degraded);
+
+        // Dead
+        let report = health.report(now + Duration::from_secs(3), &thresholds);
+        assert_eq!(report.status, HealthStatus::Bad);
+
+        // Errors
+        health.on_error(now);
+        assert_eq!(health.report(now, &thresholds).status, HealthStatus::Degraded);
+        for _ in 0..3 {
+            health.on_error(now);
+        }
+        assert_eq!(health.report(now, &thresholds).status, HealthStatus::Bad);
+    }
+
+    #[test]
+    fn test_packet_sequence() {
+        let nominal = Duration::from_millis(1);
+        let mut sequence = PacketSequence::default();
+        // 1 kHz with a little jitter, 3 packets missing after 4000, 5000 sent twice, then the
+        // device clock goes back
+        for t in [1000, 2010, 2990, 4000, 8000, 9005, 5000, 5000, 6000] {
+            sequence.on_timestamp(t, nominal);
+        }
+        assert_eq!(sequence.anomalies, SequenceAnomalies { dropped: 3, duplicate: 1, out_of_order: 1 });
+        sequence.reset();
+        sequence.on_timestamp(100_000, nominal);
+        assert_eq!(sequence.anomalies, SequenceAnomalies::default());
+    }
+}
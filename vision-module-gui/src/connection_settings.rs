@@ -0,0 +1,66 @@
+//! Remembers the last device connected to, so it can be connected to again at launch
+
+use serde::{Deserialize, Serialize};
+
+use crate::settings_file;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ConnectionSettings {
+    /// Serial port of the last device that was connected to successfully.
+    pub remembered_port: Option<String>,
+    /// Connect to `remembered_port` at launch instead of the first port in the list.
+    pub auto_connect: bool,
+}
+
+/// Which device the config window selects when it first lists the ports.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StartupSelection {
+    /// Index of the remembered port in the list.
+    Remembered(usize),
+    /// Auto-connect is on but the remembered port isn't plugged in. Nothing is selected, it's
+    /// left to the user rather than connecting to some other device.
+    NotFound(String),
+    /// Auto-connect is off or nothing is remembered yet, the first port as always.
+    First,
+}
+
+impl ConnectionSettings {
+    pub fn startup_selection(&self, port_names: &[String]) -> StartupSelection {
+        match (&self.remembered_port, self.auto_connect) {
+            (Some(port), true) => match port_names.iter().position(|p| p == port) {
+                Some(i) => StartupSelection::Remembered(i),
+                None => StartupSelection::NotFound(port.clone()),
+            },
+            _ => StartupSelection::First,
+        }
+    }
+
+    /// Load the settings from `connection.toml` in the config directory, or the defaults.
+    pub fn load_from_file() -> Self {
+        settings_file::load("connection.toml", toml::from_str)
+    }
+
+    pub fn save_to_file(&self) {
+        settings_file::save("connection.toml", self, "connection settings");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectionSettings, StartupSelection};
+
+    #[test]
+    fn test_startup_selection() {
+        let ports = ["COM3", "COM7"].map(String::from);
+        let remembered = |port: &str, auto_connect| ConnectionSettings { remembered_port: Some(port.into()), auto_connect };
+
+        assert_eq!(remembered("COM7", true).startup_selection(&ports), StartupSelection::Remembered(1));
+        assert_eq!(remembered("COM9", true).startup_selection(&ports), StartupSelection::NotFound("COM9".into()));
+        assert_eq!(remembered("COM9", true).startup_selection(&[]), StartupSelection::NotFound("COM9".into()));
+        // Off, or on with nothing remembered
+        assert_eq!(remembered("COM7", false).startup_selection(&ports), StartupSelection::First);
+        let nothing_remembered = ConnectionSettings { auto_connect: true, ..Default::default() };
+        assert_eq!(nothing_remembered.startup_selection(&ports), StartupSelection::First);
+    }
+}
@@ -1,6 +1,49 @@
 use iui::controls::{FontDescription, SlantStyle, StretchStyle};
 use iui::draw::{self, text, Brush, FillMode, Path, SolidBrush, StrokeParams, Transform};
 use nalgebra::{Point2, Rotation2, SMatrix, Transform2, Vector2};
+use serde::{Deserialize, Serialize};
+
+/// Shape markers are drawn with, so nf and wf detections can be told apart in dense overlays.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MarkerGlyph {
+    Cross,
+    /// Cross turned 45°, an X.
+    RotatedCross,
+    Diamond,
+    Square,
+    Circle,
+}
+
+impl MarkerGlyph {
+    /// Same order as the comboboxes in the main window.
+    pub const ALL: [Self; 5] = [Self::Cross, Self::RotatedCross, Self::Diamond, Self::Square, Self::Circle];
+
+    pub fn index(self) -> i32 {
+        Self::ALL.iter().position(|&g| g == self).unwrap() as i32
+    }
+
+    pub fn from_index(index: i32) -> Option<Self> {
+        Self::ALL.get(usize::try_from(index).ok()?).copied()
+    }
+}
+
+/// Draws `glyph` centered on (x, y), reaching `r` out from the center.
+pub fn draw_glyph(ctx: &draw::DrawContext, path: &Path, glyph: MarkerGlyph, x: f64, y: f64, r: f64) {
+    match glyph {
+        MarkerGlyph::Cross => draw_crosshair(ctx, path, x, y, r),
+        MarkerGlyph::RotatedCross => draw_crosshair_rotated(ctx, path, x, y, r),
+        MarkerGlyph::Diamond => draw_diamond(ctx, path, x, y, 2. * r, 2. * r),
+        MarkerGlyph::Square => {
+            let side = r * std::f64::consts::SQRT_2;
+            draw_square(ctx, path, Transform2::from_matrix_unchecked(SMatrix::<f64, 3, 3>::new(
+                side, 0., x,
+                0., side, y,
+                0., 0., 1.,
+            )));
+        }
+        MarkerGlyph::Circle => draw_circle(ctx, path, x, y, r),
+    }
+}
 
 pub fn draw_crosshair(ctx: &draw::DrawContext, path: &Path, x: f64, y: f64, r: f64) {
     path.new_figure(ctx, x-r, y);
@@ -20,6 +63,11 @@ pub fn draw_crosshair_rotated(ctx: &draw::DrawContext, path: &Path, x: f64, y: f
     path.line_to(ctx, x+r, y-r);
 }
 
+pub fn draw_circle(ctx: &draw::DrawContext, path: &Path, x: f64, y: f64, r: f64) {
+    path.new_figure_with_arc(ctx, x, y, r, 0., std::f64::consts::TAU, false);
+    path.close_figure(ctx);
+}
+
 pub fn draw_grid(ctx: &draw::DrawContext, path: &Path, x_subdiv: usize, y_subdiv: usize, transform: SMatrix<f64, 3, 3>) {
     for y in 0..=y_subdiv {
         let p1 = Point2::new(0.0, y as f64 / y_subdiv as f64);
@@ -61,15 +109,16 @@ pub fn draw_square(ctx: &draw::DrawContext, path: &Path, transform: Transform2<f
     path.close_figure(ctx);
 }
 
-/// Draws a crosshair and associated text at a given position.
-pub fn draw_marker(ctx: &draw::DrawContext, path: &Path, position: Point2<f64>, label: &str) {
-    draw_crosshair(&ctx, path, position.x, position.y, 50.0);
+/// Draws `glyph` and associated text at a given position.
+pub fn draw_marker(ctx: &draw::DrawContext, path: &Path, glyph: MarkerGlyph, position: Point2<f64>, label: &str) {
+    draw_glyph(&ctx, path, glyph, position.x, position.y, 50.0);
     draw_text(&ctx, position.x + 20.0, position.y + 20.0, label);
 }
 
-/// Draws a rotated crosshair and associated text at a given position.
-pub fn draw_marker_rotated(ctx: &draw::DrawContext, path: &Path, position: Point2<f64>, label: &str) {
-    draw_crosshair_rotated(&ctx, path, position.x, position.y, 50.0);
+/// Same as `draw_marker` with the text a line further down, so wf labels don't cover nf labels
+/// of the same marker.
+pub fn draw_marker_lower_label(ctx: &draw::DrawContext, path: &Path, glyph: MarkerGlyph, position: Point2<f64>, label: &str) {
+    draw_glyph(&ctx, path, glyph, position.x, position.y, 50.0);
     draw_text(&ctx, position.x + 20.0, position.y + 20.0 + 30.0, label);
 }
 
@@ -0,0 +1,255 @@
+//! Extra devices that run alongside the one picked in the config window, each with its own
+//! `MotRunner` and state. The window shows one of them at a time, nothing is combined between
+//! devices yet.
+
+use std::sync::Arc;
+
+use ats_usb::device::UsbDevice;
+use ats_usb::packet::Port;
+use iui::controls::{Area, AreaDrawParams, AreaHandler, Window, WindowType};
+use iui::UI;
+use nalgebra::Vector2;
+use leptos_reactive::{create_effect, RwSignal, SignalGet, SignalGetUntracked, SignalSet, SignalWith, SignalWithUntracked};
+use parking_lot::Mutex;
+use serialport::SerialPortInfo;
+use serialport::SerialPortType::UsbPort;
+use tokio::task::AbortHandle;
+
+use crate::camera_model_settings::CameraModelSettings;
+use crate::config_window::{display_for_serial_port, list_devices, reject_bad_intrinsics};
+use crate::mot_runner::{self, MotRunner};
+use crate::{tracking_canvas_helpers, CloneButShorter};
+
+/// An extra device and the task running it.
+pub struct DeviceRunner {
+    pub name: String,
+    pub runner: Arc<Mutex<MotRunner>>,
+    task: AbortHandle,
+}
+
+/// The extra devices, in the order they were added.
+#[derive(Clone, Default)]
+pub struct DeviceRunners(Arc<Mutex<Vec<DeviceRunner>>>);
+
+impl DeviceRunners {
+    /// Starts `runner` on its device, must be called from within the tokio runtime.
+    pub fn start(&self, name: String, runner: MotRunner) {
+        let runner = Arc::new(Mutex::new(runner));
+        let task = tokio::spawn(mot_runner::run(runner.c())).abort_handle();
+        self.0.lock().push(DeviceRunner { name, runner, task });
+    }
+
+    /// Stops the device at `index` and forgets it.
+    pub fn stop(&self, index: usize) {
+        let mut runners = self.0.lock();
+        if index >= runners.len() {
+            return;
+        }
+        let removed = runners.remove(index);
+        removed.task.abort();
+        tokio::spawn(mot_runner::shutdown(removed.runner));
+    }
+
+    /// Stops every device, for when the app closes.
+    pub async fn shutdown(&self) {
+        let runners = std::mem::take(&mut *self.0.lock());
+        for removed in runners {
+            removed.task.abort();
+            mot_runner::shutdown(removed.runner).await;
+        }
+    }
+
+    pub fn runner(&self, index: usize) -> Option<Arc<Mutex<MotRunner>>> {
+        self.0.lock().get(index).map(|r| r.runner.c())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.0.lock().iter().map(|r| r.name.clone()).collect()
+    }
+}
+
+/// `mot_runner` is the config window's runner, new devices start out with its settings.
+pub fn devices_window(ui: &UI, mot_runner: Arc<Mutex<MotRunner>>, runners: DeviceRunners) -> Window {
+    let mut window = Window::new(ui, "Devices", 480, 480, WindowType::NoMenubar);
+    let ports = RwSignal::new(Vec::<SerialPortInfo>::new());
+    let port = RwSignal::new(-1);
+    let names = RwSignal::new(Vec::<String>::new());
+    let shown = RwSignal::new(-1);
+    let status = RwSignal::new(String::new());
+    let health = RwSignal::new(String::new());
+    crate::layout! { ui,
+        let vbox = VerticalBox(padded: true) {
+            Compact : let add_hbox = HorizontalBox(padded: true) {
+                Stretchy : let port_combobox = Combobox(signal: port) {}
+                Compact : let refresh_button = Button("Refresh")
+                Compact : let add_button = Button("Add", enabled: move || port.get() >= 0)
+            }
+            Compact : let shown_hbox = HorizontalBox(padded: true) {
+                Compact : let shown_label = Label("Show")
+                Stretchy : let shown_combobox = Combobox(signal: shown) {}
+                Compact : let remove_button = Button("Remove", enabled: move || shown.get() >= 0)
+            }
+            Compact : let status_label = Label(move || status.get())
+            Compact : let health_label = Label(move || health.get())
+            Stretchy : let area = Area(Box::new(DeviceCanvas { ctx: ui.c(), runners: runners.c(), shown }))
+        }
+    }
+    window.set_child(ui, vbox);
+
+    create_effect({
+        let ui = ui.c();
+        let port_combobox = port_combobox.c();
+        move |_| {
+            port_combobox.clear(&ui);
+            ports.with(|ports| {
+                for p in ports {
+                    port_combobox.append(&ui, &display_for_serial_port(p));
+                }
+            });
+            port.set(-1);
+        }
+    });
+    create_effect({
+        let ui = ui.c();
+        let mut shown_combobox = shown_combobox.c();
+        move |_| {
+            shown_combobox.clear(&ui);
+            names.with(|names| {
+                for name in names {
+                    shown_combobox.append(&ui, name);
+                }
+            });
+            shown_combobox.set_selected(&ui, shown.get_untracked());
+        }
+    });
+
+    let refresh = {
+        let ui = ui.c();
+        let window = window.c();
+        move || match list_devices() {
+            Ok(p) => ports.set(p),
+            Err(e) => window.modal_err(&ui, "Failed to list serial ports", &e.to_string()),
+        }
+    };
+    refresh_button.on_clicked(ui, {
+        let refresh = refresh.c();
+        move |_| refresh()
+    });
+
+    add_button.on_clicked(ui, {
+        let ui = ui.c();
+        let window = window.c();
+        let runners = runners.c();
+        move |_| {
+            let Some(port_info) = usize::try_from(port.get_untracked()).ok().and_then(|i| ports.with_untracked(|p| p.get(i).cloned())) else {
+                return;
+            };
+            let name = port_info.port_name.clone();
+            if names.with_untracked(|n| n.contains(&name)) {
+                status.set(format!("{name} is already added"));
+                return;
+            }
+            let wait_dsr = matches!(&port_info.port_type, UsbPort(p) if p.pid == 0x5210);
+            status.set(format!("Connecting to {name}..."));
+            let ui = ui.c();
+            let window = window.c();
+            let mot_runner = mot_runner.c();
+            let runners = runners.c();
+            ui.spawn({
+                let ui = ui.c();
+                async move {
+                    let connected = async {
+                        let device = UsbDevice::connect_serial(&name, wait_dsr).await?;
+                        let mut config = device.read_config().await?;
+                        let rejected = reject_bad_intrinsics(&mut config);
+                        let camera_models = CameraModelSettings::load_from_file();
+                        config.camera_model_type_nf = camera_models.nf;
+                        config.camera_model_type_wf = camera_models.wf;
+                        let mut resolutions = [Vector2::zeros(); 2];
+                        for (resolution, port) in resolutions.iter_mut().zip([Port::Nf, Port::Wf]) {
+                            *resolution = Vector2::new(device.resolution_x(port).await?, device.resolution_y(port).await?);
+                        }
+                        anyhow::Ok((device, config, resolutions, rejected))
+                    }.await;
+                    match connected {
+                        Ok((device, config, resolutions, rejected)) => {
+                            let runner = mot_runner.lock().for_device(device, config, resolutions);
+                            runners.start(name.clone(), runner);
+                            names.set(runners.names());
+                            shown.set(names.with_untracked(|n| n.len() as i32 - 1));
+                            status.set(format!("Connected to {name}"));
+                            if !rejected.is_empty() {
+                                window.modal_err_async(&ui, "Rejected camera calibration", &rejected.join("\n")).await;
+                            }
+                        }
+                        Err(e) => {
+                            status.set(format!("Failed to connect to {name}"));
+                            window.modal_err_async(&ui, "Failed to connect", &e.to_string()).await;
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    remove_button.on_clicked(ui, {
+        let runners = runners.c();
+        move |_| {
+            let Ok(index) = usize::try_from(shown.get_untracked()) else { return };
+            runners.stop(index);
+            names.set(runners.names());
+            // Show the next device, or the new last one
+            shown.set(names.with_untracked(|n| if n.is_empty() { -1 } else { index.min(n.len() - 1) as i32 }));
+            status.set(String::new());
+        }
+    });
+
+    window.on_closing(ui, {
+        let ui = ui.c();
+        move |win: &mut Window| {
+            win.hide(&ui);
+        }
+    });
+
+    ui.ui_timer(33, {
+        let window = window.c();
+        let ui = ui.c();
+        let area = area.c();
+        let runners = runners.c();
+        let mut ticks = 0u32;
+        move || {
+            if !window.visible(&ui) {
+                return true;
+            }
+            area.queue_redraw_all(&ui);
+            // The label doesn't need to keep up with the canvas
+            ticks = ticks.wrapping_add(1);
+            if ticks % 8 == 0 {
+                let summary = usize::try_from(shown.get_untracked()).ok().and_then(|i| runners.runner(i)).map(|runner| {
+                    let runner = runner.lock();
+                    runner.health.report(runner.clock.now(), &runner.health_thresholds).summary()
+                });
+                health.set(summary.unwrap_or_default());
+            }
+            true
+        }
+    });
+    refresh();
+    window
+}
+
+/// Tracking view of the shown device.
+struct DeviceCanvas {
+    ctx: UI,
+    runners: DeviceRunners,
+    shown: RwSignal<i32>,
+}
+
+impl AreaHandler for DeviceCanvas {
+    fn draw(&mut self, area: &Area, draw_params: &AreaDrawParams) {
+        let Some(runner) = usize::try_from(self.shown.get_untracked()).ok().and_then(|i| self.runners.runner(i)) else {
+            return;
+        };
+        tracking_canvas_helpers::draw(self.ctx.c(), runner, area, draw_params, false);
+    }
+}
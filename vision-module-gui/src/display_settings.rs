@@ -0,0 +1,164 @@
+//! Brightness, contrast and palette of the canvases, for demos on projectors or in bright rooms
+
+use iui::draw::{Brush, SolidBrush};
+use serde::{Deserialize, Serialize};
+
+use crate::{custom_shapes::MarkerGlyph, settings_file, MotState, MARKER_PALETTE};
+
+/// Marker colors that stay distinguishable on a washed out projector.
+pub const HIGH_CONTRAST_PALETTE: [(f64, f64, f64); 4] = [
+    (1.0, 1.0, 0.0),
+    (0.0, 1.0, 1.0),
+    (1.0, 0.5, 0.0),
+    (1.0, 1.0, 1.0),
+];
+
+/// One color per valid `ScreenId`, far enough apart to tell screens apart at a glance.
+pub const SCREEN_PALETTE: [(f64, f64, f64); 7] = [
+    (0.9, 0.1, 0.1),
+    (0.1, 0.6, 0.1),
+    (0.1, 0.3, 0.9),
+    (0.9, 0.6, 0.0),
+    (0.6, 0.1, 0.8),
+    (0.0, 0.7, 0.7),
+    (0.6, 0.4, 0.2),
+];
+
+/// The defaults draw everything exactly as it was before these settings existed.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct DisplaySettings {
+    /// Added to every channel after the contrast, between -1 and 1.
+    pub brightness: f64,
+    /// Scales every channel around mid gray.
+    pub contrast: f64,
+    pub high_contrast_palette: bool,
+    /// Draw markers and the aimpoint in the color of their screen instead of the marker palette.
+    pub color_by_screen: bool,
+    /// Indexed by screen id, only edited in `display.toml`.
+    pub screen_colors: [(f64, f64, f64); 7],
+    pub nf_glyph: MarkerGlyph,
+    pub wf_glyph: MarkerGlyph,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        Self {
+            brightness: 0.,
+            contrast: 1.,
+            high_contrast_palette: false,
+            color_by_screen: false,
+            screen_colors: SCREEN_PALETTE,
+            nf_glyph: MarkerGlyph::Cross,
+            wf_glyph: MarkerGlyph::RotatedCross,
+        }
+    }
+}
+
+impl DisplaySettings {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn adjust(&self, (r, g, b): (f64, f64, f64)) -> (f64, f64, f64) {
+        let adjust = |c: f64| ((c - 0.5) * self.contrast + 0.5 + self.brightness).clamp(0., 1.);
+        (adjust(r), adjust(g), adjust(b))
+    }
+
+    pub fn brush(&self, color: (f64, f64, f64), a: f64) -> Brush {
+        let (r, g, b) = self.adjust(color);
+        Brush::Solid(SolidBrush { r, g, b, a })
+    }
+
+    pub fn palette(&self) -> &'static [(f64, f64, f64); 4] {
+        if self.high_contrast_palette {
+            &HIGH_CONTRAST_PALETTE
+        } else {
+            &MARKER_PALETTE
+        }
+    }
+
+    /// Color of `screen_id`, or None for ids that aren't a screen.
+    pub fn screen_color(&self, screen_id: u8) -> Option<(f64, f64, f64)> {
+        self.screen_colors.get(usize::from(screen_id)).copied()
+    }
+
+    /// Color of the marker with the (screen_id, mot_id) `key`, from its screen if `color_by_screen`
+    /// is set, otherwise from the marker palette.
+    pub fn marker_color(&self, state: &MotState, key: Option<(u8, u8)>) -> (f64, f64, f64) {
+        if self.color_by_screen {
+            if let Some(color) = key.and_then(|(screen_id, _)| self.screen_color(screen_id)) {
+                return color;
+            }
+        }
+        state.marker_color(key, self.palette())
+    }
+
+    /// Load the settings from `display.toml` in the config directory, or the defaults.
+    pub fn load_from_file() -> Self {
+        settings_file::load("display.toml", toml::from_str)
+    }
+
+    pub fn save_to_file(&self) {
+        settings_file::save("display.toml", self, "display settings");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{custom_shapes::MarkerGlyph, MotState};
+
+    use super::{DisplaySettings, SCREEN_PALETTE};
+
+    #[test]
+    fn test_adjust() {
+        let default = DisplaySettings::default();
+        for color in [(0.5, 0.5, 0.5), (1., 0., 0.), (0.25, 0.75, 0.1)] {
+            assert_eq!(default.adjust(color), color);
+        }
+
+        let contrast = DisplaySettings { contrast: 2., ..Default::default() };
+        assert_eq!(contrast.adjust((0.5, 0.25, 0.9)), (0.5, 0., 1.));
+
+        let brighter = DisplaySettings { brightness: 0.25, ..Default::default() };
+        assert_eq!(brighter.adjust((0.5, 0.9, 0.)), (0.75, 1., 0.25));
+    }
+
+    #[test]
+    fn test_missing_fields_default() {
+        let settings: DisplaySettings = toml::from_str("brightness = 0.1").unwrap();
+        assert_eq!(settings, DisplaySettings { brightness: 0.1, ..Default::default() });
+    }
+
+    #[test]
+    fn test_glyphs() {
+        let settings = DisplaySettings { nf_glyph: MarkerGlyph::Circle, wf_glyph: MarkerGlyph::Square, ..Default::default() };
+        let loaded: DisplaySettings = toml::from_str(&toml::to_string(&settings).unwrap()).unwrap();
+        assert_eq!(loaded, settings);
+        for glyph in MarkerGlyph::ALL {
+            assert_eq!(MarkerGlyph::from_index(glyph.index()), Some(glyph));
+        }
+        assert_eq!(MarkerGlyph::from_index(-1), None);
+    }
+
+    #[test]
+    fn test_screen_colors() {
+        let mut state = MotState::default();
+        state.assign_marker_color((2, 5));
+        let by_marker = DisplaySettings::default();
+        let by_screen = DisplaySettings { color_by_screen: true, ..Default::default() };
+        assert_eq!(by_marker.marker_color(&state, Some((2, 5))), by_marker.palette()[0]);
+        assert_eq!(by_screen.marker_color(&state, Some((2, 5))), SCREEN_PALETTE[2]);
+        // Not a screen, falls back to the marker palette
+        assert_eq!(by_screen.marker_color(&state, Some((0xff, 5))), state.marker_color(Some((0xff, 5)), by_screen.palette()));
+        assert_eq!(by_screen.screen_color(7), None);
+
+        // Every default screen color is distinct
+        for (i, a) in SCREEN_PALETTE.iter().enumerate() {
+            assert!(SCREEN_PALETTE[i + 1..].iter().all(|b| a != b));
+        }
+
+        let toml = toml::to_string(&by_screen).unwrap();
+        assert_eq!(toml::from_str::<DisplaySettings>(&toml).unwrap(), by_screen);
+    }
+}
@@ -0,0 +1,96 @@
+//! Live numeric readout of the IMU for checking sensor health and mounting orientation
+
+use std::sync::Arc;
+
+use ats_usb::packet::AccelReport;
+use iui::controls::{Window, WindowType};
+use iui::UI;
+use leptos_reactive::{create_effect, RwSignal, SignalGet, SignalSet, SignalWith};
+use nalgebra::Vector3;
+use parking_lot::Mutex;
+
+use crate::mot_runner::{AccelScale, AccelScaleCheck, MadgwickReadout, MotRunner, G};
+use crate::CloneButShorter;
+
+pub fn imu_window(ui: &UI, imu_readout: RwSignal<Option<AccelReport>>, madgwick_readout: RwSignal<Option<MadgwickReadout>>, mot_runner: Arc<Mutex<MotRunner>>) -> Window {
+    let mut window = Window::new(ui, "IMU", 10, 10, WindowType::NoMenubar);
+    window.on_closing(ui, {
+        let ui = ui.c();
+        move |win: &mut Window| {
+            win.hide(&ui);
+        }
+    });
+
+    let readout = move |f: fn(&AccelReport) -> String| {
+        move || imu_readout.with(|r| r.as_ref().map(f).unwrap_or_else(|| "-".into()))
+    };
+    let scale_tolerance = RwSignal::new((AccelScaleCheck::default().tolerance * 100.).round() as i32);
+    let scale_check = move || {
+        let check = AccelScaleCheck { tolerance: scale_tolerance.get() as f32 / 100., ..Default::default() };
+        imu_readout.with(|r| match r.as_ref().map(|r| check.check(r)) {
+            None => "-".into(),
+            Some(AccelScale::Moving) => "moving, hold still to check".into(),
+            Some(AccelScale::Ok(m)) => format!("ok ({m:.3} g)"),
+            Some(AccelScale::Off(m)) => format!("WARNING: {m:.3} g at rest, accelerometer may be mis-scaled"),
+        })
+    };
+
+    let clock_drift_readout = mot_runner.lock().clock_drift_readout;
+    let clock_drift = move || clock_drift_readout.with(|r| match r {
+        Some(r) => r.summary(),
+        None => "-".into(),
+    });
+
+    let madgwick = move |f: fn(&MadgwickReadout) -> String| {
+        move || madgwick_readout.with(|r| r.as_ref().map(f).unwrap_or_else(|| "-".into()))
+    };
+
+    crate::layout! { ui,
+        let vbox = VerticalBox(padded: true) {
+            Compact : let form = Form(padded: true) {
+                (Compact, "Accel (g)") : let accel_label = Label(readout(|r| fmt_axes(r.accel / G)))
+                (Compact, "Gyro (dps)") : let gyro_label = Label(readout(|r| fmt_axes(r.gyro.map(f32::to_degrees))))
+                (Compact, "Accel, filter frame (g)") : let accel_swizzled_label = Label(readout(|r| fmt_axes(-r.accel.xzy() / G)))
+                (Compact, "Gyro, filter frame (dps)") : let gyro_swizzled_label = Label(readout(|r| fmt_axes(-r.gyro.xzy().map(f32::to_degrees))))
+                (Compact, "Accel magnitude (g)") : let accel_magnitude_label = Label(readout(|r| format!("{:.3}", r.accel.norm() / G)))
+                (Compact, "1 g at rest") : let scale_check_label = Label(scale_check)
+                (Compact, "Tolerance (%)") : let scale_tolerance_spinbox = Spinbox(1, 100, signal: scale_tolerance)
+                (Compact, "Device clock vs host") : let clock_drift_label = Label(clock_drift)
+            }
+            Compact : let madgwick_checkbox = Checkbox("Show Madgwick filter", checked: false)
+            Compact : let madgwick_form = Form(padded: true) {
+                (Compact, "Quaternion (w x y z)") : let quat_label = Label(madgwick(|r| {
+                    let q = r.quat.quaternion();
+                    format!("{:>8.4} {:>8.4} {:>8.4} {:>8.4}", q.w, q.i, q.j, q.k)
+                }))
+                (Compact, "Rate of change (dps)") : let rate_label = Label(madgwick(|r| format!("{:.2}", r.rate)))
+                (Compact, "Sample period (ms)") : let sample_period_label = Label(madgwick(|r| format!("{:.2}", r.sample_period * 1000.)))
+            }
+        }
+    }
+    madgwick_form.hide(ui);
+    madgwick_checkbox.on_toggled(ui, {
+        let ui = ui.c();
+        let mot_runner = mot_runner.c();
+        let madgwick_form = madgwick_form.c();
+        move |checked| {
+            mot_runner.lock().madgwick_readout_enabled = checked;
+            if checked {
+                madgwick_form.c().show(&ui);
+            } else {
+                madgwick_form.c().hide(&ui);
+                madgwick_readout.set(None);
+            }
+        }
+    });
+    create_effect(move |_| {
+        mot_runner.lock().accel_scale_check.tolerance = scale_tolerance.get() as f32 / 100.;
+    });
+    window.set_child(ui, vbox);
+
+    window
+}
+
+fn fmt_axes(v: Vector3<f32>) -> String {
+    format!("{:>8.3} {:>8.3} {:>8.3}", v.x, v.y, v.z)
+}
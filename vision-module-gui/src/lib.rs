@@ -1,19 +1,42 @@
+use std::collections::HashMap;
+
 use arrayvec::ArrayVec;
-use nalgebra::{Matrix3, Matrix3x1, Point2, Rotation3};
+use nalgebra::{Isometry3, Matrix3, Matrix3x1, Point2, Rotation3};
 use ats_cv::{foveated::FoveatedAimpointState, kalman::Pva2d};
 use serde::Serialize;
 use ats_usb::packet::MotData;
 
+pub mod aimpoint_server;
+pub mod camera_model_settings;
+pub mod clock;
+pub mod clock_drift;
 pub mod config_window;
+pub mod connection_health;
+pub mod connection_settings;
 pub mod marker_config_window;
+pub mod marker_snapshot;
 pub mod layout_macro;
 pub mod mot_runner;
 pub mod run_raw_canvas;
 pub mod run_canvas;
 pub mod test_canvas;
 pub mod custom_shapes;
+pub mod devices_window;
+pub mod display_settings;
 pub mod tracking_canvas_helpers;
 pub mod plots_window;
+pub mod imu_window;
+pub mod pose_log;
+pub mod recording_settings;
+pub mod pose_override_window;
+pub mod screen_info;
+pub mod setup_assistant;
+pub mod setup_wizard;
+pub mod settings_file;
+pub mod stereo_refinement;
+pub mod stereo_window;
+pub mod target_regions;
+pub mod window_layout;
 
 pub trait CloneButShorter: Clone {
     /// Use mainly for GUI code.
@@ -24,15 +47,31 @@ pub trait CloneButShorter: Clone {
 
 impl<T: Clone> CloneButShorter for T {}
 
+/// Colors used to draw identified markers. Markers beyond the palette size cycle through it.
+pub const MARKER_PALETTE: [(f64, f64, f64); 4] = [
+    (1.0, 0.0, 0.0),
+    (0.0, 1.0, 0.0),
+    (0.0, 0.0, 1.0),
+    (1.0, 0.0, 1.0),
+];
+
 #[derive(Serialize)]
 pub struct TestFrame {
     pub fv_aimpoint_x: Option<f64>,
     pub fv_aimpoint_y: Option<f64>,
+    /// Name of the target region the aimpoint fell in
+    pub region: Option<String>,
 }
 
 pub struct MotState {
     // Coordinates between 0.0 and 1.0
     pub fv_aimpoint: Point2<f64>,
+    // Whether fv_aimpoint is live, false while tracking is lost and it's only the last position
+    pub fv_aimpoint_valid: bool,
+    // Near field points in the last combined markers report
+    pub nf_point_count: usize,
+    // Gates the pose until the marker set has settled
+    pub marker_stability: mot_runner::MarkerStability,
     pub nf_aimpoint: Point2<f64>,
     pub wf_aimpoint: Point2<f64>,
 
@@ -45,6 +84,30 @@ pub struct MotState {
     pub wf_markers: ArrayVec<Point2<f64>, 16>,
     pub wf_reproj: ArrayVec<Point2<f64>, 16>,
 
+    // (screen_id, mot_id) of each entry in nf_markers/wf_markers
+    pub nf_marker_keys: ArrayVec<Option<(u8, u8)>, 16>,
+    pub wf_marker_keys: ArrayVec<(u8, u8), 16>,
+    // Palette index for each (screen_id, mot_id) seen so far
+    pub marker_colors: HashMap<(u8, u8), usize>,
+    // Debounced pattern index for each (screen_id, mot_id) seen so far
+    pub pattern_ids: HashMap<(u8, u8), mot_runner::PatternIdDebounce>,
+    // Track id of each entry in nf_markers, stable from frame to frame
+    pub nf_track_ids: ArrayVec<Option<u32>, 16>,
+    pub nf_marker_tracks: mot_runner::MarkerTracks,
+    pub nf_track_histories: mot_runner::TrackHistories,
+    // Track id whose trajectory is drawn, picked by clicking a marker on the tracking canvas
+    pub selected_track: Option<u32>,
+
+    // Indices of a pair of markers that are too close together to be matched reliably
+    pub nf_markers_too_close: Option<(usize, usize)>,
+    pub wf_markers_too_close: Option<(usize, usize)>,
+
+    // Set while the markers don't match the configured screen info
+    pub pattern_mismatch: Option<f64>,
+
+    // Near field markers captured to compare the live ones against, in the same order as nf_markers
+    pub reference_markers: Option<ArrayVec<Option<Point2<f64>>, 16>>,
+
     pub fv_aimpoint_pva2d: Pva2d<f64>,
 
     pub screen_id: u8,
@@ -55,6 +118,13 @@ pub struct MotState {
     pub translation_mat: Matrix3x1<f64>,
 
     pub fv_state: ats_cv::foveated::FoveatedAimpointState,
+    // Last PnP pose of fv_state, screen to nf camera. The ats_cv telemetry series is shared by
+    // every runner in the process, so each keeps its own copy
+    pub pnp_solution: Option<Isometry3<f32>>,
+    // Aimpoints from the eskf pose and from pnp_solution on their own, fv_aimpoint is the eskf one
+    // unless there's a pose override
+    pub eskf_aimpoint: Option<Point2<f64>>,
+    pub pnp_aimpoint: Option<Point2<f64>>,
 
     pub fv_aimpoint_history: [Point2<f64>; 40],
     pub fv_aimpoint_history_index: usize,
@@ -64,6 +134,9 @@ impl Default for MotState {
     fn default() -> Self {
         Self {
             fv_aimpoint: Point2::new(0.0, 0.0),
+            fv_aimpoint_valid: false,
+            nf_point_count: 0,
+            marker_stability: Default::default(),
             nf_aimpoint: Point2::new(0.0, 0.0),
             wf_aimpoint: Point2::new(0.0, 0.0),
             nf_data: None,
@@ -78,11 +151,56 @@ impl Default for MotState {
             nf_markers: Default::default(),
             wf_markers: Default::default(),
             wf_reproj: Default::default(),
+            nf_marker_keys: Default::default(),
+            wf_marker_keys: Default::default(),
+            marker_colors: HashMap::new(),
+            pattern_ids: HashMap::new(),
+            nf_track_ids: Default::default(),
+            nf_marker_tracks: Default::default(),
+            nf_track_histories: Default::default(),
+            selected_track: None,
+            nf_markers_too_close: None,
+            wf_markers_too_close: None,
+            pattern_mismatch: None,
+            reference_markers: None,
             fv_aimpoint_pva2d: Pva2d::new(0.2, 1.0),
             // fv_aimpoint_pva2d: Default::default(),
             fv_state: FoveatedAimpointState::new(),
+            pnp_solution: None,
+            eskf_aimpoint: None,
+            pnp_aimpoint: None,
             fv_aimpoint_history: [Point2::new(0.0, 0.0); 40],
             fv_aimpoint_history_index: 0,
         }
     }
 }
+
+impl MotState {
+    /// Returns the palette index assigned to the marker `key`, assigning the next one in order if
+    /// the marker hasn't been seen before.
+    pub fn assign_marker_color(&mut self, key: (u8, u8)) -> usize {
+        let next = self.marker_colors.len() % MARKER_PALETTE.len();
+        *self.marker_colors.entry(key).or_insert(next)
+    }
+
+    /// The identified near field markers in pattern order, `None` for the ones that weren't matched.
+    pub fn measured_nf_markers(&self) -> ArrayVec<Option<Point2<f64>>, 16> {
+        self.nf_markers.iter().enumerate()
+            .map(|(i, &p)| self.nf_marker_keys.get(i).copied().flatten().map(|_| p))
+            .collect()
+    }
+
+    /// Takes the current near field markers as the reference the live ones are drawn against.
+    pub fn capture_reference(&mut self) {
+        self.reference_markers = Some(self.measured_nf_markers());
+    }
+
+    /// Returns the color for the marker `key` from `palette`, or magenta if it hasn't been
+    /// assigned one.
+    pub fn marker_color(&self, key: Option<(u8, u8)>, palette: &[(f64, f64, f64); 4]) -> (f64, f64, f64) {
+        match key.and_then(|k| self.marker_colors.get(&k)) {
+            Some(&i) => palette[i],
+            None => (1.0, 0.0, 1.0),
+        }
+    }
+}
@@ -5,6 +5,7 @@ mod calibrate;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::Read;
+use std::path::Path;
 use std::sync::Arc;
 use directories::ProjectDirs;
 use nalgebra::Matrix2x4;
@@ -12,9 +13,9 @@ use tracing::error;
 
 use ats_usb::{packet::MarkerPattern};
 use crate::CloneButShorter;
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use iui::{
-    controls::Form,
+    controls::{Checkbox, FileTypeFilter, Form, LayoutStrategy, VerticalBox},
     prelude::{Window, WindowType},
     UI,
 };
@@ -46,6 +47,8 @@ pub fn marker_config_window(
                 Compact : let save_button = Button("Save")
                 Compact : let reload_button = Button("Reload")
                 Compact : let load_defaults_button = Button("Load defaults")
+                Compact : let export_button = Button("Export...")
+                Compact : let import_button = Button("Import...")
             }
         }
     }
@@ -56,8 +59,10 @@ pub fn marker_config_window(
         "Calibrate",
         calibrate::create(ui, marker_offset_calibrating, mot_runner.c(), marker_settings, config_win.c()),
     );
+    tab_group.append(&ui, "Enabled Markers", enabled_markers(ui, mot_runner.c()));
     tab_group.set_margined(&ui, 0, true);
     tab_group.set_margined(&ui, 1, true);
+    tab_group.set_margined(&ui, 2, true);
 
     config_win.set_child(&ui, vbox);
 
@@ -87,6 +92,40 @@ pub fn marker_config_window(
     //     apply_button_on_click();
     // });
 
+    export_button.on_clicked(&ui, {
+        let ui = ui.c();
+        let config_win = config_win.c();
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let Some(path) = config_win.save_file_with_filter(&ui, &[FileTypeFilter::new("json").extension("json")]) else {
+                return;
+            };
+            // What's in the form, the views it doesn't show yet come from the runner
+            let mut markers_settings = mot_runner.lock().markers_settings.clone();
+            marker_settings.apply(&mut markers_settings);
+            if let Err(e) = markers_settings.save_to_path(&path) {
+                config_win.modal_err(&ui, "Failed to export marker settings", &format!("{e:#}"));
+            }
+        }
+    });
+    import_button.on_clicked(&ui, {
+        let ui = ui.c();
+        let config_win = config_win.c();
+        let mot_runner = mot_runner.c();
+        move |_| {
+            let Some(path) = config_win.open_file(&ui) else {
+                return;
+            };
+            match MarkersSettings::load_from_path(&path) {
+                Ok(markers_settings) => {
+                    marker_settings.set_view(&markers_settings.views[0]);
+                    mot_runner.lock().markers_settings = markers_settings;
+                }
+                Err(e) => config_win.modal_err(&ui, "Failed to import marker settings", &format!("{e:#}")),
+            }
+        }
+    });
+
     reload_button.on_clicked(&ui, {
         move |_| {
             ui_ctx.spawn(async move {
@@ -100,20 +139,46 @@ pub fn marker_config_window(
     config_win
 }
 
+/// One checkbox per pattern index. Takes effect right away, a disabled marker is left out of
+/// matching until it's enabled again.
+fn enabled_markers(ui: &UI, mot_runner: Arc<Mutex<MotRunner>>) -> VerticalBox {
+    crate::layout! { &ui,
+        let vbox = VerticalBox(padded: true) {
+            Compact : let row_0 = HorizontalBox(padded: true) {}
+            Compact : let row_1 = HorizontalBox(padded: true) {}
+        }
+    }
+    let mask = mot_runner.lock().marker_mask;
+    for pattern_ix in 0..16 {
+        let mut checkbox = Checkbox::new(ui, &pattern_ix.to_string());
+        checkbox.set_checked(ui, mask.contains(pattern_ix));
+        checkbox.on_toggled(ui, {
+            let mot_runner = mot_runner.c();
+            move |checked| mot_runner.lock().marker_mask.set(pattern_ix, checked)
+        });
+        if pattern_ix < 8 {
+            row_0.append(ui, checkbox, LayoutStrategy::Compact);
+        } else {
+            row_1.append(ui, checkbox, LayoutStrategy::Compact);
+        }
+    }
+    vbox
+}
+
 // -2047,-2047 represents the top left corner of the view
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Position {
     pub x: i32,
     pub y: i32,
 }
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Marker {
     pub position: Position,
 }
 
 // the markers are positioned in a cross pattern around the center of the view
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct View {
     pub marker_top: Marker,
     pub marker_right: Marker,
@@ -130,7 +195,7 @@ struct MarkersSettingsForm {
     marker_left: (RwSignal<i32>, RwSignal<i32>),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct MarkersSettings {
     pub views: Vec<View>,
 }
@@ -181,15 +246,7 @@ impl MarkersSettingsForm {
                     Ok(markers_settings) => {
                         // todo don't only use the first one
                         if markers_settings.views.len() > 0 {
-                            let view = &markers_settings.views[0];
-                            self.marker_top.0.set(view.marker_top.position.x);
-                            self.marker_top.1.set(view.marker_top.position.y);
-                            self.marker_right.0.set(view.marker_right.position.x);
-                            self.marker_right.1.set(view.marker_right.position.y);
-                            self.marker_bottom.0.set(view.marker_bottom.position.x);
-                            self.marker_bottom.1.set(view.marker_bottom.position.y);
-                            self.marker_left.0.set(view.marker_left.position.x);
-                            self.marker_left.1.set(view.marker_left.position.y);
+                            self.set_view(&markers_settings.views[0]);
                         }
                     },
                     Err(e) => error!("{}", e),
@@ -208,6 +265,17 @@ impl MarkersSettingsForm {
         Ok(())
     }
 
+    fn set_view(&self, view: &View) {
+        self.marker_top.0.set(view.marker_top.position.x);
+        self.marker_top.1.set(view.marker_top.position.y);
+        self.marker_right.0.set(view.marker_right.position.x);
+        self.marker_right.1.set(view.marker_right.position.y);
+        self.marker_bottom.0.set(view.marker_bottom.position.x);
+        self.marker_bottom.1.set(view.marker_bottom.position.y);
+        self.marker_left.0.set(view.marker_left.position.x);
+        self.marker_left.1.set(view.marker_left.position.y);
+    }
+
     fn _validate(&self, _errors: &mut Vec<String>) {
         macro_rules! _validators {
             ($($display:literal $reg:ident : $ty:ty $({ $( $check:expr ),* $(,)? })? ),* $(,)?) => {
@@ -285,3 +353,89 @@ impl Default for MarkersSettings {
         }
     }
 }
+
+/// Largest marker coordinate, the edges of the view.
+const MAX_POSITION: i32 = 2047;
+
+impl MarkersSettings {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Parses and validates an export from `to_json`.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let markers_settings: Self = serde_json::from_str(json)?;
+        let errors = markers_settings.validate();
+        if !errors.is_empty() {
+            bail!("{}", errors.join(", "));
+        }
+        Ok(markers_settings)
+    }
+
+    pub fn save_to_path(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_json()?).with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    pub fn load_from_path(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+        Self::from_json(&json).with_context(|| format!("invalid marker settings {}", path.display()))
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if self.views.is_empty() {
+            errors.push("no views".into());
+        }
+        for (i, view) in self.views.iter().enumerate() {
+            let markers = [
+                ("top", view.marker_top),
+                ("right", view.marker_right),
+                ("bottom", view.marker_bottom),
+                ("left", view.marker_left),
+            ];
+            for (name, marker) in markers {
+                let Position { x, y } = marker.position;
+                if !(-MAX_POSITION..=MAX_POSITION).contains(&x) || !(-MAX_POSITION..=MAX_POSITION).contains(&y) {
+                    errors.push(format!("view {i} {name} marker ({x}, {y}) is outside the view"));
+                }
+            }
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MarkersSettings, Position};
+
+    #[test]
+    fn test_markers_settings_round_trip() {
+        let mut markers_settings = MarkersSettings::default();
+        markers_settings.views[0].marker_left.position = Position { x: -1800, y: 35 };
+        let mut second = markers_settings.views[0].clone();
+        second.marker_top.position = Position { x: 12, y: -2000 };
+        markers_settings.views.push(second);
+
+        let json = markers_settings.to_json().unwrap();
+        assert_eq!(MarkersSettings::from_json(&json).unwrap(), markers_settings);
+
+        let mut path = std::env::temp_dir();
+        path.push(format!("markers_settings_{}.json", std::process::id()));
+        markers_settings.save_to_path(&path).unwrap();
+        let loaded = MarkersSettings::load_from_path(&path);
+        _ = std::fs::remove_file(&path);
+        assert_eq!(loaded.unwrap(), markers_settings);
+    }
+
+    #[test]
+    fn test_markers_settings_import_validation() {
+        assert!(MarkersSettings::from_json(r#"{"views": []}"#).is_err());
+        assert!(MarkersSettings::from_json("not json").is_err());
+
+        let mut markers_settings = MarkersSettings::default();
+        markers_settings.views[0].marker_right.position.x = 5000;
+        let json = markers_settings.to_json().unwrap();
+        let err = MarkersSettings::from_json(&json).unwrap_err().to_string();
+        assert!(err.contains("right"), "{err}");
+    }
+}
@@ -0,0 +1,166 @@
+//! The current frame's marker to pattern assignment as JSON, to attach to a report about a frame
+//! that was matched wrong. Carries the pose and config along so it can be looked at on its own.
+
+use std::path::Path;
+
+use anyhow::Result;
+use ats_usb::packet::GeneralConfig;
+use nalgebra::{Isometry3, Point2, Point3};
+use opencv_ros_camera::RosOpenCvIntrinsics;
+use serde::Serialize;
+
+use crate::pose_log::PoseRecord;
+use crate::MotState;
+
+/// One point of either sensor and what it was matched to.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct MarkerAssignment {
+    pub screen_id: u8,
+    pub mot_id: u8,
+    /// Index in the marker pattern `match3`/`identify_markers2` put the point at this frame, `None`
+    /// if it wasn't matched.
+    pub pattern_index: Option<usize>,
+    /// The debounced pattern id, nf only.
+    pub committed_pattern_id: Option<usize>,
+    /// Undistorted, 0 to 1 across the sensor.
+    pub x: f64,
+    pub y: f64,
+}
+
+/// fx, fy, cx, cy
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct CameraSnapshot {
+    pub fx: f32,
+    pub fy: f32,
+    pub cx: f32,
+    pub cy: f32,
+}
+
+impl From<&RosOpenCvIntrinsics<f32>> for CameraSnapshot {
+    fn from(intrinsics: &RosOpenCvIntrinsics<f32>) -> Self {
+        let p = &intrinsics.p;
+        Self { fx: p.m11, fy: p.m22, cx: p.m13, cy: p.m23 }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ConfigSnapshot {
+    pub camera_model_nf: CameraSnapshot,
+    pub camera_model_wf: CameraSnapshot,
+    pub stereo_translation: [f32; 3],
+    /// Quaternion as i, j, k, w.
+    pub stereo_rotation: [f32; 4],
+    /// From the loaded screen info, in pattern order.
+    pub marker_points: Vec<[f64; 3]>,
+    pub uuid: [u8; 6],
+}
+
+impl ConfigSnapshot {
+    pub fn new(config: &GeneralConfig, marker_points: &[Point3<f64>]) -> Self {
+        let Isometry3 { translation, rotation } = config.stereo_iso;
+        Self {
+            camera_model_nf: (&config.camera_model_nf).into(),
+            camera_model_wf: (&config.camera_model_wf).into(),
+            stereo_translation: translation.vector.into(),
+            stereo_rotation: [rotation.i, rotation.j, rotation.k, rotation.w],
+            marker_points: marker_points.iter().map(|p| p.coords.into()).collect(),
+            uuid: config.uuid,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct MarkerSnapshot {
+    pub screen_id: u8,
+    pub nf: Vec<MarkerAssignment>,
+    pub wf: Vec<MarkerAssignment>,
+    pub pose: PoseRecord,
+    pub config: ConfigSnapshot,
+}
+
+// todo don't use hardcoded 4095x4095 res assumption
+fn normalized(p: Point2<f64>) -> (f64, f64) {
+    (p.x / 4095., p.y / 4095.)
+}
+
+impl MarkerSnapshot {
+    pub fn from_state(timestamp_ms: u64, state: &MotState, config: &GeneralConfig, marker_points: &[Point3<f64>]) -> Self {
+        let mut nf = vec![];
+        for (i, (&p, &key)) in state.nf_markers.iter().zip(&state.nf_marker_keys).enumerate() {
+            // Pattern slots nothing was matched to
+            let Some((screen_id, mot_id)) = key else { continue };
+            let committed_pattern_id = state.pattern_ids.get(&(screen_id, mot_id)).and_then(|d| d.committed());
+            let (x, y) = normalized(p);
+            nf.push(MarkerAssignment { screen_id, mot_id, pattern_index: Some(i), committed_pattern_id, x, y });
+        }
+        for &(screen_id, mot_id, p) in &state.nf_points {
+            let committed_pattern_id = state.pattern_ids.get(&(screen_id, mot_id)).and_then(|d| d.committed());
+            let (x, y) = normalized(p);
+            nf.push(MarkerAssignment { screen_id, mot_id, pattern_index: None, committed_pattern_id, x, y });
+        }
+
+        let mut wf = vec![];
+        for (i, (&p, &(screen_id, mot_id))) in state.wf_markers.iter().zip(&state.wf_marker_keys).enumerate() {
+            let (x, y) = normalized(p);
+            wf.push(MarkerAssignment { screen_id, mot_id, pattern_index: Some(i), committed_pattern_id: None, x, y });
+        }
+        for &(screen_id, mot_id, p) in &state.wf_points {
+            let (x, y) = normalized(p);
+            wf.push(MarkerAssignment { screen_id, mot_id, pattern_index: None, committed_pattern_id: None, x, y });
+        }
+
+        Self {
+            screen_id: state.screen_id,
+            nf,
+            wf,
+            pose: PoseRecord::from_state(timestamp_ms, state),
+            config: ConfigSnapshot::new(config, marker_points),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn save_to_path(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_json()?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ats_usb::packet::GeneralConfig;
+    use nalgebra::{Point2, Point3};
+
+    use crate::MotState;
+
+    use super::MarkerSnapshot;
+
+    #[test]
+    fn test_marker_snapshot() {
+        let mut state = MotState::default();
+        state.nf_markers = [Point2::new(2047.5, 4095.), Point2::new(-9999., -9999.)].into_iter().collect();
+        state.nf_marker_keys = [Some((1, 4)), None].into_iter().collect();
+        for _ in 0..10 {
+            state.pattern_ids.entry((1, 4)).or_default().update(Some(0), 3);
+        }
+        state.nf_points = [(1, 9, Point2::new(0., 4095.))].into_iter().collect();
+        state.wf_markers = [Point2::new(2047.5, 2047.5)].into_iter().collect();
+        state.wf_marker_keys = [(1, 2)].into_iter().collect();
+        let marker_points = [Point3::new(0.5, 1., 0.)];
+
+        let snapshot = MarkerSnapshot::from_state(1234, &state, &GeneralConfig::default(), &marker_points);
+        let json: serde_json::Value = serde_json::from_str(&snapshot.to_json().unwrap()).unwrap();
+        assert_eq!(json["nf"].as_array().unwrap().len(), 2);
+        assert_eq!(json["nf"][0], serde_json::json!({
+            "screen_id": 1, "mot_id": 4, "pattern_index": 0, "committed_pattern_id": 0, "x": 0.5, "y": 1.0,
+        }));
+        assert_eq!(json["nf"][1]["mot_id"], 9);
+        assert!(json["nf"][1]["pattern_index"].is_null());
+        assert_eq!(json["wf"][0]["x"], 0.5);
+        assert_eq!(json["pose"]["timestamp_ms"], 1234);
+        assert_eq!(json["config"]["marker_points"], serde_json::json!([[0.5, 1.0, 0.0]]));
+        assert_eq!(json["config"]["stereo_rotation"], serde_json::json!([0.0, 0.0, 0.0, 1.0]));
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use ahrs::Ahrs;
 use ats_cv::calculate_rotational_offset;
@@ -5,19 +6,31 @@ use ats_cv::foveated::{identify_markers2, match3};
 use ats_cv::kalman::Pva2d;
 use opencv_ros_camera::RosOpenCvIntrinsics;
 use parking_lot::Mutex;
-use std::time::{Duration, UNIX_EPOCH};
+use std::time::Duration;
 use arrayvec::ArrayVec;
 use iui::concurrent::Context;
 use leptos_reactive::{RwSignal, SignalGetUntracked};
-use nalgebra::{Const, Isometry3, Matrix3, Point2, Rotation3, Scalar, Translation3, UnitVector3, Vector2, Vector3};
+use nalgebra::{Const, Isometry3, Matrix3, Point2, Point3, Rotation3, Scalar, Translation3, UnitQuaternion, UnitVector3, Vector2, Vector3};
 use sqpnp::types::{SQPSolution, SolverParameters};
 use tokio::time::{sleep, Instant};
 use tokio_stream::StreamExt;
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 use crate::{CloneButShorter, TestFrame, MotState};
+use crate::aimpoint_server::{AimpointMessage, AimpointServer};
 use crate::marker_config_window::MarkersSettings;
+use crate::clock::Clock;
+use crate::clock_drift::{ClockDrift, ClockDriftReport};
+use crate::connection_health::{ConnectionHealth, HealthThresholds, PacketSequence};
+use crate::display_settings::DisplaySettings;
+use crate::pose_log::{PoseLog, PoseRecord};
+use crate::screen_info::ScreenInfo;
+use crate::stereo_refinement::{StereoRefinement, StereoSample};
+use crate::setup_assistant::{MarkerCountCheck, MarkerCountStatus};
+use crate::target_regions::TargetRegions;
+use crate::test_canvas::AimpointLostBehavior;
+use crate::tracking_canvas_helpers::{CanvasMirror, LabelVerbosity, Overlay};
 use ats_usb::device::UsbDevice;
-use ats_usb::packet::{CombinedMarkersReport, GeneralConfig, MarkerPattern, MotData, Packet};
+use ats_usb::packet::{AccelReport, CameraModelType, CombinedMarkersReport, GeneralConfig, GeneralWriteConfig, ImpactReport, MarkerPattern, MotData, Packet, PacketData, PacketType, ScreenId};
 
 pub fn transform_aimpoint_to_identity(center_aim: Point2<f64>, p1: Point2<f64>, p2: Point2<f64>, p3: Point2<f64>, p4: Point2<f64>) -> Option<Point2<f64>> {
     ats_cv::transform_aim_point(center_aim, p1, p2, p3, p4,
@@ -25,19 +38,67 @@ pub fn transform_aimpoint_to_identity(center_aim: Point2<f64>, p1: Point2<f64>,
                         Point2::new(0.5, 0.), Point2::new(1., 0.5))
 }
 
-pub fn my_pnp(projections: &[Vector2<f64>]) -> Option<SQPSolution> {
-    let _3dpoints = [
-        Vector3::new((0.35 - 0.5) * 16./9., -0.5, 0.),
-        Vector3::new((0.65 - 0.5) * 16./9., -0.5, 0.),
-        Vector3::new((0.65 - 0.5) * 16./9., 0.5, 0.),
-        Vector3::new((0.35 - 0.5) * 16./9., 0.5, 0.),
-    ];
-    let solver = sqpnp::PnpSolver::new(&_3dpoints, &projections, None, SolverParameters::default());
+/// How `my_pnp` picks a pose when the solver returns more than one, which happens when the
+/// markers are seen nearly head on and the pose can flip.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PnpDisambiguation {
+    /// Whatever the solver returned first.
+    #[default]
+    First,
+    /// The solution closest to `prior`, usually the eskf prediction. Falls back to `First`
+    /// without a prior.
+    ClosestToPrior,
+    /// The solution that reprojects the screen points closest to the projections.
+    LowestReprojectionError,
+}
+
+/// The camera to screen pose of an sqpnp solution.
+fn pnp_solution_pose(solution: &SQPSolution) -> Isometry3<f64> {
+    let r_hat = Rotation3::from_matrix_unchecked(solution.r_hat.reshape_generic(Const::<3>, Const::<3>).transpose());
+    Isometry3::from_parts(Translation3::from(solution.t), UnitQuaternion::from_rotation_matrix(&r_hat))
+}
+
+/// Sum of squared differences between `projections` and `points` seen from `pose`.
+fn pnp_reprojection_error(pose: &Isometry3<f64>, points: &[Vector3<f64>], projections: &[Vector2<f64>]) -> f64 {
+    points.iter().zip(projections).map(|(p, proj)| {
+        let p = pose * Point3::from(*p);
+        (p.coords.xy() / p.z - proj).norm_squared()
+    }).sum()
+}
+
+/// Rotation angle plus translation distance, the screen is 1 high so the two are about comparable.
+fn pose_distance(a: &Isometry3<f64>, b: &Isometry3<f64>) -> f64 {
+    a.rotation.angle_to(&b.rotation) + (a.translation.vector - b.translation.vector).norm()
+}
+
+/// Index of the pose in `candidates` to use, see `PnpDisambiguation`.
+pub fn choose_pnp_solution(candidates: &[Isometry3<f64>], points: &[Vector3<f64>], projections: &[Vector2<f64>], prior: Option<&Isometry3<f64>>, mode: PnpDisambiguation) -> usize {
+    let argmin = |cost: &dyn Fn(&Isometry3<f64>) -> f64| {
+        candidates.iter().map(cost).enumerate().min_by(|a, b| a.1.total_cmp(&b.1)).map_or(0, |(i, _)| i)
+    };
+    match (mode, prior) {
+        (PnpDisambiguation::ClosestToPrior, Some(prior)) => argmin(&|pose| pose_distance(pose, prior)),
+        (PnpDisambiguation::LowestReprojectionError, _) => argmin(&|pose| pnp_reprojection_error(pose, points, projections)),
+        _ => 0,
+    }
+}
+
+/// Solve for the screen to camera pose from normalized `projections` of `points`. The points
+/// don't need to be coplanar, each one is used at its own depth.
+pub fn my_pnp(points: &[Vector3<f64>], projections: &[Vector2<f64>], prior: Option<&Isometry3<f64>>, mode: PnpDisambiguation) -> Option<SQPSolution> {
+    let solver = sqpnp::PnpSolver::new(points, projections, None, SolverParameters::default());
     if let Some(mut solver) = solver {
         solver.solve();
         debug!("pnp found {} solutions", solver.number_of_solutions());
-        if solver.number_of_solutions() >= 1 {
-            return Some(solver.solution_ptr(0).unwrap().clone());
+        let solutions: Vec<_> = (0..solver.number_of_solutions()).filter_map(|i| solver.solution_ptr(i)).collect();
+        if solutions.len() > 1 {
+            let candidates: Vec<_> = solutions.iter().map(|s| pnp_solution_pose(s)).collect();
+            let chosen = choose_pnp_solution(&candidates, points, projections, prior, mode);
+            debug!("pnp is ambiguous, {} solutions, using {chosen} by {mode:?}", solutions.len());
+            return Some(solutions[chosen].clone());
+        }
+        if let Some(solution) = solutions.first() {
+            return Some((*solution).clone());
         }
     } else {
         info!("pnp solver failed");
@@ -45,6 +106,12 @@ pub fn my_pnp(projections: &[Vector2<f64>]) -> Option<SQPSolution> {
     None
 }
 
+/// `my_pnp` with the loaded screen info's marker points, as the screen to camera pose.
+pub fn marker_pose(marker_points: &[Point3<f64>], projections: &[Vector2<f64>], prior: Option<&Isometry3<f64>>, mode: PnpDisambiguation) -> Option<Isometry3<f64>> {
+    let points: Vec<_> = marker_points.iter().map(|p| p.coords).collect();
+    my_pnp(&points, projections, prior, mode).map(|solution| pnp_solution_pose(&solution))
+}
+
 /// Given 4 points in the following shape
 ///
 /// ```
@@ -97,19 +164,635 @@ pub fn sort_points<T: Scalar + PartialOrd>(a: &mut [Point2<T>], pattern: MarkerP
     }
 }
 
+/// How close a set of 4 chosen markers is to forming a rectangle.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RectangleQuality {
+    /// Largest deviation of a corner angle from 90°, in degrees.
+    pub max_angle_error: f64,
+    /// Smallest ratio of shorter to longer side over both pairs of opposite sides. 1.0 is perfect.
+    pub side_ratio: f64,
+}
+
+/// Measure how rectangular the quadrilateral formed by `points` is. The points can be in any
+/// order, they are sorted around their centroid first.
+pub fn rectangle_quality(points: &[Point2<f64>; 4]) -> RectangleQuality {
+    let centroid = points.iter().fold(Vector2::zeros(), |acc, p| acc + p.coords) / 4.;
+    let mut corners = *points;
+    corners.sort_by(|a, b| {
+        let a = f64::atan2(a.y - centroid.y, a.x - centroid.x);
+        let b = f64::atan2(b.y - centroid.y, b.x - centroid.x);
+        a.total_cmp(&b)
+    });
+
+    let mut max_angle_error = 0f64;
+    let mut sides = [0.; 4];
+    for i in 0..4 {
+        let p = corners[i];
+        let prev = corners[(i + 3) % 4];
+        let next = corners[(i + 1) % 4];
+        let angle = (prev - p).angle(&(next - p)).to_degrees();
+        max_angle_error = max_angle_error.max((angle - 90.).abs());
+        sides[i] = (next - p).norm();
+    }
+    let ratio = |a: f64, b: f64| a.min(b) / a.max(b);
+    RectangleQuality {
+        max_angle_error,
+        side_ratio: ratio(sides[0], sides[2]).min(ratio(sides[1], sides[3])),
+    }
+}
+
+/// Arm/disarm state for impact recording, so impacts during setup don't end up in the dataset.
+#[derive(Clone, Debug, Default)]
+pub struct ImpactArming {
+    armed: bool,
+    shots: u32,
+    last_activity: Option<std::time::Instant>,
+    /// Automatically disarm after recording this many impacts.
+    pub max_shots: Option<u32>,
+    /// Automatically disarm after this long without recording an impact.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl ImpactArming {
+    pub fn arm(&mut self, now: std::time::Instant) {
+        self.armed = true;
+        self.shots = 0;
+        self.last_activity = Some(now);
+    }
+
+    pub fn disarm(&mut self) {
+        self.armed = false;
+    }
+
+    pub fn is_armed(&self, now: std::time::Instant) -> bool {
+        if !self.armed {
+            return false;
+        }
+        match (self.idle_timeout, self.last_activity) {
+            (Some(timeout), Some(last)) => now.duration_since(last) < timeout,
+            _ => true,
+        }
+    }
+
+    /// Call on every impact. Returns whether the impact should be recorded, it isn't counted
+    /// until `on_recorded`.
+    pub fn on_impact(&mut self, now: std::time::Instant) -> bool {
+        if !self.is_armed(now) {
+            self.armed = false;
+            return false;
+        }
+        true
+    }
+
+    /// Call once an impact `on_impact` allowed has actually been recorded.
+    pub fn on_recorded(&mut self, now: std::time::Instant) {
+        self.shots += 1;
+        self.last_activity = Some(now);
+        if self.max_shots.is_some_and(|max| self.shots >= max) {
+            self.armed = false;
+        }
+    }
+}
+
+/// Default `ImpactDebounce::window`, shorter than the time between two shots of anything that
+/// fires fast.
+pub const DEFAULT_IMPACT_DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// One physical impact can ring into several `ImpactReport`s. Drops the ones that come within
+/// `window` of the last accepted impact. The window doesn't extend on dropped impacts, so shots
+/// further apart than it always count.
+///
+/// Goes by the device timestamp rather than when the host got the report, so reports that are
+/// batched or delayed on the way don't merge or split impacts.
+#[derive(Clone, Debug)]
+pub struct ImpactDebounce {
+    pub window: Duration,
+    /// Device timestamp in µs.
+    last: Option<u32>,
+}
+
+impl Default for ImpactDebounce {
+    fn default() -> Self {
+        Self { window: DEFAULT_IMPACT_DEBOUNCE, last: None }
+    }
+}
+
+impl ImpactDebounce {
+    /// Call on every impact with its `ImpactReport::timestamp`. Returns whether it's a new impact
+    /// rather than a bounce of the last one.
+    pub fn accept(&mut self, timestamp: u32) -> bool {
+        // The timestamp wraps, a device clock that went back counts as far apart
+        let within = |last: u32| u128::from(timestamp.wrapping_sub(last)) < self.window.as_micros();
+        if self.last.is_some_and(within) {
+            return false;
+        }
+        self.last = Some(timestamp);
+        true
+    }
+}
+
+/// Standard gravity, the accel in `AccelReport` is in m/s^2.
+pub const G: f32 = 9.81;
+
+/// How often `accel_stream` updates the IMU window's readout signal.
+pub const IMU_READOUT_PERIOD: Duration = Duration::from_millis(100);
+
+/// A healthy accelerometer reads 1 g while the device is still, anything else means it's
+/// mis-scaled or badly calibrated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccelScaleCheck {
+    /// The device counts as still below this angular rate, in rad/s.
+    pub max_gyro: f32,
+    /// Allowed deviation of the magnitude from 1 g, as a fraction of 1 g.
+    pub tolerance: f32,
+}
+
+impl Default for AccelScaleCheck {
+    fn default() -> Self {
+        Self { max_gyro: 2f32.to_radians(), tolerance: 0.05 }
+    }
+}
+
+/// Result of `AccelScaleCheck::check`, magnitudes are in g.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccelScale {
+    /// Can't tell while the device is rotating.
+    Moving,
+    Ok(f32),
+    Off(f32),
+}
+
+impl AccelScaleCheck {
+    pub fn check(&self, report: &AccelReport) -> AccelScale {
+        if report.gyro.norm() > self.max_gyro {
+            return AccelScale::Moving;
+        }
+        let magnitude = report.accel.norm() / G;
+        if (magnitude - 1.).abs() <= self.tolerance {
+            AccelScale::Ok(magnitude)
+        } else {
+            AccelScale::Off(magnitude)
+        }
+    }
+}
+
+/// Madgwick filter internals, updated with the IMU readout while
+/// `MotRunner::madgwick_readout_enabled` is set.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MadgwickReadout {
+    pub quat: UnitQuaternion<f32>,
+    /// How fast the estimate is rotating in deg/s. Near 0 at rest once the filter has settled.
+    pub rate: f32,
+    /// Seconds, set from the IMU timestamps.
+    pub sample_period: f32,
+}
+
+/// The packet types that get recorded while `record_packets` is set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecordMask(u32);
+
+impl Default for RecordMask {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl RecordMask {
+    pub const ALL: Self = Self(u32::MAX);
+    pub const NONE: Self = Self(0);
+
+    pub fn contains(self, ty: PacketType) -> bool {
+        self.0 & (1 << ty as u32) != 0
+    }
+
+    pub fn set(&mut self, ty: PacketType, record: bool) {
+        if record {
+            self.0 |= 1 << ty as u32;
+        } else {
+            self.0 &= !(1 << ty as u32);
+        }
+    }
+}
+
+/// Timestamps and pushes `packet` onto the recording, unless its type is masked out.
+fn record_packet(packets: &Mutex<Vec<(u128, PacketData)>>, mask: RecordMask, timestamp: u128, packet: PacketData) {
+    if mask.contains(packet.ty()) {
+        packets.lock().push((timestamp, packet));
+    }
+}
+
+/// Feeds `packet` to the recording and to the impact capture, whichever are running. Captures are
+/// written from the impact path, see `flush_impact_capture`.
+fn record(runner: &mut MotRunner, packet: PacketData) {
+    let timestamp = runner.clock.unix_millis();
+    if let Some(capture) = &mut runner.impact_capture {
+        if runner.record_mask.contains(packet.ty()) {
+            capture.push(timestamp, packet.clone());
+        }
+    }
+    if runner.record_packets {
+        record_packet(&runner.packets, runner.record_mask, timestamp, packet);
+    }
+}
+
+/// Pre-trigger capture. Keeps a rolling buffer of the last `pre_roll` of packets and, once
+/// triggered by an impact, everything after it until the capture is taken with `finish`.
+pub struct ImpactCapture {
+    pub pre_roll: Duration,
+    pub post_roll: Duration,
+    /// Captures are written next to this path, with the impact timestamp appended to the name.
+    pub path: std::path::PathBuf,
+    buffer: std::collections::VecDeque<(u128, PacketData)>,
+    trigger: Option<u128>,
+}
+
+impl ImpactCapture {
+    pub fn new(path: std::path::PathBuf, pre_roll: Duration, post_roll: Duration) -> Self {
+        Self { pre_roll, post_roll, path, buffer: Default::default(), trigger: None }
+    }
+
+    /// `timestamp` is in milliseconds, same as the recording.
+    pub fn push(&mut self, timestamp: u128, packet: PacketData) {
+        self.buffer.push_back((timestamp, packet));
+        if self.trigger.is_none() {
+            self.drop_before(timestamp);
+        }
+    }
+
+    /// Starts the post-roll, returns whether a new capture was started. Impacts during the
+    /// post-roll of another one are part of its capture.
+    pub fn trigger(&mut self, timestamp: u128) -> bool {
+        if self.trigger.is_some() {
+            return false;
+        }
+        self.drop_before(timestamp);
+        self.trigger = Some(timestamp);
+        true
+    }
+
+    /// Takes the packets of the capture started by `trigger`, up to the end of its post-roll.
+    /// Packets after that are kept as the pre-roll of the next capture. `None` if that capture
+    /// isn't running anymore.
+    pub fn finish(&mut self, trigger: u128) -> Option<Vec<(u128, PacketData)>> {
+        if self.trigger != Some(trigger) {
+            return None;
+        }
+        self.trigger = None;
+        let end = trigger + self.post_roll.as_millis();
+        let len = self.buffer.iter().position(|&(t, _)| t > end).unwrap_or(self.buffer.len());
+        Some(self.buffer.drain(..len).collect())
+    }
+
+    pub fn path_for(&self, trigger: u128) -> std::path::PathBuf {
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        self.path.with_file_name(format!("{stem}-{trigger}.bin"))
+    }
+
+    fn drop_before(&mut self, timestamp: u128) {
+        let pre_roll = self.pre_roll.as_millis();
+        while self.buffer.front().is_some_and(|&(t, _)| t + pre_roll < timestamp) {
+            self.buffer.pop_front();
+        }
+    }
+}
+
+/// Aimpoints of recently recorded datapoints, drawn as shot markers on the test canvas.
+#[derive(Clone, Debug)]
+pub struct ShotMarkers {
+    shots: Vec<(std::time::Instant, Point2<f64>)>,
+    pub enabled: bool,
+    /// How long a shot stays on the canvas, `None` to keep it until cleared.
+    pub persistence: Option<Duration>,
+}
+
+impl Default for ShotMarkers {
+    fn default() -> Self {
+        Self { shots: vec![], enabled: true, persistence: None }
+    }
+}
+
+impl ShotMarkers {
+    pub fn push(&mut self, now: std::time::Instant, aimpoint: Point2<f64>) {
+        if let Some(persistence) = self.persistence {
+            self.shots.retain(|&(t, _)| now.duration_since(t) < persistence);
+        }
+        self.shots.push((now, aimpoint));
+    }
+
+    pub fn clear(&mut self) {
+        self.shots.clear();
+    }
+
+    /// Shots still shown at `now`, oldest first.
+    pub fn visible(&self, now: std::time::Instant) -> impl Iterator<Item = Point2<f64>> + '_ {
+        self.shots.iter()
+            .filter(move |&&(t, _)| self.enabled && self.persistence.map_or(true, |p| now.duration_since(t) < p))
+            .map(|&(_, p)| p)
+    }
+}
+
 pub struct MotRunner {
     pub state: MotState,
     pub device: Option<UsbDevice>,
     pub markers_settings: MarkersSettings,
     pub general_config: GeneralConfig,
-    pub record_impact: bool,
+    pub impact_arming: ImpactArming,
+    pub impact_debounce: ImpactDebounce,
+    pub shot_markers: ShotMarkers,
     pub record_packets: bool,
+    pub record_mask: RecordMask,
     pub datapoints: Arc<Mutex<Vec<crate::TestFrame>>>,
     pub packets: Arc<Mutex<Vec<(u128, ats_usb::packet::PacketData)>>>,
     pub ui_update: RwSignal<()>,
-    pub ui_ctx: Context,
+    /// Latest accel report, updated at `IMU_READOUT_PERIOD` for the IMU readout window.
+    pub imu_readout: RwSignal<Option<ats_usb::packet::AccelReport>>,
+    /// Updated along with `imu_readout` while `madgwick_readout_enabled` is set. Debug only.
+    pub madgwick_readout: RwSignal<Option<MadgwickReadout>>,
+    pub madgwick_readout_enabled: bool,
+    /// Detected vs expected marker counts for the marker setup assistant, only set when they change.
+    pub marker_count: RwSignal<Option<MarkerCountStatus>>,
+    pub marker_count_check: MarkerCountCheck,
+    /// `None` without a UI, e.g. in tests, then nothing is sent to it.
+    pub ui_ctx: Option<Context>,
     pub nf_offset: Vector2<f64>,
     pub wfnf_realign: bool,
+    /// Minimum distance in normalized image coordinates between two markers before they are
+    /// considered too close together to be matched reliably.
+    pub marker_min_separation: f64,
+    /// Set by the stream loops when new data arrives, cleared when the canvases are redrawn.
+    pub redraw_pending: bool,
+    /// Which sorter the raw canvas uses before computing the marker grid, `None` keeps the order
+    /// `choose_rectangle` picked them in. Debug only.
+    pub raw_sort_pattern: Option<MarkerPattern>,
+    pub target_regions: TargetRegions,
+    pub screen_info: ScreenInfo,
+    /// When set, the aimpoint is computed from this pose instead of the tracking filter. Debug only.
+    pub pose_override: Option<Isometry3<f32>>,
+    /// Draw error vectors from the measured markers to their expected reprojections. Debug only.
+    pub show_marker_errors: bool,
+    /// Draw the eskf and PnP aimpoints separately on the test canvas. Debug only.
+    pub show_split_aimpoints: bool,
+    /// Mirrors the raw canvas. Debug only.
+    pub raw_mirror: CanvasMirror,
+    /// Draw the undistorted position of each blob next to the reported one on the raw canvas.
+    /// Debug only.
+    pub show_undistortion: bool,
+    /// Number of consecutive frames a marker has to be matched to the same pattern index before
+    /// its pattern id changes.
+    pub pattern_id_debounce_frames: u32,
+    /// Number of frames in a row the nf marker set has to stay the same before the aimpoint is
+    /// output. 0 doesn't wait, even with too few points.
+    pub acquire_frames: u32,
+    /// Fake impacts injected into `impact_loop` for testing without firing the device.
+    pub simulated_impacts: tokio::sync::broadcast::Sender<ImpactReport>,
+    /// Must be set for `simulate_impact` to do anything. Debug only.
+    pub allow_simulated_impacts: bool,
+    pub nf_point_window: PointWindow,
+    pub wf_point_window: PointWindow,
+    /// `pattern_mismatch` above which the observed markers are considered to not be the
+    /// configured pattern, a fraction of the mean distance between markers.
+    pub pattern_mismatch_threshold: f64,
+    /// Number of x and y subdivisions of the grid drawn on the canvases.
+    pub grid_subdivisions: (usize, usize),
+    /// Blobs smaller than this aren't drawn on the raw canvas. Display only, the DSP area
+    /// threshold registers are left alone.
+    pub raw_min_area: u16,
+    /// Number of object report frames the raw canvas averages, 1 shows every frame as is.
+    pub raw_average_frames: usize,
+    pub nf_average: FrameAverage,
+    pub wf_average: FrameAverage,
+    pub health: ConnectionHealth,
+    pub health_thresholds: HealthThresholds,
+    /// Drops and repeats in the accel stream, going by the device timestamps. The marker stream
+    /// has no timestamp or id to check.
+    pub accel_sequence: PacketSequence,
+    /// Longest gap between IMU samples that is predicted as is.
+    pub max_imu_dt: Duration,
+    /// When set, the pose and aimpoint of every combined markers frame are written to it.
+    pub pose_log: Option<PoseLog>,
+    /// When set, the aimpoint and impacts are streamed to its websocket clients.
+    pub aimpoint_server: Option<AimpointServer>,
+    /// Text drawn next to the nf and wf markers on the tracking canvases.
+    pub label_verbosity: LabelVerbosity,
+    /// How the test canvas draws the aimpoint while `MotState::fv_aimpoint_valid` is false.
+    pub aimpoint_lost_behavior: AimpointLostBehavior,
+    /// Decimal places of the aimpoint readout on the test canvas, `None` hides it.
+    pub aimpoint_readout_precision: Option<usize>,
+    /// When set, packets around every impact are written to their own recording.
+    pub impact_capture: Option<ImpactCapture>,
+    pub tracked_screen_ids: ScreenIdFilter,
+    /// Pattern indices used for matching, to keep tracking around a damaged or covered marker.
+    pub marker_mask: MarkerMask,
+    /// Tracking canvas layers, bottom first.
+    pub overlay_order: Vec<Overlay>,
+    /// Brightness, contrast and palette the canvases are drawn with.
+    pub display: DisplaySettings,
+    /// Where the loops get the time from, a `RealClock` outside of tests.
+    pub clock: Arc<dyn Clock>,
+    /// How far a marker can move between frames and keep its track id, as a fraction of the
+    /// sensor size. Anything that moves further is taken for a DSP glitch and starts a new track.
+    pub track_gate: f64,
+    pub accel_scale_check: AccelScaleCheck,
+    pub clock_drift: ClockDrift,
+    /// Updated from `clock_drift` with the IMU readout.
+    pub clock_drift_readout: RwSignal<Option<ClockDriftReport>>,
+    /// Refines `stereo_iso` every frame the markers match while set.
+    pub stereo_refinement: Option<StereoRefinement>,
+    /// How the per sensor aimpoints pick a pose when PnP is ambiguous. Debug only.
+    pub pnp_disambiguation: PnpDisambiguation,
+}
+
+/// `config` with everything that can be written to the device replaced by `write`.
+pub fn general_config_with_write(config: &GeneralConfig, write: GeneralWriteConfig) -> GeneralConfig {
+    GeneralConfig {
+        impact_threshold: write.impact_threshold,
+        accel_odr: write.accel_odr,
+        camera_model_nf: write.camera_model_nf,
+        camera_model_wf: write.camera_model_wf,
+        stereo_iso: write.stereo_iso,
+        ..config.clone()
+    }
+}
+
+/// The part of `config` that can be written to the device.
+pub fn general_write_config(config: &GeneralConfig) -> GeneralWriteConfig {
+    GeneralWriteConfig {
+        impact_threshold: config.impact_threshold,
+        accel_odr: config.accel_odr,
+        camera_model_nf: config.camera_model_nf.clone(),
+        camera_model_wf: config.camera_model_wf.clone(),
+        stereo_iso: config.stereo_iso,
+    }
+}
+
+impl MotRunner {
+    /// A runner with the GUI's default settings and its own signals and buffers, without a
+    /// device. Needs a leptos runtime.
+    pub fn new(ui_ctx: Option<Context>) -> Self {
+        Self {
+            state: Default::default(),
+            device: None,
+            markers_settings: Default::default(),
+            impact_arming: Default::default(),
+            impact_debounce: Default::default(),
+            shot_markers: Default::default(),
+            record_packets: false,
+            record_mask: Default::default(),
+            datapoints: Default::default(),
+            packets: Default::default(),
+            ui_update: RwSignal::new(()),
+            imu_readout: RwSignal::new(None),
+            madgwick_readout: RwSignal::new(None),
+            madgwick_readout_enabled: false,
+            marker_count: RwSignal::new(None),
+            marker_count_check: Default::default(),
+            ui_ctx,
+            nf_offset: Vector2::default(),
+            general_config: Default::default(),
+            wfnf_realign: true,
+            marker_min_separation: 0.01,
+            redraw_pending: false,
+            raw_sort_pattern: None,
+            target_regions: Default::default(),
+            screen_info: Default::default(),
+            pose_override: None,
+            show_marker_errors: false,
+            show_split_aimpoints: false,
+            show_undistortion: false,
+            raw_mirror: Default::default(),
+            pattern_id_debounce_frames: 3,
+            acquire_frames: 5,
+            simulated_impacts: tokio::sync::broadcast::channel(4).0,
+            allow_simulated_impacts: false,
+            nf_point_window: PointWindow::NF_DEFAULT,
+            wf_point_window: PointWindow::WF_DEFAULT,
+            pattern_mismatch_threshold: DEFAULT_PATTERN_MISMATCH_THRESHOLD,
+            grid_subdivisions: (10, 10),
+            raw_min_area: 0,
+            raw_average_frames: 1,
+            nf_average: Default::default(),
+            wf_average: Default::default(),
+            health: Default::default(),
+            health_thresholds: Default::default(),
+            accel_sequence: Default::default(),
+            max_imu_dt: Duration::from_millis(100),
+            pose_log: None,
+            aimpoint_server: None,
+            label_verbosity: LabelVerbosity::Full,
+            aimpoint_lost_behavior: AimpointLostBehavior::Gray,
+            aimpoint_readout_precision: None,
+            impact_capture: None,
+            tracked_screen_ids: Default::default(),
+            marker_mask: Default::default(),
+            overlay_order: Overlay::DEFAULT_ORDER.to_vec(),
+            display: Default::default(),
+            clock: Arc::new(crate::clock::RealClock),
+            track_gate: 0.05,
+            accel_scale_check: Default::default(),
+            clock_drift: Default::default(),
+            clock_drift_readout: RwSignal::new(None),
+            stereo_refinement: None,
+            pnp_disambiguation: PnpDisambiguation::LowestReprojectionError,
+        }
+    }
+
+    /// A runner for another device, with its own state and this runner's tracking and display
+    /// settings. `resolutions` are the device's nf and wf scale resolutions.
+    pub fn for_device(&self, device: UsbDevice, general_config: GeneralConfig, resolutions: [Vector2<u16>; 2]) -> Self {
+        Self {
+            device: Some(device),
+            general_config,
+            nf_point_window: PointWindow { resolution: resolutions[0], ..self.nf_point_window },
+            wf_point_window: PointWindow { resolution: resolutions[1], ..self.wf_point_window },
+            impact_debounce: ImpactDebounce { window: self.impact_debounce.window, ..Default::default() },
+            markers_settings: self.markers_settings.clone(),
+            screen_info: self.screen_info.clone(),
+            marker_min_separation: self.marker_min_separation,
+            pattern_id_debounce_frames: self.pattern_id_debounce_frames,
+            acquire_frames: self.acquire_frames,
+            pattern_mismatch_threshold: self.pattern_mismatch_threshold,
+            grid_subdivisions: self.grid_subdivisions,
+            label_verbosity: self.label_verbosity,
+            overlay_order: self.overlay_order.clone(),
+            display: self.display,
+            health_thresholds: self.health_thresholds,
+            max_imu_dt: self.max_imu_dt,
+            track_gate: self.track_gate,
+            pnp_disambiguation: self.pnp_disambiguation,
+            clock: self.clock.clone(),
+            ..Self::new(self.ui_ctx)
+        }
+    }
+
+    /// Copy of the whole general config.
+    pub fn general_config(&self) -> GeneralConfig {
+        self.general_config.clone()
+    }
+
+    /// Replaces the whole general config at once and returns the old one. Build the new config
+    /// before locking the runner, then the loops, which hold the lock while they use the config,
+    /// only ever see the old or the new one and never a mix of both.
+    pub fn set_general_config(&mut self, config: GeneralConfig) -> GeneralConfig {
+        std::mem::replace(&mut self.general_config, config)
+    }
+
+    /// Changes the general config in place, for edits that start from the current config. Unlike
+    /// `general_config()` followed by `set_general_config`, nothing can change it in between.
+    pub fn update_general_config(&mut self, f: impl FnOnce(&mut GeneralConfig)) {
+        f(&mut self.general_config);
+    }
+
+    /// Runs `f` on the UI thread, does nothing without a UI.
+    fn queue_main(&self, f: impl FnMut() + Send + 'static) {
+        if let Some(ui_ctx) = self.ui_ctx {
+            ui_ctx.queue_main(f);
+        }
+    }
+
+    /// Counts an error towards the connection health.
+    pub fn on_connection_error(&mut self) {
+        let now = self.clock.now();
+        self.health.on_error(now);
+    }
+
+    /// Injects an impact into `impact_loop` as if the device had reported one.
+    pub fn simulate_impact(&self) {
+        if !self.allow_simulated_impacts {
+            return;
+        }
+        info!("simulating impact");
+        // Has to advance like the device's µs timestamp for the debounce, wrapping included
+        let timestamp = (self.clock.unix_millis() * 1000) as u32;
+        if self.simulated_impacts.send(ImpactReport { timestamp }).is_err() {
+            info!("impact loop isn't running, simulated impact dropped");
+        }
+    }
+
+    /// Recompute the aimpoint from the filter's pose, or from `pose_override` if it is set.
+    pub fn update_raycast_aimpoint(&mut self) {
+        (self.state.eskf_aimpoint, self.state.pnp_aimpoint) = split_aimpoints(&self.state);
+        let (rotmat, transmat, fv_aimpoint) = match &self.pose_override {
+            Some(pose) => raycast_aimpoint_from_pose(&pose.rotation, &pose.translation.vector.into()),
+            None => get_raycast_aimpoint(&self.state.fv_state),
+        };
+
+        self.state.rotation_mat = rotmat.cast();
+        self.state.translation_mat = transmat.coords.cast();
+        self.state.fv_aimpoint_valid = aimpoint_valid(fv_aimpoint, self.state.nf_point_count, self.pose_override.is_some());
+        if let Some(fv_aimpoint) = fv_aimpoint {
+            self.state.fv_aimpoint = fv_aimpoint.cast();
+        }
+    }
+}
+
+/// Fewest near field points the aimpoint is trusted from. With fewer the filter is only coasting on
+/// its last pose.
+pub const MIN_AIMPOINT_POINTS: usize = 4;
+
+/// Whether an aimpoint from `update_raycast_aimpoint` is live. A pose override always is, it
+/// doesn't come from the markers.
+pub fn aimpoint_valid(aimpoint: Option<Point2<f32>>, nf_point_count: usize, pose_override: bool) -> bool {
+    aimpoint.is_some() && (pose_override || nf_point_count >= MIN_AIMPOINT_POINTS)
 }
 
 pub async fn run(runner: Arc<Mutex<MotRunner>>) {
@@ -120,6 +803,18 @@ pub async fn run(runner: Arc<Mutex<MotRunner>>) {
     );
 }
 
+/// Takes the device out of the runner, which ends the stream loops, and tells it to stop
+/// streaming.
+pub async fn shutdown(runner: Arc<Mutex<MotRunner>>) {
+    let device = runner.lock().device.take();
+    if let Some(device) = device {
+        info!("stopping device streams");
+        if let Err(e) = device.shutdown().await {
+            error!("failed to stop device streams: {e}");
+        }
+    }
+}
+
 pub async fn frame_loop(runner: Arc<Mutex<MotRunner>>) {
     let device = runner.lock().device.c().unwrap();
     let mut mot_data_stream = device.stream_mot_data().await.unwrap();
@@ -128,9 +823,15 @@ pub async fn frame_loop(runner: Arc<Mutex<MotRunner>>) {
             return;
         }
         if let Some(mot_data) = mot_data_stream.next().await {
-            let nf_data = mot_data.mot_data_nf;
-            let wf_data = mot_data.mot_data_wf;
             let mut runner = runner.lock();
+            let now = runner.clock.now();
+            runner.health.on_packet(now);
+            let (nf_data, wf_data) = if runner.raw_average_frames > 1 {
+                let frames = runner.raw_average_frames;
+                (runner.nf_average.push(mot_data.mot_data_nf, frames), runner.wf_average.push(mot_data.mot_data_wf, frames))
+            } else {
+                (mot_data.mot_data_nf, mot_data.mot_data_wf)
+            };
             let nf_data = ArrayVec::<MotData,16>::from_iter(nf_data.into_iter());
             // let nf_data = ArrayVec::<MotData,16>::from_iter(dummy_nf_data());
             let wf_data = ArrayVec::<MotData,16>::from_iter(wf_data.into_iter());
@@ -138,18 +839,36 @@ pub async fn frame_loop(runner: Arc<Mutex<MotRunner>>) {
             let state = &mut runner.state;
             state.nf_data = Some(nf_data);
             state.wf_data = Some(wf_data);
+            runner.redraw_pending = true;
 
-            if runner.record_packets {
-                runner.packets.lock().push((std::time::SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(), ats_usb::packet::PacketData::ObjectReport(mot_data)));
-            }
+            record(&mut runner, ats_usb::packet::PacketData::ObjectReport(mot_data));
         }
     }
 }
 
+// 1920x1080 abe's wall
+const SCREEN_HEIGHT_METERS: f32 = 1.2838;
+
+// 3840x2160 (16:9) SVT
+// const SCREEN_HEIGHT_METERS: f32 = ???;
+
 fn get_raycast_aimpoint(fv_state: &ats_cv::foveated::FoveatedAimpointState) -> (Matrix3<f32>, nalgebra::Point3<f32>, Option<Point2<f32>>) {
-    let orientation = fv_state.filter.orientation;
-    let position = fv_state.filter.position;
+    raycast_aimpoint_from_pose(&fv_state.filter.orientation, &fv_state.filter.position)
+}
 
+/// The aimpoints of the eskf pose and of the last PnP solution, each on its own.
+pub fn split_aimpoints(state: &MotState) -> (Option<Point2<f64>>, Option<Point2<f64>>) {
+    let (_, _, eskf) = get_raycast_aimpoint(&state.fv_state);
+    let pnp = state.pnp_solution.and_then(|pnp_iso| {
+        // The PnP solution maps the screen into the camera, the camera pose is its inverse
+        let pose = pnp_iso.inverse();
+        raycast_aimpoint_from_pose(&pose.rotation, &pose.translation.vector.into()).2
+    });
+    (eskf.map(|p| p.cast()), pnp.map(|p| p.cast()))
+}
+
+/// The aimpoint math behind `get_raycast_aimpoint`, for an arbitrary pose in the filter's frame.
+pub fn raycast_aimpoint_from_pose(orientation: &UnitQuaternion<f32>, position: &nalgebra::Point3<f32>) -> (Matrix3<f32>, nalgebra::Point3<f32>, Option<Point2<f32>>) {
     let flip_yz = Matrix3::new(
         1., 0., 0.,
         0., -1., 0.,
@@ -157,15 +876,9 @@ fn get_raycast_aimpoint(fv_state: &ats_cv::foveated::FoveatedAimpointState) -> (
     );
 
     let rotmat = flip_yz * orientation.to_rotation_matrix() * flip_yz;
-    let transmat = flip_yz * position;
-
-    // 1920x1080 abe's wall
-    let screen_height_meters = 1.2838;
+    let transmat = flip_yz * *position;
 
-    // 3840x2160 (16:9) SVT
-    // let screen_height_meters: f64 = ???;
-
-    let screen_3dpoints = ats_cv::calculate_screen_3dpoints(screen_height_meters, 16./9.);
+    let screen_3dpoints = ats_cv::calculate_screen_3dpoints(SCREEN_HEIGHT_METERS, 16./9.);
 
     let fv_aimpoint = ats_cv::calculate_aimpoint_from_pose_and_screen_3dpoints(
         &rotmat,
@@ -176,6 +889,59 @@ fn get_raycast_aimpoint(fv_state: &ats_cv::foveated::FoveatedAimpointState) -> (
     (rotmat, transmat, fv_aimpoint)
 }
 
+/// Feeds one frame of matched markers to the stereo refinement and applies a step to
+/// `stereo_iso`. Frames where not every marker matches, or without a pose to take the marker
+/// depth from, are skipped.
+fn stereo_refinement_step(
+    runner: &mut MotRunner,
+    nf_normalized: &[Point2<f64>],
+    wf_normalized: &[Point2<f64>],
+    wf_points: &[Point2<f64>],
+    gravity_vec: UnitVector3<f64>,
+) {
+    let Some((wf_match_ix, _)) = identify_markers2(wf_normalized, gravity_vec) else {
+        return;
+    };
+    let wf_match = wf_match_ix.map(|i| wf_normalized[i].coords);
+    let (nf_match_ix, _) = match3(nf_normalized, &wf_match);
+    if !nf_match_ix.iter().all(Option::is_some) {
+        return;
+    }
+    let Some(pnp_iso) = runner.state.pnp_solution else {
+        return;
+    };
+    let pnp_iso: Isometry3<f64> = pnp_iso.cast();
+    let marker_points = &runner.screen_info.marker_points;
+    let depth = marker_points.iter().map(|p| (pnp_iso * p).z).sum::<f64>() / marker_points.len() as f64;
+
+    // wf_normalized has already been moved into the nf camera by the current stereo_iso
+    let camera_model_wf = &runner.general_config.camera_model_wf;
+    let fx = camera_model_wf.p.m11 as f64;
+    let fy = camera_model_wf.p.m22 as f64;
+    let cx = camera_model_wf.p.m13 as f64;
+    let cy = camera_model_wf.p.m23 as f64;
+    let sample = StereoSample {
+        nf: nf_match_ix.iter().map(|i| nf_normalized[i.unwrap()]).collect(),
+        wf: wf_match_ix.iter().map(|&i| {
+            let p = wf_points[i];
+            let p = p / SENSOR_FULL_SCALE * f64::from(CAMERA_MODEL_RESOLUTION);
+            Point2::new((p.x - cx) / fx, (p.y - cy) / fy)
+        }).collect(),
+        depth,
+    };
+    let iso = runner.general_config.stereo_iso.cast();
+    let Some(refinement) = runner.stereo_refinement.as_mut() else {
+        return;
+    };
+    refinement.add_sample(sample);
+    let iso = refinement.step(&iso).cast();
+    runner.update_general_config(|config| config.stereo_iso = iso);
+}
+
+/// Held from observing the markers until the PnP solution is read back, so another runner can't
+/// push its own solution to the shared telemetry series in between.
+static TELEMETRY_LOCK: Mutex<()> = Mutex::new(());
+
 async fn combined_markers_loop(runner: Arc<Mutex<MotRunner>>) {
     let device = runner.lock().device.c().unwrap();
     let mut combined_markers_stream = device.stream_combined_markers().await.unwrap();
@@ -184,16 +950,27 @@ async fn combined_markers_loop(runner: Arc<Mutex<MotRunner>>) {
         if let Some(combined_markers_report) = combined_markers_stream.next().await {
             let CombinedMarkersReport { nf_points, wf_points, nf_screen_ids, wf_screen_ids } = combined_markers_report;
             let mut runner = runner.lock();
-            let nf_point_tuples = filter_and_create_point_tuples(&nf_points, &nf_screen_ids);
-            let wf_point_tuples = filter_and_create_point_tuples(&wf_points, &wf_screen_ids);
+            let now = runner.clock.now();
+            runner.health.on_packet(now);
+            let nf_point_tuples = filter_and_create_point_tuples(&nf_points, &nf_screen_ids, &runner.nf_point_window, runner.tracked_screen_ids);
+            let wf_point_tuples = filter_and_create_point_tuples(&wf_points, &wf_screen_ids, &runner.wf_point_window, runner.tracked_screen_ids);
+            let expected = runner.screen_info.marker_count;
+            let previous = runner.marker_count_check.last;
+            let marker_count = runner.marker_count_check.update(nf_point_tuples.len(), wf_point_tuples.len(), expected);
+            if previous != Some(marker_count) {
+                let signal = runner.marker_count;
+                runner.queue_main(move || {
+                    leptos_reactive::SignalSet::set(&signal, Some(marker_count));
+                });
+            }
 
             // println!("nf: {} wf: {}", filtered_nf_point_tuples.len(), filtered_wf_point_tuples.len());
 
             let nf_points_slice = nf_point_tuples.iter().map(|(_, _, p)| *p).collect::<Vec<_>>();
             let wf_points_slice = wf_point_tuples.iter().map(|(_, _, p)| *p).collect::<Vec<_>>();
 
-            let nf_points_transformed = transform_points(&nf_points_slice, &runner.general_config.camera_model_nf);
-            let wf_points_transformed = transform_points(&wf_points_slice, &runner.general_config.camera_model_wf);
+            let nf_points_transformed = transform_points(&nf_points_slice, &runner.general_config.camera_model_nf, runner.general_config.camera_model_type_nf, runner.nf_point_window.resolution);
+            let wf_points_transformed = transform_points(&wf_points_slice, &runner.general_config.camera_model_wf, runner.general_config.camera_model_type_wf, runner.wf_point_window.resolution);
 
             let nf_point_tuples = nf_point_tuples.iter().enumerate().map(|(i, (screen_id, id, _))| (*screen_id, *id, nf_points_transformed[i])).collect::<Vec<_>>();
             let wf_point_tuples = wf_point_tuples.iter().enumerate().map(|(i, (screen_id, id, _))| (*screen_id, *id, wf_points_transformed[i])).collect::<Vec<_>>();
@@ -204,16 +981,21 @@ async fn combined_markers_loop(runner: Arc<Mutex<MotRunner>>) {
                 let fy = runner.general_config.camera_model_nf.p.m22 as f64;
                 let cx = runner.general_config.camera_model_nf.p.m13 as f64;
                 let cy = runner.general_config.camera_model_nf.p.m23 as f64;
-                Point2::new((p.x/4095.*98. - cx) / fx, (p.y/4095.*98. - cy) / fy)
+                let p = p / SENSOR_FULL_SCALE * f64::from(CAMERA_MODEL_RESOLUTION);
+                Point2::new((p.x - cx) / fx, (p.y - cy) / fy)
             }).collect();
             let nf_normalized: Vec<_> = nf_points_transformed.iter().map(|&p| {
                 let fx = runner.general_config.camera_model_nf.p.m11 as f64;
                 let fy = runner.general_config.camera_model_nf.p.m22 as f64;
                 let cx = runner.general_config.camera_model_nf.p.m13 as f64;
                 let cy = runner.general_config.camera_model_nf.p.m23 as f64;
-                Point2::new((p.x/4095.*98. - cx) / fx, (p.y/4095.*98. - cy) / fy)
+                let p = p / SENSOR_FULL_SCALE * f64::from(CAMERA_MODEL_RESOLUTION);
+                Point2::new((p.x - cx) / fx, (p.y - cy) / fy)
             }).collect();
 
+            runner.state.nf_markers_too_close = find_close_marker_pair(&nf_normalized, runner.marker_min_separation);
+            runner.state.wf_markers_too_close = find_close_marker_pair(&wf_normalized, runner.marker_min_separation);
+
             let gravity_vec = runner.state.orientation.inverse_transform_vector(&Vector3::z_axis());
             let gravity_vec = UnitVector3::new_unchecked(gravity_vec.xzy());
             if runner.wfnf_realign {
@@ -229,11 +1011,14 @@ async fn combined_markers_loop(runner: Arc<Mutex<MotRunner>>) {
                         eprintln!("nf_ordered = {nf_ordered:?}");
                         eprintln!("wf_ordered = {wf_ordered:?}");
                         let q = calculate_rotational_offset(&wf_ordered, &nf_ordered);
-                        runner.general_config.stereo_iso.rotation *= q.cast();
+                        runner.update_general_config(|config| config.stereo_iso.rotation *= q.cast());
                         runner.wfnf_realign = false;
                     }
                 }
             }
+            if runner.stereo_refinement.is_some() {
+                stereo_refinement_step(&mut runner, &nf_normalized, &wf_normalized, &wf_points_transformed, gravity_vec.cast());
+            }
 
             // let nf_point_tuples_transformed = filtered_nf_point_tuples.iter().map(|(id, _)| *id).zip(&mut nf_points_transformed).collect::<Vec<_>>();
             // let wf_point_tuples_transformed = filtered_wf_point_tuples.iter().map(|(id, _)| *id).zip(&mut wf_points_transformed).collect::<Vec<_>>();
@@ -253,21 +1038,30 @@ async fn combined_markers_loop(runner: Arc<Mutex<MotRunner>>) {
             // step at marker hz
             runner.state.fv_aimpoint_pva2d.step();
 
+            let acquire_frames = runner.acquire_frames;
+            let acquired = runner.state.marker_stability.update(&nf_normalized, acquire_frames, STABILITY_TOLERANCE);
+            runner.state.nf_point_count = nf_normalized.len();
+            let telemetry = TELEMETRY_LOCK.lock();
             runner.state.fv_state.observe_markers(&nf_normalized, &wf_normalized, gravity_vec.cast());
-
-            let (rotmat, transmat, fv_aimpoint) = get_raycast_aimpoint(&runner.state.fv_state);
-
-            runner.state.rotation_mat = rotmat.cast();
-            runner.state.translation_mat = transmat.coords.cast();
-            if let Some(fv_aimpoint) = fv_aimpoint {
-                runner.state.fv_aimpoint = fv_aimpoint.cast();
+            runner.state.pnp_solution = ats_cv::telemetry::pnp_solutions().get_last().map(|iso| iso.cast());
+            drop(telemetry);
+            // The filter always gets the markers, only the aimpoint waits for the set to settle
+            if acquired {
+                runner.update_raycast_aimpoint();
+            } else {
+                runner.state.fv_aimpoint_valid = false;
             }
 
-            if let Some(x) = calculate_individual_aimpoint(&nf_points_transformed, runner.state.orientation, None, &runner.general_config.camera_model_nf) {
+            // The eskf pose is the near field camera's on the screen, PnP solves for the inverse.
+            // There's no prior for the wide field camera.
+            let eskf_pose: Isometry3<f64> = Isometry3::from_parts(runner.state.fv_state.filter.position.into(), runner.state.fv_state.filter.orientation).cast();
+            let nf_prior = eskf_pose.inverse();
+            let mode = runner.pnp_disambiguation;
+            if let Some(x) = calculate_individual_aimpoint(&nf_points_transformed, &runner.screen_info.marker_points, runner.state.orientation, None, &runner.general_config.camera_model_nf, Some(&nf_prior), mode) {
                 runner.state.nf_aimpoint = x;
             }
 
-            if let Some(x) = calculate_individual_aimpoint(&wf_points_transformed, runner.state.orientation, Some(&runner.general_config.stereo_iso.cast()), &runner.general_config.camera_model_wf) {
+            if let Some(x) = calculate_individual_aimpoint(&wf_points_transformed, &runner.screen_info.marker_points, runner.state.orientation, Some(&runner.general_config.stereo_iso.cast()), &runner.general_config.camera_model_wf, None, mode) {
                 runner.state.wf_aimpoint = x;
             }
 
@@ -284,27 +1078,36 @@ async fn combined_markers_loop(runner: Arc<Mutex<MotRunner>>) {
             };
 
             let mut nf_markers = ArrayVec::<_, 16>::new();
-
-            if wf_marker_ix.len() >= 6 {
-                let chosen_wf_markers: [_; 6] = [
-                    wf_normalized[wf_marker_ix[0]].coords,
-                    wf_normalized[wf_marker_ix[1]].coords,
-                    wf_normalized[wf_marker_ix[2]].coords,
-                    wf_normalized[wf_marker_ix[3]].coords,
-                    wf_normalized[wf_marker_ix[4]].coords,
-                    wf_normalized[wf_marker_ix[5]].coords,
-                ];
+            let mut nf_marker_keys = ArrayVec::<_, 16>::new();
+
+            let marker_count = runner.screen_info.marker_count;
+            if wf_marker_ix.len() >= marker_count {
+                let enabled = enabled_wf_markers(&wf_marker_ix[..marker_count], runner.marker_mask);
+                let chosen_wf_markers: ArrayVec<_, 16> = enabled
+                    .iter()
+                    .map(|&(_, i)| wf_normalized[i].coords)
+                    .collect();
                 let match_result = ats_cv::foveated::match3(&nf_normalized, &chosen_wf_markers);
-                for i in 0..6 {
-                    let j = match_result.0[i];
+                for i in 0..marker_count {
+                    // Disabled markers keep their slot so nf_markers stays in pattern order
+                    let j = enabled.iter().position(|&(pattern_ix, _)| pattern_ix == i).and_then(|k| match_result.0.get(k).copied().flatten());
                     if let Some(j) = j {
                         nf_markers.push(nf_points_transformed[j]);
+                        nf_marker_keys.push(Some((nf_point_tuples[j].0, nf_point_tuples[j].1)));
                     } else {
                         nf_markers.push(Point2::new(-9999., -9999.));
+                        nf_marker_keys.push(None);
                     }
                 }
             }
 
+            let debounce_frames = runner.pattern_id_debounce_frames;
+            for &(screen_id, mot_id, _) in &nf_point_tuples {
+                let key = (screen_id, mot_id);
+                let assigned = nf_marker_keys.iter().position(|&k| k == Some(key));
+                runner.state.pattern_ids.entry(key).or_default().update(assigned, debounce_frames);
+            }
+
             runner.state.nf_points = nf_point_tuples
                 .into_iter()
                 .filter(|p| !nf_markers.contains(&p.2))
@@ -316,98 +1119,579 @@ async fn combined_markers_loop(runner: Arc<Mutex<MotRunner>>) {
                 .map(|x| *x.1)
                 .collect();
             runner.state.nf_markers = nf_markers;
+            let track_gate = runner.track_gate * SENSOR_FULL_SCALE;
+            let state = &mut runner.state;
+            state.nf_track_ids = state.nf_marker_tracks.associate(&state.measured_nf_markers(), track_gate).into_iter().collect();
+            state.nf_track_histories.update(&state.nf_track_ids, &state.nf_markers);
+            check_pattern_mismatch(&mut runner);
             runner.state.wf_markers = wf_marker_ix
-                .into_iter()
-                .map(|i| wf_points_transformed[i])
+                .iter()
+                .map(|&i| wf_points_transformed[i])
                 .collect();
             runner.state.wf_reproj = wf_reproj;
 
+            let wf_marker_keys: ArrayVec<_, 16> = wf_marker_ix
+                .iter()
+                .map(|&i| (wf_point_tuples[i].0, wf_point_tuples[i].1))
+                .collect();
+            for &key in nf_marker_keys.iter().flatten().chain(&wf_marker_keys) {
+                runner.state.assign_marker_color(key);
+            }
+            runner.state.nf_marker_keys = nf_marker_keys;
+            runner.state.wf_marker_keys = wf_marker_keys;
+            runner.redraw_pending = true;
+
             let index = runner.state.fv_aimpoint_history_index;
             runner.state.fv_aimpoint_history[index] = runner.state.nf_aimpoint;
             runner.state.fv_aimpoint_history_index = (index + 1) % runner.state.fv_aimpoint_history.len();
 
-            if runner.record_packets {
-                runner.packets.lock().push((std::time::SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(), ats_usb::packet::PacketData::CombinedMarkersReport(combined_markers_report)));
+            record(&mut runner, ats_usb::packet::PacketData::CombinedMarkersReport(combined_markers_report));
+
+            let runner = &mut *runner;
+            if let Some(pose_log) = &mut runner.pose_log {
+                let timestamp_ms = runner.clock.unix_millis() as u64;
+                if let Err(e) = pose_log.write(&PoseRecord::from_state(timestamp_ms, &runner.state)) {
+                    error!("failed to write pose log, stopping it: {e}");
+                    runner.pose_log = None;
+                }
+            }
+            if let Some(server) = runner.aimpoint_server.as_mut().filter(|_| acquired) {
+                let aimpoint = runner.state.fv_aimpoint;
+                server.publish_limited(runner.clock.now(), &AimpointMessage::Aimpoint { x: aimpoint.x, y: aimpoint.y, screen_id: runner.state.screen_id });
             }
         }
     }
 }
 
-fn calculate_individual_aimpoint(points: &[Point2<f64>], orientation: Rotation3<f32>, iso: Option<&Isometry3<f32>>, intrinsics: &RosOpenCvIntrinsics<f32>) -> Option<Point2<f64>> {
-    let fx = intrinsics.p.m11 * (4095./98.);
-    let fy = intrinsics.p.m22 * (4095./98.);
+/// Aimpoint of one sensor on its own, from a PnP pose against the first four of `marker_points`.
+/// The sensor points are matched to the markers by sorting both as a rectangle, with the image
+/// points levelled by gravity first. `iso` takes the pose into the near field camera's frame.
+/// `prior` and `mode` pick the pose when PnP is ambiguous, see `my_pnp`.
+fn calculate_individual_aimpoint(points: &[Point2<f64>], marker_points: &[Point3<f64>], orientation: Rotation3<f32>, iso: Option<&Isometry3<f32>>, intrinsics: &RosOpenCvIntrinsics<f32>, prior: Option<&Isometry3<f64>>, mode: PnpDisambiguation) -> Option<Point2<f64>> {
+    if points.len() < 4 || marker_points.len() < 4 {
+        return None;
+    }
+    let fx = intrinsics.p.m11 as f64;
+    let fy = intrinsics.p.m22 as f64;
+    let cx = intrinsics.p.m13 as f64;
+    let cy = intrinsics.p.m23 as f64;
 
     let gravity_vec = orientation.inverse_transform_vector(&Vector3::z());
 	let gravity_angle = f64::atan2(-gravity_vec.z as f64, -gravity_vec.x as f64) + std::f64::consts::PI/2.;
 
-    if points.len() > 3 {
-        let mut rotated_points = ats_cv::mot_rotate(&points, -gravity_angle);
-        sort_points(&mut rotated_points, MarkerPattern::Rectangle);
-        // todo rotating back is bad, select with slice instead
-        let points = ats_cv::mot_rotate(&rotated_points, gravity_angle);
-
-        let projections = ats_cv::calculate_projections(
-            &points,
-            // 1/math.tan(38.3 / 180 * math.pi / 2) * 2047.5 (value used in the sim)
-            Vector2::new(fx as f64, fy as f64),
-            Vector2::new(4095., 4095.),
-        );
-        let solution = ats_cv::solve_pnp_with_dynamic_screen_points(
-            projections.as_slice(),
-            &[
-                Point2::new(0.35, 0.),
-                Point2::new(0.65, 0.),
-                Point2::new(0.65, 1.),
-                Point2::new(0.35, 1.),
-            ],
-            16./9.,
-            1.,
-        );
-        if let Some(sol) = solution {
-            let r_hat = Rotation3::from_matrix_unchecked(sol.r_hat.reshape_generic(Const::<3>, Const::<3>).transpose());
-            let t = Translation3::from(sol.t);
-            let tf = t * r_hat;
-            let ctf = tf.inverse();
-
-            let flip_yz = Matrix3::new(
-                1.0, 0.0, 0.0,
-                0.0, -1.0, 0.0,
-                0.0, 0.0, -1.0,
-            );
-
-            if let Some(iso) = iso {
-                let iso = iso.cast();
-                let rotation_mat = flip_yz * (iso.rotation * ctf.rotation).to_rotation_matrix() * flip_yz;
-                let translation_mat = flip_yz * ctf.translation.vector;
-
-                let screen_3dpoints = ats_cv::calculate_screen_3dpoints(1., 16./9.);
-
-                return ats_cv::calculate_aimpoint_from_pose_and_screen_3dpoints(&rotation_mat, &translation_mat, &screen_3dpoints);
+    let mut rotated_points = ats_cv::mot_rotate(&points[..4], -gravity_angle);
+    sort_points(&mut rotated_points, MarkerPattern::Rectangle);
+    // todo rotating back is bad, select with slice instead
+    let points = ats_cv::mot_rotate(&rotated_points, gravity_angle);
+    let projections: Vec<_> = points.iter().map(|&p| {
+        let p = p / SENSOR_FULL_SCALE * f64::from(CAMERA_MODEL_RESOLUTION);
+        Vector2::new((p.x - cx) / fx, (p.y - cy) / fy)
+    }).collect();
+
+    // Same order for the markers, by their position on the screen
+    let mut order = [0, 1, 2, 3].map(|i| marker_points[i].xy());
+    sort_points(&mut order, MarkerPattern::Rectangle);
+    let markers = order.map(|xy| *marker_points[..4].iter().find(|p| p.xy() == xy).unwrap());
+
+    let pnp_iso = marker_pose(&markers, &projections, prior, mode)?;
+    let ctf = pnp_iso.inverse();
+
+    let flip_yz = Matrix3::new(
+        1.0, 0.0, 0.0,
+        0.0, -1.0, 0.0,
+        0.0, 0.0, -1.0,
+    );
+    let rotation = match iso {
+        Some(iso) => iso.rotation.cast() * ctf.rotation,
+        None => ctf.rotation,
+    };
+    let rotation_mat = flip_yz * rotation.to_rotation_matrix().matrix() * flip_yz;
+    let translation_mat = flip_yz * ctf.translation.vector;
+    let screen_3dpoints = ats_cv::calculate_screen_3dpoints(1., 16./9.);
+    ats_cv::calculate_aimpoint_from_pose_and_screen_3dpoints(&rotation_mat, &translation_mat, &screen_3dpoints)
+}
+
+/// Projects `marker` onto the near field sensor, in `SENSOR_FULL_SCALE` sensor coordinates. `camera_pose`
+/// is the pose of the camera in the marker's frame.
+pub fn reproject_marker(marker: &nalgebra::Point3<f64>, camera_pose: &Isometry3<f64>, intrinsics: &RosOpenCvIntrinsics<f32>) -> Point2<f64> {
+    let fx = intrinsics.p.m11 as f64;
+    let fy = intrinsics.p.m22 as f64;
+    let cx = intrinsics.p.m13 as f64;
+    let cy = intrinsics.p.m23 as f64;
+    let p = camera_pose.inverse_transform_point(marker);
+    let p = p / p.z;
+    Point2::new(p.x*fx + cx, p.y*fy + cy) / f64::from(CAMERA_MODEL_RESOLUTION) * SENSOR_FULL_SCALE
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MarkerErrors {
+    /// From each measured marker to its expected position, `None` where the marker wasn't seen.
+    pub vectors: ArrayVec<Option<Vector2<f64>>, 16>,
+    pub max: f64,
+    pub mean: f64,
+}
+
+/// Compares measured marker positions against the expected ones, index by index.
+pub fn marker_errors(measured: &[Option<Point2<f64>>], expected: &[Point2<f64>]) -> MarkerErrors {
+    MarkerErrors::from_vectors(measured.iter().zip(expected).map(|(m, e)| m.map(|m| e - m)).collect())
+}
+
+impl MarkerErrors {
+    fn from_vectors(vectors: ArrayVec<Option<Vector2<f64>>, 16>) -> Self {
+        let norms = vectors.iter().flatten().map(|v| v.norm());
+        let count = norms.clone().count();
+        Self {
+            max: norms.clone().fold(0., f64::max),
+            mean: if count == 0 { 0. } else { norms.sum::<f64>() / count as f64 },
+            vectors,
+        }
+    }
+}
+
+/// How far each marker moved from the captured reference, index by index. Markers missing from
+/// either side get no vector.
+pub fn reference_differences(reference: &[Option<Point2<f64>>], live: &[Option<Point2<f64>>]) -> MarkerErrors {
+    MarkerErrors::from_vectors(reference.iter().zip(live).map(|(r, l)| Some(l.as_ref()? - r.as_ref()?)).collect())
+}
+
+/// How far the observed marker layout is from the expected one. Each set of pairwise distances is
+/// divided by its mean so the camera distance doesn't matter, then this is the largest difference
+/// between corresponding distances. `None` if fewer than 3 markers were observed.
+pub fn pattern_mismatch(observed: &[Option<Point2<f64>>], expected: &[Point2<f64>]) -> Option<f64> {
+    let pairs: ArrayVec<_, 16> = observed.iter().zip(expected).filter_map(|(o, e)| Some((o.as_ref()?, e))).collect();
+    if pairs.len() < 3 {
+        return None;
+    }
+    let mut observed_dists = ArrayVec::<f64, 120>::new();
+    let mut expected_dists = ArrayVec::<f64, 120>::new();
+    for (i, a) in pairs.iter().enumerate() {
+        for b in &pairs[i + 1..] {
+            observed_dists.push((a.0 - b.0).norm());
+            expected_dists.push((a.1 - b.1).norm());
+        }
+    }
+    let observed_mean = observed_dists.iter().sum::<f64>() / observed_dists.len() as f64;
+    let expected_mean = expected_dists.iter().sum::<f64>() / expected_dists.len() as f64;
+    if observed_mean == 0. || expected_mean == 0. {
+        return None;
+    }
+    Some(observed_dists.iter().zip(&expected_dists).map(|(o, e)| (o / observed_mean - e / expected_mean).abs()).fold(0., f64::max))
+}
+
+/// Default `pattern_mismatch_threshold`, loose enough for marker positions measured by hand on a
+/// large wall.
+pub const DEFAULT_PATTERN_MISMATCH_THRESHOLD: f64 = 0.15;
+
+/// The `pattern_mismatch` to warn about, if any.
+pub fn pattern_mismatch_warning(mismatch: f64, threshold: f64) -> Option<f64> {
+    (mismatch > threshold).then_some(mismatch)
+}
+
+/// How far the identified near field markers are from the configured pattern reprojected with the
+/// state's last PnP solution, `None` without a solution or enough matched markers.
+pub fn state_pattern_mismatch(state: &MotState, marker_points: &[Point3<f64>], camera_model_nf: &RosOpenCvIntrinsics<f32>) -> Option<f64> {
+    let pose: Isometry3<f64> = state.pnp_solution?.inverse().cast();
+    let observed: ArrayVec<_, 16> = state.nf_markers.iter().zip(&state.nf_marker_keys)
+        .map(|(&p, &k)| k.map(|_| p))
+        .collect();
+    let expected: ArrayVec<_, 16> = marker_points.iter().take(16)
+        .map(|p| reproject_marker(p, &pose, camera_model_nf))
+        .collect();
+    pattern_mismatch(&observed, &expected)
+}
+
+/// Warns when the identified markers don't line up with the configured pattern, which usually
+/// means the wrong screen info is loaded.
+fn check_pattern_mismatch(runner: &mut MotRunner) {
+    let Some(mismatch) = state_pattern_mismatch(&runner.state, &runner.screen_info.marker_points, &runner.general_config.camera_model_nf) else { return };
+    let mismatch = pattern_mismatch_warning(mismatch, runner.pattern_mismatch_threshold);
+    if mismatch.is_some() && runner.state.pattern_mismatch.is_none() {
+        warn!("marker layout doesn't match the configured screen info, mismatch = {:.3}", mismatch.unwrap());
+    }
+    runner.state.pattern_mismatch = mismatch;
+}
+
+/// How far, in 4095x4095 sensor units, a blob can move away from its average before
+/// `FrameAverage` starts over.
+pub const AVERAGE_MOTION_THRESHOLD: f64 = 30.;
+
+/// Averages the last few object report frames of one sensor per mot slot, for a steadier raw
+/// view of a static scene. Moving a blob further than `AVERAGE_MOTION_THRESHOLD` from its
+/// average, or a slot appearing or disappearing, starts over so nothing gets smeared.
+#[derive(Clone, Debug, Default)]
+pub struct FrameAverage {
+    window: VecDeque<[MotData; 16]>,
+}
+
+impl FrameAverage {
+    pub fn reset(&mut self) {
+        self.window.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.window.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.window.is_empty()
+    }
+
+    /// Adds `frame` and returns the average of the last `frames` frames.
+    pub fn push(&mut self, frame: [MotData; 16], frames: usize) -> [MotData; 16] {
+        if let Some(average) = self.average() {
+            let moved = frame.iter().zip(&average).any(|(new, avg)| {
+                if (new.area > 0) != (avg.area > 0) {
+                    return true;
+                }
+                let d = Vector2::new(f64::from(new.cx) - f64::from(avg.cx), f64::from(new.cy) - f64::from(avg.cy));
+                new.area > 0 && d.norm() > AVERAGE_MOTION_THRESHOLD
+            });
+            if moved {
+                self.reset();
+            }
+        }
+        self.window.push_back(frame);
+        while self.window.len() > frames.max(1) {
+            self.window.pop_front();
+        }
+        self.average().unwrap()
+    }
+
+    fn average(&self) -> Option<[MotData; 16]> {
+        let n = self.window.len();
+        if n == 0 {
+            return None;
+        }
+        let latest = self.window.back()?;
+        Some(std::array::from_fn(|slot| {
+            if latest[slot].area == 0 {
+                return latest[slot];
+            }
+            let mean = |f: fn(&MotData) -> f64| self.window.iter().map(|frame| f(&frame[slot])).sum::<f64>() / n as f64;
+            MotData {
+                area: mean(|m| m.area.into()).round() as u16,
+                cx: mean(|m| m.cx.into()).round() as u16,
+                cy: mean(|m| m.cy.into()).round() as u16,
+                avg_brightness: mean(|m| m.avg_brightness.into()).round() as u8,
+                max_brightness: mean(|m| m.max_brightness.into()).round() as u8,
+                boundary_left: mean(|m| m.boundary_left.into()).round() as u8,
+                boundary_right: mean(|m| m.boundary_right.into()).round() as u8,
+                boundary_up: mean(|m| m.boundary_up.into()).round() as u8,
+                boundary_down: mean(|m| m.boundary_down.into()).round() as u8,
+                ..latest[slot]
+            }
+        }))
+    }
+}
+
+/// How far, in normalized image units, a marker can move between frames and still count as the
+/// same marker set for `MarkerStability`.
+pub const STABILITY_TOLERANCE: f64 = 0.05;
+
+/// Holds off the aimpoint after the markers come into view until the marker set has kept its count
+/// and rough positions for a number of frames, so a half settled set right after acquisition
+/// doesn't make the aimpoint jump. Once acquired it stays acquired until the markers are lost.
+#[derive(Clone, Debug, Default)]
+pub struct MarkerStability {
+    previous: ArrayVec<Point2<f64>, 16>,
+    /// Frames in a row the marker set has been the same, counting the first.
+    pub stable_frames: u32,
+    pub acquired: bool,
+}
+
+impl MarkerStability {
+    /// Feed this frame's nf points, returns whether the aimpoint can be output. `frames` of 0
+    /// always can.
+    pub fn update(&mut self, points: &[Point2<f64>], frames: u32, tolerance: f64) -> bool {
+        let same = points.len() == self.previous.len()
+            && points.iter().all(|p| self.previous.iter().any(|q| (p - q).norm() <= tolerance));
+        self.stable_frames = if same { self.stable_frames.saturating_add(1) } else { 1 };
+        self.previous = points.iter().take(16).copied().collect();
+        if frames == 0 {
+            self.acquired = true;
+        } else if points.len() < MIN_AIMPOINT_POINTS {
+            self.stable_frames = 0;
+            self.acquired = false;
+        } else if self.stable_frames >= frames {
+            self.acquired = true;
+        }
+        self.acquired
+    }
+}
+
+/// Only commits a new pattern id for a marker once `match3` has assigned it the same pattern
+/// index for several frames in a row, so a one frame mismatch doesn't flicker.
+#[derive(Clone, Debug, Default)]
+pub struct PatternIdDebounce {
+    committed: Option<usize>,
+    candidate: Option<usize>,
+    count: u32,
+}
+
+impl PatternIdDebounce {
+    /// Feed this frame's assignment, returns the committed pattern id.
+    pub fn update(&mut self, assigned: Option<usize>, frames: u32) -> Option<usize> {
+        if assigned == self.committed {
+            self.count = 0;
+        } else {
+            if assigned == self.candidate && self.count > 0 {
+                self.count += 1;
             } else {
-                let rotation_mat = flip_yz * ctf.rotation.matrix() * flip_yz;
-                let translation_mat = flip_yz * ctf.translation.vector;
+                self.candidate = assigned;
+                self.count = 1;
+            }
+            if self.count >= frames {
+                self.committed = assigned;
+                self.count = 0;
+            }
+        }
+        self.committed
+    }
 
-                let screen_3dpoints = ats_cv::calculate_screen_3dpoints(1., 16./9.);
+    pub fn committed(&self) -> Option<usize> {
+        self.committed
+    }
+}
+
+/// Frame to frame association of markers. A marker keeps its track id as long as it's within the
+/// gate of where it was in the previous frame, unlike `mot_id` which is just the slot the
+/// firmware happened to report it in. A marker missing for a frame comes back with a new id.
+#[derive(Clone, Debug, Default)]
+pub struct MarkerTracks {
+    previous: Vec<(u32, Point2<f64>)>,
+    next_id: u32,
+    /// Number of new tracks whose closest previous marker was lost in the same frame, i.e. the
+    /// marker probably jumped further than the gate.
+    pub rejected_jumps: u64,
+}
 
-                return ats_cv::calculate_aimpoint_from_pose_and_screen_3dpoints(&rotation_mat, &translation_mat, &screen_3dpoints);
+impl MarkerTracks {
+    /// Returns the track id of each of `points`. `None` points, markers that weren't matched this
+    /// frame, don't get one. `gate` is in the same units as the points.
+    pub fn associate(&mut self, points: &[Option<Point2<f64>>], gate: f64) -> Vec<Option<u32>> {
+        // Greedy nearest neighbor, closest pairs get matched first
+        let mut pairs = vec![];
+        for (i, p) in points.iter().enumerate() {
+            let Some(p) = p else { continue };
+            for (j, (_, q)) in self.previous.iter().enumerate() {
+                let distance = (p - q).norm();
+                if distance <= gate {
+                    pairs.push((distance, i, j));
+                }
+            }
+        }
+        pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut ids = vec![None; points.len()];
+        let mut taken = vec![false; self.previous.len()];
+        for (_, i, j) in pairs {
+            if ids[i].is_none() && !taken[j] {
+                ids[i] = Some(self.previous[j].0);
+                taken[j] = true;
+            }
+        }
+        // A new track is a rejected jump if the gate is all that kept it from its closest lost one
+        let mut jumped = vec![false; self.previous.len()];
+        for (i, p) in points.iter().enumerate() {
+            let Some(p) = p.filter(|_| ids[i].is_none()) else { continue };
+            let closest = self.previous.iter().enumerate().min_by(|a, b| (p - a.1.1).norm().total_cmp(&(p - b.1.1).norm()));
+            if let Some((j, _)) = closest.filter(|&(j, _)| !taken[j] && !jumped[j]) {
+                jumped[j] = true;
+                self.rejected_jumps += 1;
             }
         }
+        let ids: Vec<Option<u32>> = ids.into_iter().zip(points).map(|(id, p)| p.map(|_| id.unwrap_or_else(|| {
+            self.next_id += 1;
+            self.next_id - 1
+        }))).collect();
+        self.previous = ids.iter().zip(points).filter_map(|(&id, &p)| Some((id?, p?))).collect();
+        ids
+    }
+}
+
+/// Recent positions of every nf marker track, so the trajectory of one can be drawn as soon as
+/// it's selected. A track's history is dropped once the track is lost.
+#[derive(Clone, Debug, Default)]
+pub struct TrackHistories {
+    histories: HashMap<u32, VecDeque<Point2<f64>>>,
+}
+
+impl TrackHistories {
+    /// Number of positions kept for each track.
+    pub const LEN: usize = 60;
+
+    /// `ids` are the track ids of `points`, as returned by `MarkerTracks::associate`.
+    pub fn update(&mut self, ids: &[Option<u32>], points: &[Point2<f64>]) {
+        self.histories.retain(|&id, _| ids.contains(&Some(id)));
+        for (id, &p) in ids.iter().zip(points) {
+            let Some(id) = *id else { continue };
+            let history = self.histories.entry(id).or_default();
+            if history.len() == Self::LEN {
+                history.pop_front();
+            }
+            history.push_back(p);
+        }
+    }
+
+    /// Oldest first.
+    pub fn get(&self, id: u32) -> Option<&VecDeque<Point2<f64>>> {
+        self.histories.get(&id)
+    }
+}
+
+/// The screen ids that markers are tracked from. Markers of other screens, e.g. a neighboring
+/// display, are dropped before anything else sees them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScreenIdFilter(u8);
+
+impl Default for ScreenIdFilter {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl ScreenIdFilter {
+    pub const ALL: Self = Self(u8::MAX);
+    pub const NONE: Self = Self(0);
+
+    pub fn contains(self, screen_id: ScreenId) -> bool {
+        screen_id.is_valid() && self.0 & (1 << screen_id.0) != 0
+    }
+
+    pub fn set(&mut self, screen_id: ScreenId, tracked: bool) {
+        if !screen_id.is_valid() {
+            return;
+        }
+        if tracked {
+            self.0 |= 1 << screen_id.0;
+        } else {
+            self.0 &= !(1 << screen_id.0);
+        }
+    }
+}
+
+/// Pattern indices of the markers that are used for matching.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MarkerMask(u16);
+
+impl Default for MarkerMask {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl MarkerMask {
+    pub const ALL: Self = Self(u16::MAX);
+    pub const NONE: Self = Self(0);
+
+    pub fn contains(self, pattern_ix: usize) -> bool {
+        pattern_ix < 16 && self.0 & (1 << pattern_ix) != 0
+    }
+
+    pub fn set(&mut self, pattern_ix: usize, enabled: bool) {
+        if pattern_ix >= 16 {
+            return;
+        }
+        if enabled {
+            self.0 |= 1 << pattern_ix;
+        } else {
+            self.0 &= !(1 << pattern_ix);
+        }
+    }
+}
+
+/// The identified wf markers that go into `match3`, as (pattern index, index into the wf points).
+fn enabled_wf_markers(wf_marker_ix: &[usize], mask: MarkerMask) -> ArrayVec<(usize, usize), 16> {
+    wf_marker_ix
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|&(pattern_ix, _)| mask.contains(pattern_ix))
+        .take(16)
+        .collect()
+}
+
+/// The part of a sensor that points are accepted from. Points near the edge are often markers
+/// that are partly cut off, so their centroids can't be trusted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PointWindow {
+    /// Fraction of the resolution dropped from each edge.
+    pub margin: f64,
+    /// Scale resolution the sensor reports points in, points go from 0 to one less than it.
+    pub resolution: Vector2<u16>,
+}
+
+impl PointWindow {
+    /// Keeps 400 to 3696 inclusive.
+    pub const NF_DEFAULT: Self = Self { margin: 400. / 4096., resolution: FULL_RESOLUTION };
+    /// The wide field sensor is what sees the markers near the edge of the screen, so keep more of it.
+    pub const WF_DEFAULT: Self = Self { margin: 0.02, resolution: FULL_RESOLUTION };
+
+    /// Both ends of the window are inclusive.
+    pub fn contains(&self, p: Point2<u16>) -> bool {
+        let axis = |v: u16, resolution: u16| {
+            let margin = (self.margin * f64::from(resolution)).round() as u16;
+            (margin..=resolution.saturating_sub(margin)).contains(&v)
+        };
+        axis(p.x, self.resolution.x) && axis(p.y, self.resolution.y)
+    }
+
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        if !(0.0..0.5).contains(&self.margin) {
+            errors.push("edge margin: must be between 0 and 0.5".into());
+        }
+        if self.resolution.x == 0 || self.resolution.y == 0 {
+            errors.push("edge margin: resolution must not be 0".into());
+        }
+    }
+}
+
+/// Side length in pixels of the square sensor the camera models are calibrated against.
+pub const CAMERA_MODEL_RESOLUTION: f32 = 98.;
+
+/// Unscaled sensor resolution, see `PointWindow::resolution`.
+pub const FULL_RESOLUTION: Vector2<u16> = Vector2::new(4096, 4096);
+
+/// Largest coordinate of the sensor space everything after `transform_points` works in, whatever
+/// resolution the sensors report points in.
+pub const SENSOR_FULL_SCALE: f64 = 4095.;
+
+/// Largest coordinate a sensor reporting at `resolution` sends.
+fn max_coordinate(resolution: Vector2<u16>) -> Vector2<f64> {
+    resolution.map(|r| f64::from(r.max(2) - 1))
+}
+
+/// Rescales a point reported at `resolution` to `SENSOR_FULL_SCALE`, without undistorting it.
+pub fn to_full_scale(p: Point2<f64>, resolution: Vector2<u16>) -> Point2<f64> {
+    let max = max_coordinate(resolution);
+    Point2::new(p.x / max.x, p.y / max.y) * SENSOR_FULL_SCALE
+}
+
+/// Returns the principal point of `intrinsics` if it's outside a `resolution` by `resolution`
+/// sensor. Undistorting with such a camera model throws the points all over the place, so it's
+/// most likely a bad calibration.
+pub fn principal_point_outside_sensor(intrinsics: &RosOpenCvIntrinsics<f32>, resolution: f32) -> Option<Point2<f32>> {
+    let principal_point = Point2::new(intrinsics.p.m13, intrinsics.p.m23);
+    let inside = |v: f32| (0.0..=resolution).contains(&v);
+    if inside(principal_point.x) && inside(principal_point.y) {
+        None
+    } else {
+        Some(principal_point)
     }
-    None
 }
 
 fn filter_and_create_point_tuples(
     points: &[Point2<u16>],
-    screen_ids: &[u8],
+    screen_ids: &[ScreenId],
+    window: &PointWindow,
+    tracked_screen_ids: ScreenIdFilter,
 ) -> Vec<(u8, u8, Point2<f64>)> {
     points
         .iter()
         .zip(screen_ids.iter())
         .enumerate()
         .filter_map(|(id, (pos, &screen_id))| {
-            // screen id of 7 means there is no marker
-            if screen_id < 7 && (400..3696).contains(&pos.x) && (400..3696).contains(&pos.y) {
-                Some((screen_id, id as u8, Point2::new(pos.x as f64, pos.y as f64)))
+            if tracked_screen_ids.contains(screen_id) && window.contains(*pos) {
+                Some((screen_id.0, id as u8, Point2::new(pos.x as f64, pos.y as f64)))
             } else {
                 None
             }
@@ -415,19 +1699,164 @@ fn filter_and_create_point_tuples(
         .collect()
 }
 
-fn transform_points(points: &[Point2<f64>], camera_intrinsics: &RosOpenCvIntrinsics<f32>) -> Vec<Point2<f64>> {
-    let scaled_points = points.iter().map(|p| Point2::new(p.x / 4095. * 98., p.y / 4095. * 98.)).collect::<Vec<_>>();
-    let undistorted_points = ats_cv::undistort_points(&ats_cv::ros_opencv_intrinsics_type_convert(camera_intrinsics), &scaled_points);
-    undistorted_points.iter().map(|p| Point2::new(p.x / 98. * 4095., p.y / 98. * 4095.)).collect()
+/// Returns the indices of the first pair of points that are closer together than `min_dist`.
+pub fn find_close_marker_pair(points: &[Point2<f64>], min_dist: f64) -> Option<(usize, usize)> {
+    for i in 0..points.len() {
+        for j in i + 1..points.len() {
+            if nalgebra::distance(&points[i], &points[j]) < min_dist {
+                return Some((i, j));
+            }
+        }
+    }
+    None
+}
+
+/// Undistorts `points` in sensor coordinates with `camera_intrinsics`, whose distortion
+/// coefficients are interpreted according to `model`.
+pub fn transform_points(points: &[Point2<f64>], camera_intrinsics: &RosOpenCvIntrinsics<f32>, model: CameraModelType, resolution: Vector2<u16>) -> Vec<Point2<f64>> {
+    let camera = f64::from(CAMERA_MODEL_RESOLUTION);
+    let scaled_points = points.iter().map(|&p| to_full_scale(p, resolution) / SENSOR_FULL_SCALE * camera).collect::<Vec<_>>();
+    let undistorted_points = match model {
+        CameraModelType::Pinhole => ats_cv::undistort_points(&ats_cv::ros_opencv_intrinsics_type_convert(camera_intrinsics), &scaled_points),
+        CameraModelType::Fisheye => undistort_fisheye(camera_intrinsics, &scaled_points),
+    };
+    undistorted_points.iter().map(|&p| p / camera * SENSOR_FULL_SCALE).collect()
+}
+
+/// k1 to k4 of the equidistant fisheye model, see `CameraModelType::Fisheye`.
+fn fisheye_coefficients(intrinsics: &RosOpenCvIntrinsics<f32>) -> [f64; 4] {
+    let d = &intrinsics.distortion;
+    [d.radial1(), d.radial2(), d.tangential1(), d.tangential2()].map(f64::from)
+}
+
+/// The distorted angle θd = θ(1 + k1θ² + k2θ⁴ + k3θ⁶ + k4θ⁸) and its derivative.
+fn fisheye_theta_d(k: &[f64; 4], theta: f64) -> (f64, f64) {
+    let t2 = theta * theta;
+    let poly = 1. + t2 * (k[0] + t2 * (k[1] + t2 * (k[2] + t2 * k[3])));
+    let dpoly = 1. + t2 * (3. * k[0] + t2 * (5. * k[1] + t2 * (7. * k[2] + t2 * 9. * k[3])));
+    (theta * poly, dpoly)
+}
+
+/// Like `ats_cv::undistort_points` for the equidistant fisheye model. Inverts θd(θ) with a few
+/// Newton steps, the undistorted point is then at tan(θ) from the principal point.
+fn undistort_fisheye(intrinsics: &RosOpenCvIntrinsics<f32>, points: &[Point2<f64>]) -> Vec<Point2<f64>> {
+    let k = fisheye_coefficients(intrinsics);
+    let fx = intrinsics.p.m11 as f64;
+    let fy = intrinsics.p.m22 as f64;
+    let cx = intrinsics.p.m13 as f64;
+    let cy = intrinsics.p.m23 as f64;
+    points.iter().map(|p| {
+        let x = (p.x - cx) / fx;
+        let y = (p.y - cy) / fy;
+        let theta_d = x.hypot(y);
+        if theta_d < 1e-12 {
+            return *p;
+        }
+        let mut theta = theta_d;
+        for _ in 0..10 {
+            let (f, df) = fisheye_theta_d(&k, theta);
+            theta -= (f - theta_d) / df;
+        }
+        let scale = theta.tan() / theta_d;
+        Point2::new(x * scale * fx + cx, y * scale * fy + cy)
+    }).collect()
+}
+
+/// Why the distortion coefficients of `intrinsics` can't be of a `model` camera, if they can't.
+pub fn distortion_mismatch(intrinsics: &RosOpenCvIntrinsics<f32>, model: CameraModelType) -> Option<String> {
+    match model {
+        CameraModelType::Pinhole => None,
+        CameraModelType::Fisheye => {
+            let k3 = intrinsics.distortion.radial3();
+            if k3 != 0. {
+                return Some(format!("a fisheye model has four distortion coefficients, but the fifth is {k3}"));
+            }
+            // θd(θ) has to keep increasing up to 90° off axis, otherwise it can't be inverted
+            let k = fisheye_coefficients(intrinsics);
+            let folds = (0..=90).any(|deg| fisheye_theta_d(&k, f64::from(deg).to_radians()).1 <= 0.);
+            folds.then(|| "the fisheye distortion folds back on itself within 90° of the optical axis".into())
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImuDt {
+    /// The timestamp went backwards, skip the sample and start over.
+    Reset,
+    /// No usable previous sample, predict with the nominal sample period.
+    Nominal,
+    Elapsed(Duration),
+}
+
+/// How far to predict the eskf for an IMU sample at `timestamp` (µs). Gaps longer than `max_dt`,
+/// e.g. after a pause, are predicted as a single nominal sample period instead so one huge step
+/// doesn't destabilize the filter.
+pub fn imu_prediction_dt(prev_timestamp: Option<u32>, timestamp: u32, nominal: Duration, max_dt: Duration) -> ImuDt {
+    let Some(prev_timestamp) = prev_timestamp else {
+        return ImuDt::Nominal;
+    };
+    if timestamp < prev_timestamp {
+        return ImuDt::Reset;
+    }
+    let elapsed = Duration::from_micros(u64::from(timestamp - prev_timestamp));
+    if elapsed > max_dt {
+        debug!("imu gap of {elapsed:?}, clamping prediction to {nominal:?}");
+        ImuDt::Nominal
+    } else {
+        ImuDt::Elapsed(elapsed)
+    }
 }
 
 async fn accel_stream(runner: Arc<Mutex<MotRunner>>) {
     let device = runner.lock().device.c().unwrap();
     let mut accel_stream = device.stream_accel().await.unwrap();
     let mut prev_timestamp = None;
+    let mut last_imu_readout: Option<std::time::Instant> = None;
+    let mut scale_off = false;
+    let mut prev_quat = None;
     while runner.lock().device.is_some() {
         if let Some(accel) = accel_stream.next().await {
             let mut runner = runner.lock();
+
+            let now = runner.clock.now();
+            runner.clock_drift.on_sample(accel.timestamp, now);
+            if last_imu_readout.map_or(true, |t| now.duration_since(t) >= IMU_READOUT_PERIOD) {
+                last_imu_readout = Some(now);
+                let imu_readout = runner.imu_readout;
+                let clock_drift = runner.clock_drift.report();
+                let clock_drift_readout = runner.clock_drift_readout;
+                runner.queue_main(move || {
+                    leptos_reactive::SignalSet::set(&imu_readout, Some(accel));
+                    leptos_reactive::SignalSet::set(&clock_drift_readout, clock_drift);
+                });
+                if runner.madgwick_readout_enabled {
+                    let quat = runner.state.madgwick.quat;
+                    let rate = match prev_quat {
+                        Some((prev, t)) => {
+                            let dt = now.duration_since(t).as_secs_f32();
+                            if dt > 0. { quat.angle_to(&prev).to_degrees() / dt } else { 0. }
+                        }
+                        None => 0.,
+                    };
+                    prev_quat = Some((quat, now));
+                    let readout = MadgwickReadout { quat, rate, sample_period: runner.state.madgwick.sample_period() };
+                    let madgwick_readout = runner.madgwick_readout;
+                    runner.queue_main(move || {
+                        leptos_reactive::SignalSet::set(&madgwick_readout, Some(readout));
+                    });
+                } else {
+                    prev_quat = None;
+                }
+            }
+            // Only warn once per excursion, it's checked with every sample
+            match runner.accel_scale_check.check(&accel) {
+                AccelScale::Off(magnitude) if !scale_off => {
+                    warn!("accel magnitude is {magnitude:.3} g at rest, the accelerometer may be mis-scaled");
+                    scale_off = true;
+                }
+                AccelScale::Ok(_) => scale_off = false,
+                _ => (),
+            }
             let accel_odr = runner.general_config.accel_odr;
             // println!("{:7.3?} {:7.3?}", accel.accel.xzy(), accel.gyro.xzy());
             // println!("{:7.3?}", accel.accel.norm());
@@ -435,22 +1864,24 @@ async fn accel_stream(runner: Arc<Mutex<MotRunner>>) {
             // print rotation in degrees
             // println!("Rotation: {}", accel.gyro.xzy().map(|x| x.to_degrees()));
 
-            if let Some(_prev_timestamp) = prev_timestamp {
-                if accel.timestamp < _prev_timestamp {
+            let nominal_dt = Duration::from_secs_f32(1./accel_odr as f32);
+            runner.accel_sequence.on_timestamp(accel.timestamp, nominal_dt);
+            match imu_prediction_dt(prev_timestamp, accel.timestamp, nominal_dt, runner.max_imu_dt) {
+                ImuDt::Reset => {
+                    // Counted in `accel_sequence`
+                    debug!("accel timestamp went back from {prev_timestamp:?} to {}, restarting", accel.timestamp);
                     prev_timestamp = None;
                     continue;
                 }
-            }
-
-            if let Some(prev_timestamp) = prev_timestamp {
-                let elapsed = accel.timestamp as u64 - prev_timestamp as u64;
-                // println!("elapsed: {}", elapsed);
-                runner.state.fv_state.predict(-accel.accel.xzy(), -accel.gyro.xzy(), Duration::from_micros(elapsed));
+                ImuDt::Nominal => {
+                    runner.state.fv_state.predict(-accel.accel.xzy(), -accel.gyro.xzy(), nominal_dt);
+                }
+                ImuDt::Elapsed(elapsed) => {
+                    runner.state.fv_state.predict(-accel.accel.xzy(), -accel.gyro.xzy(), elapsed);
 
-                let sample_period = runner.state.madgwick.sample_period_mut();
-                *sample_period = elapsed as f32/1_000_000.;
-            } else {
-                runner.state.fv_state.predict(-accel.accel.xzy(), -accel.gyro.xzy(), Duration::from_secs_f32(1./accel_odr as f32));
+                    let sample_period = runner.state.madgwick.sample_period_mut();
+                    *sample_period = elapsed.as_secs_f32();
+                }
             }
             prev_timestamp = Some(accel.timestamp);
 
@@ -458,18 +1889,11 @@ async fn accel_stream(runner: Arc<Mutex<MotRunner>>) {
             runner.state.orientation = runner.state.madgwick.quat.to_rotation_matrix();
 
             ats_cv::series_add!(imu_data, (-accel.accel.xzy().cast(), -accel.gyro.xzy().cast()));
+            runner.redraw_pending = true;
 
-            let (rotmat, transmat, fv_aimpoint) = get_raycast_aimpoint(&runner.state.fv_state);
+            runner.update_raycast_aimpoint();
 
-            runner.state.rotation_mat = rotmat.cast();
-            runner.state.translation_mat = transmat.coords.cast();
-            if let Some(fv_aimpoint) = fv_aimpoint {
-                runner.state.fv_aimpoint = fv_aimpoint.cast();
-            }
-
-            if runner.record_packets {
-                runner.packets.lock().push((std::time::SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(), ats_usb::packet::PacketData::AccelReport(accel)));
-            }
+            record(&mut runner, ats_usb::packet::PacketData::AccelReport(accel));
         }
     }
 }
@@ -478,13 +1902,35 @@ async fn accel_stream(runner: Arc<Mutex<MotRunner>>) {
 async fn impact_loop(runner: Arc<Mutex<MotRunner>>) {
     let device = runner.lock().device.c().unwrap();
     let mut impact_stream = device.stream_impact().await.unwrap();
+    let mut simulated_impacts = runner.lock().simulated_impacts.subscribe();
     while runner.lock().device.is_some() {
-        if let Some(_impact) = impact_stream.next().await {
-            let runner = runner.lock();
-            if runner.record_impact {
+        let impact = tokio::select! {
+            impact = impact_stream.next() => impact,
+            impact = simulated_impacts.recv() => impact.ok(),
+        };
+        if let Some(impact) = impact {
+            let runner_arc = &runner;
+            let mut runner = runner.lock();
+            let now = runner.clock.now();
+            if !runner.impact_debounce.accept(impact.timestamp) {
+                debug!("ignoring impact within the debounce window");
+                continue;
+            }
+            if runner.impact_arming.on_impact(now) {
+                let timestamp = runner.clock.unix_millis();
+                if let Some(capture) = &mut runner.impact_capture {
+                    if capture.trigger(timestamp) {
+                        tokio::spawn(flush_impact_capture(runner_arc.c(), timestamp, capture.post_roll));
+                    }
+                }
+                if runner.datapoints.is_locked() {
+                    continue;
+                }
+
                 let mut frame = TestFrame {
                     fv_aimpoint_x: None,
                     fv_aimpoint_y: None,
+                    region: None,
                 };
 
                 {
@@ -492,17 +1938,21 @@ async fn impact_loop(runner: Arc<Mutex<MotRunner>>) {
                     let fv_aimpoint = fv_aimpoint;
                     frame.fv_aimpoint_x = Some(fv_aimpoint.x);
                     frame.fv_aimpoint_y = Some(fv_aimpoint.y);
-                }
-
-                if runner.datapoints.is_locked() {
-                    continue;
+                    frame.region = runner.target_regions.classify(fv_aimpoint).map(|i| runner.target_regions.regions[i].name.clone());
                 }
 
                 runner.datapoints.lock().push(frame);
+                runner.impact_arming.on_recorded(now);
+                let fv_aimpoint = runner.state.fv_aimpoint_history[runner.state.fv_aimpoint_history_index];
+                runner.shot_markers.push(now, fv_aimpoint);
+                if let Some(server) = &runner.aimpoint_server {
+                    server.publish(&AimpointMessage::Impact { x: fv_aimpoint.x, y: fv_aimpoint.y, screen_id: runner.state.screen_id });
+                }
+                runner.redraw_pending = true;
 
                 let ui_update = runner.ui_update.c();
 
-                runner.ui_ctx.queue_main(move || {
+                runner.queue_main(move || {
                     leptos_reactive::SignalSet::set(&ui_update, ());
                 });
             }
@@ -510,6 +1960,28 @@ async fn impact_loop(runner: Arc<Mutex<MotRunner>>) {
     }
 }
 
+/// Writes the impact capture started at `trigger` once its post-roll is over.
+async fn flush_impact_capture(runner: Arc<Mutex<MotRunner>>, trigger: u128, post_roll: Duration) {
+    // Give the last packets of the post-roll a moment to arrive
+    sleep(post_roll + Duration::from_millis(100)).await;
+    let (path, general_config, packets) = {
+        let mut runner = runner.lock();
+        let general_config = runner.general_config.clone();
+        let Some(capture) = &mut runner.impact_capture else { return };
+        let Some(packets) = capture.finish(trigger) else { return };
+        (capture.path_for(trigger), general_config, packets)
+    };
+    let result = tokio::task::spawn_blocking({
+        let path = path.clone();
+        move || ats_playback::write_file(&path, &general_config, &packets, false)
+    }).await;
+    match result {
+        Ok(Ok(())) => info!("wrote impact capture to {}", path.display()),
+        Ok(Err(e)) => error!("failed to write impact capture to {}: {e}", path.display()),
+        Err(e) => error!("impact capture writer for {} failed: {e}", path.display()),
+    }
+}
+
 pub fn rescale(val: f64) -> f64 {
     rescale_generic(-2047.0, 2047.0, 0.0, 1.0, val)
 }
@@ -545,3 +2017,857 @@ fn _dummy_nf_data() -> [MotData; 4] {
         },
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ats_usb::packet::{AccelReport, ScreenId};
+    use nalgebra::{Point2, Point3, UnitQuaternion, Vector2, Vector3};
+
+    #[test]
+    fn test_find_close_marker_pair() {
+        let points = [
+            Point2::new(-0.2, -0.1),
+            Point2::new(0.1, 0.05),
+            Point2::new(0.1005, 0.0502),
+            Point2::new(0.3, 0.2),
+        ];
+        assert_eq!(super::find_close_marker_pair(&points, 0.01), Some((1, 2)));
+        assert_eq!(super::find_close_marker_pair(&points[..2], 0.01), None);
+    }
+
+    #[test]
+    fn test_rectangle_quality() {
+        let perfect = [
+            Point2::new(0., 0.),
+            Point2::new(2., 0.),
+            Point2::new(2., 1.),
+            Point2::new(0., 1.),
+        ];
+        let quality = super::rectangle_quality(&perfect);
+        assert!(quality.max_angle_error < 1e-9);
+        assert!((quality.side_ratio - 1.).abs() < 1e-9);
+
+        let skewed = [
+            Point2::new(0., 0.),
+            Point2::new(2., 0.),
+            Point2::new(2.5, 1.),
+            Point2::new(0., 1.5),
+        ];
+        let quality = super::rectangle_quality(&skewed);
+        assert!(quality.max_angle_error > 10.);
+        assert!(quality.side_ratio < 0.9);
+    }
+
+    #[test]
+    fn test_impacts_ignored_while_disarmed() {
+        let now = std::time::Instant::now();
+        let mut arming = super::ImpactArming {
+            max_shots: Some(2),
+            ..Default::default()
+        };
+        assert!(!arming.on_impact(now));
+
+        arming.arm(now);
+        // Impacts that weren't recorded don't count
+        assert!(arming.on_impact(now));
+        assert!(arming.on_impact(now));
+        assert!(arming.on_impact(now));
+        arming.on_recorded(now);
+        assert!(arming.on_impact(now));
+        arming.on_recorded(now);
+        // auto-disarmed after 2 shots
+        assert!(!arming.on_impact(now));
+
+        arming.arm(now);
+        arming.disarm();
+        assert!(!arming.on_impact(now));
+
+        arming.idle_timeout = Some(std::time::Duration::from_secs(10));
+        arming.arm(now);
+        let later = now + std::time::Duration::from_secs(8);
+        assert!(arming.on_impact(later));
+        arming.on_recorded(later);
+        // The timeout restarts from the last recorded impact
+        assert!(arming.on_impact(now + std::time::Duration::from_secs(16)));
+        assert!(!arming.on_impact(now + std::time::Duration::from_secs(19)));
+    }
+
+    #[test]
+    fn test_frame_average() {
+        use ats_usb::packet::MotData;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let blob = |cx: u16, cy: u16| MotData { area: 20, cx, cy, boundary_left: 40, boundary_right: 44, ..Default::default() };
+        let mut average = super::FrameAverage::default();
+        let mut averaged = [MotData::default(); 16];
+        for _ in 0..64 {
+            let mut frame = [MotData::default(); 16];
+            frame[0] = blob(1000 + rng.gen_range(0, 9) - 4, 2000 + rng.gen_range(0, 9) - 4);
+            averaged = average.push(frame, 64);
+        }
+        assert_eq!(average.len(), 64);
+        assert!((i32::from(averaged[0].cx) - 1000).abs() <= 1, "{}", averaged[0].cx);
+        assert!((i32::from(averaged[0].cy) - 2000).abs() <= 1, "{}", averaged[0].cy);
+        assert_eq!((averaged[0].boundary_left, averaged[0].boundary_right), (40, 44));
+        assert_eq!(averaged[1].area, 0);
+
+        // Only the last N frames count
+        let mut frame = [MotData::default(); 16];
+        frame[0] = blob(1010, 2010);
+        average.push(frame, 4);
+        assert_eq!(average.len(), 4);
+
+        // Moving the device starts over
+        frame[0] = blob(1500, 2000);
+        assert_eq!(average.push(frame, 64)[0].cx, 1500);
+        assert_eq!(average.len(), 1);
+    }
+
+    #[test]
+    fn test_accel_scale_check() {
+        let check = super::AccelScaleCheck::default();
+        let report = |accel: Vector3<f32>, gyro: Vector3<f32>| AccelReport { accel, gyro, ..Default::default() };
+
+        let still = Vector3::zeros();
+        assert!(matches!(check.check(&report(Vector3::new(0., super::G, 0.), still)), super::AccelScale::Ok(_)));
+        assert!(matches!(check.check(&report(Vector3::new(0., 0., 1.04 * super::G), still)), super::AccelScale::Ok(_)));
+        assert!(matches!(check.check(&report(Vector3::new(0., 0., 1.1 * super::G), still)), super::AccelScale::Off(_)));
+        // Scaled as if it were in g instead of m/s^2
+        assert!(matches!(check.check(&report(Vector3::new(0., 1., 0.), still)), super::AccelScale::Off(_)));
+
+        // Rotating at 10 dps, the magnitude isn't checked
+        let rotating = Vector3::new(0., 10f32.to_radians(), 0.);
+        assert_eq!(check.check(&report(Vector3::new(0., 2. * super::G, 0.), rotating)), super::AccelScale::Moving);
+        // Slow enough to count as still
+        let drifting = Vector3::new(0., 1f32.to_radians(), 0.);
+        assert!(matches!(check.check(&report(Vector3::new(0., 2. * super::G, 0.), drifting)), super::AccelScale::Off(_)));
+    }
+
+    #[test]
+    fn test_impact_debounce() {
+        let t0 = 1_000_000u32;
+        let ms = |ms: u32| t0 + ms * 1000;
+        let mut debounce = super::ImpactDebounce { window: Duration::from_millis(50), ..Default::default() };
+        assert!(debounce.accept(ms(0)));
+        // Ringing from the same impact
+        assert!(!debounce.accept(ms(10)));
+        assert!(!debounce.accept(ms(40)));
+        // A separate shot just past the window, measured from the accepted impact
+        assert!(debounce.accept(ms(60)));
+        assert!(debounce.accept(ms(120)));
+
+        debounce.window = Duration::ZERO;
+        assert!(debounce.accept(ms(120)));
+
+        // Across the timestamp wrapping around
+        let mut debounce = super::ImpactDebounce::default();
+        assert!(debounce.accept(u32::MAX - 10_000));
+        assert!(!debounce.accept(20_000));
+        assert!(debounce.accept(50_000));
+        // The device clock was reset
+        assert!(debounce.accept(1_000));
+    }
+
+    #[test]
+    fn test_imu_prediction_dt() {
+        use super::{imu_prediction_dt, ImuDt};
+
+        let nominal = Duration::from_millis(10);
+        let max_dt = Duration::from_millis(100);
+        assert_eq!(imu_prediction_dt(None, 1_000_000, nominal, max_dt), ImuDt::Nominal);
+        assert_eq!(imu_prediction_dt(Some(1_000_000), 1_010_000, nominal, max_dt), ImuDt::Elapsed(nominal));
+        assert_eq!(imu_prediction_dt(Some(1_000_000), 1_100_000, nominal, max_dt), ImuDt::Elapsed(max_dt));
+        // 5 second pause
+        assert_eq!(imu_prediction_dt(Some(1_000_000), 6_000_000, nominal, max_dt), ImuDt::Nominal);
+        assert_eq!(imu_prediction_dt(Some(1_000_000), 999_000, nominal, max_dt), ImuDt::Reset);
+    }
+
+    #[test]
+    fn test_record_mask() {
+        use ats_usb::packet::{AccelReport, CombinedMarkersReport, PacketData, PacketType};
+        use parking_lot::Mutex;
+
+        let mut mask = super::RecordMask::NONE;
+        mask.set(PacketType::CombinedMarkersReport, true);
+        let packets = Mutex::new(vec![]);
+        super::record_packet(&packets, mask, 0, PacketData::AccelReport(AccelReport::default()));
+        super::record_packet(&packets, mask, 0, PacketData::CombinedMarkersReport(CombinedMarkersReport::default()));
+        super::record_packet(&packets, mask, 0, PacketData::AccelReport(AccelReport::default()));
+        let packets = packets.into_inner();
+        assert_eq!(packets.len(), 1);
+        assert!(matches!(packets[0].1, PacketData::CombinedMarkersReport(_)));
+
+        mask.set(PacketType::CombinedMarkersReport, false);
+        assert_eq!(mask, super::RecordMask::NONE);
+    }
+
+    #[test]
+    fn test_marker_tracks() {
+        let mut tracks = super::MarkerTracks::default();
+        let frame1 = [(1000., 1000.), (2000., 1000.), (1500., 2000.)].map(|(x, y)| Some(Point2::new(x, y)));
+        let ids1 = tracks.associate(&frame1, 100.);
+        assert_eq!(ids1, [Some(0), Some(1), Some(2)]);
+
+        // Moved slightly and reported in a different order
+        let frame2 = [(1510., 2020.), (1010., 990.), (2030., 1005.)].map(|(x, y)| Some(Point2::new(x, y)));
+        assert_eq!(tracks.associate(&frame2, 100.), [Some(2), Some(0), Some(1)]);
+
+        // One jumps out of the gate and one disappears
+        let frame3 = [(1020., 980.), (3000., 3000.)].map(|(x, y)| Some(Point2::new(x, y)));
+        assert_eq!(tracks.associate(&frame3, 100.), [Some(0), Some(3)]);
+        assert_eq!(tracks.rejected_jumps, 1);
+    }
+
+    #[test]
+    fn test_transform_points_without_distortion() {
+        use opencv_ros_camera::RosOpenCvIntrinsics;
+        use super::transform_points;
+
+        // from_params has no distortion coefficients
+        let intrinsics = RosOpenCvIntrinsics::from_params(145., 0., 145., 45., 45.);
+        let points = [Point2::new(0., 0.), Point2::new(2047.5, 2047.5), Point2::new(4095., 100.), Point2::new(300., 3900.)];
+        let undistorted = transform_points(&points, &intrinsics, ats_usb::packet::CameraModelType::Pinhole, super::FULL_RESOLUTION);
+        assert_eq!(undistorted.len(), points.len());
+        for (p, u) in points.iter().zip(&undistorted) {
+            assert!((p - u).norm() < 1e-6, "{p} -> {u}");
+        }
+    }
+
+    #[test]
+    fn test_transform_points_scaled_resolution() {
+        use opencv_ros_camera::{Distortion, RosOpenCvIntrinsics};
+        use super::{transform_points, FULL_RESOLUTION, SENSOR_FULL_SCALE};
+
+        // A sensor scaled to 2048 reports the same view in half the units
+        let half = Vector2::new(2048, 2048);
+        let points = [Point2::new(0., 0.), Point2::new(1023.5, 1023.5), Point2::new(2047., 50.), Point2::new(150., 1950.)];
+        let intrinsics = RosOpenCvIntrinsics::from_params(145., 0., 145., 45., 45.);
+        let undistorted = transform_points(&points, &intrinsics, ats_usb::packet::CameraModelType::Pinhole, half);
+        for (p, u) in points.iter().zip(&undistorted) {
+            let expected = *p * SENSOR_FULL_SCALE / 2047.;
+            assert!((expected - *u).norm() < 1e-6, "{p} -> {u}");
+            assert!((0.0..=SENSOR_FULL_SCALE).contains(&u.x) && (0.0..=SENSOR_FULL_SCALE).contains(&u.y), "{u}");
+        }
+
+        // With distortion, the same point at either resolution undistorts to the same place
+        let distortion = Distortion::from_opencv_vec(nalgebra::Vector5::new(-0.1, 0.02, 0.001, -0.001, 0.));
+        let intrinsics = RosOpenCvIntrinsics::from_params_with_distortion(145., 0., 145., 49., 49., distortion);
+        let full: Vec<_> = points.iter().map(|&p| p * 4095. / 2047.).collect();
+        let at_half = transform_points(&points, &intrinsics, ats_usb::packet::CameraModelType::Pinhole, half);
+        let at_full = transform_points(&full, &intrinsics, ats_usb::packet::CameraModelType::Pinhole, FULL_RESOLUTION);
+        for (a, b) in at_half.iter().zip(&at_full) {
+            assert!((a - b).norm() < 1e-6, "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn test_transform_points_fisheye() {
+        use ats_usb::packet::CameraModelType;
+        use opencv_ros_camera::{Distortion, RosOpenCvIntrinsics};
+        use super::{distortion_mismatch, transform_points};
+
+        let k = [0.05f32, -0.02, 0.004, -0.001];
+        let distortion = Distortion::from_opencv_vec(nalgebra::Vector5::new(k[0], k[1], k[2], k[3], 0.));
+        let k = k.map(f64::from);
+        let intrinsics = RosOpenCvIntrinsics::from_params_with_distortion(34., 0., 34., 49., 49., distortion);
+        assert_eq!(distortion_mismatch(&intrinsics, CameraModelType::Fisheye), None);
+
+        // Distort ideal points with the fisheye model, in the 98 unit sensor space, and check
+        // they come back
+        let ideal = [Point2::new(49., 49.), Point2::new(60., 40.), Point2::new(10., 85.), Point2::new(90., 12.)];
+        let distorted: Vec<_> = ideal.iter().map(|p| {
+            let (x, y) = ((p.x - 49.) / 34., (p.y - 49.) / 34.);
+            let r = x.hypot(y);
+            if r == 0. {
+                return *p * 4095. / 98.;
+            }
+            let theta = r.atan();
+            let t2 = theta * theta;
+            let theta_d = theta * (1. + k[0] * t2 + k[1] * t2 * t2 + k[2] * t2.powi(3) + k[3] * t2.powi(4));
+            let s = theta_d / r;
+            Point2::new(x * s * 34. + 49., y * s * 34. + 49.) * 4095. / 98.
+        }).collect();
+        let undistorted = transform_points(&distorted, &intrinsics, CameraModelType::Fisheye, super::FULL_RESOLUTION);
+        for (p, u) in ideal.iter().zip(&undistorted) {
+            let p = *p * 4095. / 98.;
+            assert!((p - u).norm() < 1e-3, "{p} -> {u}");
+        }
+
+        // The same coefficients read as a plumb bob model land somewhere else
+        let pinhole = transform_points(&distorted, &intrinsics, CameraModelType::Pinhole, super::FULL_RESOLUTION);
+        assert!((pinhole[2] - undistorted[2]).norm() > 1.);
+
+        // A plumb bob calibration with k3 can't be a fisheye one
+        let distortion = Distortion::from_opencv_vec(nalgebra::Vector5::new(-0.3, 0.1, 0.001, 0.002, -0.02));
+        let plumb_bob = RosOpenCvIntrinsics::from_params_with_distortion(145., 0., 145., 45., 45., distortion);
+        assert_eq!(distortion_mismatch(&plumb_bob, CameraModelType::Pinhole), None);
+        assert!(distortion_mismatch(&plumb_bob, CameraModelType::Fisheye).is_some());
+    }
+
+    #[test]
+    fn test_marker_jump_rejected() {
+        let mut tracks = super::MarkerTracks::default();
+        let frame1 = [Some(Point2::new(1000., 1000.)), Some(Point2::new(2000., 1000.))];
+        assert_eq!(tracks.associate(&frame1, 100.), [Some(0), Some(1)]);
+        // A glitched centroid a quarter of the sensor away doesn't keep the track
+        let frame2 = [Some(Point2::new(1000., 1000.)), Some(Point2::new(2000., 2000.))];
+        assert_eq!(tracks.associate(&frame2, 100.), [Some(0), Some(2)]);
+        assert_eq!(tracks.rejected_jumps, 1);
+
+        // Markers appearing without any being lost aren't jumps
+        let frame3 = [Some(Point2::new(1000., 1000.)), Some(Point2::new(2000., 2000.)), Some(Point2::new(3000., 3000.))];
+        assert_eq!(tracks.associate(&frame3, 100.), [Some(0), Some(2), Some(3)]);
+        assert_eq!(tracks.rejected_jumps, 1);
+
+        // Neither is a marker going unmatched and coming back
+        let frame4 = [Some(Point2::new(1000., 1000.)), None, Some(Point2::new(3000., 3000.))];
+        assert_eq!(tracks.associate(&frame4, 100.), [Some(0), None, Some(3)]);
+        assert_eq!(tracks.associate(&frame3, 100.), [Some(0), Some(4), Some(3)]);
+        assert_eq!(tracks.rejected_jumps, 1);
+    }
+
+    #[test]
+    fn test_track_histories() {
+        let mut histories = super::TrackHistories::default();
+        histories.update(&[Some(0), Some(1)], &[Point2::new(0., 0.), Point2::new(100., 0.)]);
+        histories.update(&[Some(1), Some(0)], &[Point2::new(101., 0.), Point2::new(1., 0.)]);
+        assert_eq!(histories.get(0).unwrap().iter().copied().collect::<Vec<_>>(), [Point2::new(0., 0.), Point2::new(1., 0.)]);
+        assert_eq!(histories.get(1).unwrap().len(), 2);
+
+        // Track 1 is lost
+        histories.update(&[Some(0), None], &[Point2::new(2., 0.), Point2::new(-9999., -9999.)]);
+        assert!(histories.get(1).is_none());
+
+        for i in 0..2 * super::TrackHistories::LEN {
+            histories.update(&[Some(0)], &[Point2::new(i as f64, 1.)]);
+        }
+        let history = histories.get(0).unwrap();
+        assert_eq!(history.len(), super::TrackHistories::LEN);
+        assert_eq!(history.back(), Some(&Point2::new((2 * super::TrackHistories::LEN - 1) as f64, 1.)));
+    }
+
+    #[test]
+    fn test_general_config_swapped_whole() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use ats_usb::packet::{CameraModelType, GeneralConfig, GeneralWriteConfig};
+        use parking_lot::Mutex;
+
+        let runtime = leptos_reactive::create_runtime();
+        let write = |n: u8| GeneralWriteConfig {
+            impact_threshold: n,
+            accel_odr: u16::from(n) * 100,
+            camera_model_nf: GeneralConfig::default().camera_model_nf,
+            camera_model_wf: GeneralConfig::default().camera_model_wf,
+            stereo_iso: nalgebra::Isometry3::translation(f32::from(n), 0., 0.),
+        };
+        let runner = Arc::new(Mutex::new(super::MotRunner::new(None)));
+        runner.lock().set_general_config(super::general_config_with_write(&GeneralConfig::default(), write(1)));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let reader = std::thread::spawn({
+            let runner = runner.clone();
+            let done = done.clone();
+            move || {
+                while !done.load(Ordering::Relaxed) {
+                    let c = runner.lock().general_config();
+                    assert_eq!(c.accel_odr, u16::from(c.impact_threshold) * 100);
+                    assert_eq!(c.stereo_iso.translation.x, f32::from(c.impact_threshold));
+                }
+            }
+        });
+        // Selecting a model type while configs are being applied
+        let model_type = std::thread::spawn({
+            let runner = runner.clone();
+            move || runner.lock().update_general_config(|config| config.camera_model_type_wf = CameraModelType::Fisheye)
+        });
+        for i in 0..10000 {
+            let n = (i % 2 + 1) as u8;
+            // Same as the config window's apply
+            runner.lock().update_general_config(|config| *config = super::general_config_with_write(config, write(n)));
+        }
+        model_type.join().unwrap();
+        done.store(true, Ordering::Relaxed);
+        reader.join().unwrap();
+        // Applying a write doesn't undo the model type
+        assert_eq!(runner.lock().general_config().camera_model_type_wf, CameraModelType::Fisheye);
+        runtime.dispose();
+    }
+
+    #[test]
+    fn test_runners_independent() {
+        use ats_usb::packet::{AccelReport, PacketData};
+
+        let runtime = leptos_reactive::create_runtime();
+        let mut a = super::MotRunner::new(None);
+        let mut b = super::MotRunner::new(None);
+
+        // Only a has a pose, connection errors, packets and a marker count, only b gets a new
+        // stereo calibration
+        a.state.pnp_solution = Some(nalgebra::Isometry3::translation(0., 0., 2.));
+        a.on_connection_error();
+        b.update_general_config(|config| config.stereo_iso = nalgebra::Isometry3::translation(0.1, 0., 0.));
+        a.packets.lock().push((0, PacketData::AccelReport(AccelReport::default())));
+        let status = crate::setup_assistant::MarkerCountStatus { nf_detected: 4, wf_detected: 4, expected: 4, complete: true };
+        leptos_reactive::SignalSet::set(&a.marker_count, Some(status));
+
+        assert_eq!(b.state.pnp_solution, None);
+        let now = a.clock.now();
+        assert_eq!(a.health.report(now, &a.health_thresholds).errors, 1);
+        assert_eq!(b.health.report(now, &b.health_thresholds).errors, 0);
+        assert_eq!(a.general_config().stereo_iso, nalgebra::Isometry3::identity());
+        assert_eq!(b.general_config().stereo_iso.translation.x, 0.1);
+        assert!(b.packets.lock().is_empty());
+        assert_eq!(leptos_reactive::SignalGetUntracked::get_untracked(&b.marker_count), None);
+        runtime.dispose();
+    }
+
+    #[test]
+    fn test_runner_on_mock_clock() {
+        use std::sync::Arc;
+        use std::time::UNIX_EPOCH;
+
+        use crate::clock::{Clock, MockClock};
+        use crate::connection_health::HealthStatus;
+
+        let runtime = leptos_reactive::create_runtime();
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        let mut runner = super::MotRunner { clock: clock.clone(), ..super::MotRunner::new(None) };
+
+        // Errors age out of the error window on the runner's clock
+        for _ in 0..200 {
+            runner.health.on_packet(runner.clock.now());
+            clock.advance(Duration::from_millis(10));
+        }
+        runner.on_connection_error();
+        let report = |runner: &super::MotRunner| runner.health.report(runner.clock.now(), &runner.health_thresholds);
+        assert_eq!(report(&runner).errors, 1);
+        clock.advance(runner.health_thresholds.error_window + Duration::from_millis(1));
+        assert_eq!(report(&runner).errors, 0);
+        assert_eq!(report(&runner).status, HealthStatus::Bad);
+
+        // Simulated impacts are stamped from the clock, so they're only debounced when the clock
+        // hasn't moved past the window
+        runner.allow_simulated_impacts = true;
+        let mut impacts = runner.simulated_impacts.subscribe();
+        runner.simulate_impact();
+        let first = impacts.try_recv().unwrap();
+        assert!(runner.impact_debounce.accept(first.timestamp));
+        runner.simulate_impact();
+        assert!(!runner.impact_debounce.accept(impacts.try_recv().unwrap().timestamp));
+        clock.advance(runner.impact_debounce.window + Duration::from_millis(1));
+        runner.simulate_impact();
+        let later = impacts.try_recv().unwrap();
+        assert_eq!(later.timestamp.wrapping_sub(first.timestamp), (runner.impact_debounce.window.as_micros() + 1000) as u32);
+        assert!(runner.impact_debounce.accept(later.timestamp));
+
+        // Arming times out after idling on the clock
+        runner.impact_arming.idle_timeout = Some(Duration::from_secs(10));
+        runner.impact_arming.arm(runner.clock.now());
+        clock.advance(Duration::from_secs(9));
+        assert!(runner.impact_arming.on_impact(runner.clock.now()));
+        clock.advance(Duration::from_secs(2));
+        assert!(!runner.impact_arming.on_impact(runner.clock.now()));
+        runtime.dispose();
+    }
+
+    #[test]
+    fn test_impact_capture_boundaries() {
+        use ats_usb::packet::{AccelReport, PacketData};
+
+        let packet = || PacketData::AccelReport(AccelReport::default());
+        let timestamps = |packets: Vec<(u128, PacketData)>| packets.iter().map(|&(t, _)| t).collect::<Vec<_>>();
+        let mut capture = super::ImpactCapture::new("capture.bin".into(), Duration::from_millis(100), Duration::from_millis(50));
+        for t in (0..=1000).step_by(10) {
+            capture.push(t, packet());
+        }
+        assert!(capture.trigger(1000));
+        // A second impact during the post-roll doesn't restart it
+        assert!(!capture.trigger(1020));
+        for t in (1010..=1060).step_by(10) {
+            capture.push(t, packet());
+        }
+        assert!(capture.finish(1020).is_none());
+        let packets = capture.finish(1000).unwrap();
+        assert_eq!(timestamps(packets), (900..=1050).step_by(10).collect::<Vec<_>>());
+        assert_eq!(capture.path_for(1000), std::path::PathBuf::from("capture-1000.bin"));
+        assert!(capture.finish(1000).is_none());
+
+        // Packets past the post-roll are part of the next pre-roll
+        assert!(capture.trigger(1100));
+        capture.push(1151, packet());
+        assert_eq!(timestamps(capture.finish(1100).unwrap()), [1060]);
+    }
+
+    #[test]
+    fn test_shot_markers_persistence() {
+        let t0 = std::time::Instant::now();
+        let mut shots = super::ShotMarkers::default();
+        shots.push(t0, Point2::new(0.1, 0.1));
+        shots.push(t0 + Duration::from_secs(5), Point2::new(0.2, 0.2));
+
+        // Kept until cleared by default
+        assert_eq!(shots.visible(t0 + Duration::from_secs(600)).count(), 2);
+
+        shots.persistence = Some(Duration::from_secs(3));
+        let visible: Vec<_> = shots.visible(t0 + Duration::from_secs(6)).collect();
+        assert_eq!(visible, [Point2::new(0.2, 0.2)]);
+
+        shots.enabled = false;
+        assert_eq!(shots.visible(t0 + Duration::from_secs(6)).count(), 0);
+
+        shots.clear();
+        shots.enabled = true;
+        assert_eq!(shots.visible(t0).count(), 0);
+    }
+
+    #[test]
+    fn test_filter_no_marker_screen_id() {
+        let points = [Point2::new(2000, 2000), Point2::new(2100, 2100)];
+        let screen_ids = [ScreenId::NONE, ScreenId(0)];
+        let tuples = super::filter_and_create_point_tuples(&points, &screen_ids, &super::PointWindow::NF_DEFAULT, super::ScreenIdFilter::ALL);
+        assert_eq!(tuples, [(0, 1, Point2::new(2100., 2100.))]);
+    }
+
+    #[test]
+    fn test_filter_untracked_screen_ids() {
+        let points = [Point2::new(2000, 2000), Point2::new(2100, 2100), Point2::new(2200, 2200)];
+        let screen_ids = [ScreenId(0), ScreenId(1), ScreenId(2)];
+        let mut tracked = super::ScreenIdFilter::NONE;
+        tracked.set(ScreenId(1), true);
+        let tuples = super::filter_and_create_point_tuples(&points, &screen_ids, &super::PointWindow::NF_DEFAULT, tracked);
+        assert_eq!(tuples, [(1, 1, Point2::new(2100., 2100.))]);
+
+        tracked.set(ScreenId(1), false);
+        let tuples = super::filter_and_create_point_tuples(&points, &screen_ids, &super::PointWindow::NF_DEFAULT, tracked);
+        assert!(tuples.is_empty());
+    }
+
+    #[test]
+    fn test_choose_pnp_solution() {
+        use nalgebra::{Isometry3, Vector3};
+        use super::{choose_pnp_solution, PnpDisambiguation};
+
+        let points = [(-0.25, -0.5), (0.25, -0.5), (0.25, 0.5), (-0.25, 0.5)].map(|(x, y)| Vector3::new(x, y, 0.));
+        // The true pose and its flip about the line of sight, both 3 screen heights out
+        let truth = Isometry3::new(Vector3::new(0.1, 0., 3.), Vector3::new(0., 0.15, 0.));
+        let flipped = Isometry3::new(Vector3::new(0.1, 0., 3.), Vector3::new(0., -0.15, 0.));
+        let projections = points.map(|p| {
+            let p = truth * Point3::from(p);
+            Vector2::new(p.x / p.z, p.y / p.z)
+        });
+        let candidates = [flipped, truth];
+
+        assert_eq!(choose_pnp_solution(&candidates, &points, &projections, None, PnpDisambiguation::First), 0);
+        // The prediction is a bit off from the truth, still much closer to it than the flip
+        let prior = Isometry3::new(Vector3::new(0.12, 0.01, 2.9), Vector3::new(0.01, 0.12, 0.));
+        assert_eq!(choose_pnp_solution(&candidates, &points, &projections, Some(&prior), PnpDisambiguation::ClosestToPrior), 1);
+        assert_eq!(choose_pnp_solution(&candidates, &points, &projections, None, PnpDisambiguation::ClosestToPrior), 0);
+        assert_eq!(choose_pnp_solution(&candidates, &points, &projections, None, PnpDisambiguation::LowestReprojectionError), 1);
+    }
+
+    #[test]
+    fn test_marker_pose_non_coplanar() {
+        use nalgebra::{Isometry3, Vector3};
+        use super::{marker_pose, PnpDisambiguation};
+
+        // Markers on two walls meeting in a corner, half a screen height behind the screen
+        let marker_points = [
+            (-0.8, -0.5, 0.), (-0.8, 0.5, 0.), (-0.3, 0., 0.25),
+            (0.3, 0., 0.5), (0.8, -0.5, 0.1), (0.8, 0.5, 0.4),
+        ].map(|(x, y, z)| Point3::new(x, y, z));
+        let truth = Isometry3::new(Vector3::new(0.2, -0.1, 3.), Vector3::new(0.05, 0.2, 0.02));
+        let projections = marker_points.map(|p| {
+            let p = truth * p;
+            Vector2::new(p.x / p.z, p.y / p.z)
+        });
+
+        let pose = marker_pose(&marker_points, &projections, None, PnpDisambiguation::LowestReprojectionError).unwrap();
+        assert!((pose.translation.vector - truth.translation.vector).norm() < 1e-4, "{pose}");
+        assert!(pose.rotation.angle_to(&truth.rotation) < 1e-4, "{pose}");
+
+        // Flattening the markers onto the screen doesn't fit the projections anymore
+        let flat = marker_points.map(|p| Vector3::new(p.x, p.y, 0.));
+        let flat_pose = marker_pose(&flat.map(Point3::from), &projections, None, PnpDisambiguation::LowestReprojectionError).unwrap();
+        assert!(super::pnp_reprojection_error(&flat_pose, &flat, &projections) > 1e-6);
+    }
+
+    #[test]
+    fn test_marker_stability() {
+        use super::{MarkerStability, STABILITY_TOLERANCE};
+        let square = |offset: f64| [(-0.1, -0.1), (0.1, -0.1), (0.1, 0.1), (-0.1, 0.1)].map(|(x, y)| Point2::new(x + offset, y));
+        let mut stability = MarkerStability::default();
+
+        // Coming into view, a marker still missing and then a big jump
+        assert!(!stability.update(&square(0.)[..3], 3, STABILITY_TOLERANCE));
+        assert!(!stability.update(&square(0.), 3, STABILITY_TOLERANCE));
+        assert!(!stability.update(&square(0.3), 3, STABILITY_TOLERANCE));
+        assert_eq!(stability.stable_frames, 1);
+        // Settled, small movement still counts, output starts on the third frame
+        assert!(!stability.update(&square(0.31), 3, STABILITY_TOLERANCE));
+        let mut reordered = square(0.31);
+        reordered.reverse();
+        assert!(stability.update(&reordered, 3, STABILITY_TOLERANCE));
+
+        // Acquired until the markers are lost
+        assert!(stability.update(&square(0.5), 3, STABILITY_TOLERANCE));
+        assert!(!stability.update(&[], 3, STABILITY_TOLERANCE));
+        assert!(!stability.update(&square(0.5), 3, STABILITY_TOLERANCE));
+
+        // 0 frames doesn't wait, even for enough points
+        let mut stability = MarkerStability::default();
+        assert!(stability.update(&square(0.), 0, STABILITY_TOLERANCE));
+        assert!(stability.update(&square(0.)[..2], 0, STABILITY_TOLERANCE));
+    }
+
+    #[test]
+    fn test_aimpoint_validity() {
+        use super::aimpoint_valid;
+        let aimpoint = Some(Point2::new(0.5, 0.5));
+        // Markers in view
+        assert!(aimpoint_valid(aimpoint, 4, false));
+        // Markers went out of view, the filter still has a pose
+        assert!(!aimpoint_valid(aimpoint, 2, false));
+        assert!(!aimpoint_valid(aimpoint, 0, false));
+        // No pose at all
+        assert!(!aimpoint_valid(None, 4, false));
+        // Back in view
+        assert!(aimpoint_valid(aimpoint, 6, false));
+        // The override doesn't need markers
+        assert!(aimpoint_valid(aimpoint, 0, true));
+        assert!(!aimpoint_valid(None, 0, true));
+    }
+
+    #[test]
+    fn test_disabled_marker_not_matched() {
+        let wf_marker_ix = [7, 2, 5, 0];
+        assert_eq!(super::enabled_wf_markers(&wf_marker_ix, super::MarkerMask::ALL).as_slice(), [(0, 7), (1, 2), (2, 5), (3, 0)]);
+
+        let mut mask = super::MarkerMask::ALL;
+        mask.set(2, false);
+        assert!(!mask.contains(2));
+        assert_eq!(super::enabled_wf_markers(&wf_marker_ix, mask).as_slice(), [(0, 7), (1, 2), (3, 0)]);
+
+        mask.set(2, true);
+        mask.set(0, false);
+        mask.set(99, false);
+        assert_eq!(super::enabled_wf_markers(&wf_marker_ix, mask).as_slice(), [(1, 2), (2, 5), (3, 0)]);
+        assert!(super::enabled_wf_markers(&wf_marker_ix, super::MarkerMask::NONE).is_empty());
+    }
+
+    #[test]
+    fn test_point_window() {
+        let nf = super::PointWindow::NF_DEFAULT;
+        assert!(nf.contains(Point2::new(400, 2000)));
+        assert!(!nf.contains(Point2::new(399, 2000)));
+        assert!(nf.contains(Point2::new(2000, 3696)));
+        assert!(!nf.contains(Point2::new(2000, 3697)));
+
+        let wf = super::PointWindow::WF_DEFAULT;
+        assert!(wf.contains(Point2::new(82, 2000)));
+        assert!(!wf.contains(Point2::new(81, 2000)));
+        assert!(wf.contains(Point2::new(2000, 4014)));
+        assert!(!wf.contains(Point2::new(2000, 4015)));
+
+        // Scaled to a lower resolution
+        let half = super::PointWindow { resolution: Vector2::new(2048, 2048), ..nf };
+        assert!(half.contains(Point2::new(200, 1848)));
+        assert!(!half.contains(Point2::new(199, 1000)));
+        assert!(!half.contains(Point2::new(1000, 1849)));
+
+        let mut errors = vec![];
+        super::PointWindow { margin: 0.5, ..nf }.validate(&mut errors);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_principal_point_outside_sensor() {
+        use opencv_ros_camera::RosOpenCvIntrinsics;
+        use super::{principal_point_outside_sensor, CAMERA_MODEL_RESOLUTION};
+
+        let centered = RosOpenCvIntrinsics::from_params(145., 0., 145., 45., 45.);
+        assert_eq!(principal_point_outside_sensor(&centered, CAMERA_MODEL_RESOLUTION), None);
+        let edge = RosOpenCvIntrinsics::from_params(145., 0., 145., 0., 98.);
+        assert_eq!(principal_point_outside_sensor(&edge, CAMERA_MODEL_RESOLUTION), None);
+
+        let outside = RosOpenCvIntrinsics::from_params(145., 0., 145., 45., 4000.);
+        assert_eq!(principal_point_outside_sensor(&outside, CAMERA_MODEL_RESOLUTION), Some(Point2::new(45., 4000.)));
+        let negative = RosOpenCvIntrinsics::from_params(145., 0., 145., -3., 45.);
+        assert_eq!(principal_point_outside_sensor(&negative, CAMERA_MODEL_RESOLUTION), Some(Point2::new(-3., 45.)));
+    }
+
+    #[test]
+    fn test_raycast_aimpoint_from_pose() {
+        let height = super::SCREEN_HEIGHT_METERS;
+        let width = height * 16. / 9.;
+        let straight_ahead = UnitQuaternion::identity();
+
+        // Two meters in front of the middle of the screen, looking straight at it
+        let position = Point3::new(width / 2., height / 2., -2.);
+        let (_, _, aimpoint) = super::raycast_aimpoint_from_pose(&straight_ahead, &position);
+        let aimpoint = aimpoint.unwrap();
+        assert!((aimpoint - Point2::new(0.5, 0.5)).norm() < 1e-4, "{aimpoint}");
+
+        // Sliding over to the left quarter of the screen moves the aimpoint with it
+        let position = Point3::new(width / 4., height / 2., -2.);
+        let (_, _, aimpoint) = super::raycast_aimpoint_from_pose(&straight_ahead, &position);
+        let aimpoint = aimpoint.unwrap();
+        assert!((aimpoint - Point2::new(0.25, 0.5)).norm() < 1e-4, "{aimpoint}");
+    }
+
+    #[test]
+    fn test_split_aimpoints() {
+        let height = super::SCREEN_HEIGHT_METERS;
+        let width = height * 16. / 9.;
+        let mut state = crate::MotState::default();
+        assert_eq!(super::split_aimpoints(&state).1, None);
+
+        // PnP says the middle of the screen, the eskf has drifted to the left quarter
+        let pnp_pose = nalgebra::Isometry3::translation(width / 2., height / 2., -2.);
+        state.pnp_solution = Some(pnp_pose.inverse());
+        state.fv_state.filter.orientation = UnitQuaternion::identity();
+        state.fv_state.filter.position = Point3::new(width / 4., height / 2., -2.);
+        let (eskf, pnp) = super::split_aimpoints(&state);
+        let (eskf, pnp) = (eskf.unwrap(), pnp.unwrap());
+        assert!((pnp - Point2::new(0.5, 0.5)).norm() < 1e-4, "{pnp}");
+        assert!((eskf - Point2::new(0.25, 0.5)).norm() < 1e-4, "{eskf}");
+    }
+
+    #[test]
+    fn test_pattern_id_debounce() {
+        let mut debounce = super::PatternIdDebounce::default();
+        for _ in 0..3 {
+            debounce.update(Some(2), 3);
+        }
+        assert_eq!(debounce.committed(), Some(2));
+
+        // A single mismatched frame doesn't change the committed id
+        assert_eq!(debounce.update(Some(1), 3), Some(2));
+        assert_eq!(debounce.update(Some(2), 3), Some(2));
+        assert_eq!(debounce.update(Some(1), 3), Some(2));
+        assert_eq!(debounce.update(None, 3), Some(2));
+
+        // but a consistent one does
+        assert_eq!(debounce.update(Some(1), 3), Some(2));
+        assert_eq!(debounce.update(Some(1), 3), Some(2));
+        assert_eq!(debounce.update(Some(1), 3), Some(1));
+    }
+
+    #[test]
+    fn test_pattern_mismatch() {
+        let expected = [
+            Point2::new(0., 0.),
+            Point2::new(160., 0.),
+            Point2::new(160., 90.),
+            Point2::new(0., 90.),
+        ];
+
+        // Same layout, scaled and moved
+        let observed = expected.map(|p| Some(p * 3. + Vector2::new(500., 700.)));
+        assert!(super::pattern_mismatch(&observed, &expected).unwrap() < 1e-9);
+
+        // Square instead of 16:9
+        let observed = [
+            Some(Point2::new(0., 0.)),
+            Some(Point2::new(100., 0.)),
+            Some(Point2::new(100., 100.)),
+            Some(Point2::new(0., 100.)),
+        ];
+        assert!(super::pattern_mismatch(&observed, &expected).unwrap() > 0.15);
+
+        // Not enough markers to tell
+        assert_eq!(super::pattern_mismatch(&[Some(Point2::new(0., 0.)), None, None, Some(Point2::new(1., 1.))], &expected), None);
+    }
+
+    #[test]
+    fn test_pattern_mismatch_threshold() {
+        let expected = [
+            Point2::new(0., 0.),
+            Point2::new(160., 0.),
+            Point2::new(160., 90.),
+            Point2::new(0., 90.),
+        ];
+        // 16:10 instead of 16:9, close enough to be measurement error on some walls
+        let observed = [
+            Some(Point2::new(0., 0.)),
+            Some(Point2::new(160., 0.)),
+            Some(Point2::new(160., 100.)),
+            Some(Point2::new(0., 100.)),
+        ];
+        let mismatch = super::pattern_mismatch(&observed, &expected).unwrap();
+        assert!(mismatch > 0.02 && mismatch < super::DEFAULT_PATTERN_MISMATCH_THRESHOLD, "{mismatch}");
+        assert_eq!(super::pattern_mismatch_warning(mismatch, super::DEFAULT_PATTERN_MISMATCH_THRESHOLD), None);
+        assert_eq!(super::pattern_mismatch_warning(mismatch, 0.02), Some(mismatch));
+    }
+
+    #[test]
+    fn test_pnp_solution_per_state() {
+        // Two devices on 16:9 screens, only one of them has a pose yet
+        let marker_points = [
+            Point3::new(0., 0., 0.),
+            Point3::new(1.6, 0., 0.),
+            Point3::new(1.6, 0.9, 0.),
+            Point3::new(0., 0.9, 0.),
+        ];
+        let camera_model = ats_usb::packet::GeneralConfig::default().camera_model_nf;
+        let pnp_iso = nalgebra::Isometry3::translation(-0.8, -0.45, 2.);
+        let mut a = crate::MotState::default();
+        let mut b = crate::MotState::default();
+        for state in [&mut a, &mut b] {
+            state.nf_markers = marker_points.iter().map(|p| super::reproject_marker(p, &pnp_iso.inverse(), &camera_model)).collect();
+            state.nf_marker_keys = (1..=4).map(|i| Some((0, i))).collect();
+        }
+        a.pnp_solution = Some(pnp_iso.cast());
+
+        assert!(super::state_pattern_mismatch(&a, &marker_points, &camera_model).unwrap() < 1e-6);
+        assert_eq!(super::state_pattern_mismatch(&b, &marker_points, &camera_model), None);
+        assert_eq!(b.pnp_solution, None);
+    }
+
+    #[test]
+    fn test_reference_differences() {
+        let mut state = crate::MotState::default();
+        state.nf_markers = [Point2::new(100., 100.), Point2::new(-9999., -9999.), Point2::new(300., 300.), Point2::new(400., 400.)].into_iter().collect();
+        state.nf_marker_keys = [Some((0, 1)), None, Some((0, 3)), Some((0, 4))].into_iter().collect();
+        state.capture_reference();
+        let reference = state.reference_markers.clone().unwrap();
+        assert_eq!(reference[1], None);
+
+        // One marker moved, one disappeared and the unmatched one showed up
+        let live = [Some(Point2::new(100., 100.)), Some(Point2::new(200., 200.)), None, Some(Point2::new(406., 408.))];
+        let differences = super::reference_differences(&reference, &live);
+        assert_eq!(differences.vectors.as_slice(), [Some(Vector2::zeros()), None, None, Some(Vector2::new(6., 8.))]);
+        assert_eq!(differences.max, 10.);
+        assert_eq!(differences.mean, 5.);
+
+        // Nothing moved
+        let differences = super::reference_differences(&reference, &state.measured_nf_markers());
+        assert_eq!(differences.max, 0.);
+    }
+
+    #[test]
+    fn test_marker_errors() {
+        let measured = [
+            Some(Point2::new(100., 100.)),
+            None,
+            Some(Point2::new(300., 300.)),
+            Some(Point2::new(400., 400.)),
+        ];
+        let expected = [
+            Point2::new(103., 104.),
+            Point2::new(200., 200.),
+            Point2::new(300., 300.),
+            Point2::new(400., 410.),
+        ];
+        let errors = super::marker_errors(&measured, &expected);
+        assert_eq!(errors.vectors[0], Some(Vector2::new(3., 4.)));
+        assert_eq!(errors.vectors[1], None);
+        assert_eq!(errors.vectors[2], Some(Vector2::zeros()));
+        assert_eq!(errors.vectors[3], Some(Vector2::new(0., 10.)));
+        assert_eq!(errors.max, 10.);
+        assert_eq!(errors.mean, 5.);
+    }
+}
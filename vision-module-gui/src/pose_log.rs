@@ -0,0 +1,109 @@
+//! Per-frame log of the computed pose and aimpoint, for offline analysis of tracking quality.
+//! Unlike the packet recorder this is the processed output, not the raw input.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::MotState;
+
+/// One CSV row per combined markers frame.
+///
+/// - `timestamp_ms`: milliseconds since the unix epoch when the frame was processed
+/// - `r11`..`r33`: `MotState::rotation_mat`, row major
+/// - `tx`, `ty`, `tz`: `MotState::translation_mat`
+/// - `aimpoint_x`, `aimpoint_y`: `MotState::fv_aimpoint`, 0 to 1 across the screen
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct PoseRecord {
+    pub timestamp_ms: u64,
+    pub r11: f64,
+    pub r12: f64,
+    pub r13: f64,
+    pub r21: f64,
+    pub r22: f64,
+    pub r23: f64,
+    pub r31: f64,
+    pub r32: f64,
+    pub r33: f64,
+    pub tx: f64,
+    pub ty: f64,
+    pub tz: f64,
+    pub aimpoint_x: f64,
+    pub aimpoint_y: f64,
+}
+
+impl PoseRecord {
+    pub fn from_state(timestamp_ms: u64, state: &MotState) -> Self {
+        let r = &state.rotation_mat;
+        let t = &state.translation_mat;
+        Self {
+            timestamp_ms,
+            r11: r.m11, r12: r.m12, r13: r.m13,
+            r21: r.m21, r22: r.m22, r23: r.m23,
+            r31: r.m31, r32: r.m32, r33: r.m33,
+            tx: t.x,
+            ty: t.y,
+            tz: t.z,
+            aimpoint_x: state.fv_aimpoint.x,
+            aimpoint_y: state.fv_aimpoint.y,
+        }
+    }
+}
+
+pub struct PoseLog<W: Write = File> {
+    writer: csv::Writer<W>,
+}
+
+impl PoseLog {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> PoseLog<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer: csv::Writer::from_writer(writer) }
+    }
+
+    pub fn write(&mut self, record: &PoseRecord) -> Result<()> {
+        self.writer.serialize(record)?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> Result<W> {
+        self.writer.into_inner().map_err(|e| anyhow::anyhow!("{}", e.error()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PoseLog, PoseRecord};
+
+    #[test]
+    fn test_pose_log_csv() {
+        let record = PoseRecord {
+            timestamp_ms: 1700000000123,
+            r11: 1., r12: 0., r13: 0.,
+            r21: 0., r22: 1., r23: 0.,
+            r31: 0., r32: 0., r33: 1.,
+            tx: 0.5,
+            ty: -0.25,
+            tz: 2.,
+            aimpoint_x: 0.4,
+            aimpoint_y: 0.6,
+        };
+        let mut log = PoseLog::new(vec![]);
+        log.write(&record).unwrap();
+        log.write(&PoseRecord { timestamp_ms: 1700000000133, ..record }).unwrap();
+        let csv = String::from_utf8(log.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            csv,
+            "timestamp_ms,r11,r12,r13,r21,r22,r23,r31,r32,r33,tx,ty,tz,aimpoint_x,aimpoint_y\n\
+             1700000000123,1.0,0.0,0.0,0.0,1.0,0.0,0.0,0.0,1.0,0.5,-0.25,2.0,0.4,0.6\n\
+             1700000000133,1.0,0.0,0.0,0.0,1.0,0.0,0.0,0.0,1.0,0.5,-0.25,2.0,0.4,0.6\n",
+        );
+    }
+}
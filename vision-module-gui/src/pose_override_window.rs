@@ -0,0 +1,67 @@
+//! Debug window for overriding the tracked pose to check the aimpoint math in isolation
+
+use std::sync::Arc;
+
+use iui::controls::{Window, WindowType};
+use iui::UI;
+use leptos_reactive::{create_effect, RwSignal, SignalGet, SignalSet};
+use nalgebra::{Isometry3, Translation3, UnitQuaternion};
+use parking_lot::Mutex;
+
+use crate::mot_runner::MotRunner;
+use crate::CloneButShorter;
+
+pub fn pose_override_window(ui: &UI, mot_runner: Arc<Mutex<MotRunner>>) -> Window {
+    let mut window = Window::new(ui, "Pose Override", 10, 10, WindowType::NoMenubar);
+    window.on_closing(ui, {
+        let ui = ui.c();
+        move |win: &mut Window| {
+            win.hide(&ui);
+        }
+    });
+
+    let enabled = RwSignal::new(false);
+    // degrees
+    let roll = RwSignal::new(0);
+    let pitch = RwSignal::new(0);
+    let yaw = RwSignal::new(0);
+    // millimeters
+    let x = RwSignal::new(0);
+    let y = RwSignal::new(0);
+    let z = RwSignal::new(-2000);
+
+    crate::layout! { ui,
+        let vbox = VerticalBox(padded: true) {
+            Compact : let enabled_checkbox = Checkbox("Override pose", checked: false)
+            Compact : let form = Form(padded: true) {
+                (Compact, "Roll (deg)") : let roll_spinbox = Spinbox(-180, 180, signal: roll)
+                (Compact, "Pitch (deg)") : let pitch_spinbox = Spinbox(-180, 180, signal: pitch)
+                (Compact, "Yaw (deg)") : let yaw_spinbox = Spinbox(-180, 180, signal: yaw)
+                (Compact, "X (mm)") : let x_spinbox = Spinbox(-10000, 10000, signal: x)
+                (Compact, "Y (mm)") : let y_spinbox = Spinbox(-10000, 10000, signal: y)
+                (Compact, "Z (mm)") : let z_spinbox = Spinbox(-10000, 10000, signal: z)
+            }
+        }
+    }
+    window.set_child(ui, vbox);
+
+    enabled_checkbox.on_toggled(ui, move |checked| enabled.set(checked));
+
+    create_effect(move |_| {
+        let pose = enabled.get().then(|| {
+            let rotation = UnitQuaternion::from_euler_angles(
+                (roll.get() as f32).to_radians(),
+                (pitch.get() as f32).to_radians(),
+                (yaw.get() as f32).to_radians(),
+            );
+            let translation = Translation3::new(x.get() as f32 / 1000., y.get() as f32 / 1000., z.get() as f32 / 1000.);
+            Isometry3::from_parts(translation, rotation)
+        });
+        let mut runner = mot_runner.lock();
+        runner.pose_override = pose;
+        runner.update_raycast_aimpoint();
+        runner.redraw_pending = true;
+    });
+
+    window
+}
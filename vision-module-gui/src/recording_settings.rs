@@ -0,0 +1,104 @@
+//! Where quick recordings are written and what they're named, so a session of recordings doesn't
+//! need a save dialog for each one
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+
+use crate::settings_file;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct RecordingSettings {
+    /// `None` uses `default_directory`.
+    pub directory: Option<PathBuf>,
+}
+
+/// `recordings` in the data directory.
+pub fn default_directory() -> Option<PathBuf> {
+    ProjectDirs::from("com", "odysseyarm",  "odyssey").map(|d| d.data_dir().join("recordings"))
+}
+
+/// UTC year, month, day, hour, minute and second of `unix_secs`.
+fn utc_date_time(unix_secs: u64) -> (u64, u64, u64, u64, u64, u64) {
+    let days = unix_secs / 86400;
+    let secs = unix_secs % 86400;
+    // Howard Hinnant's civil_from_days, with the era starting on 0000-03-01
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// `rec_<UTC timestamp>_<uuid>.bin`, e.g. `rec_20231114T221320.123Z_2a450307090d.bin`. Sorts by
+/// time and tells devices apart.
+pub fn auto_name(unix_millis: u128, uuid: &[u8; 6]) -> String {
+    let (year, month, day, hour, minute, second) = utc_date_time((unix_millis / 1000) as u64);
+    let millis = unix_millis % 1000;
+    let uuid = uuid.map(|b| format!("{b:02x}")).concat();
+    format!("rec_{year:04}{month:02}{day:02}T{hour:02}{minute:02}{second:02}.{millis:03}Z_{uuid}.bin")
+}
+
+/// Creates `name` in `dir`, or `name` with `-1`, `-2`, ... before the extension if it's taken, so
+/// an earlier recording is never overwritten.
+pub fn create_new(dir: &Path, name: &str) -> io::Result<(PathBuf, File)> {
+    fs::create_dir_all(dir)?;
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    let mut n = 0;
+    loop {
+        let path = if n == 0 { dir.join(name) } else { dir.join(format!("{stem}-{n}.{extension}")) };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && n < 100 => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+impl RecordingSettings {
+    pub fn directory(&self) -> Option<PathBuf> {
+        self.directory.clone().or_else(default_directory)
+    }
+
+    /// Load the settings from `recording.toml` in the config directory, or the defaults.
+    pub fn load_from_file() -> Self {
+        settings_file::load("recording.toml", toml::from_str)
+    }
+
+    pub fn save_to_file(&self) {
+        settings_file::save("recording.toml", self, "recording settings");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{auto_name, create_new, utc_date_time};
+
+    #[test]
+    fn test_auto_name() {
+        assert_eq!(auto_name(1_700_000_000_123, &[42, 69, 3, 7, 9, 13]), "rec_20231114T221320.123Z_2a450307090d.bin");
+        assert_eq!(auto_name(0, &[0; 6]), "rec_19700101T000000.000Z_000000000000.bin");
+        // Leap day and the last second of a year
+        assert_eq!(utc_date_time(1_709_164_800), (2024, 2, 29, 0, 0, 0));
+        assert_eq!(utc_date_time(1_735_689_599), (2024, 12, 31, 23, 59, 59));
+    }
+    #[test]
+    fn test_create_new() {
+        let dir = std::env::temp_dir().join(format!("recording_settings_test_{}", std::process::id()));
+        let name = "rec_20231114T221320.123Z_2a450307090d.bin";
+        let (first, _) = create_new(&dir, name).unwrap();
+        let (second, _) = create_new(&dir, name).unwrap();
+        assert_eq!(first, dir.join(name));
+        assert_eq!(second, dir.join("rec_20231114T221320.123Z_2a450307090d-1.bin"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
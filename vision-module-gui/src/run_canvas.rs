@@ -1,6 +1,7 @@
 use std::sync::Arc;
+use nalgebra::Point2;
 use parking_lot::Mutex;
-use iui::controls::{Area, AreaDrawParams, AreaHandler};
+use iui::controls::{Area, AreaDrawParams, AreaHandler, AreaKeyEvent, AreaMouseEvent};
 use iui::UI;
 use crate::mot_runner::MotRunner;
 use crate::{tracking_canvas_helpers, CloneButShorter};
@@ -8,10 +9,35 @@ use crate::{tracking_canvas_helpers, CloneButShorter};
 pub struct RunCanvas {
     pub ctx: UI,
     pub runner: Arc<Mutex<MotRunner>>,
+    /// Starts out false, `TOGGLE_RAW_KEY` flips it.
+    pub raw: bool,
+    pub last_draw_width: Option<f64>,
+    pub last_draw_height: Option<f64>,
 }
 
 impl AreaHandler for RunCanvas {
     fn draw(&mut self, _area: &Area, draw_params: &AreaDrawParams) {
-        tracking_canvas_helpers::draw(self.ctx.c(), self.runner.c(), _area, draw_params, false);
+        self.last_draw_width = Some(draw_params.area_width);
+        self.last_draw_height = Some(draw_params.area_height);
+        tracking_canvas_helpers::draw(self.ctx.c(), self.runner.c(), _area, draw_params, self.raw);
+    }
+
+    fn key_event(&mut self, _area: &Area, area_key_event: &AreaKeyEvent) -> bool {
+        tracking_canvas_helpers::toggle_raw_on_key(&self.runner, area_key_event, &mut self.raw)
+    }
+
+    fn mouse_event(&mut self, _area: &Area, mouse_event: &AreaMouseEvent) {
+        // Clicking a marker selects it for the trajectory overlay, clicking elsewhere keeps the
+        // current selection. Picking goes by the processed view.
+        if mouse_event.down == 1 && !self.raw {
+            let Some(w) = self.last_draw_width else { return };
+            let Some(h) = self.last_draw_height else { return };
+            let mut runner = self.runner.lock();
+            let click = Point2::new(mouse_event.x, mouse_event.y);
+            if let Some(id) = tracking_canvas_helpers::pick_nf_track(&runner.state, w, h, click) {
+                runner.state.selected_track = Some(id);
+                runner.redraw_pending = true;
+            }
+        }
     }
 }
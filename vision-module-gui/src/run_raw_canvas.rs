@@ -1,6 +1,6 @@
 use std::sync::Arc;
 use parking_lot::Mutex;
-use iui::controls::{Area, AreaDrawParams, AreaHandler};
+use iui::controls::{Area, AreaDrawParams, AreaHandler, AreaKeyEvent};
 use iui::UI;
 use crate::mot_runner::MotRunner;
 use crate::{tracking_canvas_helpers, CloneButShorter};
@@ -8,10 +8,16 @@ use crate::{tracking_canvas_helpers, CloneButShorter};
 pub struct RunRawCanvas {
     pub ctx: UI,
     pub runner: Arc<Mutex<MotRunner>>,
+    /// Starts out true, `TOGGLE_RAW_KEY` flips it.
+    pub raw: bool,
 }
 
 impl AreaHandler for RunRawCanvas {
     fn draw(&mut self, _area: &Area, draw_params: &AreaDrawParams) {
-        tracking_canvas_helpers::draw(self.ctx.c(), self.runner.c(), _area, draw_params, true);
+        tracking_canvas_helpers::draw(self.ctx.c(), self.runner.c(), _area, draw_params, self.raw);
+    }
+
+    fn key_event(&mut self, _area: &Area, area_key_event: &AreaKeyEvent) -> bool {
+        tracking_canvas_helpers::toggle_raw_on_key(&self.runner, area_key_event, &mut self.raw)
     }
 }
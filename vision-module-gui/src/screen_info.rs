@@ -0,0 +1,268 @@
+//! Description of the screen and the marker pattern around it
+
+use std::path::Path;
+
+use anyhow::Context;
+use nalgebra::{Matrix3, Point3, Vector2, Vector3};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug)]
+pub struct ScreenInfo {
+    /// Number of markers the pattern is expected to have.
+    pub marker_count: usize,
+    /// Marker positions, in the same order the markers are identified in. z is each marker's own
+    /// depth, the markers don't have to be on one plane.
+    pub marker_points: Vec<Point3<f64>>,
+}
+
+impl Default for ScreenInfo {
+    fn default() -> Self {
+        let marker_points: Vec<_> = ats_cv::foveated::marker_pattern::<f64>().into_iter().map(Point3::from).collect();
+        Self {
+            marker_count: marker_points.len(),
+            marker_points,
+        }
+    }
+}
+
+/// On-disk form of `ScreenInfo`, the same json `screen-info.json` is written in.
+#[derive(Serialize, Deserialize)]
+struct ScreenInfoFile {
+    marker_count: usize,
+    marker_points: Vec<[f64; 3]>,
+}
+
+impl ScreenInfo {
+    /// Loads a screen info json from anywhere, so a separate file can be kept per venue. The
+    /// file is validated and every problem with it is returned in the error.
+    pub fn load_from_path(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let file: ScreenInfoFile = serde_json::from_str(&data)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        let screen_info = Self {
+            marker_count: file.marker_count,
+            marker_points: file.marker_points.into_iter().map(Point3::from).collect(),
+        };
+        let mut errors = vec![];
+        screen_info.validate(&mut errors);
+        if !errors.is_empty() {
+            anyhow::bail!("invalid screen info {}: {}", path.display(), errors.join(", "));
+        }
+        Ok(screen_info)
+    }
+
+    pub fn save_to_path(&self, path: &Path) -> anyhow::Result<()> {
+        let file = ScreenInfoFile {
+            marker_count: self.marker_count,
+            marker_points: self.marker_points.iter().map(|p| p.coords.into()).collect(),
+        };
+        std::fs::write(path, serde_json::to_string_pretty(&file)?)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        if !(4..=16).contains(&self.marker_count) {
+            errors.push("marker count: must be between 4 and 16".into());
+        }
+        if self.marker_points.len() != self.marker_count {
+            errors.push(format!(
+                "marker count: expected {} marker points, got {}",
+                self.marker_count,
+                self.marker_points.len(),
+            ));
+        }
+        for (i, p) in self.marker_points.iter().enumerate() {
+            if !p.iter().all(|c| c.is_finite()) {
+                errors.push(format!("marker point {i}: coordinates must be finite"));
+            }
+        }
+        for (i, a) in self.marker_points.iter().enumerate() {
+            if let Some(j) = self.marker_points[..i].iter().position(|b| (a - b).norm() < MIN_MARKER_DISTANCE) {
+                errors.push(format!("marker point {i}: same position as marker point {j}"));
+            }
+        }
+        let finite = self.marker_points.iter().all(|p| p.iter().all(|c| c.is_finite()));
+        if finite && self.marker_points.len() >= 3 && self.spread().y < MIN_MARKER_DISTANCE {
+            errors.push("marker points: all on one line, the pose can't be solved".into());
+        }
+    }
+
+    /// Width and height of the box around the marker points on the screen plane, in meters.
+    pub fn marker_span(&self) -> Vector2<f64> {
+        let mut min = Vector2::repeat(f64::INFINITY);
+        let mut max = Vector2::repeat(f64::NEG_INFINITY);
+        for p in &self.marker_points {
+            min = min.inf(&p.xy().coords);
+            max = max.sup(&p.xy().coords);
+        }
+        if self.marker_points.is_empty() { Vector2::zeros() } else { max - min }
+    }
+
+    /// Whether every marker is on one plane, not necessarily the screen's.
+    pub fn is_coplanar(&self) -> bool {
+        self.spread().z < MIN_MARKER_DISTANCE
+    }
+
+    /// Standard deviations of the marker points along their principal axes, largest first.
+    fn spread(&self) -> Vector3<f64> {
+        let n = self.marker_points.len();
+        if n == 0 {
+            return Vector3::zeros();
+        }
+        let mean = self.marker_points.iter().map(|p| p.coords).sum::<Vector3<f64>>() / n as f64;
+        let covariance = self.marker_points.iter()
+            .map(|p| (p.coords - mean) * (p.coords - mean).transpose())
+            .sum::<Matrix3<f64>>() / n as f64;
+        let mut spread = covariance.symmetric_eigenvalues().map(|e| e.max(0.).sqrt());
+        spread.as_mut_slice().sort_by(|a, b| b.total_cmp(a));
+        spread
+    }
+}
+
+/// Markers closer than this, in meters, are taken to be the same point.
+const MIN_MARKER_DISTANCE: f64 = 1e-3;
+
+/// Unit screen dimensions are entered in. Everything is stored in meters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LengthUnit {
+    #[default]
+    Meters,
+    Inches,
+    Feet,
+}
+
+impl LengthUnit {
+    /// Combobox labels, in `from_index` order.
+    pub const NAMES: [&'static str; 3] = ["m", "in", "ft"];
+
+    pub fn from_index(index: i32) -> Self {
+        match index {
+            1 => Self::Inches,
+            2 => Self::Feet,
+            _ => Self::Meters,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Meters => Self::NAMES[0],
+            Self::Inches => Self::NAMES[1],
+            Self::Feet => Self::NAMES[2],
+        }
+    }
+
+    fn meters_per_unit(self) -> f64 {
+        match self {
+            Self::Meters => 1.,
+            Self::Inches => 0.0254,
+            Self::Feet => 0.3048,
+        }
+    }
+
+    pub fn to_meters(self, value: f64) -> f64 {
+        value * self.meters_per_unit()
+    }
+
+    pub fn from_meters(self, meters: f64) -> f64 {
+        meters / self.meters_per_unit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Point3;
+
+    use super::{LengthUnit, ScreenInfo};
+
+    fn pattern(n: usize) -> Vec<Point3<f64>> {
+        (0..n).map(|i| Point3::new(i as f64, (i % 2) as f64, 0.)).collect()
+    }
+
+    #[test]
+    fn test_validate_marker_count() {
+        for n in [4, 8] {
+            let mut errors = vec![];
+            ScreenInfo { marker_count: n, marker_points: pattern(n) }.validate(&mut errors);
+            assert!(errors.is_empty(), "{errors:?}");
+
+            let mut errors = vec![];
+            ScreenInfo { marker_count: n, marker_points: pattern(6) }.validate(&mut errors);
+            assert_eq!(errors.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_validate_marker_points() {
+        // A rectangle on the screen and two markers on the wall behind it
+        let mut marker_points = vec![
+            Point3::new(0., 0., 0.), Point3::new(1., 0., 0.), Point3::new(1., 1., 0.), Point3::new(0., 1., 0.),
+        ];
+        let screen_info = ScreenInfo { marker_count: 4, marker_points: marker_points.clone() };
+        let mut errors = vec![];
+        screen_info.validate(&mut errors);
+        assert!(errors.is_empty(), "{errors:?}");
+        assert!(screen_info.is_coplanar());
+
+        marker_points.extend([Point3::new(-0.5, 0.5, 0.3), Point3::new(1.5, 0.5, 0.6)]);
+        let screen_info = ScreenInfo { marker_count: 6, marker_points };
+        let mut errors = vec![];
+        screen_info.validate(&mut errors);
+        assert!(errors.is_empty(), "{errors:?}");
+        assert!(!screen_info.is_coplanar());
+        assert_eq!(screen_info.marker_span(), nalgebra::Vector2::new(2., 1.));
+
+        let line = (0..4).map(|i| Point3::new(i as f64, 0., 0.)).collect();
+        let mut errors = vec![];
+        ScreenInfo { marker_count: 4, marker_points: line }.validate(&mut errors);
+        assert_eq!(errors, ["marker points: all on one line, the pose can't be solved"]);
+
+        let mut marker_points = screen_info.marker_points.clone();
+        marker_points[5] = marker_points[2];
+        marker_points[4].z = f64::NAN;
+        let mut errors = vec![];
+        ScreenInfo { marker_count: 6, marker_points }.validate(&mut errors);
+        assert_eq!(errors, ["marker point 4: coordinates must be finite", "marker point 5: same position as marker point 2"]);
+    }
+
+    #[test]
+    fn test_load_from_path() {
+        let dir = std::env::temp_dir().join(format!("screen-info-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("venue-a.json");
+        let screen_info = ScreenInfo { marker_count: 6, marker_points: pattern(6) };
+        screen_info.save_to_path(&path).unwrap();
+        let loaded = ScreenInfo::load_from_path(&path).unwrap();
+        assert_eq!(loaded.marker_count, 6);
+        assert_eq!(loaded.marker_points, screen_info.marker_points);
+
+        let invalid = dir.join("invalid.json");
+        ScreenInfo { marker_count: 6, marker_points: pattern(5) }.save_to_path(&invalid).unwrap();
+        let e = ScreenInfo::load_from_path(&invalid).unwrap_err();
+        assert!(e.to_string().contains("expected 6 marker points, got 5"), "{e}");
+
+        let garbage = dir.join("garbage.json");
+        std::fs::write(&garbage, "{ \"marker_count\": ").unwrap();
+        assert!(ScreenInfo::load_from_path(&garbage).is_err());
+        assert!(ScreenInfo::load_from_path(&dir.join("missing.json")).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_length_unit_conversion() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        assert!(close(LengthUnit::Meters.to_meters(1.2838), 1.2838));
+        assert!(close(LengthUnit::Inches.to_meters(100.), 2.54));
+        assert!(close(LengthUnit::Feet.to_meters(10.), 3.048));
+        assert!(close(LengthUnit::Feet.to_meters(1.), LengthUnit::Inches.to_meters(12.)));
+
+        for unit in [LengthUnit::Meters, LengthUnit::Inches, LengthUnit::Feet] {
+            assert!(close(unit.from_meters(unit.to_meters(3.5)), 3.5));
+        }
+        assert_eq!(LengthUnit::from_index(1), LengthUnit::Inches);
+        assert_eq!(LengthUnit::from_index(2), LengthUnit::Feet);
+        assert_eq!(LengthUnit::from_index(2).name(), "ft");
+    }
+}
@@ -0,0 +1,52 @@
+//! Reading and writing the toml settings files in the config directory
+
+use std::fmt::Display;
+use std::fs;
+use std::path::PathBuf;
+
+use directories::ProjectDirs;
+use serde::Serialize;
+use tracing::error;
+
+/// `file_name` in the config directory.
+pub fn path(file_name: &str) -> Option<PathBuf> {
+    ProjectDirs::from("com", "odysseyarm",  "odyssey").map(|d| d.config_dir().join(file_name))
+}
+
+/// Parse `file_name` with `parse`, or the defaults if it's missing or doesn't parse.
+pub fn load<T: Default, E: Display>(file_name: &str, parse: impl FnOnce(&str) -> Result<T, E>) -> T {
+    let Some(path) = path(file_name) else {
+        return T::default();
+    };
+    let Ok(data) = fs::read_to_string(path) else {
+        return T::default();
+    };
+    match parse(&data) {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("{}", e);
+            T::default()
+        }
+    }
+}
+
+/// Write `settings` to `file_name`, `what` names them in the error if it fails.
+pub fn save<T: Serialize>(file_name: &str, settings: &T, what: &str) {
+    let Some(path) = path(file_name) else {
+        return;
+    };
+    if let Some(dir) = path.parent() {
+        if let Err(e) = fs::create_dir_all(dir) {
+            error!("Unable to create config directory: {e}");
+            return;
+        }
+    }
+    match toml::to_string(settings) {
+        Ok(data) => {
+            if let Err(e) = fs::write(path, data) {
+                error!("Unable to save {what}: {e}");
+            }
+        }
+        Err(e) => error!("{}", e),
+    }
+}
@@ -0,0 +1,168 @@
+//! Marker count assistant for putting up the markers on a wall, so one person can see from the
+//! wall whether every marker is visible to both sensors
+
+use iui::controls::{ColorButton, LayoutStrategy, Window, WindowType};
+use iui::UI;
+use leptos_reactive::{create_effect, RwSignal, SignalWith, SignalWithUntracked};
+
+use crate::CloneButShorter;
+
+/// Number of frames in a row every marker has to be seen for before the setup counts as done, so
+/// a marker flickering in and out at the edge of the view doesn't.
+pub const STEADY_FRAMES: u32 = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MarkerCount {
+    Missing(usize),
+    /// More points than markers, usually a reflection or another light.
+    Extra(usize),
+    Ok,
+}
+
+impl MarkerCount {
+    pub fn compare(detected: usize, expected: usize) -> Self {
+        if detected < expected {
+            Self::Missing(expected - detected)
+        } else if detected > expected {
+            Self::Extra(detected - expected)
+        } else {
+            Self::Ok
+        }
+    }
+
+    fn describe(self, detected: usize, expected: usize) -> String {
+        match self {
+            Self::Missing(n) => format!("{detected} / {expected}, {n} missing"),
+            Self::Extra(n) => format!("{detected} / {expected}, {n} extra"),
+            Self::Ok => format!("{detected} / {expected}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MarkerCountStatus {
+    pub nf_detected: usize,
+    pub wf_detected: usize,
+    pub expected: usize,
+    /// Both sensors have seen exactly the expected markers for `STEADY_FRAMES` frames.
+    pub complete: bool,
+}
+
+/// Compares the number of points each sensor sees with the number of markers in the screen info,
+/// fed from `combined_markers_loop`.
+#[derive(Clone, Debug, Default)]
+pub struct MarkerCountCheck {
+    steady: u32,
+    /// What the last `update` returned.
+    pub last: Option<MarkerCountStatus>,
+}
+
+impl MarkerCountCheck {
+    pub fn update(&mut self, nf_detected: usize, wf_detected: usize, expected: usize) -> MarkerCountStatus {
+        let ok = MarkerCount::compare(nf_detected, expected) == MarkerCount::Ok
+            && MarkerCount::compare(wf_detected, expected) == MarkerCount::Ok;
+        self.steady = if ok { self.steady.saturating_add(1) } else { 0 };
+        let status = MarkerCountStatus { nf_detected, wf_detected, expected, complete: self.steady >= STEADY_FRAMES };
+        self.last = Some(status);
+        status
+    }
+}
+
+/// Times the swatch blinks white when every marker has been seen, counting on and off.
+const BLINKS: u32 = 6;
+
+/// Green once every marker has been seen, red until then.
+fn set_swatch(ui: &UI, swatch: &mut ColorButton, complete: bool) {
+    let (r, g, b) = if complete { (0., 0.8, 0.) } else { (0.8, 0., 0.) };
+    swatch.set_color(ui, r, g, b, 1.);
+}
+
+pub fn setup_assistant_window(ui: &UI, status: RwSignal<Option<MarkerCountStatus>>) -> Window {
+    let mut window = Window::new(ui, "Marker Setup", 10, 10, WindowType::NoMenubar);
+    window.on_closing(ui, {
+        let ui = ui.c();
+        move |win: &mut Window| {
+            win.hide(&ui);
+        }
+    });
+
+    let count = move |detected: fn(&MarkerCountStatus) -> usize| {
+        move || status.with(|s| match s {
+            Some(s) => MarkerCount::compare(detected(s), s.expected).describe(detected(s), s.expected),
+            None => "-".into(),
+        })
+    };
+    let complete = move || status.with(|s| s.map_or(false, |s| s.complete));
+
+    crate::layout! { ui,
+        let vbox = VerticalBox(padded: true) {
+            Compact : let form = Form(padded: true) {
+                (Compact, "Near field") : let nf_label = Label(count(|s| s.nf_detected))
+                (Compact, "Wide field") : let wf_label = Label(count(|s| s.wf_detected))
+            }
+            Compact : let status_hbox = HorizontalBox(padded: true) {
+                Stretchy : let status_label = Label(move || {
+                    if complete() { "All markers visible" } else { "Waiting for every marker to be seen steadily" }
+                })
+            }
+        }
+    }
+    let mut swatch = ColorButton::new(ui);
+    swatch.disable(ui);
+    status_hbox.append(ui, swatch.c(), LayoutStrategy::Compact);
+    create_effect({
+        let ui = ui.c();
+        let swatch = swatch.c();
+        move |was_complete: Option<bool>| {
+            let complete = complete();
+            set_swatch(&ui, &mut swatch.c(), complete);
+            if complete && was_complete != Some(true) {
+                // Blink, a change from the steady red is easier to catch from the wall
+                let mut blinks = BLINKS;
+                ui.ui_timer(250, {
+                    let ui = ui.c();
+                    let mut swatch = swatch.c();
+                    move || {
+                        blinks -= 1;
+                        if blinks % 2 == 1 {
+                            swatch.set_color(&ui, 1., 1., 1., 1.);
+                        } else {
+                            set_swatch(&ui, &mut swatch, status.with_untracked(|s| s.map_or(false, |s| s.complete)));
+                        }
+                        blinks > 0
+                    }
+                });
+            }
+            complete
+        }
+    });
+    window.set_child(ui, vbox);
+
+    window
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MarkerCount, MarkerCountCheck, STEADY_FRAMES};
+
+    #[test]
+    fn test_marker_count() {
+        assert_eq!(MarkerCount::compare(4, 6), MarkerCount::Missing(2));
+        assert_eq!(MarkerCount::compare(7, 6), MarkerCount::Extra(1));
+        assert_eq!(MarkerCount::compare(6, 6), MarkerCount::Ok);
+
+        let mut check = MarkerCountCheck::default();
+        for _ in 1..STEADY_FRAMES {
+            assert!(!check.update(6, 6, 6).complete);
+        }
+        assert!(check.update(6, 6, 6).complete);
+        // One sensor losing a marker starts over
+        assert!(!check.update(6, 5, 6).complete);
+        assert!(!check.update(6, 6, 6).complete);
+        // A reflection isn't a match either
+        let mut check = MarkerCountCheck::default();
+        for _ in 0..2 * STEADY_FRAMES {
+            assert!(!check.update(7, 6, 6).complete);
+        }
+    }
+}
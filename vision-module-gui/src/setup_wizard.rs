@@ -0,0 +1,409 @@
+//! Walks a first-time user through setting up a wall, one step at a time, pointing at the windows
+//! that already do each part and only moving on once the step checks out
+
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use ats_usb::device::UsbDevice;
+use iui::controls::{Window, WindowType};
+use iui::UI;
+use leptos_reactive::{create_effect, create_rw_signal, ReadSignal, RwSignal, SignalGet, SignalGetUntracked, SignalSet, SignalUpdate, SignalWith, SignalWithUntracked};
+use parking_lot::Mutex;
+
+use crate::mot_runner::{general_write_config, MotRunner};
+use crate::setup_assistant::MarkerCountStatus;
+use crate::stereo_refinement::MIN_SAMPLES;
+use crate::CloneButShorter;
+
+/// Peak blob brightness that counts as a good exposure. Dimmer markers get lost at an angle,
+/// brighter ones are clipped and their centroids get pulled around.
+pub const EXPOSURE_BRIGHTNESS: RangeInclusive<u8> = 100..=250;
+
+/// Stereo refinement error, in normalized image units (about radians), below which the stereo
+/// calibration counts as synced.
+pub const STEREO_ERROR_OK: f64 = 0.003;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WizardStep {
+    LoadScreenInfo,
+    ConnectDevice,
+    SetExposure,
+    VerifyMarkers,
+    StereoSync,
+    MarkerOffset,
+    Save,
+}
+
+impl WizardStep {
+    pub const ALL: [Self; 7] = [
+        Self::LoadScreenInfo,
+        Self::ConnectDevice,
+        Self::SetExposure,
+        Self::VerifyMarkers,
+        Self::StereoSync,
+        Self::MarkerOffset,
+        Self::Save,
+    ];
+
+    pub fn index(self) -> usize {
+        Self::ALL.iter().position(|&s| s == self).unwrap()
+    }
+
+    pub fn next(self) -> Option<Self> {
+        Self::ALL.get(self.index() + 1).copied()
+    }
+
+    pub fn prev(self) -> Option<Self> {
+        self.index().checked_sub(1).map(|i| Self::ALL[i])
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Self::LoadScreenInfo => "Load screen info",
+            Self::ConnectDevice => "Connect the device",
+            Self::SetExposure => "Set the exposure",
+            Self::VerifyMarkers => "Check every marker is seen",
+            Self::StereoSync => "Sync the stereo calibration",
+            Self::MarkerOffset => "Calibrate the marker offset",
+            Self::Save => "Save to the device",
+        }
+    }
+
+    pub fn instructions(self) -> &'static str {
+        match self {
+            Self::LoadScreenInfo => "Load the screen info of this wall with Load Screen Info in the main window.",
+            Self::ConnectDevice => "Pick the device in the config window. Keep it pointed at the wall from here on.",
+            Self::SetExposure => "Start raw tracking and adjust the exposure time of both sensors in the config window until the markers are bright but not clipped.",
+            Self::VerifyMarkers => "Start tracking. Every marker has to be seen steadily by both sensors, the marker setup window shows which are missing.",
+            Self::StereoSync => "Start the stereo refinement in the stereo schematic and move the device around slowly until the error settles.",
+            Self::MarkerOffset => "Aim at the center of the test window and move the aimpoint onto it with the arrow keys, then close the test window.",
+            Self::Save => "Write the settings, stereo calibration included, to the device and save them to its flash.",
+        }
+    }
+
+    /// Label of the button that opens what the step needs, if anything.
+    pub fn action(self) -> Option<&'static str> {
+        match self {
+            Self::LoadScreenInfo => None,
+            Self::ConnectDevice | Self::SetExposure => Some("Open config"),
+            Self::VerifyMarkers => Some("Open marker setup"),
+            Self::StereoSync => Some("Open stereo schematic"),
+            Self::MarkerOffset => Some("Start offset calibration"),
+            Self::Save => Some("Save"),
+        }
+    }
+
+    /// What still has to be done before moving on, or `None` if the step is done.
+    pub fn blocker(self, progress: &SetupProgress) -> Option<String> {
+        match self {
+            Self::LoadScreenInfo => (!progress.screen_info_loaded).then(|| "No screen info loaded".into()),
+            Self::ConnectDevice => (!progress.device_connected).then(|| "Not connected".into()),
+            Self::SetExposure => {
+                let check = |name: &str, peak: Option<u8>| match peak {
+                    None => Some(format!("No {name} blobs, is raw tracking running?")),
+                    Some(b) if b < *EXPOSURE_BRIGHTNESS.start() => Some(format!("{name} markers too dim ({b}), raise the exposure")),
+                    Some(b) if b > *EXPOSURE_BRIGHTNESS.end() => Some(format!("{name} markers clipped ({b}), lower the exposure")),
+                    Some(_) => None,
+                };
+                check("Near field", progress.nf_peak_brightness).or_else(|| check("Wide field", progress.wf_peak_brightness))
+            }
+            Self::VerifyMarkers => (!progress.markers_complete).then(|| "Waiting for every marker to be seen steadily".into()),
+            Self::StereoSync => match progress.stereo_error {
+                None => Some("Waiting for stereo samples".into()),
+                Some(e) if e >= STEREO_ERROR_OK => Some(format!("Stereo error {e:.4}, keep moving")),
+                Some(_) => None,
+            },
+            Self::MarkerOffset => (!progress.offset_calibrated).then(|| "Offset not calibrated yet".into()),
+            Self::Save => (!progress.saved).then(|| "Not saved yet".into()),
+        }
+    }
+}
+
+/// Everything the steps check, gathered from the runner and the other windows.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SetupProgress {
+    pub screen_info_loaded: bool,
+    pub device_connected: bool,
+    /// Brightest blob of each sensor in the last frame, `None` without frames.
+    pub nf_peak_brightness: Option<u8>,
+    pub wf_peak_brightness: Option<u8>,
+    pub markers_complete: bool,
+    /// Latest stereo refinement error, once it has `MIN_SAMPLES` samples.
+    pub stereo_error: Option<f64>,
+    /// A marker offset calibration was started and finished.
+    pub offset_calibrated: bool,
+    pub saved: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SetupWizard {
+    pub step: WizardStep,
+    /// Set once the last step is done.
+    pub finished: bool,
+}
+
+impl Default for SetupWizard {
+    fn default() -> Self {
+        Self { step: WizardStep::LoadScreenInfo, finished: false }
+    }
+}
+
+impl SetupWizard {
+    /// Moves to the next step if the current one is done. Returns whether it did.
+    pub fn advance(&mut self, progress: &SetupProgress) -> bool {
+        if self.finished || self.step.blocker(progress).is_some() {
+            return false;
+        }
+        match self.step.next() {
+            Some(next) => self.step = next,
+            None => self.finished = true,
+        }
+        true
+    }
+
+    /// Going back is always allowed, to redo a step. From the end it goes back to the last step.
+    pub fn back(&mut self) {
+        if self.finished {
+            self.finished = false;
+        } else if let Some(prev) = self.step.prev() {
+            self.step = prev;
+        }
+    }
+}
+
+/// The windows the steps point at.
+pub struct WizardWindows {
+    pub config: Window,
+    pub marker_setup: Window,
+    pub stereo: Window,
+}
+
+pub fn setup_wizard_window(
+    ui: &UI,
+    mot_runner: Arc<Mutex<MotRunner>>,
+    device: ReadSignal<Option<UsbDevice>>,
+    screen_info_path: RwSignal<Option<PathBuf>>,
+    marker_count: RwSignal<Option<MarkerCountStatus>>,
+    marker_offset_calibrating: RwSignal<bool>,
+    windows: WizardWindows,
+) -> Window {
+    let mut window = Window::new(ui, "Setup Wizard", 480, 200, WindowType::NoMenubar);
+    window.on_closing(ui, {
+        let ui = ui.c();
+        move |win: &mut Window| {
+            win.hide(&ui);
+        }
+    });
+
+    let wizard = create_rw_signal(SetupWizard::default());
+    let progress = create_rw_signal(SetupProgress::default());
+    let offset_calibrated = create_rw_signal(false);
+    let saved = create_rw_signal(false);
+    let blocker = move || progress.with(|p| wizard.with(|w| w.step.blocker(p)));
+
+    crate::layout! { ui,
+        let vbox = VerticalBox(padded: true) {
+            Compact : let title_label = Label(move || wizard.with(|w| {
+                format!("Step {} of {}: {}", w.step.index() + 1, WizardStep::ALL.len(), w.step.title())
+            }))
+            Compact : let instructions_label = Label(move || wizard.with(|w| w.step.instructions().to_string()))
+            Compact : let status_label = Label(move || {
+                if wizard.with(|w| w.finished) {
+                    "Setup complete".into()
+                } else {
+                    blocker().unwrap_or_else(|| "Done".into())
+                }
+            })
+            Compact : let buttons_hbox = HorizontalBox(padded: true) {
+                Compact : let action_button = Button(move || wizard.with(|w| w.step.action().unwrap_or("")))
+                Stretchy : let spacer = Spacer()
+                Compact : let back_button = Button("Back", enabled: move || wizard.with(|w| w.step.prev().is_some() || w.finished))
+                Compact : let next_button = Button(
+                    move || wizard.with(|w| if w.step.next().is_some() { "Next" } else { "Finish" }),
+                    enabled: move || !wizard.with(|w| w.finished) && blocker().is_none(),
+                )
+            }
+        }
+    }
+    window.set_child(ui, vbox);
+
+    create_effect({
+        let ui = ui.c();
+        let action_button = action_button.c();
+        move |_| {
+            if wizard.with(|w| w.step.action().is_some()) {
+                action_button.c().show(&ui);
+            } else {
+                action_button.c().hide(&ui);
+            }
+        }
+    });
+
+    // The calibration is done once the test window it runs in is closed again
+    create_effect(move |was_calibrating: Option<bool>| {
+        let calibrating = marker_offset_calibrating.get();
+        if was_calibrating == Some(true) && !calibrating {
+            offset_calibrated.set(true);
+        }
+        calibrating
+    });
+
+    action_button.on_clicked(ui, {
+        let ui = ui.c();
+        let window = window.c();
+        let mot_runner = mot_runner.c();
+        let WizardWindows { config, marker_setup, stereo } = windows;
+        move |_| match wizard.get_untracked().step {
+            WizardStep::LoadScreenInfo => (),
+            WizardStep::ConnectDevice | WizardStep::SetExposure => config.c().show(&ui),
+            WizardStep::VerifyMarkers => marker_setup.c().show(&ui),
+            WizardStep::StereoSync => stereo.c().show(&ui),
+            WizardStep::MarkerOffset => marker_offset_calibrating.set(true),
+            WizardStep::Save => {
+                let Some(device) = device.get_untracked() else {
+                    return;
+                };
+                if device.read_only() {
+                    window.modal_err(&ui, "Can't save", "Read-only mode is on, turn it off in the config window to save.");
+                    return;
+                }
+                // Whatever was edited and refined along the way, stereo calibration included
+                let config = general_write_config(&mot_runner.lock().general_config);
+                let ui = ui.c();
+                let window = window.c();
+                ui.spawn({
+                    let ui = ui.c();
+                    async move {
+                        if let Err(e) = device.write_config(config).await {
+                            window.modal_err_async(&ui, "Failed to write the config", &e.to_string()).await;
+                            return;
+                        }
+                        match device.flash_settings().await {
+                            Ok(()) => saved.set(true),
+                            Err(e) => window.modal_err_async(&ui, "Failed to request flash settings", &e.to_string()).await,
+                        }
+                    }
+                });
+            }
+        }
+    });
+    back_button.on_clicked(ui, move |_| wizard.update(SetupWizard::back));
+    next_button.on_clicked(ui, move |_| {
+        let progress = progress.get_untracked();
+        wizard.update(|w| {
+            w.advance(&progress);
+        });
+    });
+
+    ui.ui_timer(250, {
+        let ui = ui.c();
+        let window = window.c();
+        move || {
+            if !window.visible(&ui) {
+                return true;
+            }
+            let new_progress = {
+                let runner = mot_runner.lock();
+                let peak = |data: Option<&arrayvec::ArrayVec<ats_usb::packet::MotData, 16>>| {
+                    data.and_then(|d| d.iter().filter(|m| m.area > 0).map(|m| m.max_brightness).max())
+                };
+                SetupProgress {
+                    screen_info_loaded: screen_info_path.with_untracked(Option::is_some),
+                    device_connected: device.with_untracked(Option::is_some),
+                    nf_peak_brightness: peak(runner.state.nf_data.as_ref()),
+                    wf_peak_brightness: peak(runner.state.wf_data.as_ref()),
+                    markers_complete: marker_count.with_untracked(|s| s.is_some_and(|s| s.complete)),
+                    stereo_error: runner.stereo_refinement.as_ref()
+                        .filter(|r| r.sample_count() >= MIN_SAMPLES)
+                        .and_then(|r| r.errors.back().copied()),
+                    offset_calibrated: offset_calibrated.get_untracked(),
+                    saved: saved.get_untracked(),
+                }
+            };
+            if progress.get_untracked() != new_progress {
+                progress.set(new_progress);
+            }
+            true
+        }
+    });
+
+    window
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SetupProgress, SetupWizard, WizardStep, STEREO_ERROR_OK};
+
+    #[test]
+    fn test_step_gating() {
+        let mut wizard = SetupWizard::default();
+        let mut progress = SetupProgress::default();
+        assert!(!wizard.advance(&progress));
+        assert_eq!(wizard.step, WizardStep::LoadScreenInfo);
+
+        progress.screen_info_loaded = true;
+        assert!(wizard.advance(&progress));
+        assert_eq!(wizard.step, WizardStep::ConnectDevice);
+        // Each step only looks at its own check
+        progress.markers_complete = true;
+        assert!(!wizard.advance(&progress));
+        progress.device_connected = true;
+        assert!(wizard.advance(&progress));
+
+        // Exposure needs both sensors in range
+        assert_eq!(wizard.step, WizardStep::SetExposure);
+        progress.nf_peak_brightness = Some(180);
+        assert!(!wizard.advance(&progress));
+        progress.wf_peak_brightness = Some(255);
+        assert!(WizardStep::SetExposure.blocker(&progress).unwrap().contains("clipped"));
+        progress.wf_peak_brightness = Some(40);
+        assert!(WizardStep::SetExposure.blocker(&progress).unwrap().contains("dim"));
+        assert!(!wizard.advance(&progress));
+        progress.wf_peak_brightness = Some(200);
+        assert!(wizard.advance(&progress));
+
+        assert!(wizard.advance(&progress));
+        assert_eq!(wizard.step, WizardStep::StereoSync);
+        progress.stereo_error = Some(STEREO_ERROR_OK * 2.);
+        assert!(!wizard.advance(&progress));
+        progress.stereo_error = Some(STEREO_ERROR_OK / 2.);
+        assert!(wizard.advance(&progress));
+
+        // Back is always allowed and the step has to pass again
+        wizard.back();
+        assert_eq!(wizard.step, WizardStep::StereoSync);
+        progress.stereo_error = None;
+        assert!(!wizard.advance(&progress));
+        progress.stereo_error = Some(0.);
+        assert!(wizard.advance(&progress));
+
+        assert!(!wizard.advance(&progress));
+        progress.offset_calibrated = true;
+        assert!(wizard.advance(&progress));
+        assert_eq!(wizard.step, WizardStep::Save);
+        assert!(!wizard.advance(&progress));
+        progress.saved = true;
+        assert!(wizard.advance(&progress));
+        assert!(wizard.finished);
+        assert!(!wizard.advance(&progress));
+
+        wizard.back();
+        assert!(!wizard.finished);
+        assert_eq!(wizard.step, WizardStep::Save);
+        wizard.back();
+        assert_eq!(wizard.step, WizardStep::MarkerOffset);
+    }
+
+    #[test]
+    fn test_steps_in_order() {
+        assert_eq!(WizardStep::LoadScreenInfo.prev(), None);
+        assert_eq!(WizardStep::Save.next(), None);
+        for (i, step) in WizardStep::ALL.into_iter().enumerate() {
+            assert_eq!(step.index(), i);
+            if let Some(next) = step.next() {
+                assert_eq!(next.prev(), Some(step));
+            }
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Iterative refinement of `stereo_iso` from matched nf/wf markers, for fixing up the stereo
+//! calibration in the field without the calibration tools
+
+use std::collections::VecDeque;
+
+use nalgebra::{Isometry3, Matrix6, Point2, Vector3, Vector6};
+
+/// Frames kept to refine over. Older frames are dropped as new ones come in.
+pub const MAX_SAMPLES: usize = 30;
+/// Frames needed before the first step, so one bad match can't pull the calibration around.
+pub const MIN_SAMPLES: usize = 5;
+/// Largest rotation a single step may apply, in radians.
+pub const MAX_ROTATION_STEP: f64 = 0.5 * std::f64::consts::PI / 180.;
+/// Largest translation a single step may apply, in meters.
+pub const MAX_TRANSLATION_STEP: f64 = 0.005;
+/// Error history kept for display.
+const MAX_ERRORS: usize = 200;
+
+/// One frame of markers seen by both sensors, in matching order.
+#[derive(Clone, Debug)]
+pub struct StereoSample {
+    /// Normalized image coordinates in the nf camera.
+    pub nf: Vec<Point2<f64>>,
+    /// Normalized image coordinates in the wf camera.
+    pub wf: Vec<Point2<f64>>,
+    /// Distance from the nf camera to the markers, in meters. The markers are taken to lie on a
+    /// plane facing the nf camera at this distance.
+    pub depth: f64,
+}
+
+/// Where `stereo_iso` puts each wf marker in the nf image, minus where the nf camera saw it.
+fn residuals(samples: &VecDeque<StereoSample>, iso: &Isometry3<f64>, out: &mut Vec<f64>) {
+    out.clear();
+    for sample in samples {
+        for (nf, wf) in sample.nf.iter().zip(&sample.wf) {
+            // Scale the wf bearing so the point lands at the sample depth in the nf frame
+            let bearing = iso.rotation * Vector3::new(wf.x, wf.y, 1.);
+            let t = iso.translation.vector;
+            let s = (sample.depth - t.z) / bearing.z;
+            let p = bearing * s + t;
+            out.push(p.x / p.z - nf.x);
+            out.push(p.y / p.z - nf.y);
+        }
+    }
+}
+
+fn rms(residuals: &[f64]) -> f64 {
+    if residuals.is_empty() {
+        return 0.;
+    }
+    (residuals.iter().map(|r| r * r).sum::<f64>() / (residuals.len() / 2) as f64).sqrt()
+}
+
+/// Applies an update of (translation, rotation vector) in the nf frame.
+fn perturb(iso: &Isometry3<f64>, delta: &Vector6<f64>) -> Isometry3<f64> {
+    Isometry3::new(delta.fixed_rows::<3>(0).into_owned(), delta.fixed_rows::<3>(3).into_owned()) * iso
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StereoRefinement {
+    samples: VecDeque<StereoSample>,
+    /// RMS disagreement after each step, in normalized image units (about radians).
+    pub errors: VecDeque<f64>,
+}
+
+impl StereoRefinement {
+    pub fn add_sample(&mut self, sample: StereoSample) {
+        if sample.nf.is_empty() || sample.nf.len() != sample.wf.len() || !sample.depth.is_finite() || sample.depth <= 0. {
+            return;
+        }
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub fn sample_count(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// RMS nf/wf disagreement of the current samples under `iso`.
+    pub fn error(&self, iso: &Isometry3<f64>) -> f64 {
+        let mut r = vec![];
+        residuals(&self.samples, iso, &mut r);
+        rms(&r)
+    }
+
+    /// One Gauss-Newton step over the rotation and translation of `iso`. The step is bounded and
+    /// only taken if it lowers the error, otherwise `iso` is returned as is. With every marker at
+    /// about the same depth the translation is barely observable, the damping keeps it from
+    /// wandering off.
+    pub fn step(&mut self, iso: &Isometry3<f64>) -> Isometry3<f64> {
+        if self.samples.len() < MIN_SAMPLES {
+            return *iso;
+        }
+        let mut r0 = vec![];
+        residuals(&self.samples, iso, &mut r0);
+        let error = rms(&r0);
+
+        // Numerical jacobian, one column per parameter
+        const EPS: f64 = 1e-7;
+        let mut columns: [Vec<f64>; 6] = Default::default();
+        for (k, column) in columns.iter_mut().enumerate() {
+            let mut delta = Vector6::zeros();
+            delta[k] = EPS;
+            residuals(&self.samples, &perturb(iso, &delta), column);
+            for (c, r) in column.iter_mut().zip(&r0) {
+                *c = (*c - r) / EPS;
+            }
+        }
+        let mut jtj = Matrix6::zeros();
+        let mut jtr = Vector6::zeros();
+        let dot = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>();
+        for (i, ci) in columns.iter().enumerate() {
+            jtr[i] = dot(ci, &r0);
+            for (j, cj) in columns.iter().enumerate() {
+                jtj[(i, j)] = dot(ci, cj);
+            }
+        }
+        for i in 0..6 {
+            jtj[(i, i)] *= 1. + 1e-3;
+            jtj[(i, i)] += 1e-12;
+        }
+        let Some(mut delta) = jtj.cholesky().map(|c| c.solve(&-jtr)) else {
+            self.push_error(error);
+            return *iso;
+        };
+
+        let translation = delta.fixed_rows::<3>(0).norm();
+        if translation > MAX_TRANSLATION_STEP {
+            delta.fixed_rows_mut::<3>(0).scale_mut(MAX_TRANSLATION_STEP / translation);
+        }
+        let rotation = delta.fixed_rows::<3>(3).norm();
+        if rotation > MAX_ROTATION_STEP {
+            delta.fixed_rows_mut::<3>(3).scale_mut(MAX_ROTATION_STEP / rotation);
+        }
+
+        let new_iso = perturb(iso, &delta);
+        let mut r1 = vec![];
+        residuals(&self.samples, &new_iso, &mut r1);
+        let new_error = rms(&r1);
+        if new_error.is_finite() && new_error < error {
+            self.push_error(new_error);
+            new_iso
+        } else {
+            self.push_error(error);
+            *iso
+        }
+    }
+
+    fn push_error(&mut self, error: f64) {
+        if self.errors.len() == MAX_ERRORS {
+            self.errors.pop_front();
+        }
+        self.errors.push_back(error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Isometry3, Point2, Point3, Vector3};
+
+    use super::{StereoRefinement, StereoSample};
+
+    fn project(p: Point3<f64>) -> Point2<f64> {
+        Point2::new(p.x / p.z, p.y / p.z)
+    }
+
+    #[test]
+    fn test_refinement_reduces_error() {
+        // wf camera 3 cm to the side of nf, slightly turned
+        let truth = Isometry3::new(Vector3::new(0.03, 0.002, 0.), Vector3::new(0.01, -0.02, 0.005));
+        let mut refinement = StereoRefinement::default();
+        for (i, depth) in [1.5, 2., 2.5, 3., 4., 5.].into_iter().enumerate() {
+            let offset = Vector3::new(0.1 * i as f64 - 0.2, 0.05 * i as f64, 0.);
+            let markers = [(-0.4, -0.25), (0.4, -0.25), (0.4, 0.25), (-0.4, 0.25), (0., -0.3), (0., 0.3)]
+                .map(|(x, y)| Point3::new(x, y, depth) + offset);
+            refinement.add_sample(StereoSample {
+                nf: markers.iter().map(|&p| project(p)).collect(),
+                wf: markers.iter().map(|&p| project(truth.inverse_transform_point(&p))).collect(),
+                depth,
+            });
+        }
+        assert!(refinement.error(&truth) < 1e-12);
+
+        // About 2 degrees and 1 cm off
+        let miscalibrated = Isometry3::new(Vector3::new(0.02, 0.002, 0.), Vector3::new(0.02, -0.05, 0.01));
+        let initial_error = refinement.error(&miscalibrated);
+        let mut iso = miscalibrated;
+        for _ in 0..50 {
+            let next = refinement.step(&iso);
+            // Bounded steps
+            assert!((next.rotation * iso.rotation.inverse()).angle() <= super::MAX_ROTATION_STEP + 1e-9);
+            iso = next;
+        }
+        let final_error = refinement.error(&iso);
+        assert!(final_error < initial_error * 1e-3, "{initial_error} -> {final_error}");
+        assert!((iso.rotation * truth.rotation.inverse()).angle() < 1e-4);
+        assert!((iso.translation.vector - truth.translation.vector).norm() < 1e-3);
+        // Never gets worse
+        let errors: Vec<_> = refinement.errors.iter().collect();
+        assert!(errors.windows(2).all(|w| w[1] <= w[0]), "{errors:?}");
+    }
+
+    #[test]
+    fn test_refinement_waits_for_samples() {
+        let mut refinement = StereoRefinement::default();
+        let iso = Isometry3::translation(0.03, 0., 0.);
+        refinement.add_sample(StereoSample { nf: vec![Point2::new(0., 0.)], wf: vec![Point2::new(0.1, 0.)], depth: 2. });
+        // Mismatched and depthless samples are dropped
+        refinement.add_sample(StereoSample { nf: vec![Point2::new(0., 0.)], wf: vec![], depth: 2. });
+        refinement.add_sample(StereoSample { nf: vec![Point2::new(0., 0.)], wf: vec![Point2::new(0., 0.)], depth: 0. });
+        assert_eq!(refinement.sample_count(), 1);
+        assert_eq!(refinement.step(&iso), iso);
+        assert!(refinement.errors.is_empty());
+    }
+}
@@ -0,0 +1,229 @@
+//! Top-down schematic of the stereo setup for debugging calibration: the nf and wf camera
+//! frustums, the baseline between them and the screen plane from the last PnP solution, with
+//! controls for refining the stereo calibration
+
+use std::sync::Arc;
+
+use iui::controls::{Area, AreaDrawParams, AreaHandler, Window, WindowType};
+use iui::draw::{Brush, FillMode, Path, SolidBrush, StrokeParams};
+use iui::UI;
+use leptos_reactive::{RwSignal, SignalGet, SignalGetUntracked, SignalSet};
+use nalgebra::{Isometry3, Point2, Point3, Vector2};
+use opencv_ros_camera::RosOpenCvIntrinsics;
+use parking_lot::Mutex;
+
+use crate::custom_shapes::{draw_line, draw_text, solid_brush};
+use crate::mot_runner::{MotRunner, CAMERA_MODEL_RESOLUTION};
+use crate::stereo_refinement::{StereoRefinement, MIN_SAMPLES};
+use crate::CloneButShorter;
+
+/// How far out the frustums are drawn, in meters.
+const FRUSTUM_DEPTH: f32 = 1.0;
+
+/// Corners of the image plane at `depth` in front of the camera, in the camera frame (x right,
+/// y down, z forward), in the order top left, top right, bottom right, bottom left. Distortion is
+/// ignored.
+pub fn frustum_corners(intrinsics: &RosOpenCvIntrinsics<f32>, resolution: f32, depth: f32) -> [Point3<f32>; 4] {
+    let fx = intrinsics.p.m11;
+    let fy = intrinsics.p.m22;
+    let cx = intrinsics.p.m13;
+    let cy = intrinsics.p.m23;
+    let unproject = |u: f32, v: f32| Point3::new((u - cx) / fx * depth, (v - cy) / fy * depth, depth);
+    [
+        unproject(0., 0.),
+        unproject(resolution, 0.),
+        unproject(resolution, resolution),
+        unproject(0., resolution),
+    ]
+}
+
+/// Horizontal and vertical field of view in degrees.
+pub fn fov_degrees(intrinsics: &RosOpenCvIntrinsics<f32>, resolution: f32) -> Vector2<f32> {
+    let [tl, _, br, _] = frustum_corners(intrinsics, resolution, 1.);
+    Vector2::new(
+        (br.x.atan() - tl.x.atan()).to_degrees(),
+        (br.y.atan() - tl.y.atan()).to_degrees(),
+    )
+}
+
+/// `config_stereo_iso` is the config window's stereo calibration, it gets the refined one when
+/// refinement stops.
+pub fn stereo_window(ui: &UI, mot_runner: Arc<Mutex<MotRunner>>, config_stereo_iso: RwSignal<Isometry3<f32>>) -> Window {
+    let mut window = Window::new(ui, "Stereo Schematic", 480, 480, WindowType::NoMenubar);
+    let refining = RwSignal::new(false);
+    crate::layout! { ui,
+        let vbox = VerticalBox(padded: false) {
+            Compact : let refine_button = Button(move || {
+                if !refining.get() { "Start Stereo Refinement" } else { "Stop Stereo Refinement" }
+            })
+            Stretchy : let area = Area(Box::new(StereoCanvas { runner: mot_runner.c() }))
+        }
+    }
+    window.set_child(ui, vbox);
+
+    refine_button.on_clicked(ui, move |_| {
+        let refine = !refining.get_untracked();
+        let refined = {
+            let mut runner = mot_runner.lock();
+            runner.stereo_refinement = refine.then(StereoRefinement::default);
+            runner.general_config.stereo_iso
+        };
+        if !refine {
+            // Into the config window, apply the config to keep it
+            config_stereo_iso.set(refined);
+        }
+        refining.set(refine);
+    });
+
+    window.on_closing(ui, {
+        let ui = ui.c();
+        move |win: &mut Window| {
+            win.hide(&ui);
+        }
+    });
+
+    ui.ui_timer(100, {
+        let window = window.c();
+        let ui = ui.c();
+        let area = area.c();
+        move || {
+            if window.visible(&ui) {
+                area.queue_redraw_all(&ui);
+            }
+            true
+        }
+    });
+    window
+}
+
+struct StereoCanvas {
+    runner: Arc<Mutex<MotRunner>>,
+}
+
+impl AreaHandler for StereoCanvas {
+    fn draw(&mut self, _area: &Area, draw_params: &AreaDrawParams) {
+        let ctx = &draw_params.context;
+        let width = draw_params.area_width;
+        let height = draw_params.area_height;
+
+        let background = Path::new(ctx, FillMode::Winding);
+        background.add_rectangle(ctx, 0., 0., width, height);
+        background.end(ctx);
+        ctx.fill(&background, &Brush::Solid(SolidBrush { r: 0.15, g: 0.15, b: 0.15, a: 1. }));
+
+        let (config, screen, refinement) = {
+            let runner = self.runner.lock();
+            // The PnP pose maps screen points into the nf camera frame
+            let screen = runner.state.pnp_solution.map(|pnp_iso| {
+                runner.screen_info.marker_points.iter()
+                    .map(|p| pnp_iso * p.cast::<f32>())
+                    .collect::<Vec<_>>()
+            });
+            let refinement = runner.stereo_refinement.as_ref()
+                .map(|r| (r.sample_count(), r.errors.front().copied(), r.errors.back().copied()));
+            (runner.general_config.clone(), screen, refinement)
+        };
+
+        // Looking down on the x-z plane of the nf camera, forward is up. Fit the frustums and
+        // the screen, with a bit of margin.
+        let mut extent = FRUSTUM_DEPTH;
+        for p in screen.iter().flatten() {
+            extent = extent.max(p.x.abs()).max(p.z.abs());
+        }
+        let scale = 0.9 * width.min(height) / (2. * extent as f64);
+        let origin = Point2::new(width / 2., height * 0.9);
+        let to_canvas = |p: &Point3<f32>| origin + Vector2::new(p.x as f64, -p.z as f64) * scale;
+
+        let draw_frustum = |iso: &Isometry3<f32>, intrinsics: &RosOpenCvIntrinsics<f32>, color: (f64, f64, f64), name: &str| {
+            let brush = solid_brush(color.0, color.1, color.2);
+            let apex = to_canvas(&iso.transform_point(&Point3::origin()));
+            let [tl, tr, ..] = frustum_corners(intrinsics, CAMERA_MODEL_RESOLUTION, FRUSTUM_DEPTH);
+            let left = to_canvas(&iso.transform_point(&tl));
+            let right = to_canvas(&iso.transform_point(&tr));
+            draw_line(ctx, apex.x, apex.y, left.x, left.y, &brush, 1.);
+            draw_line(ctx, apex.x, apex.y, right.x, right.y, &brush, 1.);
+            draw_line(ctx, left.x, left.y, right.x, right.y, &brush, 1.);
+            let fov = fov_degrees(intrinsics, CAMERA_MODEL_RESOLUTION);
+            draw_text(ctx, left.x, left.y - 16., &format!("{name} {:.1}° x {:.1}°", fov.x, fov.y));
+        };
+        draw_frustum(&Isometry3::identity(), &config.camera_model_nf, (1., 0.6, 0.), "nf");
+        // stereo_iso takes wf camera points into the nf camera frame
+        draw_frustum(&config.stereo_iso, &config.camera_model_wf, (0., 0.8, 1.), "wf");
+
+        let nf = to_canvas(&Point3::origin());
+        let wf = to_canvas(&config.stereo_iso.transform_point(&Point3::origin()));
+        draw_line(ctx, nf.x, nf.y, wf.x, wf.y, &solid_brush(1., 1., 1.), 2.);
+        draw_text(ctx, 10., height - 20., &format!(
+            "baseline {:.1} mm",
+            config.stereo_iso.translation.vector.norm() * 1000.,
+        ));
+
+        if let Some((samples, first, last)) = refinement {
+            // Normalized image units are about radians
+            let text = match (first, last) {
+                (Some(first), Some(last)) => format!(
+                    "refinement: {samples} frames, error {:.2} -> {:.2} mrad",
+                    first * 1000.,
+                    last * 1000.,
+                ),
+                _ => format!("refinement: waiting for frames ({samples} / {MIN_SAMPLES})"),
+            };
+            draw_text(ctx, 10., 30., &text);
+        }
+
+        match screen {
+            Some(points) if !points.is_empty() => {
+                let path = Path::new(ctx, FillMode::Winding);
+                for (i, p) in points.iter().enumerate() {
+                    let p = to_canvas(p);
+                    if i == 0 {
+                        path.new_figure(ctx, p.x, p.y);
+                    } else {
+                        path.line_to(ctx, p.x, p.y);
+                    }
+                }
+                path.end(ctx);
+                ctx.stroke(&path, &solid_brush(0., 1., 0.), &StrokeParams {
+                    cap: 0, // Bevel
+                    join: 0, // Flat
+                    thickness: 2.,
+                    miter_limit: 0.,
+                    dashes: vec![],
+                    dash_phase: 0.,
+                });
+                draw_text(ctx, 10., 10., "screen (from last PnP solution)");
+            }
+            _ => draw_text(ctx, 10., 10., "screen: no pose yet"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opencv_ros_camera::RosOpenCvIntrinsics;
+
+    use super::{fov_degrees, frustum_corners};
+
+    #[test]
+    fn test_frustum_corners() {
+        // Principal point in the middle of a 98x98 sensor, 49 px from the center to the edge
+        let intrinsics = RosOpenCvIntrinsics::from_params(49., 0., 98., 49., 49.);
+        let [tl, tr, br, bl] = frustum_corners(&intrinsics, 98., 2.);
+        for p in [tl, tr, br, bl] {
+            assert_eq!(p.z, 2.);
+        }
+        assert_eq!((tl.x, tl.y), (-2., -1.));
+        assert_eq!((tr.x, tr.y), (2., -1.));
+        assert_eq!((br.x, br.y), (2., 1.));
+        assert_eq!((bl.x, bl.y), (-2., 1.));
+
+        let fov = fov_degrees(&intrinsics, 98.);
+        assert!((fov.x - 90.).abs() < 1e-3, "{fov}");
+        assert!((fov.y - 2. * 0.5f32.atan().to_degrees()).abs() < 1e-3, "{fov}");
+
+        // Principal point on the left edge puts the whole frustum to the right
+        let shifted = RosOpenCvIntrinsics::from_params(49., 0., 98., 0., 49.);
+        let [tl, tr, ..] = frustum_corners(&shifted, 98., 1.);
+        assert_eq!((tl.x, tr.x), (0., 2.));
+    }
+}
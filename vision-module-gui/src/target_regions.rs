@@ -0,0 +1,62 @@
+//! Scoring regions on the screen that impacts are classified into
+
+use nalgebra::Point2;
+use serde::{Deserialize, Serialize};
+
+use crate::settings_file;
+
+/// A circular region on the screen. Coordinates are between 0.0 and 1.0, the same as the
+/// aimpoint.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TargetRegion {
+    pub name: String,
+    pub center: [f64; 2],
+    pub radius: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TargetRegions {
+    /// Checked in order, so inner rings should come before the outer rings.
+    pub regions: Vec<TargetRegion>,
+}
+
+impl Default for TargetRegions {
+    fn default() -> Self {
+        let ring = |name: &str, radius| TargetRegion { name: name.into(), center: [0.5, 0.5], radius };
+        Self {
+            regions: vec![
+                ring("bullseye", 0.05),
+                ring("inner", 0.15),
+                ring("outer", 0.3),
+            ],
+        }
+    }
+}
+
+impl TargetRegions {
+    /// Returns the index of the first region containing `aimpoint`.
+    pub fn classify(&self, aimpoint: Point2<f64>) -> Option<usize> {
+        self.regions.iter().position(|r| {
+            nalgebra::distance(&Point2::from(r.center), &aimpoint) <= r.radius
+        })
+    }
+
+    /// Load the regions from `regions.toml` in the config directory, falling back to the defaults.
+    pub fn load_from_file() -> Self {
+        settings_file::load("regions.toml", toml::from_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Point2;
+
+    #[test]
+    fn test_classify() {
+        let regions = super::TargetRegions::default();
+        assert_eq!(regions.classify(Point2::new(0.5, 0.5)), Some(0));
+        assert_eq!(regions.classify(Point2::new(0.6, 0.5)), Some(1));
+        assert_eq!(regions.classify(Point2::new(0.5, 0.75)), Some(2));
+        assert_eq!(regions.classify(Point2::new(0.95, 0.95)), None);
+    }
+}
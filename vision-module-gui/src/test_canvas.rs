@@ -1,12 +1,142 @@
+use std::collections::HashMap;
 use std::sync::Arc;
-use nalgebra::{Point2, Scale2};
+use nalgebra::{Point2, Scale2, Vector2};
+use serde::{Deserialize, Serialize};
 use parking_lot::Mutex;
 use iui::controls::{Area, AreaDrawParams, AreaHandler, AreaKeyEvent, Modifiers, Window};
 use iui::draw::{Brush, FillMode, Path, SolidBrush, StrokeParams};
 use iui::UI;
 use tracing::debug;
-use crate::custom_shapes::{draw_crosshair, draw_grid, draw_text};
+use crate::custom_shapes::{draw_crosshair, draw_diamond, draw_grid, draw_text};
 use crate::mot_runner::MotRunner;
+use crate::settings_file;
+use crate::tracking_canvas_helpers::draw_screen_legend;
+use crate::CloneButShorter;
+
+/// What the test canvas does with the aimpoint crosshair while tracking is lost.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AimpointLostBehavior {
+    Hide,
+    #[default]
+    Gray,
+    /// Keep drawing the last aimpoint as if it was live.
+    HoldLast,
+}
+
+impl AimpointLostBehavior {
+    /// Same order as the combobox in the main window.
+    pub fn from_index(index: i32) -> Self {
+        match index {
+            0 => Self::Hide,
+            2 => Self::HoldLast,
+            _ => Self::Gray,
+        }
+    }
+}
+
+/// The aimpoint as sent to the aimpoint server and pose log, 0 to 1 across the screen.
+pub fn format_aimpoint(aimpoint: Point2<f64>, precision: usize) -> String {
+    format!("aimpoint = ({:.*}, {:.*})", precision, aimpoint.x, precision, aimpoint.y)
+}
+
+/// What a key does on the test canvas.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TestAction {
+    /// Moves the offset by `COARSE_STEP`.
+    CoarseUp,
+    CoarseDown,
+    CoarseLeft,
+    CoarseRight,
+    /// Moves the offset by `FINE_STEP`, a tenth of that with shift held.
+    FineUp,
+    FineDown,
+    FineLeft,
+    FineRight,
+    ResetOffset,
+    Close,
+}
+
+pub const COARSE_STEP: f64 = 0.1;
+pub const FINE_STEP: f64 = 0.001;
+
+impl TestAction {
+    /// Applies an offset action to `offset`. Returns false for the actions that aren't one.
+    pub fn apply_offset(self, offset: &mut Vector2<f64>, shift: bool) -> bool {
+        let fine = if shift { FINE_STEP / 10. } else { FINE_STEP };
+        match self {
+            Self::CoarseUp => offset.y -= COARSE_STEP,
+            Self::CoarseDown => offset.y += COARSE_STEP,
+            Self::CoarseLeft => offset.x -= COARSE_STEP,
+            Self::CoarseRight => offset.x += COARSE_STEP,
+            Self::FineUp => offset.y -= fine,
+            Self::FineDown => offset.y += fine,
+            Self::FineLeft => offset.x -= fine,
+            Self::FineRight => offset.x += fine,
+            Self::ResetOffset => *offset = Vector2::zeros(),
+            Self::Close => return false,
+        }
+        true
+    }
+}
+
+/// Name of the key in `key_event`, as used in `test_keys.toml`: the character for printable keys,
+/// otherwise one of `Up`, `Down`, `Left`, `Right`, `Escape` and `Backspace`.
+fn key_name(key_event: &AreaKeyEvent) -> Option<String> {
+    let name = match key_event.ext_key as _ {
+        ui_sys::uiExtKeyUp => "Up",
+        ui_sys::uiExtKeyDown => "Down",
+        ui_sys::uiExtKeyLeft => "Left",
+        ui_sys::uiExtKeyRight => "Right",
+        ui_sys::uiExtKeyEscape => "Escape",
+        _ => match key_event.key {
+            8 => "Backspace",
+            k if k.is_ascii_graphic() => return Some(char::from(k).to_string()),
+            _ => return None,
+        },
+    };
+    Some(name.into())
+}
+
+/// Key bindings of the test canvas. Entries in `test_keys.toml`, like `z = "CoarseUp"`, are
+/// added on top of the defaults, replacing the default of the same key.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct TestKeyMap {
+    pub bindings: HashMap<String, TestAction>,
+}
+
+impl Default for TestKeyMap {
+    /// WASD move the offset coarsely, the arrows finely, Backspace resets it and q or Escape
+    /// close the test window.
+    fn default() -> Self {
+        use TestAction::*;
+        let bindings = [
+            ("w", CoarseUp), ("s", CoarseDown), ("a", CoarseLeft), ("d", CoarseRight),
+            ("Up", FineUp), ("Down", FineDown), ("Left", FineLeft), ("Right", FineRight),
+            ("Backspace", ResetOffset), ("q", Close), ("Escape", Close),
+        ];
+        Self { bindings: bindings.into_iter().map(|(k, a)| (k.to_string(), a)).collect() }
+    }
+}
+
+impl TestKeyMap {
+    pub fn action(&self, key: &str) -> Option<TestAction> {
+        self.bindings.get(key).copied()
+    }
+
+    /// The defaults with the bindings in `toml` on top.
+    pub fn from_toml(toml: &str) -> Result<Self, toml::de::Error> {
+        let overrides: TestKeyMap = toml::from_str(toml)?;
+        let mut key_map = Self::default();
+        key_map.bindings.extend(overrides.bindings);
+        Ok(key_map)
+    }
+
+    /// Load the key map from `test_keys.toml` in the config directory, or the defaults.
+    pub fn load_from_file() -> Self {
+        settings_file::load("test_keys.toml", Self::from_toml)
+    }
+}
 
 pub struct TestCanvas {
     pub ctx: UI,
@@ -15,6 +145,7 @@ pub struct TestCanvas {
     pub runner: Arc<Mutex<MotRunner>>,
     pub last_draw_width: Option<f64>,
     pub last_draw_height: Option<f64>,
+    pub key_map: TestKeyMap,
 }
 
 impl AreaHandler for TestCanvas {
@@ -27,19 +158,15 @@ impl AreaHandler for TestCanvas {
         background.add_rectangle(ctx, 0., 0., draw_params.area_width, draw_params.area_height);
         background.end(ctx);
 
-        ctx.fill(&background, &Brush::Solid(SolidBrush {
-            r: 0.5,
-            g: 0.5,
-            b: 0.5,
-            a: 1.,
-        }));
+        let runner = self.runner.lock();
+        ctx.fill(&background, &runner.display.brush((0.5, 0.5, 0.5), 1.));
 
         let fv_ch_path = Path::new(ctx, FillMode::Winding);
         let nf_ch_path = Path::new(ctx, FillMode::Winding);
         let wf_ch_path = Path::new(ctx, FillMode::Winding);
-        let runner = self.runner.lock();
         let state = &runner.state;
-        {
+        let aimpoint_lost = !state.fv_aimpoint_valid;
+        if !aimpoint_lost || runner.aimpoint_lost_behavior != AimpointLostBehavior::Hide {
             let aimpoint = state.fv_aimpoint;
             draw_crosshair(&ctx, &fv_ch_path, aimpoint.x*draw_params.area_width, aimpoint.y*draw_params.area_height, 30.);
         }
@@ -56,6 +183,39 @@ impl AreaHandler for TestCanvas {
             60.0,
             &format!("screen_id = {}", runner.state.screen_id),
         );
+        draw_text(
+            &ctx,
+            20.0,
+            100.0,
+            if runner.impact_arming.is_armed(runner.clock.now()) { "ARMED" } else { "disarmed" },
+        );
+        {
+            let thresholds = runner.health_thresholds;
+            let report = runner.health.report(runner.clock.now(), &thresholds);
+            let (r, g, b) = report.status.color();
+            let dot = Path::new(ctx, FillMode::Winding);
+            dot.new_figure_with_arc(ctx, draw_params.area_width - 30., 30., 10., 0., std::f64::consts::TAU, false);
+            dot.end(ctx);
+            ctx.fill(&dot, &Brush::Solid(SolidBrush { r, g, b, a: 1. }));
+            draw_text(&ctx, draw_params.area_width - 250., 50., &report.summary());
+        }
+        if state.pattern_mismatch.is_some() {
+            draw_text(
+                &ctx,
+                20.0,
+                140.0,
+                "WRONG SCREEN INFO? Markers don't match the configured layout",
+            );
+        }
+        if state.nf_point_count > 0 && !state.marker_stability.acquired {
+            draw_text(&ctx, 20.0, 180.0, "acquiring…");
+        } else if aimpoint_lost {
+            draw_text(&ctx, 20.0, 180.0, "TRACKING LOST");
+        }
+        if let Some(precision) = runner.aimpoint_readout_precision {
+            draw_text(&ctx, 20.0, 220.0, &format_aimpoint(state.fv_aimpoint, precision));
+        }
+        draw_screen_legend(ctx, &runner.display, draw_params.area_width - 120., 80.);
         {
             let aimpoint = state.nf_aimpoint;
             let p = Scale2::new(draw_params.area_width, draw_params.area_height) * (aimpoint + runner.nf_offset);
@@ -69,6 +229,32 @@ impl AreaHandler for TestCanvas {
         }
         wf_ch_path.end(ctx);
 
+        let shots_path = Path::new(ctx, FillMode::Winding);
+        for p in runner.shot_markers.visible(runner.clock.now()) {
+            draw_diamond(&ctx, &shots_path, p.x*draw_params.area_width, p.y*draw_params.area_height, 16., 16.);
+        }
+        shots_path.end(ctx);
+
+        if runner.show_split_aimpoints {
+            let stroke = StrokeParams {
+                cap: 0, // Bevel
+                join: 0, // Flat
+                thickness: 3.,
+                miter_limit: 0.,
+                dashes: vec![],
+                dash_phase: 0.,
+            };
+            for (aimpoint, color, name) in [(state.eskf_aimpoint, (1., 0., 1.), "eskf"), (state.pnp_aimpoint, (0., 0.8, 1.), "pnp")] {
+                let Some(aimpoint) = aimpoint else { continue };
+                let (x, y) = (aimpoint.x * draw_params.area_width, aimpoint.y * draw_params.area_height);
+                let path = Path::new(ctx, FillMode::Winding);
+                draw_crosshair(&ctx, &path, x, y, 20.);
+                path.end(ctx);
+                ctx.stroke(&path, &runner.display.brush(color, 1.), &stroke);
+                draw_text(&ctx, x + 20., y - 30., name);
+            }
+        }
+
         let nf_grid_path = Path::new(ctx, FillMode::Winding);
 
         // todo lol... i know
@@ -83,7 +269,7 @@ impl AreaHandler for TestCanvas {
             Point2::new(1., 0.5), // right
         );
         if let Some(transform) = transform.and_then(|t| t.try_inverse()) {
-            draw_grid(ctx, &nf_grid_path, 10, 10, transform);
+            draw_grid(ctx, &nf_grid_path, runner.grid_subdivisions.0, runner.grid_subdivisions.1, transform);
         }
         nf_grid_path.end(ctx);
 
@@ -96,12 +282,14 @@ impl AreaHandler for TestCanvas {
             dash_phase: 0.,
         };
 
-        let brush = Brush::Solid(SolidBrush {
-            r: 0.,
-            g: 1.,
-            b: 0.,
-            a: 1.,
-        });
+        let fv_color = if aimpoint_lost && runner.aimpoint_lost_behavior == AimpointLostBehavior::Gray {
+            (0.3, 0.3, 0.3)
+        } else if runner.display.color_by_screen {
+            runner.display.screen_color(state.screen_id).unwrap_or((0., 1., 0.))
+        } else {
+            (0., 1., 0.)
+        };
+        let brush = runner.display.brush(fv_color, 1.);
 
         ctx.stroke(&fv_ch_path, &brush, &stroke);
 
@@ -114,31 +302,20 @@ impl AreaHandler for TestCanvas {
             dash_phase: 0.,
         };
 
-        let brush = Brush::Solid(SolidBrush {
-            r: 1.,
-            g: 0.,
-            b: 0.,
-            a: 1.,
-        });
+        let brush = runner.display.brush((1., 0., 0.), 1.);
 
         ctx.stroke(&nf_ch_path, &brush, &stroke);
 
-        let brush = Brush::Solid(SolidBrush {
-            r: 0.,
-            g: 0.,
-            b: 1.,
-            a: 1.,
-        });
+        let brush = runner.display.brush((0., 0., 1.), 1.);
 
         ctx.stroke(&wf_ch_path, &brush, &stroke);
 
+        let brush = runner.display.brush((1., 1., 0.), 1.);
+
+        ctx.stroke(&shots_path, &brush, &stroke);
+
         // Grid
-        let brush = Brush::Solid(SolidBrush {
-            r: 0.5,
-            g: 0.,
-            b: 0.,
-            a: 1.,
-        });
+        let brush = runner.display.brush((0.5, 0., 0.), 1.);
         let stroke = StrokeParams {
             cap: 0, // Bevel
             join: 0, // Flat
@@ -155,27 +332,14 @@ impl AreaHandler for TestCanvas {
         if area_key_event.up {
             return true;
         }
-        let mut slow_speed = 0.001;
-        if area_key_event.modifiers.contains(Modifiers::MODIFIER_SHIFT) {
-            slow_speed = 0.0001;
-        }
-        match area_key_event.ext_key as _ {
-            ui_sys::uiExtKeyUp => self.runner.lock().nf_offset.y -= slow_speed,
-            ui_sys::uiExtKeyDown => self.runner.lock().nf_offset.y += slow_speed,
-            ui_sys::uiExtKeyLeft => self.runner.lock().nf_offset.x -= slow_speed,
-            ui_sys::uiExtKeyRight => self.runner.lock().nf_offset.x += slow_speed,
-            ui_sys::uiExtKeyEscape => (self.on_closing)(&mut self.window),
-            _ => match area_key_event.key {
-                b'w' => self.runner.lock().nf_offset.y -= 0.1,
-                b's' => self.runner.lock().nf_offset.y += 0.1,
-                b'a' => self.runner.lock().nf_offset.x -= 0.1,
-                b'd' => self.runner.lock().nf_offset.x += 0.1,
-                b'q' => (self.on_closing)(&mut self.window),
-                // Backspace
-                8 => self.runner.lock().nf_offset = Default::default(),
-                _ => (),
-            }
+        let Some(action) = key_name(area_key_event).and_then(|k| self.key_map.action(&k)) else {
+            return true;
+        };
+        let shift = area_key_event.modifiers.contains(Modifiers::MODIFIER_SHIFT);
+        if !action.apply_offset(&mut self.runner.lock().nf_offset, shift) {
+            (self.on_closing)(&mut self.window);
         }
+        self.runner.lock().redraw_pending = true;
         true
     }
 
@@ -187,6 +351,44 @@ impl AreaHandler for TestCanvas {
             let aimpoint = state.state.nf_aimpoint;
             state.nf_offset.x = mouse_event.x / w - aimpoint.x;
             state.nf_offset.y = mouse_event.y / h - aimpoint.y;
+            state.redraw_pending = true;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Point2;
+
+    use super::{format_aimpoint, TestAction, TestKeyMap, COARSE_STEP};
+
+    #[test]
+    fn test_format_aimpoint() {
+        let aimpoint = Point2::new(0.123456, 0.5);
+        assert_eq!(format_aimpoint(aimpoint, 4), "aimpoint = (0.1235, 0.5000)");
+        assert_eq!(format_aimpoint(aimpoint, 0), "aimpoint = (0, 0)");
+    }
+
+    #[test]
+    fn test_key_map() {
+        // AZERTY: z and q for up and left, with closing moved off q
+        let key_map = TestKeyMap::from_toml("z = \"CoarseUp\"\nq = \"CoarseLeft\"\nx = \"Close\"").unwrap();
+        assert_eq!(key_map.action("z"), Some(TestAction::CoarseUp));
+        assert_eq!(key_map.action("q"), Some(TestAction::CoarseLeft));
+        assert_eq!(key_map.action("x"), Some(TestAction::Close));
+        // The rest of the defaults are kept
+        assert_eq!(key_map.action("w"), Some(TestAction::CoarseUp));
+        assert_eq!(key_map.action("Escape"), Some(TestAction::Close));
+        assert_eq!(TestKeyMap::default().action("q"), Some(TestAction::Close));
+        assert_eq!(key_map.action("p"), None);
+
+        let mut offset = nalgebra::Vector2::new(0., 0.);
+        assert!(key_map.action("q").unwrap().apply_offset(&mut offset, false));
+        assert_eq!(offset, nalgebra::Vector2::new(-COARSE_STEP, 0.));
+        assert!(key_map.action("Backspace").unwrap().apply_offset(&mut offset, false));
+        assert_eq!(offset, nalgebra::Vector2::zeros());
+        assert!(!key_map.action("x").unwrap().apply_offset(&mut offset, false));
+
+        assert!(TestKeyMap::from_toml("z = \"Jump\"").is_err());
+    }
+}
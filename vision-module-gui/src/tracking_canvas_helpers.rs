@@ -1,28 +1,207 @@
 use std::f64::consts::PI;
 use std::sync::Arc;
 use arrayvec::ArrayVec;
-use ats_cv::foveated::marker_pattern;
 use nalgebra::{Isometry3, Point2, Point3, Rotation2, Scale2, Transform2, Translation2, Vector2, Vector3};
 use parking_lot::Mutex;
-use iui::controls::{Area, AreaDrawParams};
-use iui::draw::{Brush, DrawContext, FillMode, Path, SolidBrush, StrokeParams};
+use iui::controls::{Area, AreaDrawParams, AreaKeyEvent};
+use iui::draw::{DrawContext, FillMode, Path, StrokeParams};
 use iui::UI;
-use crate::custom_shapes::{self, draw_crosshair_rotated, draw_diamond, draw_grid, draw_marker, draw_square, draw_text, solid_brush};
+use crate::custom_shapes::{self, draw_crosshair_rotated, draw_diamond, draw_glyph, draw_grid, draw_line, draw_marker, draw_square, draw_text, solid_brush};
+use crate::display_settings::DisplaySettings;
 use crate::marker_config_window::MarkersSettings;
-use crate::mot_runner::{rescale, MotRunner};
+use ats_usb::packet::{MarkerPattern, MotData, ScreenId};
+use crate::mot_runner::{marker_errors, reference_differences, rectangle_quality, MarkerErrors, reproject_marker, rescale, sort_points, to_full_scale, transform_points, MotRunner, CAMERA_MODEL_RESOLUTION, SENSOR_FULL_SCALE};
+use opencv_ros_camera::RosOpenCvIntrinsics;
 use crate::MotState;
 
+/// Layers of the tracking canvas, drawn in the order of `MotRunner::overlay_order`, the last
+/// one on top. Layers missing from the order aren't drawn at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overlay {
+    /// Nf and wf points as reported by the device.
+    Points,
+    /// Identified markers in their marker colors.
+    Markers,
+    /// Grid through the 4 chosen markers.
+    Grid,
+    /// Marker positions reprojected from the eskf and pnp poses.
+    Reprojections,
+    /// Also needs `MotRunner::show_marker_errors`.
+    MarkerErrors,
+    /// Recent positions of the marker selected by clicking it.
+    Trajectory,
+    /// How far the markers moved from the captured reference.
+    Reference,
+}
 
-pub fn draw(ctx: UI, runner: Arc<Mutex<MotRunner>>, _area: &Area, draw_params: &AreaDrawParams, raw: bool) {
-    let ctx = &draw_params.context;
-    let awidth = draw_params.area_width;
-    let aheight = draw_params.area_height;
+impl Overlay {
+    pub const DEFAULT_ORDER: [Overlay; 7] = [Overlay::Grid, Overlay::Markers, Overlay::Reprojections, Overlay::MarkerErrors, Overlay::Reference, Overlay::Points, Overlay::Trajectory];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Overlay::Points => "points",
+            Overlay::Markers => "markers",
+            Overlay::Grid => "grid",
+            Overlay::Reprojections => "reprojections",
+            Overlay::MarkerErrors => "errors",
+            Overlay::Trajectory => "trajectory",
+            Overlay::Reference => "reference",
+        }
+    }
+
+    /// Comma separated overlay names, bottom first.
+    pub fn parse_order(s: &str) -> Result<Vec<Overlay>, String> {
+        let all = Self::DEFAULT_ORDER;
+        let mut order = vec![];
+        for name in s.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let Some(&overlay) = all.iter().find(|o| o.name() == name) else {
+                return Err(format!("unknown overlay {name:?}"));
+            };
+            if order.contains(&overlay) {
+                return Err(format!("{name:?} is listed twice"));
+            }
+            order.push(overlay);
+        }
+        Ok(order)
+    }
+
+    pub fn format_order(order: &[Overlay]) -> String {
+        order.iter().map(|o| o.name()).collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// How much text is drawn next to each marker.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LabelVerbosity {
+    None,
+    IdOnly,
+    #[default]
+    Full,
+}
+
+impl LabelVerbosity {
+    /// Same order as the combobox in the main window.
+    pub fn from_index(index: i32) -> Self {
+        match index {
+            0 => Self::None,
+            1 => Self::IdOnly,
+            _ => Self::Full,
+        }
+    }
+
+    /// `full` is only built when it's shown.
+    pub fn label(self, id: impl std::fmt::Display, full: impl FnOnce() -> String) -> String {
+        match self {
+            Self::None => String::new(),
+            Self::IdOnly => id.to_string(),
+            Self::Full => full(),
+        }
+    }
+}
+
+/// Side of the square the sensor is drawn in.
+fn draw_size(awidth: f64, aheight: f64, raw: bool) -> f64 {
     let draw_size = (awidth.min(aheight).powi(2)/2.0).sqrt();
-    let draw_size = if raw {
+    if raw {
         draw_size
     } else {
         draw_size / 2.0
-    };
+    }
+}
+
+fn gravity_angle(state: &MotState) -> f64 {
+    let gravity_vec = state.orientation.inverse_transform_vector(&Vector3::z());
+    f64::atan2(-gravity_vec.z as f64, -gravity_vec.x as f64) + PI/2.
+}
+
+/// Flips the raw canvas to match a physical setup that's mirrored, like front projection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CanvasMirror {
+    pub horizontal: bool,
+    pub vertical: bool,
+}
+
+impl CanvasMirror {
+    fn scale(self) -> Vector2<f64> {
+        let sign = |mirrored| if mirrored { -1. } else { 1. };
+        Vector2::new(sign(self.horizontal), sign(self.vertical))
+    }
+}
+
+/// Takes points centered on the middle of the sensor, already rotated for gravity, to the canvas.
+fn draw_transform(awidth: f64, aheight: f64, raw: bool, mirror: CanvasMirror) -> Transform2<f64> {
+    let draw_size = draw_size(awidth, aheight, raw);
+    let scale = mirror.scale() * draw_size;
+    Transform2::from_matrix_unchecked(
+        Translation2::new(awidth/2.0, aheight/2.0).to_homogeneous()
+        * Scale2::new(scale.x, scale.y).to_homogeneous()
+    )
+}
+
+/// A `SENSOR_FULL_SCALE` point relative to the middle of the sensor, in sensor sizes.
+fn centered(point: &Point2<f64>) -> Point2<f64> {
+    point / SENSOR_FULL_SCALE - Vector2::new(0.5, 0.5)
+}
+
+/// A blob as reported by a sensor at `resolution`, relative to the middle of the sensor.
+fn raw_centered(mot_data: &MotData, resolution: Vector2<u16>) -> Point2<f64> {
+    centered(&to_full_scale(Point2::new(mot_data.cx, mot_data.cy).cast(), resolution))
+}
+
+/// Where a point in sensor coordinates ends up on the canvas.
+fn sensor_to_canvas(point: &Point2<f64>, gravity_rot: Rotation2<f64>, draw_tf: Transform2<f64>) -> Point2<f64> {
+    draw_tf * (gravity_rot * centered(point))
+}
+
+/// Track id of the nf marker drawn closest to `click` on an `awidth` by `aheight` tracking
+/// canvas, if any is within a few pixels.
+pub fn pick_nf_track(state: &MotState, awidth: f64, aheight: f64, click: Point2<f64>) -> Option<u32> {
+    let draw_tf = draw_transform(awidth, aheight, false, CanvasMirror::default());
+    let gravity_rot = Rotation2::new(-gravity_angle(state));
+    state.nf_markers.iter().zip(&state.nf_track_ids)
+        .filter_map(|(p, &id)| Some(((sensor_to_canvas(p, gravity_rot, draw_tf) - click).norm(), id?)))
+        .filter(|&(distance, _)| distance <= 20.)
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, id)| id)
+}
+
+/// Color key for `DisplaySettings::color_by_screen`, one row per screen down from (x, y). Draws
+/// nothing when markers aren't colored by screen.
+pub fn draw_screen_legend(ctx: &DrawContext, display: &DisplaySettings, x: f64, y: f64) {
+    if !display.color_by_screen {
+        return;
+    }
+    for screen_id in ScreenId::valid() {
+        let Some(color) = display.screen_color(screen_id.0) else { continue };
+        let row_y = y + 20. * f64::from(screen_id.0);
+        let swatch = Path::new(ctx, FillMode::Winding);
+        swatch.add_rectangle(ctx, x, row_y, 12., 12.);
+        swatch.end(ctx);
+        let (r, g, b) = display.adjust(color);
+        ctx.fill(&swatch, &solid_brush(r, g, b));
+        draw_text(ctx, x + 20., row_y, &format!("screen {}", screen_id.0));
+    }
+}
+
+/// Key that flips a tracking canvas between the raw sensor view and the processed view.
+pub const TOGGLE_RAW_KEY: u8 = b'r';
+
+/// Flips `raw` when `TOGGLE_RAW_KEY` is pressed on a tracking canvas. Returns whether the key was
+/// handled.
+pub fn toggle_raw_on_key(runner: &Mutex<MotRunner>, event: &AreaKeyEvent, raw: &mut bool) -> bool {
+    if event.up || event.key != TOGGLE_RAW_KEY {
+        return false;
+    }
+    *raw = !*raw;
+    runner.lock().redraw_pending = true;
+    true
+}
+
+pub fn draw(ctx: UI, runner: Arc<Mutex<MotRunner>>, _area: &Area, draw_params: &AreaDrawParams, raw: bool) {
+    let ctx = &draw_params.context;
+    let awidth = draw_params.area_width;
+    let aheight = draw_params.area_height;
+    let draw_size = draw_size(awidth, aheight, raw);
     let stroke2 = StrokeParams {
         cap: 0, // Bevel
         join: 0, // Flat
@@ -40,12 +219,20 @@ pub fn draw(ctx: UI, runner: Arc<Mutex<MotRunner>>, _area: &Area, draw_params: &
     let ch_path = Path::new(ctx, FillMode::Winding);
     let nf_path = Path::new(ctx, FillMode::Winding);
     let wf_path = Path::new(ctx, FillMode::Winding);
-    let nf_grid_path = Path::new(ctx, FillMode::Winding);
     let runner = runner.lock();
     let state = &runner.state;
+    let display = &runner.display;
 
-    let gravity_vec = state.orientation.inverse_transform_vector(&Vector3::z());
-    let gravity_angle = f64::atan2(-gravity_vec.z as f64, -gravity_vec.x as f64) + PI/2.;
+    if !display.is_default() {
+        let background = Path::new(ctx, FillMode::Winding);
+        background.add_rectangle(ctx, 0., 0., awidth, aheight);
+        background.end(ctx);
+        ctx.fill(&background, &display.brush((1., 1., 1.), 1.));
+    }
+
+    let gravity_angle = gravity_angle(state);
+    // Only the raw canvas is mirrored, text is drawn at transformed positions so it stays readable
+    let mirror = if raw { runner.raw_mirror } else { CanvasMirror::default() };
 
     // Border around the square drawing area
     {
@@ -55,7 +242,7 @@ pub fn draw(ctx: UI, runner: Arc<Mutex<MotRunner>>, _area: &Area, draw_params: &
             * Scale2::new(draw_size, draw_size).to_homogeneous()
         ));
         border_path.end(ctx);
-        ctx.stroke(&border_path, &Brush::Solid(SolidBrush { r: 0., g: 0., b: 0., a: 1. }), &stroke1);
+        ctx.stroke(&border_path, &display.brush((0., 0., 0.), 1.), &stroke1);
     }
 
     // Green line representing the up direction relative to the vision module.
@@ -63,19 +250,17 @@ pub fn draw(ctx: UI, runner: Arc<Mutex<MotRunner>>, _area: &Area, draw_params: &
         let gravity_line_path = Path::new(ctx, FillMode::Winding);
         gravity_line_path.new_figure(ctx, 0.5 * draw_params.area_width, 0.5 * draw_params.area_height);
         let angle = -gravity_angle - PI/2.;
+        let direction = mirror.scale().component_mul(&Vector2::new(angle.cos(), angle.sin()));
         gravity_line_path.line_to(
             ctx,
-            0.5 * draw_params.area_width + 50.0 * angle.cos(),
-            0.5 * draw_params.area_height + 50.0 * angle.sin(),
+            0.5 * draw_params.area_width + 50.0 * direction.x,
+            0.5 * draw_params.area_height + 50.0 * direction.y,
         );
         gravity_line_path.end(ctx);
-        ctx.stroke(&gravity_line_path, &Brush::Solid(SolidBrush { r: 0., g: 1., b: 0., a: 1. }), &stroke2);
+        ctx.stroke(&gravity_line_path, &display.brush((0., 1., 0.), 1.), &stroke2);
     }
 
-    let draw_tf = Transform2::from_matrix_unchecked(
-        Translation2::new(awidth/2.0, aheight/2.0).to_homogeneous()
-        * Scale2::new(draw_size, draw_size).to_homogeneous()
-    );
+    let draw_tf = draw_transform(awidth, aheight, raw, mirror);
 
     draw_text(
         &ctx,
@@ -83,40 +268,69 @@ pub fn draw(ctx: UI, runner: Arc<Mutex<MotRunner>>, _area: &Area, draw_params: &
         20.0,
         &format!("screen_id = {}", state.screen_id),
     );
+    draw_text(
+        &ctx,
+        20.0,
+        aheight - 30.0,
+        &format!("{} view ({} to switch)", if raw { "raw" } else { "processed" }, TOGGLE_RAW_KEY as char),
+    );
+
+    if state.nf_markers_too_close.is_some() || state.wf_markers_too_close.is_some() {
+        draw_text(
+            &ctx,
+            20.0,
+            40.0,
+            "warning: markers too close together, try a less acute angle",
+        );
+    }
+
+    if !raw && state.nf_marker_tracks.rejected_jumps > 0 {
+        draw_text(
+            &ctx,
+            20.0,
+            140.0,
+            &format!("marker jumps rejected: {}", state.nf_marker_tracks.rejected_jumps),
+        );
+    }
+
+    if let Some(mismatch) = state.pattern_mismatch {
+        draw_text(
+            &ctx,
+            20.0,
+            80.0,
+            &format!("warning: marker layout doesn't match the screen info (mismatch = {mismatch:.2}), is the right screen loaded?"),
+        );
+    }
+
+    draw_screen_legend(ctx, display, awidth - 120., 20.);
+
+    if !raw && state.nf_point_count > 0 && !state.marker_stability.acquired {
+        draw_text(
+            &ctx,
+            20.0,
+            120.0,
+            &format!("acquiring… ({}/{})", state.marker_stability.stable_frames, runner.acquire_frames),
+        );
+    }
 
     let gravity_rot = Rotation2::new(-gravity_angle);
     if raw {
-        draw_raw(ctx, state, draw_tf, gravity_rot, &nf_path, &wf_path, &nf_grid_path, &runner.markers_settings, &ch_path);
+        let resolutions = [runner.nf_point_window.resolution, runner.wf_point_window.resolution];
+        draw_raw(ctx, state, draw_tf, gravity_rot, &nf_path, &wf_path, &runner.markers_settings, &ch_path, runner.raw_sort_pattern, runner.grid_subdivisions, runner.label_verbosity, runner.raw_min_area, display, resolutions);
+        if runner.show_undistortion {
+            draw_undistortion(ctx, state, &runner.general_config, draw_tf, gravity_rot, runner.raw_min_area, resolutions);
+        }
     } else {
-        draw_not_raw(ctx, state, &runner.general_config, draw_tf, gravity_rot, &nf_path, &wf_path, &nf_grid_path, &runner.markers_settings, &ch_path);
+        draw_not_raw(ctx, state, &runner.general_config, draw_tf, gravity_rot, &nf_path, &wf_path, &runner.markers_settings, &runner.screen_info.marker_points, runner.show_marker_errors, runner.grid_subdivisions, runner.label_verbosity, &runner.overlay_order, display);
     }
 
     ch_path.end(ctx);
 
-    let brush = Brush::Solid(SolidBrush {
-        r: 1.,
-        g: 0.,
-        b: 0.,
-        a: 0.5,
-    });
+    ctx.fill(&nf_path, &display.brush((1., 0., 0.), 0.5));
 
-    ctx.fill(&nf_path, &brush);
+    ctx.fill(&wf_path, &display.brush((0., 0., 1.), 0.5));
 
-    let brush = Brush::Solid(SolidBrush {
-        r: 0.,
-        g: 0.,
-        b: 1.,
-        a: 0.5,
-    });
-
-    ctx.fill(&wf_path, &brush);
-
-    let brush = Brush::Solid(SolidBrush {
-        r: 0.,
-        g: 0.,
-        b: 0.,
-        a: 1.,
-    });
+    let brush = display.brush((0., 0., 0.), 1.);
 
     let stroke = StrokeParams {
         cap: 0, // Bevel
@@ -129,48 +343,24 @@ pub fn draw(ctx: UI, runner: Arc<Mutex<MotRunner>>, _area: &Area, draw_params: &
 
     ctx.stroke(&ch_path, &brush, &stroke);
 
-    // Grid
-    let brush = Brush::Solid(SolidBrush {
-        r: 0.5,
-        g: 0.,
-        b: 0.,
-        a: 1.,
-    });
-    let stroke = StrokeParams {
-        cap: 0, // Bevel
-        join: 0, // Flat
-        thickness: 1.,
-        miter_limit: 0.,
-        dashes: vec![],
-        dash_phase: 0.,
-    };
-    // ctx.stroke(&nf_grid_path, &brush, &stroke);
-
     // Center point
-    let brush = Brush::Solid(SolidBrush {
-        r: 0.0,
-        g: 0.,
-        b: 0.,
-        a: 1.,
-    });
+    let brush = display.brush((0., 0., 0.), 1.);
     let center_point_path = Path::new(ctx, FillMode::Winding);
     draw_diamond(ctx, &center_point_path, 0.5 * draw_params.area_width, 0.5 * draw_params.area_height, 8.0, 8.0);
     center_point_path.end(ctx);
     ctx.stroke(&center_point_path, &brush, &stroke2);
 }
 
-fn draw_raw(ctx: &DrawContext, state: &MotState, draw_tf: Transform2<f64>, gravity_rot: Rotation2<f64>, nf_path: &Path, wf_path: &Path, nf_grid_path: &Path, markers_settings: &MarkersSettings, ch_path: &Path) {
+fn draw_raw(ctx: &DrawContext, state: &MotState, draw_tf: Transform2<f64>, gravity_rot: Rotation2<f64>, nf_path: &Path, wf_path: &Path, markers_settings: &MarkersSettings, ch_path: &Path, sort_pattern: Option<MarkerPattern>, grid_subdivisions: (usize, usize), labels: LabelVerbosity, min_area: u16, display: &DisplaySettings, resolutions: [Vector2<u16>; 2]) {
+    let mut hidden = 0;
     if let Some(nf_data) = state.nf_data.as_ref() {
         let mut nf_points = ArrayVec::<Point2<f64>,16>::new();
-        for (i, mot_data) in nf_data.iter().enumerate() {
-            if mot_data.area == 0 {
-                continue;
-            }
-            // todo don't use hardcoded 4095x4095 res assumption
-            let p = Point2::new(mot_data.cx, mot_data.cy).cast::<f64>() / 4095.
-                - Vector2::new(0.5, 0.5);
-            let p = gravity_rot * p;
-            nf_points.push((p + Vector2::new(0.5, 0.5)) * 4095.);
+        let (shown, nf_hidden) = filter_raw_blobs(nf_data, min_area);
+        hidden += nf_hidden;
+        for i in shown {
+            let mot_data = &nf_data[i];
+            let p = gravity_rot * raw_centered(mot_data, resolutions[0]);
+            nf_points.push((p + Vector2::new(0.5, 0.5)) * SENSOR_FULL_SCALE);
             let p = draw_tf * p;
 
             let left = mot_data.boundary_left as f64 / 98.;
@@ -179,46 +369,30 @@ fn draw_raw(ctx: &DrawContext, state: &MotState, draw_tf: Transform2<f64>, gravi
             let up = mot_data.boundary_up as f64 / 98.;
 
             custom_shapes::draw_rectangle(ctx, &nf_path, &[left, down, right, up], &gravity_rot, &draw_tf);
-            custom_shapes::draw_marker(ctx, &ch_path, p, &format!("({:.3}, {:.3}) id={}", mot_data.cx, mot_data.cy, i));
+            let label = labels.label(i, || format!("({:.3}, {:.3}) id={}", mot_data.cx, mot_data.cy, i));
+            custom_shapes::draw_marker(ctx, &ch_path, display.nf_glyph, p, &label);
         }
 
-        if nf_points.len() >= 4 {
-            let mut chosen = ats_cv::choose_rectangle_markers(&mut nf_points, state.screen_id, 300.);
-            let points = match chosen.as_mut() {
-                // Some(p) if runner.general_config.marker_pattern == MarkerPattern::Rectangle => p,
-                _ => &mut nf_points[..4],
-            };
-            // sort_points(points, runner.general_config.marker_pattern);
-
-            let top = markers_settings.views[0].marker_top.position;
-            let left = markers_settings.views[0].marker_left.position;
-            let bottom = markers_settings.views[0].marker_bottom.position;
-            let right = markers_settings.views[0].marker_right.position;
-            let transform = ats_cv::get_perspective_transform(
-                Point2::new(rescale(bottom.x as f64), rescale(bottom.y as f64)), // bottom
-                Point2::new(rescale(left.x as f64), rescale(left.y as f64)), // left
-                Point2::new(rescale(top.x as f64), rescale(top.y as f64)), // top
-                Point2::new(rescale(right.x as f64), rescale(right.y as f64)), // right
-                points[0], points[1],
-                points[2], points[3],
-            );
-            if let Some(transform) = transform {
-                draw_grid(ctx, &nf_grid_path, 10, 10, draw_tf.to_homogeneous() * Scale2::new(1./4095., 1./4095.).to_homogeneous() * transform);
+        if let Some(mut choice) = choose_rectangle(&nf_points, state.screen_id) {
+            let points = &mut choice.chosen[..];
+            if let Some(pattern) = sort_pattern {
+                sort_points(points, pattern);
             }
+            draw_nf_grid(ctx, draw_tf, markers_settings, points, grid_subdivisions, display);
+            draw_rectangle_quality(ctx, draw_tf, points);
+            // nf_points are already rotated for gravity
+            draw_rejected_points(ctx, draw_tf, Rotation2::identity(), &choice.rejected, display);
         }
     }
     nf_path.end(ctx);
-    nf_grid_path.end(ctx);
 
     if let Some(wf_data) = state.wf_data.as_ref() {
-        for mot_data in wf_data {
-            if mot_data.area == 0 {
-                continue;
-            }
+        let (shown, wf_hidden) = filter_raw_blobs(wf_data, min_area);
+        hidden += wf_hidden;
+        for i in shown {
+            let mot_data = &wf_data[i];
 
-            let p = Point2::new(mot_data.cx, mot_data.cy).cast::<f64>() / 4095.
-                - Vector2::new(0.5, 0.5);
-            let p = gravity_rot * p;
+            let p = gravity_rot * raw_centered(mot_data, resolutions[1]);
             let p = draw_tf * p;
 
             let left = mot_data.boundary_left as f64 / 98.;
@@ -228,37 +402,67 @@ fn draw_raw(ctx: &DrawContext, state: &MotState, draw_tf: Transform2<f64>, gravi
 
             custom_shapes::draw_rectangle(ctx, &wf_path, &[left, down, right, up], &gravity_rot, &draw_tf);
 
-            draw_crosshair_rotated(&ctx, &ch_path, p.x, p.y, 50.);
+            draw_glyph(&ctx, &ch_path, display.wf_glyph, p.x, p.y, 50.);
         }
     }
     wf_path.end(ctx);
-}
-
-fn draw_not_raw(ctx: &DrawContext, state: &MotState, config: &ats_usb::packet::GeneralConfig, draw_tf: Transform2<f64>, gravity_rot: Rotation2<f64>, nf_path: &Path, wf_path: &Path, nf_grid_path: &Path, markers_settings: &MarkersSettings, ch_path: &Path) {
-    let nf_points = state.nf_points.clone().iter().map(|x| x.2).collect::<Vec<_>>();
-    let wf_points = state.wf_points.clone().iter().map(|x| x.2).collect::<Vec<_>>();
-
-    for (i, point) in nf_points.iter().enumerate() {
-        // todo don't use hardcoded 4095x4095 res assumption
-        let p = point / 4095. - Vector2::new(0.5, 0.5);
-        let p = gravity_rot * p;
-        let p = draw_tf * p;
 
-        custom_shapes::draw_marker(ctx, &ch_path, p, &format!("nf: sid={}, id={}", state.nf_points[i].0, state.nf_points[i].1));
+    if hidden > 0 {
+        draw_text(ctx, 20.0, 60.0, &format!("{hidden} blobs below min area {min_area} hidden"));
     }
-    nf_path.end(ctx);
+}
 
-    let wf_to_nf_points = ats_cv::wf_to_nf_points(&wf_points, &ats_cv::ros_opencv_intrinsics_type_convert(&config.camera_model_nf), &ats_cv::ros_opencv_intrinsics_type_convert(&config.camera_model_wf), config.stereo_iso.cast());
-    for (i, point) in wf_to_nf_points.iter().enumerate() {
-        // todo don't use hardcoded 4095x4095 res assumption
-        let p = point / 4095. - Vector2::new(0.5, 0.5);
-        let p = gravity_rot * p;
-        let p = draw_tf * p;
+/// Draws a line from each blob on the raw canvas to where it ends up after undistortion with the
+/// loaded camera model.
+fn draw_undistortion(ctx: &DrawContext, state: &MotState, config: &ats_usb::packet::GeneralConfig, draw_tf: Transform2<f64>, gravity_rot: Rotation2<f64>, min_area: u16, resolutions: [Vector2<u16>; 2]) {
+    let cameras = [
+        (state.nf_data.as_ref(), &config.camera_model_nf, config.camera_model_type_nf, resolutions[0]),
+        (state.wf_data.as_ref(), &config.camera_model_wf, config.camera_model_type_wf, resolutions[1]),
+    ];
+    for (data, intrinsics, model, resolution) in cameras {
+        let Some(data) = data else { continue };
+        let (shown, _) = filter_raw_blobs(data, min_area);
+        let raw: Vec<_> = shown.iter().map(|&i| Point2::new(data[i].cx, data[i].cy).cast::<f64>()).collect();
+        let undistorted = transform_points(&raw, intrinsics, model, resolution);
+        let path = Path::new(ctx, FillMode::Winding);
+        for (p, u) in raw.iter().zip(&undistorted) {
+            // transform_points returns full scale points, draw the raw ones the same way
+            let p = sensor_to_canvas(&to_full_scale(*p, resolution), gravity_rot, draw_tf);
+            let u = sensor_to_canvas(u, gravity_rot, draw_tf);
+            draw_line(ctx, p.x, p.y, u.x, u.y, &solid_brush(1., 1., 0.), 1.);
+            draw_diamond(ctx, &path, u.x, u.y, 6., 6.);
+        }
+        path.end(ctx);
+        ctx.fill(&path, &solid_brush(1., 1., 0.));
+    }
+}
 
-        custom_shapes::draw_marker_rotated(ctx, &ch_path, p, &format!("wf: sid={}", state.wf_points[i].0));
+/// Indices of the blobs in `data` the raw canvas draws, and the number hidden for being smaller
+/// than `min_area`. Empty slots (zero area) count as neither.
+pub fn filter_raw_blobs(data: &[MotData], min_area: u16) -> (ArrayVec<usize, 16>, usize) {
+    let mut shown = ArrayVec::new();
+    let mut hidden = 0;
+    for (i, mot_data) in data.iter().enumerate().take(16) {
+        if mot_data.area == 0 {
+            continue;
+        }
+        if mot_data.area < min_area {
+            hidden += 1;
+        } else {
+            shown.push(i);
+        }
     }
+    (shown, hidden)
+}
+
+fn draw_not_raw(ctx: &DrawContext, state: &MotState, config: &ats_usb::packet::GeneralConfig, draw_tf: Transform2<f64>, gravity_rot: Rotation2<f64>, nf_path: &Path, wf_path: &Path, markers_settings: &MarkersSettings, marker_points: &[Point3<f64>], show_marker_errors: bool, grid_subdivisions: (usize, usize), labels: LabelVerbosity, overlays: &[Overlay], display: &DisplaySettings) {
+    // Only the raw canvas draws the sensor boundaries
+    nf_path.end(ctx);
     wf_path.end(ctx);
 
+    let nf_points = state.nf_points.clone().iter().map(|x| x.2).collect::<Vec<_>>();
+    let wf_points = state.wf_points.clone().iter().map(|x| x.2).collect::<Vec<_>>();
+
     let thin = StrokeParams {
         cap: 0, // Bevel
         join: 0, // Flat
@@ -279,113 +483,366 @@ fn draw_not_raw(ctx: &DrawContext, state: &MotState, config: &ats_usb::packet::G
         thickness: 3.,
         ..thick2.clone()
     };
-    let wf_to_nf_markers = ats_cv::wf_to_nf_points(&state.wf_markers, &ats_cv::ros_opencv_intrinsics_type_convert(&config.camera_model_nf), &ats_cv::ros_opencv_intrinsics_type_convert(&config.camera_model_wf), config.stereo_iso.cast());
-    for (i, point) in wf_to_nf_markers.iter().enumerate() {
-        let wf_marker_path = Path::new(ctx, FillMode::Winding);
-        // todo don't use hardcoded 4095x4095 res assumption
-        let p = point / 4095. - Vector2::new(0.5, 0.5);
-        let p = gravity_rot * p;
-        let p = draw_tf * p;
-        draw_crosshair_rotated(&ctx, &wf_marker_path, p.x, p.y, 50.);
-        wf_marker_path.end(&ctx);
-        match i {
-            0 | 3 => ctx.stroke(&wf_marker_path, &solid_brush(1.0, 0.0, 0.0), &thin),
-            1 | 4 => ctx.stroke(&wf_marker_path, &solid_brush(0.0, 1.0, 0.0), &thin),
-            2 | 5 => ctx.stroke(&wf_marker_path, &solid_brush(0.0, 0.0, 1.0), &thin),
-            _ => ctx.stroke(&wf_marker_path, &solid_brush(1.0, 0.0, 1.0), &thin),
+    let eskf_pose: Isometry3<f64> = Isometry3::from_parts(state.fv_state.filter.position.into(), state.fv_state.filter.orientation).cast();
+
+    for overlay in overlays {
+        match overlay {
+            Overlay::Points => {
+                // Black unless colored by screen, those are stroked one by one
+                let points_path = Path::new(ctx, FillMode::Winding);
+                let screen_path = |sid: u8| {
+                    let color = display.screen_color(sid).filter(|_| display.color_by_screen)?;
+                    Some((Path::new(ctx, FillMode::Winding), color))
+                };
+                let stroke_screen_path = |(path, (r, g, b)): (Path, (f64, f64, f64))| {
+                    path.end(ctx);
+                    let (r, g, b) = display.adjust((r, g, b));
+                    ctx.stroke(&path, &solid_brush(r, g, b), &thick2);
+                };
+                for (i, point) in nf_points.iter().enumerate() {
+                    let p = centered(point);
+                    let p = gravity_rot * p;
+                    let p = draw_tf * p;
+
+                    let (sid, id, _) = state.nf_points[i];
+                    let label = labels.label(id, || format!("nf: sid={}, id={}", sid, id));
+                    match screen_path(sid) {
+                        Some(sp) => {
+                            custom_shapes::draw_marker(ctx, &sp.0, display.nf_glyph, p, &label);
+                            stroke_screen_path(sp);
+                        }
+                        None => custom_shapes::draw_marker(ctx, &points_path, display.nf_glyph, p, &label),
+                    }
+                }
+
+                let wf_to_nf_points = ats_cv::wf_to_nf_points(&wf_points, &ats_cv::ros_opencv_intrinsics_type_convert(&config.camera_model_nf), &ats_cv::ros_opencv_intrinsics_type_convert(&config.camera_model_wf), config.stereo_iso.cast());
+                for (i, point) in wf_to_nf_points.iter().enumerate() {
+                    let p = centered(point);
+                    let p = gravity_rot * p;
+                    let p = draw_tf * p;
+
+                    let (sid, id, _) = state.wf_points[i];
+                    let label = labels.label(id, || format!("wf: sid={}", sid));
+                    match screen_path(sid) {
+                        Some(sp) => {
+                            custom_shapes::draw_marker_lower_label(ctx, &sp.0, display.wf_glyph, p, &label);
+                            stroke_screen_path(sp);
+                        }
+                        None => custom_shapes::draw_marker_lower_label(ctx, &points_path, display.wf_glyph, p, &label),
+                    }
+                }
+                points_path.end(ctx);
+                ctx.stroke(&points_path, &solid_brush(0., 0., 0.), &thick2);
+            }
+            Overlay::Markers => {
+                let wf_to_nf_markers = ats_cv::wf_to_nf_points(&state.wf_markers, &ats_cv::ros_opencv_intrinsics_type_convert(&config.camera_model_nf), &ats_cv::ros_opencv_intrinsics_type_convert(&config.camera_model_wf), config.stereo_iso.cast());
+                for (i, point) in wf_to_nf_markers.iter().enumerate() {
+                    let wf_marker_path = Path::new(ctx, FillMode::Winding);
+                    let p = centered(point);
+                    let p = gravity_rot * p;
+                    let p = draw_tf * p;
+                    draw_glyph(&ctx, &wf_marker_path, display.wf_glyph, p.x, p.y, 50.);
+                    wf_marker_path.end(&ctx);
+                    let (r, g, b) = display.adjust(display.marker_color(state, state.wf_marker_keys.get(i).copied()));
+                    ctx.stroke(&wf_marker_path, &solid_brush(r, g, b), &thin);
+                }
+
+                for (i, point) in state.nf_markers.iter().enumerate() {
+                    let p = centered(point);
+                    let p = gravity_rot * p;
+                    let p = draw_tf * p;
+                    let nf_marker_path = Path::new(ctx, FillMode::Winding);
+                    let key = state.nf_marker_keys.get(i).copied().flatten();
+                    let pattern_id = key.and_then(|k| state.pattern_ids.get(&k)).and_then(|d| d.committed());
+                    let pattern_id = pattern_id.map_or("?".into(), |id| id.to_string());
+                    let label = labels.label(&pattern_id, || format!("({:.3}, {:.3}) id={}", point.x, point.y, pattern_id));
+                    custom_shapes::draw_marker(ctx, &nf_marker_path, display.nf_glyph, p, &label);
+                    nf_marker_path.end(&ctx);
+                    let (r, g, b) = display.adjust(display.marker_color(state, key));
+                    ctx.stroke(&nf_marker_path, &solid_brush(r, g, b), &thin);
+                }
+            }
+            Overlay::Grid => {
+                if let Some(choice) = choose_rectangle(&nf_points, state.screen_id) {
+                    let points = &choice.chosen;
+                    // sort_points(points, runner.general_config.marker_pattern);
+                    draw_nf_grid(ctx, draw_tf, markers_settings, points, grid_subdivisions, display);
+                    draw_rectangle_quality(ctx, draw_tf, points);
+                    draw_rejected_points(ctx, draw_tf, gravity_rot, &choice.rejected, display);
+                }
+            }
+            Overlay::Reprojections => {
+                let fx = config.camera_model_nf.p.m11 as f64;
+                let fy = config.camera_model_nf.p.m22 as f64;
+                let cx = config.camera_model_nf.p.m13 as f64;
+                let cy = config.camera_model_nf.p.m23 as f64;
+                for p in marker_points { // eskf reprojections
+                    let fv_reproj_path = Path::new(ctx, FillMode::Winding);
+                    let p = reproject_marker(p, &eskf_pose, &config.camera_model_nf);
+                    let p = centered(&p);
+                    let p = gravity_rot * p;
+                    let p = draw_tf * p;
+                    draw_crosshair_rotated(&ctx, &fv_reproj_path, p.x, p.y, 20.);
+                    fv_reproj_path.end(&ctx);
+                    ctx.stroke(&fv_reproj_path, &solid_brush(0.0, 0.69, 0.42), &thick3);
+                }
+                let pnp_iso = state.pnp_solution;
+                if let Some(pnp_iso) = pnp_iso {
+                    let reproj_tf: Isometry3<f64> = pnp_iso.inverse().cast();
+                    for p in marker_points { // pnp reprojections
+                        let pnp_reproj_path = Path::new(ctx, FillMode::Winding);
+                        let p = reproject_marker(p, &reproj_tf, &config.camera_model_nf);
+                        let p = centered(&p);
+                        let p = gravity_rot * p;
+                        let p = draw_tf * p;
+                        draw_crosshair_rotated(&ctx, &pnp_reproj_path, p.x, p.y, 20.);
+                        pnp_reproj_path.end(&ctx);
+                        ctx.stroke(&pnp_reproj_path, &solid_brush(0.3, 0.3, 0.3), &thick3);
+                    }
+                }
+                for p in &state.wf_reproj {
+                    let wf_reproj_path = Path::new(ctx, FillMode::Winding);
+                    let p = Point2::new(p.x*fx + cx, p.y*fy + cy) / f64::from(CAMERA_MODEL_RESOLUTION) * SENSOR_FULL_SCALE;
+                    let p = centered(&p);
+                    let p = gravity_rot * p;
+                    let p = draw_tf * p;
+                    draw_crosshair_rotated(&ctx, &wf_reproj_path, p.x, p.y, 20.);
+                    wf_reproj_path.end(&ctx);
+                    ctx.stroke(&wf_reproj_path, &solid_brush(0.627, 0.125, 0.941), &thick2);
+                }
+            }
+            Overlay::Trajectory => {
+                let history = state.selected_track.and_then(|id| state.nf_track_histories.get(id));
+                if let Some(history) = history.filter(|h| h.len() >= 2) {
+                    let trajectory_path = Path::new(ctx, FillMode::Winding);
+                    for (i, point) in history.iter().enumerate() {
+                        let p = sensor_to_canvas(point, gravity_rot, draw_tf);
+                        if i == 0 {
+                            trajectory_path.new_figure(ctx, p.x, p.y);
+                        } else {
+                            trajectory_path.line_to(ctx, p.x, p.y);
+                        }
+                    }
+                    trajectory_path.end(ctx);
+                    ctx.stroke(&trajectory_path, &solid_brush(1., 0.5, 0.), &thick2);
+                }
+            }
+            Overlay::MarkerErrors => {
+                if show_marker_errors {
+                    draw_marker_errors(ctx, state, draw_tf, gravity_rot, &config.camera_model_nf, &eskf_pose, marker_points);
+                }
+            }
+            Overlay::Reference => {
+                if let Some(reference) = &state.reference_markers {
+                    let differences = reference_differences(reference, &state.measured_nf_markers());
+                    draw_error_vectors(ctx, draw_tf, gravity_rot, reference, &differences);
+                    draw_text(
+                        ctx,
+                        20.0,
+                        100.0,
+                        &format!("moved from reference max = {:.1}, mean = {:.1}", differences.max, differences.mean),
+                    );
+                }
+            }
         }
     }
+}
 
-    for (i, point) in state.nf_markers.iter().enumerate() {
-        // todo don't use hardcoded 4095x4095 res assumption
-        let p = point / 4095. - Vector2::new(0.5, 0.5);
-        let p = gravity_rot * p;
-        let p = draw_tf * p;
-        let nf_marker_path = Path::new(ctx, FillMode::Winding);
-        custom_shapes::draw_marker(ctx, &nf_marker_path, p, &format!("({:.3}, {:.3}) id={}", point.x, point.y, i));
-        nf_marker_path.end(&ctx);
-        match i {
-            0 | 3 => ctx.stroke(&nf_marker_path, &solid_brush(1.0, 0.0, 0.0), &thin),
-            1 | 4 => ctx.stroke(&nf_marker_path, &solid_brush(0.0, 1.0, 0.0), &thin),
-            2 | 5 => ctx.stroke(&nf_marker_path, &solid_brush(0.0, 0.0, 1.0), &thin),
-            _ => ctx.stroke(&nf_marker_path, &solid_brush(1.0, 0.0, 1.0), &thin),
-        }
+/// Draws a line from each measured near field marker to where the eskf pose says it should be,
+/// green to red as the error grows.
+fn draw_marker_errors(ctx: &DrawContext, state: &MotState, draw_tf: Transform2<f64>, gravity_rot: Rotation2<f64>, intrinsics: &RosOpenCvIntrinsics<f32>, pose: &Isometry3<f64>, marker_points: &[Point3<f64>]) {
+    let measured = state.measured_nf_markers();
+    let expected: ArrayVec<_, 16> = marker_points.iter().take(16).map(|p| reproject_marker(p, pose, intrinsics)).collect();
+    let errors = marker_errors(&measured, &expected);
+    draw_error_vectors(ctx, draw_tf, gravity_rot, &measured, &errors);
+    draw_text(
+        ctx,
+        20.0,
+        60.0,
+        &format!("marker error max = {:.1}, mean = {:.1}", errors.max, errors.mean),
+    );
+}
+
+/// Draws each of `errors.vectors` starting at the matching point in `from`, green to red as it
+/// grows.
+fn draw_error_vectors(ctx: &DrawContext, draw_tf: Transform2<f64>, gravity_rot: Rotation2<f64>, from: &[Option<Point2<f64>>], errors: &MarkerErrors) {
+    let to_canvas = |p: Point2<f64>| draw_tf * (gravity_rot * centered(&p));
+    for (m, v) in from.iter().zip(&errors.vectors) {
+        let (Some(m), Some(v)) = (m, v) else { continue };
+        // Full red at 50 sensor units of error
+        let t = (v.norm() / 50.).min(1.);
+        let from = to_canvas(*m);
+        let to = to_canvas(m + v);
+        draw_line(ctx, from.x, from.y, to.x, to.y, &solid_brush(t, 1. - t, 0.), 2.);
     }
+}
 
-    if nf_points.len() >= 4 {
-        let mut points = nf_points.clone();
-        let mut chosen = ats_cv::choose_rectangle_markers(&mut points, state.screen_id, 300.);
-        let points = match chosen.as_mut() {
-            // Some(p) if runner.general_config.marker_pattern == MarkerPattern::Rectangle => p,
-            _ => &mut points[..4],
-        };
-        // sort_points(points, runner.general_config.marker_pattern);
-
-        let top = markers_settings.views[0].marker_top.position;
-        let left = markers_settings.views[0].marker_left.position;
-        let bottom = markers_settings.views[0].marker_bottom.position;
-        let right = markers_settings.views[0].marker_right.position;
-        let transform = ats_cv::get_perspective_transform(
-            Point2::new(rescale(bottom.x as f64), rescale(bottom.y as f64)), // bottom
-            Point2::new(rescale(left.x as f64), rescale(left.y as f64)), // left
-            Point2::new(rescale(top.x as f64), rescale(top.y as f64)), // top
-            Point2::new(rescale(right.x as f64), rescale(right.y as f64)), // right
-            points[0], points[1],
-            points[2], points[3],
-        );
-        if let Some(transform) = transform {
-            draw_grid(ctx, &nf_grid_path, 10, 10, draw_tf.to_homogeneous() * Scale2::new(1./4095., 1./4095.).to_homogeneous() * transform);
-        }
+/// The points `ats_cv::choose_rectangle_markers` was given, split by whether the grid is built
+/// from them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RectangleChoice {
+    pub chosen: [Point2<f64>; 4],
+    pub rejected: Vec<Point2<f64>>,
+}
+
+/// `None` with fewer than 4 points.
+pub fn choose_rectangle(points: &[Point2<f64>], screen_id: u8) -> Option<RectangleChoice> {
+    if points.len() < 4 {
+        return None;
     }
-    nf_grid_path.end(ctx);
-
-
-    let fx = config.camera_model_nf.p.m11 as f64;
-    let fy = config.camera_model_nf.p.m22 as f64;
-    let cx = config.camera_model_nf.p.m13 as f64;
-    let cy = config.camera_model_nf.p.m23 as f64;
-    for p in marker_pattern::<f64>() { // eskf reprojections
-        let position = state.fv_state.filter.position;
-        let orientation = state.fv_state.filter.orientation;
-        let reproj_tf = Isometry3::from_parts(position.into(), orientation);
-        let fv_reproj_path = Path::new(ctx, FillMode::Winding);
-        let p = reproj_tf.cast().inverse_transform_point(&p.into());
-        let p = p / p.z;
-        // todo don't use hardcoded 4095x4095 res assumption
-        let p = Point2::new(p.x*fx + cx, p.y*fy + cy) / 98.0 * 4095.0;
-        let p = p / 4095. - Vector2::new(0.5, 0.5);
-        let p = gravity_rot * p;
-        let p = draw_tf * p;
-        draw_crosshair_rotated(&ctx, &fv_reproj_path, p.x, p.y, 20.);
-        fv_reproj_path.end(&ctx);
-        ctx.stroke(&fv_reproj_path, &solid_brush(0.0, 0.69, 0.42), &thick3);
-    }
-    let pnp_iso = ats_cv::telemetry::pnp_solutions().get_last();
-    if let Some(pnp_iso) = pnp_iso {
-        for p in marker_pattern::<f64>() { // pnp reprojections
-            let reproj_tf = pnp_iso.inverse();
-            let pnp_reproj_path = Path::new(ctx, FillMode::Winding);
-            let p = reproj_tf.cast().inverse_transform_point(&p.into());
-            let p = p / p.z;
-            // todo don't use hardcoded 4095x4095 res assumption
-            let p = Point2::new(p.x*fx + cx, p.y*fy + cy) / 98.0 * 4095.0;
-            let p = p / 4095. - Vector2::new(0.5, 0.5);
-            let p = gravity_rot * p;
-            let p = draw_tf * p;
-            draw_crosshair_rotated(&ctx, &pnp_reproj_path, p.x, p.y, 20.);
-            pnp_reproj_path.end(&ctx);
-            ctx.stroke(&pnp_reproj_path, &solid_brush(0.3, 0.3, 0.3), &thick3);
-        }
+    let mut points = points.to_vec();
+    // The grid is built from the first 4 points once choose_rectangle_markers has reordered them
+    let _ = ats_cv::choose_rectangle_markers(&mut points, screen_id, 300.);
+    Some(RectangleChoice {
+        chosen: points[..4].try_into().unwrap(),
+        rejected: points.split_off(4),
+    })
+}
+
+/// Muted diamonds on the points that weren't picked for the rectangle.
+fn draw_rejected_points(ctx: &DrawContext, draw_tf: Transform2<f64>, gravity_rot: Rotation2<f64>, rejected: &[Point2<f64>], display: &DisplaySettings) {
+    if rejected.is_empty() {
+        return;
     }
-    for p in &state.wf_reproj {
-        let wf_reproj_path = Path::new(ctx, FillMode::Winding);
-        // todo don't use hardcoded 4095x4095 res assumption
-        let p = Point2::new(p.x*fx + cx, p.y*fy + cy) / 98.0 * 4095.0;
-        let p = p / 4095. - Vector2::new(0.5, 0.5);
-        let p = gravity_rot * p;
-        let p = draw_tf * p;
-        draw_crosshair_rotated(&ctx, &wf_reproj_path, p.x, p.y, 20.);
-        wf_reproj_path.end(&ctx);
-        ctx.stroke(&wf_reproj_path, &solid_brush(0.627, 0.125, 0.941), &thick2);
+    let path = Path::new(ctx, FillMode::Winding);
+    for point in rejected {
+        let p = draw_tf * (gravity_rot * centered(point));
+        draw_diamond(ctx, &path, p.x, p.y, 14., 14.);
+    }
+    path.end(ctx);
+    ctx.stroke(&path, &display.brush((0.6, 0.6, 0.6), 0.6), &StrokeParams {
+        cap: 0, // Bevel
+        join: 0, // Flat
+        thickness: 1.,
+        miter_limit: 0.,
+        dashes: vec![2., 2.],
+        dash_phase: 0.,
+    });
+}
+
+/// Strokes the marker grid, with the marker config's positions mapped onto the 4 chosen markers
+/// in the order they're in.
+fn draw_nf_grid(ctx: &DrawContext, draw_tf: Transform2<f64>, markers_settings: &MarkersSettings, points: &[Point2<f64>], grid_subdivisions: (usize, usize), display: &DisplaySettings) {
+    let top = markers_settings.views[0].marker_top.position;
+    let left = markers_settings.views[0].marker_left.position;
+    let bottom = markers_settings.views[0].marker_bottom.position;
+    let right = markers_settings.views[0].marker_right.position;
+    let transform = ats_cv::get_perspective_transform(
+        Point2::new(rescale(bottom.x as f64), rescale(bottom.y as f64)), // bottom
+        Point2::new(rescale(left.x as f64), rescale(left.y as f64)), // left
+        Point2::new(rescale(top.x as f64), rescale(top.y as f64)), // top
+        Point2::new(rescale(right.x as f64), rescale(right.y as f64)), // right
+        points[0], points[1],
+        points[2], points[3],
+    );
+    let Some(transform) = transform else { return };
+    let path = Path::new(ctx, FillMode::Winding);
+    draw_grid(ctx, &path, grid_subdivisions.0, grid_subdivisions.1, draw_tf.to_homogeneous() * Scale2::new(1./SENSOR_FULL_SCALE, 1./SENSOR_FULL_SCALE).to_homogeneous() * transform);
+    path.end(ctx);
+    ctx.stroke(&path, &display.brush((0.5, 0., 0.), 1.), &StrokeParams {
+        cap: 0, // Bevel
+        join: 0, // Flat
+        thickness: 1.,
+        miter_limit: 0.,
+        dashes: vec![],
+        dash_phase: 0.,
+    });
+}
+
+/// Draws the rectangle quality of the 4 chosen markers next to the top left marker of the grid.
+fn draw_rectangle_quality(ctx: &DrawContext, draw_tf: Transform2<f64>, points: &[Point2<f64>]) {
+    let points: [Point2<f64>; 4] = points[..4].try_into().unwrap();
+    let quality = rectangle_quality(&points);
+    let top_left = points.iter().min_by(|a, b| (a.x + a.y).total_cmp(&(b.x + b.y))).unwrap();
+    let p = draw_tf * centered(top_left);
+    draw_text(
+        ctx,
+        p.x,
+        p.y - 40.0,
+        &format!("angle err = {:.1}°, side ratio = {:.2}", quality.max_angle_error, quality.side_ratio),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use ats_usb::packet::MotData;
+
+    use nalgebra::Point2;
+
+    use super::{choose_rectangle, draw_transform, filter_raw_blobs, raw_centered, CanvasMirror, Overlay};
+
+    #[test]
+    fn test_parse_overlay_order() {
+        let default = Overlay::format_order(&Overlay::DEFAULT_ORDER);
+        assert_eq!(Overlay::parse_order(&default).unwrap(), Overlay::DEFAULT_ORDER);
+        assert_eq!(Overlay::parse_order(" points,grid ").unwrap(), [Overlay::Points, Overlay::Grid]);
+        assert_eq!(Overlay::parse_order("").unwrap(), []);
+        assert!(Overlay::parse_order("grid, crosshair").is_err());
+        assert!(Overlay::parse_order("grid, grid").is_err());
+    }
+
+    #[test]
+    fn test_filter_raw_blobs() {
+        let blob = |area| MotData { area, ..Default::default() };
+        let data = [blob(40), blob(0), blob(3), blob(12), blob(11)];
+
+        let (shown, hidden) = filter_raw_blobs(&data, 12);
+        assert_eq!(shown.as_slice(), [0, 3]);
+        assert_eq!(hidden, 2);
+
+        // The default only skips the empty slots
+        let (shown, hidden) = filter_raw_blobs(&data, 0);
+        assert_eq!(shown.as_slice(), [0, 2, 3, 4]);
+        assert_eq!(hidden, 0);
+    }
+
+    #[test]
+    fn test_canvas_mirror() {
+        let corner = Point2::new(0.25, -0.5);
+        let tf = draw_transform(200., 200., true, CanvasMirror::default());
+        let center = tf * Point2::origin();
+        let p = tf * corner;
+        assert_eq!(center, Point2::new(100., 100.));
+
+        let close = |a: Point2<f64>, b: Point2<f64>| (a - b).norm() < 1e-9;
+        let horizontal = draw_transform(200., 200., true, CanvasMirror { horizontal: true, vertical: false }) * corner;
+        assert!(close(horizontal, Point2::new(2. * center.x - p.x, p.y)), "{horizontal}");
+        let both = draw_transform(200., 200., true, CanvasMirror { horizontal: true, vertical: true }) * corner;
+        assert!(close(both, Point2::new(2. * center.x - p.x, 2. * center.y - p.y)), "{both}");
+    }
+
+    #[test]
+    fn test_choose_rectangle_partitions_points() {
+        let points = [
+            Point2::new(1000., 1000.),
+            Point2::new(3000., 1000.),
+            Point2::new(3000., 3000.),
+            Point2::new(1000., 3000.),
+            Point2::new(2000., 2100.),
+            Point2::new(200., 3900.),
+        ];
+        assert!(choose_rectangle(&points[..3], 0).is_none());
+
+        let choice = choose_rectangle(&points, 0).unwrap();
+        assert_eq!(choice.rejected.len(), 2);
+        let key = |p: &Point2<f64>| (p.x as i64, p.y as i64);
+        let mut all: Vec<_> = choice.chosen.iter().chain(&choice.rejected).map(key).collect();
+        let mut expected: Vec<_> = points.iter().map(key).collect();
+        all.sort();
+        expected.sort();
+        assert_eq!(all, expected);
+    }
+
+    #[test]
+    fn test_raw_centered_resolution() {
+        use nalgebra::Vector2;
+
+        let blob = |cx, cy| MotData { cx, cy, area: 1, ..Default::default() };
+        let close = |a: Point2<f64>, b: Point2<f64>| (a - b).norm() < 1e-9;
+        // A 1024x768 sensor's corners land in the same places as a full scale one's
+        let resolution = Vector2::new(1024, 768);
+        assert!(close(raw_centered(&blob(0, 0), resolution), Point2::new(-0.5, -0.5)));
+        assert!(close(raw_centered(&blob(1023, 767), resolution), Point2::new(0.5, 0.5)));
+        assert!(close(raw_centered(&blob(4095, 0), Vector2::new(4096, 4096)), Point2::new(0.5, -0.5)));
     }
 }
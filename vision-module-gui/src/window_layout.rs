@@ -0,0 +1,143 @@
+//! Remembers the size and position of the app's windows between launches
+
+use std::collections::HashMap;
+
+use iui::controls::Window;
+use iui::UI;
+use serde::{Deserialize, Serialize};
+
+use crate::settings_file;
+
+/// How much of the top of a window has to be on a screen for the user to be able to grab it.
+const MIN_VISIBLE_WIDTH: i32 = 100;
+const MIN_VISIBLE_HEIGHT: i32 = 30;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WindowGeometry {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+/// Saved geometry for each named window.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WindowLayout {
+    pub windows: HashMap<String, WindowGeometry>,
+}
+
+impl WindowLayout {
+    /// Load the layout from `windows.toml` in the config directory, or an empty layout.
+    pub fn load_from_file() -> Self {
+        settings_file::load("windows.toml", toml::from_str)
+    }
+
+    pub fn save_to_file(&self) {
+        settings_file::save("windows.toml", self, "window layout");
+    }
+
+    /// Move and resize `window` to where it was last time, if it was saved.
+    pub fn restore(&self, ui: &UI, name: &str, window: &mut Window) {
+        let Some(&geometry) = self.windows.get(name) else {
+            return;
+        };
+        let geometry = clamp_to_screens(geometry, &screens());
+        window.set_content_size(ui, geometry.width, geometry.height);
+        window.set_position(ui, geometry.x, geometry.y);
+    }
+
+    pub fn remember(&mut self, ui: &UI, name: &str, window: &Window) {
+        let (x, y) = window.position(ui);
+        let (width, height) = window.content_size(ui);
+        self.windows.insert(name.into(), WindowGeometry { x, y, width, height });
+    }
+}
+
+/// If the top of the window isn't on any of `screens`, moves it onto the closest one, shrinking
+/// it if it doesn't fit. A monitor may have been unplugged since the geometry was saved.
+pub fn clamp_to_screens(geometry: WindowGeometry, screens: &[WindowGeometry]) -> WindowGeometry {
+    let g = geometry;
+    let grabbable = screens.iter().any(|s| {
+        let overlap_w = (g.x + g.width).min(s.x + s.width) - g.x.max(s.x);
+        let overlap_h = (g.y + MIN_VISIBLE_HEIGHT).min(s.y + s.height) - g.y.max(s.y);
+        overlap_w >= MIN_VISIBLE_WIDTH.min(g.width) && overlap_h >= MIN_VISIBLE_HEIGHT
+    });
+    if grabbable {
+        return g;
+    }
+    let center = (g.x + g.width / 2, g.y + g.height / 2);
+    let distance = |s: &WindowGeometry| {
+        let dx = (s.x - center.0).max(center.0 - (s.x + s.width)).max(0) as i64;
+        let dy = (s.y - center.1).max(center.1 - (s.y + s.height)).max(0) as i64;
+        dx * dx + dy * dy
+    };
+    let Some(screen) = screens.iter().min_by_key(|s| distance(s)) else {
+        return g;
+    };
+    let width = g.width.min(screen.width);
+    let height = g.height.min(screen.height);
+    WindowGeometry {
+        x: g.x.clamp(screen.x, screen.x + screen.width - width),
+        y: g.y.clamp(screen.y, screen.y + screen.height - height),
+        width,
+        height,
+    }
+}
+
+/// The bounds of each monitor.
+#[cfg(windows)]
+fn screens() -> Vec<WindowGeometry> {
+    use winapi::shared::minwindef::{BOOL, LPARAM, TRUE};
+    use winapi::shared::windef::{HDC, HMONITOR, LPRECT};
+    use winapi::um::winuser::EnumDisplayMonitors;
+
+    unsafe extern "system" fn callback(_: HMONITOR, _: HDC, rect: LPRECT, data: LPARAM) -> BOOL {
+        let screens = &mut *(data as *mut Vec<WindowGeometry>);
+        let r = &*rect;
+        screens.push(WindowGeometry { x: r.left, y: r.top, width: r.right - r.left, height: r.bottom - r.top });
+        TRUE
+    }
+
+    let mut screens = Vec::new();
+    unsafe {
+        EnumDisplayMonitors(std::ptr::null_mut(), std::ptr::null(), Some(callback), &mut screens as *mut _ as LPARAM);
+    }
+    screens
+}
+
+/// libui can't tell us where the monitors are here, and window positions are only a hint on
+/// these platforms anyway, so nothing gets clamped.
+#[cfg(not(windows))]
+fn screens() -> Vec<WindowGeometry> {
+    vec![]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clamp_to_screens, WindowGeometry};
+
+    fn rect(x: i32, y: i32, width: i32, height: i32) -> WindowGeometry {
+        WindowGeometry { x, y, width, height }
+    }
+
+    #[test]
+    fn test_clamp_to_screens() {
+        let screens = [rect(0, 0, 1920, 1080), rect(1920, 0, 2560, 1440)];
+
+        // Already on a screen, even spanning both
+        assert_eq!(clamp_to_screens(rect(100, 100, 640, 480), &screens), rect(100, 100, 640, 480));
+        assert_eq!(clamp_to_screens(rect(1800, 100, 640, 480), &screens), rect(1800, 100, 640, 480));
+
+        // The second monitor was unplugged
+        assert_eq!(clamp_to_screens(rect(3000, 200, 640, 480), &screens[..1]), rect(1280, 200, 640, 480));
+
+        // Title bar above the top of the screen
+        assert_eq!(clamp_to_screens(rect(100, -500, 640, 480), &screens), rect(100, 0, 640, 480));
+
+        // Too big for the screen it ends up on
+        assert_eq!(clamp_to_screens(rect(-3000, 0, 2560, 1440), &screens), rect(0, 0, 1920, 1080));
+
+        // Nothing to clamp to
+        assert_eq!(clamp_to_screens(rect(-3000, 0, 640, 480), &[]), rect(-3000, 0, 640, 480));
+    }
+}